@@ -0,0 +1,19 @@
+use crate::error::Result;
+use crate::AppState;
+use std::time::Duration;
+use tauri::{AppHandle, State};
+use tracing::info;
+
+/// Shut down cleanly, giving network and discovery tasks up to `grace_ms` to finish, then exit
+#[tauri::command]
+pub async fn shutdown(
+    grace_ms: u64,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<()> {
+    info!("Shutdown requested with a {}ms grace period", grace_ms);
+
+    let result = state.shutdown(Duration::from_millis(grace_ms)).await;
+    app.exit(if result.is_ok() { 0 } else { 1 });
+    result
+}
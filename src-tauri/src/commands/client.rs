@@ -1,14 +1,21 @@
 use crate::error::Result;
 use crate::types::ClientInfo;
 use crate::AppState;
-use tauri::State;
+use tauri::{AppHandle, State};
 use tracing::{info, error};
 
+/// Payload carried by each benchmark probe
+const BENCHMARK_PAYLOAD_SIZE: usize = 4096;
+
+/// Longest benchmark a user can request
+const MAX_BENCHMARK_SECONDS: u64 = 60;
+
 /// Connect to a TCP server
 #[tauri::command]
 pub async fn connect_to_server(
     address: String,
     port: u16,
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<ClientInfo> {
     info!("Connecting to server at {}:{}", address, port);
@@ -21,7 +28,19 @@ pub async fn connect_to_server(
 
     // Create new network manager and connect to server
     let (mut manager, _message_sender) = crate::network::NetworkManager::new();
+    let config = state.config.read().await.clone();
+    manager.set_config(&config);
+
+    let logging = config.logging;
+    if logging.journal_enabled {
+        let journal = crate::journal::MessageJournal::open(logging.journal_path(), logging.journal_max_entries)?;
+        manager.enable_journal(journal);
+    }
+
     let client_info = manager.connect_to_server(address.clone(), port).await?;
+    if let Some(receiver) = manager.message_receiver.write().await.take() {
+        crate::commands::message::spawn_inbox(app, &state, receiver);
+    }
     
     // Store the network manager in state
     *network_manager = Some(manager);
@@ -83,23 +102,49 @@ pub fn get_connection_stats(_state: State<'_, AppState>) -> Result<crate::types:
     Ok(crate::types::NetworkStats::default())
 }
 
-/// Test connection to a server
+/// Test connection to a server, giving up after the configured connection timeout
 #[tauri::command]
-pub fn test_connection(address: String, port: u16) -> Result<bool> {
+pub async fn test_connection(address: String, port: u16, state: State<'_, AppState>) -> Result<bool> {
     info!("Testing connection to {}:{}", address, port);
-    
-    match std::net::TcpStream::connect(format!("{}:{}", address, port)) {
-        Ok(_) => {
+    let timeout = std::time::Duration::from_secs(state.config.read().await.network.client.connection_timeout);
+
+    match tokio::time::timeout(timeout, tokio::net::TcpStream::connect((address.as_str(), port))).await {
+        Ok(Ok(_)) => {
             info!("Connection test successful");
             Ok(true)
         },
-        Err(e) => {
+        Ok(Err(e)) => {
             error!("Connection test failed: {}", e);
             Ok(false)
+        },
+        Err(_) => {
+            error!("Connection test timed out after {:?}", timeout);
+            Ok(false)
         }
     }
 }
 
+/// Benchmark the link to `peer` ("host:port", the connected server) for the given number of seconds
+#[tauri::command]
+pub async fn benchmark_peer(peer: String, duration: u64, state: State<'_, AppState>) -> Result<crate::types::BenchmarkReport> {
+    info!("Benchmarking link to {} for {}s", peer, duration);
+
+    let target = crate::network::ConnectionTarget::parse_peer(&peer)?;
+    if duration == 0 || duration > MAX_BENCHMARK_SECONDS {
+        return Err(crate::error::MessengerError::invalid_field(
+            "duration",
+            format!("must be between 1 and {} seconds", MAX_BENCHMARK_SECONDS),
+        ));
+    }
+
+    let network_manager = state.network_manager.read().await;
+    let manager = network_manager.as_ref().ok_or(crate::error::MessengerError::NotConnected)?;
+    let report = manager.benchmark(&target, std::time::Duration::from_secs(duration), BENCHMARK_PAYLOAD_SIZE).await?;
+
+    info!("Benchmark finished: {} messages, {:.0} B/s", report.messages, report.throughput_bytes_per_sec);
+    Ok(report)
+}
+
 /// Get client configuration
 #[tauri::command]
 pub fn get_client_config(_state: State<'_, AppState>) -> Result<crate::config::ClientConfig> {
@@ -139,3 +184,31 @@ pub fn update_discovery_config(
 }
 
 // Discovery functionality moved to discovery module
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tauri::Manager;
+
+    #[tokio::test]
+    async fn test_connection_to_local_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let app = tauri::test::mock_app();
+        app.manage(AppState::new());
+        assert!(test_connection("127.0.0.1".to_string(), port, app.state::<AppState>()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_connection_to_unroutable_address_times_out() {
+        let app = tauri::test::mock_app();
+        app.manage(AppState::new());
+        app.state::<AppState>().config.write().await.network.client.connection_timeout = 1;
+
+        // TEST-NET-1 is reserved for documentation and never routed
+        let started = std::time::Instant::now();
+        assert!(!test_connection("192.0.2.1".to_string(), 9, app.state::<AppState>()).await.unwrap());
+        assert!(started.elapsed() < std::time::Duration::from_secs(3));
+    }
+}
@@ -5,28 +5,47 @@ use tracing::{info, debug};
 
 /// Get application configuration
 #[tauri::command]
-pub fn get_config(_state: State<'_, AppState>) -> Result<crate::config::AppConfig> {
+pub async fn get_config(state: State<'_, AppState>) -> Result<crate::config::AppConfig> {
     debug!("Getting application configuration");
 
-    // For now, return default config
-    // TODO: Implement actual config retrieval
-    Ok(crate::config::AppConfig::default())
+    let config = state.config.read().await;
+    Ok(config.clone())
 }
 
 /// Update application configuration
 #[tauri::command]
-pub fn update_config(
-    _new_config: crate::config::AppConfig,
-    _state: State<'_, AppState>,
+pub async fn update_config(
+    new_config: crate::config::AppConfig,
+    state: State<'_, AppState>,
 ) -> Result<()> {
     info!("Updating application configuration");
 
-    // For now, just return success
-    // TODO: Implement actual config update
+    new_config.validate()?;
+
+    let mut config = state.config.write().await;
+    new_config.save_to_file(&state.config_path)?;
+    *config = new_config;
+
     info!("Configuration updated successfully");
     Ok(())
 }
 
+/// Get the configuration in effect along with where each non-default value came from
+#[tauri::command]
+pub async fn get_effective_config(state: State<'_, AppState>) -> Result<crate::config::EffectiveConfig> {
+    debug!("Resolving effective configuration");
+
+    let path = &state.config_path;
+    let file = if path.exists() {
+        Some(crate::config::AppConfig::load_from_file(path)?)
+    } else {
+        None
+    };
+
+    let runtime = state.config.read().await.clone();
+    crate::config::EffectiveConfig::resolve(file.as_ref(), std::env::vars(), runtime)
+}
+
 /// Get application settings
 #[tauri::command]
 pub fn get_app_settings(_state: State<'_, AppState>) -> Result<crate::config::AppSettings> {
@@ -198,6 +217,38 @@ pub fn save_config_to_file(
     Ok(())
 }
 
+/// Export the shareable parts of the configuration to a profile file
+#[tauri::command]
+pub async fn export_config_profile(
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<()> {
+    info!("Exporting configuration profile to: {}", path);
+
+    let profile = state.config.read().await.to_profile();
+    profile.save_to_file(&std::path::PathBuf::from(path))?;
+
+    info!("Configuration profile exported successfully");
+    Ok(())
+}
+
+/// Import a profile file and merge it into the current configuration
+#[tauri::command]
+pub async fn import_config_profile(
+    path: String,
+    state: State<'_, AppState>,
+) -> Result<crate::config::AppConfig> {
+    info!("Importing configuration profile from: {}", path);
+
+    let profile = crate::config::ConfigProfile::load_from_file(&std::path::PathBuf::from(path))?;
+
+    let mut config = state.config.write().await;
+    config.apply_profile(profile)?;
+
+    info!("Configuration profile imported successfully");
+    Ok(config.clone())
+}
+
 /// Get default configuration file path
 #[tauri::command]
 pub fn get_default_config_path() -> Result<String> {
@@ -252,7 +303,9 @@ pub fn get_config_schema() -> Result<serde_json::Value> {
                             "connection_timeout": {"type": "integer", "minimum": 1},
                             "message_timeout": {"type": "integer", "minimum": 1},
                             "auto_start": {"type": "boolean"},
-                            "bind_all_interfaces": {"type": "boolean"}
+                            "bind_all_interfaces": {"type": "boolean"},
+                            "retry_attempts": {"type": "integer", "minimum": 0},
+                            "retry_delay": {"type": "integer", "minimum": 0}
                         }
                     },
                     "client": {
@@ -263,7 +316,8 @@ pub fn get_config_schema() -> Result<serde_json::Value> {
                             "retry_delay": {"type": "integer", "minimum": 0},
                             "auto_reconnect": {"type": "boolean"},
                             "reconnect_delay": {"type": "integer", "minimum": 1},
-                            "keep_alive": {"type": "boolean"}
+                            "keep_alive": {"type": "boolean"},
+                            "outbox_capacity": {"type": "integer", "minimum": 0}
                         }
                     }
                 }
@@ -272,8 +326,10 @@ pub fn get_config_schema() -> Result<serde_json::Value> {
                 "type": "object",
                 "properties": {
                     "encryption_enabled": {"type": "boolean"},
+                    "cipher": {"type": "string", "enum": ["Aes256Gcm", "ChaCha20Poly1305"]},
                     "key_rotation_interval": {"type": "integer", "minimum": 1},
                     "max_message_size": {"type": "integer", "minimum": 1},
+                    "max_text_length": {"type": "integer", "minimum": 1},
                     "allowed_file_types": {
                         "type": "array",
                         "items": {"type": "string"}
@@ -314,3 +370,55 @@ pub fn get_config_schema() -> Result<serde_json::Value> {
 
     Ok(schema)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tauri::Manager;
+
+    #[tokio::test]
+    async fn test_get_config_returns_live_config() {
+        let app = tauri::test::mock_app();
+        app.manage(AppState::new());
+
+        {
+            let state = app.state::<AppState>();
+            let mut config = state.config.write().await;
+            config.network.server.max_clients = 3;
+            config.ui.compact_mode = true;
+        }
+
+        let config = get_config(app.state::<AppState>()).await.unwrap();
+        assert_eq!(config.network.server.max_clients, 3);
+        assert!(config.ui.compact_mode);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_update_changes_nothing() {
+        let dir = std::env::temp_dir().join(format!("tcp-messenger-update-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.json");
+        crate::config::AppConfig::default().save_to_file(&path).unwrap();
+        let saved = std::fs::read_to_string(&path).unwrap();
+
+        let app = tauri::test::mock_app();
+        app.manage(AppState {
+            config_path: path.clone(),
+            ..AppState::new()
+        });
+
+        let mut rejected = crate::config::AppConfig::default();
+        rejected.network.server.max_clients = 0;
+        assert!(update_config(rejected, app.state::<AppState>()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), saved);
+        assert_ne!(app.state::<AppState>().config.read().await.network.server.max_clients, 0);
+
+        // A valid update is applied and saved to the state's config file
+        let mut accepted = crate::config::AppConfig::default();
+        accepted.network.server.max_clients = 4;
+        update_config(accepted, app.state::<AppState>()).await.unwrap();
+        assert_eq!(app.state::<AppState>().config.read().await.network.server.max_clients, 4);
+        assert_eq!(crate::config::AppConfig::load_from_file(&path).unwrap().network.server.max_clients, 4);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
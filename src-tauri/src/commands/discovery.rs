@@ -1,32 +1,42 @@
 use crate::error::Result;
-use crate::discovery::{NetworkDiscovery, DiscoveredServer};
+use crate::discovery::{NetworkDiscovery, DiscoveredServer, expire_discovered_servers, load_or_create_signing_key, merge_discovered_servers};
 use crate::AppState;
 use tauri::State;
 use tracing::{info, debug};
 
-/// Discover servers on the local network
+/// Discover servers on the local network, adding them to the cache
 #[tauri::command]
 pub async fn discover_servers(
-    _state: State<'_, AppState>,
+    state: State<'_, AppState>,
 ) -> Result<Vec<DiscoveredServer>> {
     info!("Starting server discovery");
 
-    let mut discovery = NetworkDiscovery::default();
+    let mut discovery = NetworkDiscovery::from_config(&state.config.read().await.network.discovery);
     let servers = discovery.discover_servers().await?;
-    
+    merge_discovered_servers(&mut *state.discovered_servers.write().await, servers.clone());
+
     info!("Found {} servers", servers.len());
     Ok(servers)
 }
 
-/// Get discovered servers (cached)
+/// Get the servers found by earlier discovery runs, without broadcasting. Servers not
+/// seen again within the discovery cache TTL are dropped.
 #[tauri::command]
 pub async fn get_discovered_servers(
-    _state: State<'_, AppState>,
+    state: State<'_, AppState>,
 ) -> Result<Vec<DiscoveredServer>> {
-    // For now, return empty list
-    // TODO: Implement caching of discovered servers
     debug!("Getting discovered servers (cached)");
-    Ok(Vec::new())
+
+    let ttl = state.config.read().await.network.discovery.cache_ttl();
+    let mut cache = state.discovered_servers.write().await;
+    let expired = expire_discovered_servers(&mut cache, chrono::Utc::now().timestamp() as u64, ttl);
+    if expired > 0 {
+        debug!("Expired {} discovered servers", expired);
+    }
+
+    let mut servers: Vec<DiscoveredServer> = cache.values().cloned().collect();
+    servers.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+    Ok(servers)
 }
 
 /// Start server announcement
@@ -35,15 +45,23 @@ pub async fn start_server_announcement(
     server_id: String,
     server_name: String,
     server_port: u16,
-    _state: State<'_, AppState>,
+    state: State<'_, AppState>,
 ) -> Result<()> {
     info!("Starting server announcement for {}", server_name);
 
-    let server_uuid = server_id.parse()
-        .map_err(|e| crate::error::MessengerError::InvalidInput(format!("Invalid server ID: {}", e)))?;
+    let server_uuid = server_id.parse::<uuid::Uuid>()
+        .map_err(|e| crate::error::MessengerError::invalid_field("server_id", e.to_string()))?;
+
+    let mut announcer = state.discovery.write().await;
+    if let Some(mut previous) = announcer.take() {
+        previous.stop().await;
+    }
 
-    let discovery = NetworkDiscovery::default();
+    let config = state.config.read().await.clone();
+    let mut discovery = NetworkDiscovery::from_config(&config.network.discovery);
+    discovery.set_signing_key(load_or_create_signing_key(&config.storage.discovery_key_path())?);
     discovery.start_server_announcement(server_uuid, server_name, server_port).await?;
+    *announcer = Some(discovery);
     
     info!("Server announcement started");
     Ok(())
@@ -52,13 +70,95 @@ pub async fn start_server_announcement(
 /// Stop server announcement
 #[tauri::command]
 pub async fn stop_server_announcement(
-    _state: State<'_, AppState>,
+    state: State<'_, AppState>,
 ) -> Result<()> {
     info!("Stopping server announcement");
     
-    // TODO: Implement actual stop functionality
-    // For now, just return success
+    if let Some(mut discovery) = state.discovery.write().await.take() {
+        discovery.stop().await;
+    }
     
     info!("Server announcement stopped");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::{DiscoveryMessage, DiscoveryMessageType};
+    use std::net::UdpSocket;
+    use tauri::Manager;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn test_discovered_servers_are_cached() {
+        // A stand-in server answering discovery requests on its own port
+        let responder = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = responder.local_addr().unwrap().port();
+        let server_id = Uuid::new_v4();
+        std::thread::spawn(move || {
+            let mut buffer = [0u8; 1024];
+            let (size, client) = responder.recv_from(&mut buffer).unwrap();
+            let request: DiscoveryMessage = serde_json::from_slice(&buffer[..size]).unwrap();
+            assert!(matches!(request.message_type, DiscoveryMessageType::ClientRequest));
+            let mut response = DiscoveryMessage {
+                message_type: DiscoveryMessageType::ServerResponse,
+                service_name: request.service_name,
+                server_id,
+                server_name: "Test Server".to_string(),
+                server_port: 8080,
+                timestamp: 0,
+                public_key: None,
+                signature: None,
+            };
+            response.sign(&ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng)).unwrap();
+            responder.send_to(&serde_json::to_vec(&response).unwrap(), client).unwrap();
+        });
+
+        let app = tauri::test::mock_app();
+        app.manage(AppState::new());
+        {
+            let state = app.state::<AppState>();
+            let mut config = state.config.write().await;
+            config.network.discovery.listen_port = port;
+            config.network.discovery.timeout = 1;
+        }
+        assert!(get_discovered_servers(app.state::<AppState>()).await.unwrap().is_empty());
+
+        let discovered = discover_servers(app.state::<AppState>()).await.unwrap();
+        assert_eq!(discovered.len(), 1);
+        assert_eq!(discovered[0].id, server_id);
+
+        let cached = get_discovered_servers(app.state::<AppState>()).await.unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].id, server_id);
+        assert_eq!(cached[0].port, 8080);
+    }
+
+    #[tokio::test]
+    async fn test_stale_servers_drop_out_of_cache() {
+        let app = tauri::test::mock_app();
+        app.manage(AppState::new());
+
+        let state = app.state::<AppState>();
+        let now = chrono::Utc::now().timestamp() as u64;
+        let ttl = state.config.read().await.network.discovery.cache_ttl().as_secs();
+        let server = |last_seen| DiscoveredServer {
+            id: Uuid::new_v4(),
+            name: "Server".to_string(),
+            address: "192.168.1.10".to_string(),
+            port: 8080,
+            discovered_at: last_seen,
+            last_seen,
+            public_key: "key".to_string(),
+        };
+        let stale = server(now - ttl - 60);
+        let fresh = server(now);
+        state.discovered_servers.write().await.extend([(stale.id, stale.clone()), (fresh.id, fresh.clone())]);
+
+        let cached = get_discovered_servers(app.state::<AppState>()).await.unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].id, fresh.id);
+        assert!(!state.discovered_servers.read().await.contains_key(&stale.id));
+    }
+}
@@ -1,19 +1,49 @@
 use crate::error::Result;
-use crate::types::{Message, MessageFilter, MessageSearch, ExportFormat};
+use crate::types::{Message, MessageFilter, MessageStatus, MessageStatusEvent, MessageSearch, MessagePreview, ExportFormat, ExportOptions, ImportConflictPolicy, ImportReport, TimestampFormat, HistogramBucket, SearchResultEvent, SearchCompleteEvent};
+use crate::protocol::ProtocolMessage;
 use crate::AppState;
-use tauri::State;
-use tracing::{info, debug};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter, State};
+use tracing::{info, debug, warn};
 use uuid::Uuid;
 
 /// Send a text message
 #[tauri::command]
 pub async fn send_message(
     content: String,
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<Uuid> {
     info!("Sending message: {}", content);
 
-    let message = Message::new_text(content, Uuid::new_v4());
+    state.config.read().await.validate_text_length(&content)?;
+
+    deliver(Message::new_text(content, state.local_id), &app, &state).await
+}
+
+/// Send a text message to one peer only. On a server, `recipient_id` is the peer id of a
+/// connected client; if that client isn't connected the message is stored as failed.
+#[tauri::command]
+pub async fn send_direct_message(
+    content: String,
+    recipient_id: Uuid,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Uuid> {
+    info!("Sending message to {}: {}", recipient_id, content);
+
+    state.config.read().await.validate_text_length(&content)?;
+
+    let message = Message {
+        recipient_id: Some(recipient_id),
+        ..Message::new_text(content, state.local_id)
+    };
+    deliver(message, &app, &state).await
+}
+
+/// Store an outgoing message, send it and record how that went
+async fn deliver(mut message: Message, app: &AppHandle, state: &AppState) -> Result<Uuid> {
     let message_id = message.id;
 
     // Store message in storage
@@ -22,20 +52,54 @@ pub async fn send_message(
         storage.store_message(message.clone()).await?;
     }
 
-    // Send message through network
+    // Send message through network, keeping the stored copy's status and retry count current
+    let sent = match state.network_manager.read().await.as_ref() {
+        Some(manager) => manager.send_message_with_retry(&mut message).await,
+        None => {
+            message.status = MessageStatus::Failed;
+            Err(crate::error::MessengerError::NotConnected)
+        },
+    };
+    let status = message.status.clone();
     {
-        let network_manager = state.network_manager.read().await;
-        if let Some(manager) = network_manager.as_ref() {
-            manager.send_message(message).await?;
-        } else {
-            return Err(crate::error::MessengerError::NotConnected);
+        // An acknowledgment may already have arrived and moved the stored copy on
+        let mut storage = state.storage.write().await;
+        if storage.get_message(&message_id)?.is_some_and(|stored| stored.status == MessageStatus::Sending) {
+            storage.store_message(message).await?;
         }
     }
 
+    if let Err(e) = sent {
+        // The UI offers a resend for failed messages
+        if let Err(emit_error) = app.emit("message-status-changed", MessageStatusEvent { message_id, status }) {
+            warn!("Failed to emit message status: {}", emit_error);
+        }
+        return Err(e);
+    }
+
     info!("Message sent successfully: {}", message_id);
     Ok(message_id)
 }
 
+/// Preview a text message without sending it
+#[tauri::command]
+pub async fn preview_message(
+    content: String,
+    state: State<'_, AppState>,
+) -> Result<MessagePreview> {
+    debug!("Previewing message of {} bytes", content.len());
+
+    let message = Message::new_text(content, Uuid::new_v4());
+
+    let config = state.config.read().await;
+    ProtocolMessage::preview(
+        &message,
+        config.security.max_message_size,
+        config.security.max_text_length,
+        config.security.encryption_enabled,
+    )
+}
+
 /// Send a system message
 #[tauri::command]
 pub fn send_system_message(
@@ -45,7 +109,7 @@ pub fn send_system_message(
 ) -> Result<Uuid> {
     info!("Sending system message: {}", content);
 
-    let message = Message::new_system(content, level, Uuid::new_v4());
+    let message = Message::new_system(content, level, state.local_id);
     let message_id = message.id;
 
     // For now, just return the message ID
@@ -54,16 +118,17 @@ pub fn send_system_message(
     Ok(message_id)
 }
 
-/// Get all messages
+/// Get a page of messages, newest first; all of them when no limit is given
 #[tauri::command]
 pub async fn get_messages(
     limit: Option<usize>,
+    offset: Option<usize>,
     state: State<'_, AppState>,
 ) -> Result<Vec<Message>> {
-    debug!("Getting messages with limit: {:?}", limit);
+    debug!("Getting messages with limit: {:?}, offset: {:?}", limit, offset);
 
     let storage = state.storage.read().await;
-    let messages: Vec<Message> = storage.get_all_messages().into_iter().cloned().collect();
+    let messages = storage.get_page(offset.unwrap_or(0), limit)?;
     
     debug!("Retrieved {} messages", messages.len());
     Ok(messages)
@@ -71,28 +136,104 @@ pub async fn get_messages(
 
 /// Get messages with filter
 #[tauri::command]
-pub fn get_messages_with_filter(
+pub async fn get_messages_with_filter(
     filter: MessageFilter,
     state: State<'_, AppState>,
 ) -> Result<Vec<Message>> {
     debug!("Getting messages with filter: {:?}", filter);
 
-    // For now, return empty vector
-    // TODO: Implement actual message filtering
-    Ok(Vec::new())
+    let storage = state.storage.read().await;
+    let messages = storage.get_messages_with_filter(&filter)?;
+
+    debug!("Retrieved {} filtered messages", messages.len());
+    Ok(messages)
+}
+
+/// Get the reactions to a message, oldest first
+#[tauri::command]
+pub async fn get_reactions(
+    message_id: Uuid,
+    state: State<'_, AppState>,
+) -> Result<Vec<Message>> {
+    debug!("Getting reactions to message: {}", message_id);
+
+    let storage = state.storage.read().await;
+    storage.get_reactions(&message_id)
 }
 
 /// Search messages
 #[tauri::command]
-pub fn search_messages(
+pub async fn search_messages(
     search: MessageSearch,
     state: State<'_, AppState>,
 ) -> Result<Vec<Message>> {
     debug!("Searching messages with query: {}", search.query);
 
-    // For now, return empty vector
-    // TODO: Implement actual message search
-    Ok(Vec::new())
+    let storage = state.storage.read().await;
+    let messages = storage.search_messages(&search)?;
+
+    debug!("Found {} matching messages", messages.len());
+    Ok(messages)
+}
+
+/// Start a streaming search. Matches are emitted as `search-result` events and a
+/// final `search-complete` event carries the total; returns the search id.
+#[tauri::command]
+pub async fn start_message_search(
+    search: MessageSearch,
+    app: AppHandle,
+    state: State<'_, AppState>,
+) -> Result<Uuid> {
+    let search_id = Uuid::new_v4();
+    debug!("Starting streaming search {} with query: {}", search_id, search.query);
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    state.searches.write().await.insert(search_id, cancelled.clone());
+
+    let storage = state.storage.clone();
+    let searches = state.searches.clone();
+    tokio::spawn(async move {
+        let total = storage.read().await.search_messages_streaming(&search, &cancelled, &mut |message| {
+            let event = SearchResultEvent { search_id, message: message.clone() };
+            if let Err(e) = app.emit("search-result", event) {
+                warn!("Failed to emit search result: {}", e);
+            }
+        });
+        searches.write().await.remove(&search_id);
+        let total = total.unwrap_or_else(|e| {
+            warn!("Streaming search {} failed: {}", search_id, e);
+            Some(0)
+        });
+
+        let complete = SearchCompleteEvent {
+            search_id,
+            total: total.unwrap_or_default(),
+            cancelled: total.is_none(),
+        };
+        debug!("Streaming search {} finished: {:?}", search_id, complete);
+        if let Err(e) = app.emit("search-complete", complete) {
+            warn!("Failed to emit search completion: {}", e);
+        }
+    });
+
+    Ok(search_id)
+}
+
+/// Cancel a streaming search; returns false if it already finished
+#[tauri::command]
+pub async fn cancel_message_search(
+    search_id: Uuid,
+    state: State<'_, AppState>,
+) -> Result<bool> {
+    debug!("Cancelling streaming search {}", search_id);
+
+    match state.searches.read().await.get(&search_id) {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            Ok(true)
+        },
+        None => Ok(false),
+    }
 }
 
 /// Get a specific message by ID
@@ -110,184 +251,274 @@ pub fn get_message(
 
 /// Delete a message
 #[tauri::command]
-pub fn delete_message(
+pub async fn delete_message(
     message_id: Uuid,
     state: State<'_, AppState>,
 ) -> Result<()> {
     info!("Deleting message: {}", message_id);
 
-    // For now, just return success
-    // TODO: Implement actual message deletion
+    let mut storage = state.storage.write().await;
+    storage.delete_message(&message_id).await?;
+
     info!("Message deleted successfully: {}", message_id);
     Ok(())
 }
 
 /// Clear all messages
 #[tauri::command]
-pub fn clear_all_messages(_state: State<'_, AppState>) -> Result<()> {
+pub async fn clear_all_messages(state: State<'_, AppState>) -> Result<()> {
     info!("Clearing all messages");
 
-    // For now, just return success
-    // TODO: Implement actual message clearing
+    let mut storage = state.storage.write().await;
+    storage.clear_all_messages().await?;
+
     info!("All messages cleared successfully");
     Ok(())
 }
 
-/// Send a file
+/// Send a file, emitting `file-transfer-progress` events as chunks go out
 #[tauri::command]
 pub async fn send_file(
     file_path: String,
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<Uuid> {
     info!("Sending file: {}", file_path);
 
-    // Read file metadata
-    let metadata = std::fs::metadata(&file_path)
-        .map_err(|e| crate::error::MessengerError::File(format!("Failed to read file metadata: {}", e)))?;
-
-    let file_name = std::path::Path::new(&file_path)
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("unknown")
-        .to_string();
-
-    let mime_type = mime_guess::from_path(&file_path)
-        .first_or_octet_stream()
-        .to_string();
-
-    // For small files (< 1MB), send in single message
-    let chunk_size = 1024 * 1024; // 1MB chunks
-    if metadata.len() <= chunk_size as u64 {
-        // Read entire file
-        let file_data = std::fs::read(&file_path)
-            .map_err(|e| crate::error::MessengerError::File(format!("Failed to read file: {}", e)))?;
-
-        let message = Message::new_file(
-            file_name,
-            metadata.len(),
-            mime_type,
-            Some(file_data),
-            Uuid::new_v4(),
-        );
-
-        let message_id = message.id;
-
-        // Store and send message
-        {
-            let mut storage = state.storage.write().await;
-            storage.store_message(message.clone()).await?;
+    crate::transfer::send_file(&state, &file_path, |event| {
+        if let Err(e) = app.emit("file-transfer-progress", event) {
+            warn!("Failed to emit file transfer progress: {}", e);
         }
+    }).await
+}
 
-        {
-            let network_manager = state.network_manager.read().await;
-            if let Some(manager) = network_manager.as_ref() {
-                manager.send_message(message).await?;
-            } else {
-                return Err(crate::error::MessengerError::NotConnected);
-            }
-        }
+/// Export messages
+#[tauri::command]
+pub async fn export_messages(
+    format: ExportFormat,
+    include_metadata: Option<bool>,
+    include_system_messages: Option<bool>,
+    timestamp_format: Option<TimestampFormat>,
+    state: State<'_, AppState>,
+) -> Result<String> {
+    info!("Exporting messages in {:?} format", format);
 
-        info!("File sent in single message: {}", message_id);
-        Ok(message_id)
-    } else {
-        // For large files, implement chunking
-        let total_chunks = ((metadata.len() + chunk_size as u64 - 1) / chunk_size as u64) as u32;
-        let file_id = Uuid::new_v4();
-
-        info!("Sending large file in {} chunks", total_chunks);
-
-        let mut file = std::fs::File::open(&file_path)
-            .map_err(|e| crate::error::MessengerError::File(format!("Failed to open file: {}", e)))?;
-
-        for chunk_index in 0..total_chunks {
-            let mut chunk_data = vec![0u8; chunk_size];
-            let bytes_read = std::io::Read::read(&mut file, &mut chunk_data)
-                .map_err(|e| crate::error::MessengerError::File(format!("Failed to read file chunk: {}", e)))?;
-
-            chunk_data.truncate(bytes_read);
-
-            let message = Message {
-                id: Uuid::new_v4(),
-                message_type: crate::types::MessageType::File {
-                    name: file_name.clone(),
-                    size: metadata.len(),
-                    mime_type: mime_type.clone(),
-                    data: Some(chunk_data),
-                    chunk_index: Some(chunk_index),
-                    total_chunks: Some(total_chunks),
-                },
-                timestamp: chrono::Utc::now(),
-                sender_id: Uuid::new_v4(),
-                recipient_id: None,
-                status: crate::types::MessageStatus::Sending,
-                encrypted: false,
-                retry_count: 0,
-                metadata: std::collections::HashMap::new(),
-            };
-
-            // Store and send chunk
-            {
-                let mut storage = state.storage.write().await;
-                storage.store_message(message.clone()).await?;
-            }
+    let options = ExportOptions {
+        format,
+        include_metadata: include_metadata.unwrap_or(true),
+        include_system_messages: include_system_messages.unwrap_or(true),
+        date_range: None,
+        filter: None,
+        timestamp_format: timestamp_format.unwrap_or_default(),
+    };
 
-            {
-                let network_manager = state.network_manager.read().await;
-                if let Some(manager) = network_manager.as_ref() {
-                    manager.send_message(message).await?;
-                } else {
-                    return Err(crate::error::MessengerError::NotConnected);
-                }
-            }
+    let storage = state.storage.read().await;
+    let export_path = storage.export_messages(&options).await?;
 
-            info!("Sent chunk {}/{} of file {}", chunk_index + 1, total_chunks, file_name);
-        }
+    info!("Messages exported to {:?}", export_path);
+    Ok(export_path.to_string_lossy().to_string())
+}
 
-        info!("File sent in chunks: {}", file_id);
-        Ok(file_id)
-    }
+/// Import messages from a JSON export, resolving id conflicts by the given policy (skip by default)
+#[tauri::command]
+pub async fn import_messages(
+    path: String,
+    policy: Option<ImportConflictPolicy>,
+    state: State<'_, AppState>,
+) -> Result<ImportReport> {
+    let policy = policy.unwrap_or_default();
+    info!("Importing messages from {} ({:?} on conflict)", path, policy);
+
+    let messages = crate::storage::MessageStorage::read_archive(std::path::Path::new(&path))?;
+    let mut storage = state.storage.write().await;
+    storage.import_messages(messages, policy).await
 }
 
-/// Export messages
+/// Export messages and return the serialized content, for small exports piped into other tools
 #[tauri::command]
-pub fn export_messages(
+pub async fn export_messages_to_bytes(
     format: ExportFormat,
-    _include_metadata: Option<bool>,
-    _include_system_messages: Option<bool>,
+    include_metadata: Option<bool>,
+    include_system_messages: Option<bool>,
+    timestamp_format: Option<TimestampFormat>,
     state: State<'_, AppState>,
-) -> Result<String> {
-    info!("Exporting messages in {:?} format", format);
+) -> Result<Vec<u8>> {
+    info!("Exporting messages in {:?} format to memory", format);
+
+    let options = ExportOptions {
+        format,
+        include_metadata: include_metadata.unwrap_or(true),
+        include_system_messages: include_system_messages.unwrap_or(true),
+        date_range: None,
+        filter: None,
+        timestamp_format: timestamp_format.unwrap_or_default(),
+    };
+
+    let storage = state.storage.read().await;
+    let messages = storage.select_for_export(&options)?;
+    let messages: Vec<&Message> = messages.iter().collect();
+
+    let mut buffer = BoundedBuffer::new(MAX_INLINE_EXPORT_SIZE);
+    crate::storage::MessageStorage::export_to_writer(&messages, &options.format, options.include_metadata, options.timestamp_format, &mut buffer)
+        .map_err(|e| if buffer.overflowed {
+            crate::error::MessengerError::MessageTooLarge { size: buffer.attempted, max: MAX_INLINE_EXPORT_SIZE }
+        } else {
+            e
+        })?;
+
+    info!("Exported {} messages ({} bytes) to memory", messages.len(), buffer.data.len());
+    Ok(buffer.data)
+}
+
+/// Largest export returned inline by `export_messages_to_bytes`
+const MAX_INLINE_EXPORT_SIZE: usize = 10 * 1024 * 1024;
+
+/// In-memory writer that refuses to grow past a limit
+struct BoundedBuffer {
+    data: Vec<u8>,
+    limit: usize,
+    attempted: usize,
+    overflowed: bool,
+}
+
+impl BoundedBuffer {
+    fn new(limit: usize) -> Self {
+        Self { data: Vec::new(), limit, attempted: 0, overflowed: false }
+    }
+}
+
+impl std::io::Write for BoundedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.attempted = self.data.len() + buf.len();
+        if self.attempted > self.limit {
+            self.overflowed = true;
+            return Err(std::io::Error::other("export exceeds the inline size limit"));
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
 
-    // For now, return a mock path
-    // TODO: Implement actual message export
-    Ok("exported_messages.json".to_string())
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 /// Get message statistics
 #[tauri::command]
-pub fn get_message_stats(_state: State<'_, AppState>) -> Result<crate::storage::StorageStats> {
-    // For now, return default stats
-    // TODO: Implement actual stats retrieval
-    Ok(crate::storage::StorageStats::default())
+pub async fn get_message_stats(state: State<'_, AppState>) -> Result<crate::storage::StorageStats> {
+    let storage = state.storage.read().await;
+    storage.get_stats()
+}
+
+/// Count messages per time bucket over `[start, end)`, including empty buckets
+#[tauri::command]
+pub async fn get_message_histogram(
+    bucket_seconds: u64,
+    start: chrono::DateTime<chrono::Utc>,
+    end: chrono::DateTime<chrono::Utc>,
+    state: State<'_, AppState>,
+) -> Result<Vec<HistogramBucket>> {
+    let storage = state.storage.read().await;
+    storage.message_histogram(std::time::Duration::from_secs(bucket_seconds), start, end)
+}
+
+/// Compact the message file and rebuild the search index
+#[tauri::command]
+pub async fn rebuild_index(state: State<'_, AppState>) -> Result<()> {
+    info!("Compacting storage and rebuilding message index");
+
+    // Compaction rewrites files, so it must not overlap another one
+    state.storage.write().await.compact().await?;
+
+    // Build and verify the new index under a read lock so reads are not blocked
+    let built = state.storage.read().await.build_index()?;
+
+    if let Some((index, generation)) = built {
+        let mut storage = state.storage.write().await;
+        if !storage.replace_index(index, generation) {
+            // Messages changed while building, so rebuild under the write lock
+            storage.rebuild_index()?;
+        }
+    }
+
+    info!("Message index rebuilt successfully");
+    Ok(())
+}
+
+/// Deliver messages arriving on a connection to storage, emitting an event for the UI for
+/// each one that matters (see `InboxEvent::name`), until the connection's channel closes
+pub(crate) fn spawn_inbox(app: AppHandle, state: &AppState, receiver: tokio::sync::mpsc::Receiver<Message>) {
+    let on_event = move |event: crate::inbox::InboxEvent| {
+        if let Err(e) = app.emit(event.name(), &event) {
+            warn!("Failed to emit {} event: {}", event.name(), e);
+        }
+    };
+    tauri::async_runtime::spawn(crate::inbox::run(state.storage.clone(), state.local_id, receiver, on_event));
+}
+
+/// Tell the peer whether we are typing; nothing is stored
+#[tauri::command]
+pub async fn send_typing(
+    is_typing: bool,
+    state: State<'_, AppState>,
+) -> Result<()> {
+    debug!("Sending typing indicator: {}", is_typing);
+
+    match state.network_manager.read().await.as_ref() {
+        Some(manager) => manager.send_message(Message::new_typing(is_typing, state.local_id)).await,
+        None => Err(crate::error::MessengerError::NotConnected),
+    }
 }
 
-/// Mark message as read
+/// Mark a message as read, sending a read receipt to its sender
 #[tauri::command]
-pub fn mark_message_read(
+pub async fn mark_message_read(
     message_id: Uuid,
     state: State<'_, AppState>,
 ) -> Result<()> {
     debug!("Marking message as read: {}", message_id);
 
-    // For now, just return success
-    // TODO: Implement actual message status update
-    Ok(())
+    crate::inbox::mark_read(&state.storage, &state.network_manager, state.local_id, &message_id).await
+}
+
+/// Pin or unpin a message; pinned messages survive history trimming
+#[tauri::command]
+pub async fn set_message_pinned(
+    message_id: Uuid,
+    pinned: bool,
+    state: State<'_, AppState>,
+) -> Result<()> {
+    debug!("Setting message {} pinned: {}", message_id, pinned);
+
+    let mut storage = state.storage.write().await;
+    storage.set_message_pinned(&message_id, pinned).await
+}
+
+/// Delete the oldest unpinned messages until at most `target_max_messages` remain.
+/// Returns the number of messages removed.
+#[tauri::command]
+pub async fn trim_history(
+    target_max_messages: usize,
+    state: State<'_, AppState>,
+) -> Result<usize> {
+    info!("Trimming message history to {} messages", target_max_messages);
+
+    let mut storage = state.storage.write().await;
+    storage.trim_history(target_max_messages).await
+}
+
+/// Delete messages older than the configured retention period. Returns the number removed.
+#[tauri::command]
+pub async fn cleanup_expired_messages(state: State<'_, AppState>) -> Result<usize> {
+    info!("Cleaning up expired messages");
+
+    let mut storage = state.storage.write().await;
+    storage.cleanup_expired_messages().await
 }
 
 /// Get unread message count
 #[tauri::command]
-pub fn get_unread_count(_state: State<'_, AppState>) -> Result<usize> {
-    // For now, return 0
-    // TODO: Implement actual unread count
-    Ok(0)
+pub async fn get_unread_count(state: State<'_, AppState>) -> Result<usize> {
+    let storage = state.storage.read().await;
+    storage.get_unread_count(&state.local_id)
 }
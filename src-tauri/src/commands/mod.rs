@@ -3,3 +3,4 @@ pub mod client;
 pub mod message;
 pub mod config;
 pub mod discovery;
+pub mod app;
@@ -1,13 +1,15 @@
 use crate::error::Result;
-use crate::types::ServerInfo;
+use crate::types::{ConnectionAttempt, PeerStats, ServerInfo};
 use crate::AppState;
-use tauri::State;
+use tauri::{AppHandle, State};
 use tracing::info;
+use uuid::Uuid;
 
 /// Start a TCP server
 #[tauri::command]
 pub async fn start_server(
     port: Option<u16>,
+    app: AppHandle,
     state: State<'_, AppState>,
 ) -> Result<ServerInfo> {
     info!("Starting TCP server on port {:?}", port);
@@ -20,7 +22,22 @@ pub async fn start_server(
 
     // Create new network manager and start server
     let (mut manager, _message_sender) = crate::network::NetworkManager::new();
+
+    let config = state.config.read().await.clone();
+    manager.set_config(&config);
+
+    let logging = config.logging;
+    if logging.journal_enabled {
+        let journal = crate::journal::MessageJournal::open(logging.journal_path(), logging.journal_max_entries)?;
+        manager.enable_journal(journal);
+    }
+
+    manager.key_manager.write().await.load_or_create_identity(&config.storage.identity_path())?;
+
     let server_info = manager.start_server(port).await?;
+    if let Some(receiver) = manager.message_receiver.write().await.take() {
+        crate::commands::message::spawn_inbox(app, &state, receiver);
+    }
     
     // Store the network manager in state
     *network_manager = Some(manager);
@@ -52,7 +69,7 @@ pub async fn get_server_status(state: State<'_, AppState>) -> Result<Option<Serv
     let network_manager = state.network_manager.read().await;
     
     if let Some(manager) = network_manager.as_ref() {
-        Ok(manager.server_info.clone())
+        Ok(manager.get_server_info().await)
     } else {
         Ok(None)
     }
@@ -66,6 +83,31 @@ pub fn get_server_stats(_state: State<'_, AppState>) -> Result<crate::types::Net
     Ok(crate::types::NetworkStats::default())
 }
 
+/// Get statistics for a single connected peer
+#[tauri::command]
+pub async fn get_peer_stats(
+    peer_id: Uuid,
+    state: State<'_, AppState>,
+) -> Result<PeerStats> {
+    let network_manager = state.network_manager.read().await;
+
+    match network_manager.as_ref() {
+        Some(manager) => manager.get_peer_stats(&peer_id).await,
+        None => Err(crate::error::MessengerError::ServerNotRunning),
+    }
+}
+
+/// Get the audit trail of incoming connection attempts
+#[tauri::command]
+pub async fn get_connection_audit(state: State<'_, AppState>) -> Result<Vec<ConnectionAttempt>> {
+    let network_manager = state.network_manager.read().await;
+
+    match network_manager.as_ref() {
+        Some(manager) => Ok(manager.get_connection_audit().await),
+        None => Ok(Vec::new()),
+    }
+}
+
 /// Check if server is running
 #[tauri::command]
 pub fn is_server_running(_state: State<'_, AppState>) -> Result<bool> {
@@ -100,3 +142,18 @@ pub fn update_server_config(
     // TODO: Implement actual config update
     Ok(())
 }
+
+/// Get the fingerprint of this node's long-term identity key, creating the key on first use
+#[tauri::command]
+pub async fn get_identity_fingerprint(state: State<'_, AppState>) -> Result<String> {
+    if let Some(manager) = state.network_manager.read().await.as_ref() {
+        if let Ok(fingerprint) = manager.key_manager.read().await.fingerprint() {
+            return Ok(fingerprint);
+        }
+    }
+
+    let path = state.config.read().await.storage.identity_path();
+    let mut key_manager = crate::encryption::KeyExchangeManager::new(0);
+    key_manager.load_or_create_identity(&path)?;
+    key_manager.fingerprint()
+}
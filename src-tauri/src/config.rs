@@ -1,7 +1,55 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use crate::error::{MessengerError, Result};
+use crate::encryption::CipherSuite;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Length of a text message as a user would count it: grapheme clusters, so "é" written
+/// as "e" plus a combining accent, or a family emoji, each count once
+pub fn text_length(content: &str) -> usize {
+    content.graphemes(true).count()
+}
+
+/// Environment variable that overrides where application data is stored
+pub const DATA_DIR_ENV: &str = "TCP_MESSENGER_DATA_DIR";
+
+/// File beside the executable that switches on portable mode
+pub const PORTABLE_MARKER: &str = "portable";
+
+/// Resolve the application data directory. In order of precedence:
+/// 1. the `TCP_MESSENGER_DATA_DIR` environment variable
+/// 2. portable mode: a `portable` file beside the executable keeps data in `data/` next to it
+/// 3. the configured `data_directory`, if any
+/// 4. the platform data directory, e.g. `~/.local/share/tcp-messenger`
+pub fn resolve_data_directory(configured: Option<&Path>) -> PathBuf {
+    let executable_dir = std::env::current_exe().ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf));
+    resolve_data_directory_from(std::env::var_os(DATA_DIR_ENV), executable_dir.as_deref(), configured)
+}
+
+fn resolve_data_directory_from(
+    env_override: Option<std::ffi::OsString>,
+    executable_dir: Option<&Path>,
+    configured: Option<&Path>,
+) -> PathBuf {
+    if let Some(dir) = env_override.filter(|dir| !dir.is_empty()) {
+        return PathBuf::from(dir);
+    }
+
+    if let Some(exe_dir) = executable_dir.filter(|dir| dir.join(PORTABLE_MARKER).is_file()) {
+        return exe_dir.join("data");
+    }
+
+    if let Some(dir) = configured {
+        return dir.to_path_buf();
+    }
+
+    let mut data_dir = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
+    data_dir.push("tcp-messenger");
+    data_dir
+}
 
 /// Main application configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,6 +75,117 @@ impl Default for AppConfig {
     }
 }
 
+/// Prefix of environment variables that override config values,
+/// e.g. `TCP_MESSENGER__NETWORK__SERVER__MAX_CLIENTS=4`
+pub const ENV_PREFIX: &str = "TCP_MESSENGER__";
+
+/// Where an effective config value came from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum ConfigSource {
+    Default,
+    File,
+    Env,
+    Runtime,
+}
+
+/// Fully resolved configuration with the source of every non-default value
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EffectiveConfig {
+    pub config: AppConfig,
+    pub sources: BTreeMap<String, ConfigSource>,
+}
+
+impl EffectiveConfig {
+    /// Work out which layer each value in the running config came from.
+    /// Layers apply in order: defaults, config file, environment, runtime updates.
+    pub fn resolve<I>(file: Option<&AppConfig>, env: I, runtime: AppConfig) -> Result<Self>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let defaults = flatten_config(&AppConfig::default())?;
+        let file_layer = file.cloned().unwrap_or_default();
+        let from_file = flatten_config(&file_layer)?;
+
+        let mut env_layer = file_layer;
+        let env_fields = env_layer.apply_env_overrides(env)?;
+        let from_env = flatten_config(&env_layer)?;
+
+        let mut sources = BTreeMap::new();
+        for (path, value) in flatten_config(&runtime)? {
+            let source = if from_env.get(&path) != Some(&value) {
+                ConfigSource::Runtime
+            } else if env_fields.contains(&path) {
+                ConfigSource::Env
+            } else if from_file.get(&path) != defaults.get(&path) {
+                ConfigSource::File
+            } else {
+                continue;
+            };
+            sources.insert(path, source);
+        }
+
+        Ok(Self {
+            config: runtime,
+            sources,
+        })
+    }
+}
+
+/// Flatten a config into dotted paths such as "network.server.max_clients"
+fn flatten_config(config: &AppConfig) -> Result<BTreeMap<String, serde_json::Value>> {
+    fn flatten(value: serde_json::Value, prefix: String, out: &mut BTreeMap<String, serde_json::Value>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, child) in map {
+                    let path = if prefix.is_empty() { key } else { format!("{}.{}", prefix, key) };
+                    flatten(child, path, out);
+                }
+            },
+            leaf => {
+                out.insert(prefix, leaf);
+            },
+        }
+    }
+
+    let value = serde_json::to_value(config)
+        .map_err(|e| MessengerError::Config(format!("Failed to serialize config: {}", e)))?;
+    let mut out = BTreeMap::new();
+    flatten(value, String::new(), &mut out);
+    Ok(out)
+}
+
+/// Shareable subset of the configuration for setting up other devices.
+/// Only sections without secrets or device-specific paths belong here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigProfile {
+    pub app: AppSettings,
+    pub network: NetworkConfig,
+    pub security: SecurityConfig,
+    pub ui: UiConfig,
+}
+
+impl ConfigProfile {
+    /// Load a profile from file
+    pub fn load_from_file(path: &PathBuf) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| MessengerError::Config(format!("Failed to read config profile: {}", e)))?;
+
+        serde_json::from_str(&content)
+            .map_err(|e| MessengerError::Config(format!("Failed to parse config profile: {}", e)))
+    }
+
+    /// Save the profile to file
+    pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)
+            .map_err(|e| MessengerError::Config(format!("Failed to serialize config profile: {}", e)))?;
+
+        std::fs::write(path, content)
+            .map_err(|e| MessengerError::Config(format!("Failed to write config profile: {}", e)))?;
+
+        Ok(())
+    }
+}
+
 /// Application settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppSettings {
@@ -57,6 +216,14 @@ pub struct NetworkConfig {
     pub server: ServerConfig,
     pub client: ClientConfig,
     pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub acknowledgment: AcknowledgmentConfig,
+    #[serde(default = "default_close_linger")]
+    pub close_linger: u64, // milliseconds
+}
+
+fn default_close_linger() -> u64 {
+    500
 }
 
 impl Default for NetworkConfig {
@@ -65,6 +232,8 @@ impl Default for NetworkConfig {
             server: ServerConfig::default(),
             client: ClientConfig::default(),
             discovery: DiscoveryConfig::default(),
+            acknowledgment: AcknowledgmentConfig::default(),
+            close_linger: default_close_linger(),
         }
     }
 }
@@ -79,6 +248,19 @@ pub struct ServerConfig {
     pub message_timeout: u64, // seconds
     pub auto_start: bool,
     pub bind_all_interfaces: bool,
+    /// Times a message that fails to go out is resent before it is marked failed
+    #[serde(default = "default_send_retry_attempts")]
+    pub retry_attempts: u32,
+    #[serde(default = "default_send_retry_delay")]
+    pub retry_delay: u64, // milliseconds
+}
+
+fn default_send_retry_attempts() -> u32 {
+    3
+}
+
+fn default_send_retry_delay() -> u64 {
+    1000
 }
 
 impl Default for ServerConfig {
@@ -91,6 +273,8 @@ impl Default for ServerConfig {
             message_timeout: 5,
             auto_start: false,
             bind_all_interfaces: true,
+            retry_attempts: default_send_retry_attempts(),
+            retry_delay: default_send_retry_delay(),
         }
     }
 }
@@ -104,6 +288,13 @@ pub struct ClientConfig {
     pub auto_reconnect: bool,
     pub reconnect_delay: u64, // seconds
     pub keep_alive: bool,
+    /// Messages held while the connection is down before sends are refused
+    #[serde(default = "default_outbox_capacity")]
+    pub outbox_capacity: usize,
+}
+
+fn default_outbox_capacity() -> usize {
+    100
 }
 
 impl Default for ClientConfig {
@@ -115,6 +306,7 @@ impl Default for ClientConfig {
             auto_reconnect: true,
             reconnect_delay: 5,
             keep_alive: true,
+            outbox_capacity: default_outbox_capacity(),
         }
     }
 }
@@ -127,6 +319,18 @@ pub struct DiscoveryConfig {
     pub listen_port: u16,
     pub service_name: String,
     pub timeout: u64, // seconds
+    #[serde(default)]
+    pub address_family: crate::discovery::AddressFamily,
+}
+
+/// Missed announcement rounds after which a discovered server is forgotten
+const DISCOVERY_TTL_ROUNDS: u32 = 3;
+
+impl DiscoveryConfig {
+    /// How long a discovered server stays cached without being seen again
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.broadcast_interval.max(self.timeout)) * DISCOVERY_TTL_ROUNDS
+    }
 }
 
 impl Default for DiscoveryConfig {
@@ -137,20 +341,52 @@ impl Default for DiscoveryConfig {
             listen_port: 9000,
             service_name: "tcp-messenger".to_string(),
             timeout: 5,
+            address_family: crate::discovery::AddressFamily::default(),
         }
     }
 }
 
+/// Acknowledgment tracking configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcknowledgmentConfig {
+    pub max_pending: usize, // per connection
+    pub pending_policy: PendingAckPolicy,
+}
+
+impl Default for AcknowledgmentConfig {
+    fn default() -> Self {
+        Self {
+            max_pending: 100,
+            pending_policy: PendingAckPolicy::Backpressure,
+        }
+    }
+}
+
+/// What to do when a connection has too many unacknowledged messages
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum PendingAckPolicy {
+    /// Refuse to send until acknowledgments arrive
+    Backpressure,
+    /// Fail the oldest pending message to make room
+    DropOldest,
+}
+
 /// Security configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SecurityConfig {
     pub encryption_enabled: bool,
     pub key_rotation_interval: u32, // number of messages
     pub max_message_size: usize, // bytes
+    #[serde(default = "default_max_text_length")]
+    pub max_text_length: usize, // grapheme clusters, see `text_length`
     pub allowed_file_types: HashSet<String>,
     pub max_file_size: u64, // bytes
     pub require_authentication: bool,
     pub session_timeout: u64, // seconds
+    #[serde(default = "default_replay_window")]
+    pub replay_window: u64, // messages
+    #[serde(default)]
+    pub cipher: CipherSuite,
 }
 
 impl Default for SecurityConfig {
@@ -169,15 +405,26 @@ impl Default for SecurityConfig {
         Self {
             encryption_enabled: true,
             key_rotation_interval: 100,
-            max_message_size: 1024 * 1024, // 1MB
+            max_message_size: crate::protocol::DEFAULT_MAX_MESSAGE_SIZE, // 1MB
+            max_text_length: default_max_text_length(),
             allowed_file_types: allowed_types,
             max_file_size: 100 * 1024 * 1024, // 100MB
             require_authentication: true,
             session_timeout: 3600, // 1 hour
+            replay_window: default_replay_window(),
+            cipher: CipherSuite::default(),
         }
     }
 }
 
+fn default_replay_window() -> u64 {
+    crate::encryption::DEFAULT_REPLAY_WINDOW
+}
+
+fn default_max_text_length() -> usize {
+    4000
+}
+
 /// UI configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UiConfig {
@@ -239,25 +486,42 @@ pub struct StorageConfig {
     pub backup_enabled: bool,
     pub backup_interval: u64, // hours
     pub max_backup_files: u32,
+    #[serde(default)]
+    pub backend: crate::storage::StorageBackendKind,
 }
 
 impl Default for StorageConfig {
     fn default() -> Self {
-        let mut data_dir = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
-        data_dir.push("tcp-messenger");
-
         Self {
-            data_directory: data_dir,
+            data_directory: resolve_data_directory(None),
             max_messages: 10000,
             message_retention_days: 30,
             enable_compression: true,
             backup_enabled: true,
             backup_interval: 24,
             max_backup_files: 7,
+            backend: crate::storage::StorageBackendKind::default(),
         }
     }
 }
 
+impl StorageConfig {
+    /// Path of the node's long-term identity key, inside the data directory
+    pub fn identity_path(&self) -> PathBuf {
+        resolve_data_directory(Some(&self.data_directory)).join("identity.key")
+    }
+
+    /// Path of the saved local user id, inside the data directory
+    pub fn local_id_path(&self) -> PathBuf {
+        resolve_data_directory(Some(&self.data_directory)).join("local_id")
+    }
+
+    /// Path of the key used to sign discovery announcements, inside the data directory
+    pub fn discovery_key_path(&self) -> PathBuf {
+        resolve_data_directory(Some(&self.data_directory)).join("discovery.key")
+    }
+}
+
 /// Logging configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggingConfig {
@@ -268,12 +532,15 @@ pub struct LoggingConfig {
     pub max_log_size: u64, // bytes
     pub max_log_files: u32,
     pub log_format: LogFormat,
+    #[serde(default)]
+    pub journal_enabled: bool,
+    #[serde(default = "default_journal_max_entries")]
+    pub journal_max_entries: usize,
 }
 
 impl Default for LoggingConfig {
     fn default() -> Self {
-        let mut log_file = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
-        log_file.push("tcp-messenger");
+        let mut log_file = resolve_data_directory(None);
         log_file.push("logs");
         log_file.push("app.log");
 
@@ -285,10 +552,27 @@ impl Default for LoggingConfig {
             max_log_size: 10 * 1024 * 1024, // 10MB
             max_log_files: 5,
             log_format: LogFormat::Json,
+            journal_enabled: false,
+            journal_max_entries: default_journal_max_entries(),
         }
     }
 }
 
+fn default_journal_max_entries() -> usize {
+    1000
+}
+
+impl LoggingConfig {
+    /// Path of the protocol message journal, next to the log file
+    pub fn journal_path(&self) -> PathBuf {
+        let mut path = self.log_file.parent()
+            .map(|p| p.to_path_buf())
+            .unwrap_or_else(|| PathBuf::from("."));
+        path.push("journal.jsonl");
+        path
+    }
+}
+
 /// Log levels
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum LogLevel {
@@ -340,31 +624,102 @@ impl AppConfig {
         Ok(())
     }
 
+    /// Apply `TCP_MESSENGER__*` overrides from the given environment variables.
+    /// Returns the dotted paths of the fields that were overridden.
+    pub fn apply_env_overrides<I>(&mut self, vars: I) -> Result<Vec<String>>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut tree = serde_json::to_value(&*self)
+            .map_err(|e| MessengerError::Config(format!("Failed to serialize config: {}", e)))?;
+        let mut overridden = Vec::new();
+
+        for (name, raw) in vars {
+            let Some(field) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let path: Vec<String> = field.split("__").map(|part| part.to_lowercase()).collect();
+            let dotted = path.join(".");
+
+            let slot = path.iter().try_fold(&mut tree, |node, key| node.get_mut(key.as_str()))
+                .ok_or_else(|| MessengerError::invalid_field(&dotted, format!("unknown configuration field set by {}", name)))?;
+
+            // Values are JSON where possible, so numbers and booleans keep their types
+            *slot = serde_json::from_str(&raw).unwrap_or(serde_json::Value::String(raw));
+            overridden.push(dotted);
+        }
+
+        *self = serde_json::from_value(tree)
+            .map_err(|e| MessengerError::Config(format!("Invalid environment override: {}", e)))?;
+        Ok(overridden)
+    }
+
+    /// Get the shareable profile of this configuration
+    pub fn to_profile(&self) -> ConfigProfile {
+        ConfigProfile {
+            app: self.app.clone(),
+            network: self.network.clone(),
+            security: self.security.clone(),
+            ui: self.ui.clone(),
+        }
+    }
+
+    /// Merge a profile into this configuration, leaving it unchanged if the result is invalid
+    pub fn apply_profile(&mut self, profile: ConfigProfile) -> Result<()> {
+        let merged = AppConfig {
+            app: profile.app,
+            network: profile.network,
+            security: profile.security,
+            ui: profile.ui,
+            storage: self.storage.clone(),
+            logging: self.logging.clone(),
+        };
+        merged.validate()?;
+
+        *self = merged;
+        Ok(())
+    }
+
     /// Validate configuration
     pub fn validate(&self) -> Result<()> {
         // Validate port range
         if self.network.server.port_range.0 >= self.network.server.port_range.1 {
-            return Err(MessengerError::Config("Invalid port range".to_string()));
+            return Err(MessengerError::invalid_field("network.server.port_range", "start port must be below end port"));
         }
 
         // Validate max clients
         if self.network.server.max_clients == 0 {
-            return Err(MessengerError::Config("Max clients must be greater than 0".to_string()));
+            return Err(MessengerError::invalid_field("network.server.max_clients", "must be greater than 0"));
+        }
+
+        // Validate pending acknowledgments
+        if self.network.acknowledgment.max_pending == 0 {
+            return Err(MessengerError::invalid_field("network.acknowledgment.max_pending", "must be greater than 0"));
         }
 
         // Validate message size
         if self.security.max_message_size == 0 {
-            return Err(MessengerError::Config("Max message size must be greater than 0".to_string()));
+            return Err(MessengerError::invalid_field("security.max_message_size", "must be greater than 0"));
+        }
+
+        // Validate text length
+        if self.security.max_text_length == 0 {
+            return Err(MessengerError::invalid_field("security.max_text_length", "must be greater than 0"));
+        }
+
+        // Validate replay window
+        if self.security.replay_window == 0 {
+            return Err(MessengerError::invalid_field("security.replay_window", "must be greater than 0"));
         }
 
         // Validate file size
         if self.security.max_file_size == 0 {
-            return Err(MessengerError::Config("Max file size must be greater than 0".to_string()));
+            return Err(MessengerError::invalid_field("security.max_file_size", "must be greater than 0"));
         }
 
         // Validate retention days
         if self.storage.message_retention_days == 0 {
-            return Err(MessengerError::Config("Message retention days must be greater than 0".to_string()));
+            return Err(MessengerError::invalid_field("storage.message_retention_days", "must be greater than 0"));
         }
 
         Ok(())
@@ -388,6 +743,17 @@ impl AppConfig {
         }
     }
 
+    /// Check that a text message is within the configured character limit
+    pub fn validate_text_length(&self, content: &str) -> Result<()> {
+        let length = text_length(content);
+        if length > self.security.max_text_length {
+            return Err(MessengerError::InvalidInput(
+                format!("Message is {} characters long (max: {})", length, self.security.max_text_length),
+            ));
+        }
+        Ok(())
+    }
+
     /// Get the next available port in the range
     pub fn get_next_available_port(&self) -> Result<u16> {
         use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
@@ -405,3 +771,158 @@ impl AppConfig {
         Err(MessengerError::Config("No available ports in range".to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_length_counts_graphemes() {
+        let mut config = AppConfig::default();
+        config.security.max_text_length = 5;
+
+        // Five characters, but far more than five bytes
+        let at_limit = "héllö";
+        assert!(at_limit.len() > 5);
+        assert!(config.validate_text_length(at_limit).is_ok());
+        assert!(config.validate_text_length("日本語🎉✓").is_ok());
+
+        // Characters made of several scalar values count once each
+        let combined = "he\u{301}llo\u{308}";
+        assert_eq!(combined.chars().count(), 7);
+        assert!(config.validate_text_length(combined).is_ok());
+        let family = "👨\u{200d}👩\u{200d}👧".repeat(5);
+        assert!(config.validate_text_length(&family).is_ok());
+
+        let over_limit = "héllö!";
+        assert!(matches!(config.validate_text_length(over_limit), Err(MessengerError::InvalidInput(_))));
+        assert!(matches!(config.validate_text_length(&format!("{}!", family)), Err(MessengerError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_profile_round_trip_without_secrets() {
+        let path = std::env::temp_dir().join(format!("tcp-messenger-profile-{}.json", uuid::Uuid::new_v4()));
+
+        let mut source = AppConfig::default();
+        source.network.server.max_clients = 8;
+        source.ui.theme = Theme::Dark;
+        source.to_profile().save_to_file(&path).unwrap();
+
+        // Walk every key in the exported file
+        fn collect_keys(value: &serde_json::Value, keys: &mut Vec<String>) {
+            if let serde_json::Value::Object(map) = value {
+                for (key, child) in map {
+                    keys.push(key.to_lowercase());
+                    collect_keys(child, keys);
+                }
+            }
+        }
+        let exported: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        let mut keys = Vec::new();
+        collect_keys(&exported, &mut keys);
+        for secret in ["psk", "secret", "private", "identity", "password"] {
+            assert!(!keys.iter().any(|key| key.contains(secret)), "profile contains a '{}' field", secret);
+        }
+        assert!(!keys.contains(&"storage".to_string()));
+        assert!(!keys.contains(&"logging".to_string()));
+
+        // Import keeps this device's storage settings
+        let mut target = AppConfig::default();
+        target.storage.data_directory = PathBuf::from("/tmp/other-device");
+        target.apply_profile(ConfigProfile::load_from_file(&path).unwrap()).unwrap();
+        assert_eq!(target.network.server.max_clients, 8);
+        assert_eq!(target.ui.theme, Theme::Dark);
+        assert_eq!(target.storage.data_directory, PathBuf::from("/tmp/other-device"));
+
+        // Invalid profiles are rejected without touching the config
+        let mut invalid = source.to_profile();
+        invalid.network.server.max_clients = 0;
+        assert!(target.apply_profile(invalid).is_err());
+        assert_eq!(target.network.server.max_clients, 8);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_saved_config_survives_reload() {
+        let path = std::env::temp_dir()
+            .join(format!("tcp-messenger-config-{}", uuid::Uuid::new_v4()))
+            .join("config.json");
+
+        let mut config = AppConfig::default();
+        config.network.server.max_clients = 4;
+        config.security.max_text_length = 280;
+        config.ui.compact_mode = true;
+        config.validate().unwrap();
+        config.save_to_file(&path).unwrap();
+
+        let reloaded = AppConfig::load_from_file(&path).unwrap();
+        assert_eq!(reloaded.network.server.max_clients, 4);
+        assert_eq!(reloaded.security.max_text_length, 280);
+        assert!(reloaded.ui.compact_mode);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_effective_config_reports_sources() {
+        let mut file = AppConfig::default();
+        file.ui.compact_mode = true;
+
+        let env = vec![
+            ("TCP_MESSENGER__NETWORK__SERVER__MAX_CLIENTS".to_string(), "4".to_string()),
+            ("UNRELATED".to_string(), "ignored".to_string()),
+        ];
+
+        let mut runtime = file.clone();
+        runtime.apply_env_overrides(env.clone()).unwrap();
+        assert_eq!(runtime.network.server.max_clients, 4);
+        runtime.security.max_text_length = 280;
+
+        let effective = EffectiveConfig::resolve(Some(&file), env, runtime).unwrap();
+        assert_eq!(effective.config.network.server.max_clients, 4);
+        assert_eq!(effective.sources.get("network.server.max_clients"), Some(&ConfigSource::Env));
+        assert_eq!(effective.sources.get("ui.compact_mode"), Some(&ConfigSource::File));
+        assert_eq!(effective.sources.get("security.max_text_length"), Some(&ConfigSource::Runtime));
+        assert_eq!(effective.sources.get("ui.theme"), None);
+
+        // Unknown fields and mistyped values are rejected
+        let mut config = AppConfig::default();
+        assert!(config.apply_env_overrides(vec![("TCP_MESSENGER__NETWORK__NOPE".to_string(), "1".to_string())]).is_err());
+        assert!(config.apply_env_overrides(vec![("TCP_MESSENGER__NETWORK__SERVER__MAX_CLIENTS".to_string(), "many".to_string())]).is_err());
+    }
+
+    #[test]
+    fn test_data_directory_precedence() {
+        let dir = std::env::temp_dir().join(format!("tcp-messenger-portable-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let configured = PathBuf::from("/srv/messenger");
+
+        // The env override wins over everything, including the platform default
+        let platform_default = resolve_data_directory_from(None, None, None);
+        let overridden = resolve_data_directory_from(Some("/tmp/override".into()), Some(&dir), Some(&configured));
+        assert_eq!(overridden, PathBuf::from("/tmp/override"));
+        assert_ne!(overridden, platform_default);
+
+        // Without a portable marker the configured directory is used
+        assert_eq!(resolve_data_directory_from(None, Some(&dir), Some(&configured)), configured);
+
+        // Portable mode keeps data beside the executable
+        std::fs::write(dir.join(PORTABLE_MARKER), "").unwrap();
+        assert_eq!(resolve_data_directory_from(None, Some(&dir), Some(&configured)), dir.join("data"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validation_names_field() {
+        let mut config = AppConfig::default();
+        assert!(config.validate().is_ok());
+
+        config.network.server.max_clients = 0;
+        match config.validate() {
+            Err(MessengerError::InvalidField { field, .. }) => assert_eq!(field, "network.server.max_clients"),
+            other => panic!("Expected InvalidField, got {:?}", other),
+        }
+    }
+}
@@ -1,26 +1,68 @@
+use crate::config::DiscoveryConfig;
 use crate::error::{MessengerError, Result};
-use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
-use std::time::{Duration, Instant};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tracing::{info, debug, warn};
 use uuid::Uuid;
 
+/// Pause between announcements when no discovery settings are given
+const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Link-local multicast group IPv6 discovery traffic is sent to
+pub const DISCOVERY_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+/// IP versions discovery runs over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AddressFamily {
+    /// IPv4 broadcast only
+    Ipv4,
+    /// IPv6 link-local multicast only
+    Ipv6,
+    /// Both, over one socket, falling back to IPv4 where IPv6 is unavailable
+    #[default]
+    DualStack,
+}
+
 /// Discovery service for finding servers on the local network
+#[derive(Debug)]
 pub struct NetworkDiscovery {
     broadcast_port: u16,
     service_name: String,
     timeout: Duration,
+    announce_interval: Duration,
+    address_family: AddressFamily,
+    /// Key announcements and responses are signed with
+    signing_key: SigningKey,
+    /// This server's own identity, set once it starts announcing
+    announcement: Option<DiscoveryMessage>,
     socket: Option<UdpSocket>,
+    shutdown: Option<watch::Sender<bool>>,
+    announcement_task: Option<JoinHandle<()>>,
 }
 
 /// Discovery message sent over UDP
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveryMessage {
     pub message_type: DiscoveryMessageType,
+    /// Name of the service the sender belongs to; peers only talk to their own service
+    pub service_name: String,
     pub server_id: Uuid,
     pub server_name: String,
     pub server_port: u16,
     pub timestamp: u64,
+    /// Sender's Ed25519 public key, base64 encoded. Client requests are unsigned.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Signature over every other field, made with `public_key`
+    #[serde(default)]
+    pub signature: Option<String>,
 }
 
 /// Type of discovery message
@@ -43,6 +85,52 @@ pub struct DiscoveredServer {
     pub port: u16,
     pub discovered_at: u64,  // Unix timestamp
     pub last_seen: u64,      // Unix timestamp
+    /// Key the server signs its announcements with, base64 encoded, for trust on first use
+    pub public_key: String,
+}
+
+impl DiscoveryMessage {
+    /// Bytes covered by the signature
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        let fields = (
+            &self.message_type,
+            &self.service_name,
+            &self.server_id,
+            &self.server_name,
+            self.server_port,
+            self.timestamp,
+            &self.public_key,
+        );
+        serde_json::to_vec(&fields).map_err(MessengerError::Serialization)
+    }
+
+    /// Sign the message, stamping it with the signer's public key
+    pub fn sign(&mut self, key: &SigningKey) -> Result<()> {
+        self.public_key = Some(STANDARD.encode(key.verifying_key().as_bytes()));
+        let signature = key.sign(&self.signed_bytes()?);
+        self.signature = Some(STANDARD.encode(signature.to_bytes()));
+        Ok(())
+    }
+
+    /// Check the signature against the embedded public key, returning that key
+    pub fn verify(&self) -> Result<String> {
+        let (Some(public_key), Some(signature)) = (&self.public_key, &self.signature) else {
+            return Err(MessengerError::Authentication("Discovery message is not signed".to_string()));
+        };
+
+        let key_bytes: [u8; 32] = STANDARD.decode(public_key).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| MessengerError::Authentication("Invalid discovery public key".to_string()))?;
+        let signature_bytes: [u8; 64] = STANDARD.decode(signature).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| MessengerError::Authentication("Invalid discovery signature".to_string()))?;
+
+        let verifying_key = VerifyingKey::from_bytes(&key_bytes)
+            .map_err(|e| MessengerError::Authentication(format!("Invalid discovery public key: {}", e)))?;
+        verifying_key.verify_strict(&self.signed_bytes()?, &Signature::from_bytes(&signature_bytes))
+            .map_err(|e| MessengerError::Authentication(format!("Discovery signature check failed: {}", e)))?;
+        Ok(public_key.clone())
+    }
 }
 
 impl NetworkDiscovery {
@@ -52,46 +140,95 @@ impl NetworkDiscovery {
             broadcast_port,
             service_name,
             timeout,
+            announce_interval: DEFAULT_ANNOUNCE_INTERVAL,
+            address_family: AddressFamily::default(),
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+            announcement: None,
             socket: None,
+            shutdown: None,
+            announcement_task: None,
         }
     }
 
+    /// Create a discovery service from the discovery settings
+    pub fn from_config(config: &DiscoveryConfig) -> Self {
+        let mut discovery = Self::new(config.listen_port, config.service_name.clone(), Duration::from_secs(config.timeout));
+        discovery.announce_interval = Duration::from_secs(config.broadcast_interval.max(1));
+        discovery.address_family = config.address_family;
+        discovery
+    }
+
+    /// Sign announcements with `key` instead of the throwaway key made at creation
+    pub fn set_signing_key(&mut self, key: SigningKey) {
+        self.signing_key = key;
+    }
+
+    /// Public key announcements are signed with, base64 encoded
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.signing_key.verifying_key().as_bytes())
+    }
+
     /// Start the discovery service as a server
-    pub async fn start_server_announcement(mut self, server_id: Uuid, server_name: String, server_port: u16) -> Result<()> {
+    pub async fn start_server_announcement(&mut self, server_id: Uuid, server_name: String, server_port: u16) -> Result<()> {
         info!("Starting server discovery announcement on port {}", self.broadcast_port);
 
-        let socket = UdpSocket::bind(format!("0.0.0.0:{}", self.broadcast_port))
-            .map_err(|e| MessengerError::Network(e))?;
-        
-        socket.set_broadcast(true)
+        let (socket, family) = Self::bind_shared(self.address_family, self.broadcast_port)
             .map_err(|e| MessengerError::Network(e))?;
-
-        self.socket = Some(socket);
-        let socket = self.socket.as_ref().unwrap();
+        let targets = Self::targets(family, self.broadcast_port);
 
         // Start announcement loop
         let announce_message = DiscoveryMessage {
             message_type: DiscoveryMessageType::ServerAnnounce,
+            service_name: self.service_name.clone(),
             server_id,
-            server_name: server_name.clone(),
+            server_name,
             server_port,
             timestamp: chrono::Utc::now().timestamp() as u64,
+            public_key: None,
+            signature: None,
         };
 
         let socket_clone = socket.try_clone()
+            .and_then(|clone| {
+                clone.set_nonblocking(true)?;
+                tokio::net::UdpSocket::from_std(clone)
+            })
             .map_err(|e| MessengerError::Network(e))?;
-        let announce_message_clone = announce_message.clone();
+        self.socket = Some(socket);
+        self.announcement = Some(announce_message.clone());
+
+        let (shutdown_tx, mut shutdown_rx) = watch::channel(false);
+        let signing_key = self.signing_key.clone();
+        let mut ticker = tokio::time::interval(self.announce_interval);
         
-        tokio::spawn(async move {
+        // The loop owns its socket clone, so it must exit before the port is released.
+        // It both announces and answers client requests arriving on the same port.
+        let announcement_task = tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
             loop {
-                if let Err(e) = Self::broadcast_announcement(&socket_clone, &announce_message_clone).await {
-                    warn!("Failed to broadcast announcement: {}", e);
+                tokio::select! {
+                    _ = ticker.tick() => {
+                        if let Err(e) = Self::broadcast_announcement(&socket_clone, &announce_message, &signing_key, &targets).await {
+                            warn!("Failed to broadcast announcement: {}", e);
+                        }
+                    },
+                    received = socket_clone.recv_from(&mut buffer) => match received {
+                        Ok((size, addr)) => {
+                            if let Err(e) = Self::answer_request(&socket_clone, &buffer[..size], addr, &announce_message, &signing_key).await {
+                                warn!("Failed to answer discovery request from {}: {}", addr, e);
+                            }
+                        },
+                        Err(e) => debug!("Error receiving discovery message: {}", e),
+                    },
+                    _ = shutdown_rx.changed() => break,
                 }
-                
-                tokio::time::sleep(Duration::from_secs(30)).await;
             }
+            debug!("Server discovery announcement loop stopped");
         });
 
+        self.shutdown = Some(shutdown_tx);
+        self.announcement_task = Some(announcement_task);
+
         info!("Server discovery announcement started");
         Ok(())
     }
@@ -100,57 +237,83 @@ impl NetworkDiscovery {
     pub async fn discover_servers(&mut self) -> Result<Vec<DiscoveredServer>> {
         info!("Starting server discovery");
 
-        let socket = UdpSocket::bind("0.0.0.0:0")
-            .map_err(|e| MessengerError::Network(e))?;
-        
-        socket.set_broadcast(true)
+        let (socket, family) = Self::bind_shared(self.address_family, 0)
+            .and_then(|(socket, family)| {
+                socket.set_nonblocking(true)?;
+                Ok((tokio::net::UdpSocket::from_std(socket)?, family))
+            })
             .map_err(|e| MessengerError::Network(e))?;
 
         // Send discovery request
         let request_message = DiscoveryMessage {
             message_type: DiscoveryMessageType::ClientRequest,
+            service_name: self.service_name.clone(),
             server_id: Uuid::new_v4(),
             server_name: "discovery-client".to_string(),
             server_port: 0,
             timestamp: chrono::Utc::now().timestamp() as u64,
+            public_key: None,
+            signature: None,
         };
 
         let message_data = serde_json::to_vec(&request_message)
             .map_err(|e| MessengerError::Serialization(e))?;
 
-        // Broadcast request to all interfaces
-        let broadcast_addr = SocketAddr::from((Ipv4Addr::BROADCAST, self.broadcast_port));
-        socket.send_to(&message_data, broadcast_addr)
-            .map_err(|e| MessengerError::Network(e))?;
-
-        debug!("Discovery request sent to {}", broadcast_addr);
+        // Broadcast request to all interfaces, over each IP version in use
+        let mut sent = 0;
+        let mut last_error = None;
+        for target in Self::targets(family, self.broadcast_port) {
+            match socket.send_to(&message_data, target).await {
+                Ok(_) => {
+                    sent += 1;
+                    debug!("Discovery request sent to {}", target);
+                },
+                Err(e) => {
+                    debug!("Failed to send discovery request to {}: {}", target, e);
+                    last_error = Some(e);
+                },
+            }
+        }
+        if let (0, Some(e)) = (sent, last_error) {
+            return Err(MessengerError::Network(e));
+        }
 
-        // Listen for responses
+        // Listen for responses until the timeout, yielding to other tasks while waiting
         let mut discovered_servers = Vec::new();
-        let start_time = Instant::now();
-
-        socket.set_read_timeout(Some(self.timeout))
-            .map_err(|e| MessengerError::Network(e))?;
+        let deadline = tokio::time::Instant::now() + self.timeout;
 
-        while start_time.elapsed() < self.timeout {
+        loop {
             let mut buffer = [0u8; 1024];
             
-            match socket.recv_from(&mut buffer) {
-                Ok((size, addr)) => {
+            match tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+                Ok(Ok((size, addr))) => {
                     debug!("Received {} bytes from {}", size, addr);
                     
                     if let Ok(discovery_message) = serde_json::from_slice::<DiscoveryMessage>(&buffer[..size]) {
+                        if discovery_message.service_name != self.service_name {
+                            debug!("Ignoring discovery message for service {}", discovery_message.service_name);
+                            continue;
+                        }
                         if matches!(discovery_message.message_type, DiscoveryMessageType::ServerResponse | DiscoveryMessageType::ServerAnnounce) {
+                            // Anyone on the network can send these, so only signed ones count
+                            let public_key = match discovery_message.verify() {
+                                Ok(public_key) => public_key,
+                                Err(e) => {
+                                    warn!("Dropping discovery message from {}: {}", addr, e);
+                                    continue;
+                                }
+                            };
                             let server_name = discovery_message.server_name.clone();
                             let server_port = discovery_message.server_port;
                             
                             let server = DiscoveredServer {
                                 id: discovery_message.server_id,
                                 name: server_name.clone(),
-                                address: addr.ip().to_string(),
+                                address: addr.ip().to_canonical().to_string(),
                                 port: server_port,
                                 discovered_at: chrono::Utc::now().timestamp() as u64,
                                 last_seen: chrono::Utc::now().timestamp() as u64,
+                                public_key,
                             };
                             
                             // Avoid duplicates
@@ -161,12 +324,10 @@ impl NetworkDiscovery {
                         }
                     }
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                    break;
-                }
-                Err(e) => {
+                Ok(Err(e)) => {
                     debug!("Error receiving discovery response: {}", e);
                 }
+                Err(_) => break,
             }
         }
 
@@ -174,80 +335,422 @@ impl NetworkDiscovery {
         Ok(discovered_servers)
     }
 
-    /// Handle incoming discovery messages (for servers)
-    async fn handle_discovery_messages(&self) -> Result<()> {
-        if let Some(socket) = &self.socket {
-            let mut buffer = [0u8; 1024];
-            
-            loop {
-                match socket.recv_from(&mut buffer) {
-                    Ok((size, addr)) => {
-                        debug!("Received discovery message from {}", addr);
-                        
-                        if let Ok(discovery_message) = serde_json::from_slice::<DiscoveryMessage>(&buffer[..size]) {
-                            match discovery_message.message_type {
-                                DiscoveryMessageType::ClientRequest => {
-                                    // Respond to client request
-                                    let response = DiscoveryMessage {
-                                        message_type: DiscoveryMessageType::ServerResponse,
-                                        server_id: discovery_message.server_id, // Use our server ID
-                                        server_name: "TCP Messenger Server".to_string(),
-                                        server_port: discovery_message.server_port, // Use our server port
-                                        timestamp: chrono::Utc::now().timestamp() as u64,
-                                    };
-                                    
-                                    let response_data = serde_json::to_vec(&response)
-                                        .map_err(|e| MessengerError::Serialization(e))?;
-                                    
-                                    socket.send_to(&response_data, addr)
-                                        .map_err(|e| MessengerError::Network(e))?;
-                                    
-                                    debug!("Sent discovery response to {}", addr);
-                                }
-                                _ => {
-                                    debug!("Ignoring discovery message type: {:?}", discovery_message.message_type);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        debug!("Error receiving discovery message: {}", e);
-                        tokio::time::sleep(Duration::from_millis(100)).await;
-                    }
-                }
-            }
-        }
-        
-        Ok(())
-    }
+    /// Reply to a client request for our service with this server's own identity. Other
+    /// discovery traffic on the port, including our own announcements, is ignored.
+    async fn answer_request(
+        socket: &tokio::net::UdpSocket,
+        data: &[u8],
+        addr: SocketAddr,
+        announcement: &DiscoveryMessage,
+        signing_key: &SigningKey,
+    ) -> Result<()> {
+        let request = match serde_json::from_slice::<DiscoveryMessage>(data) {
+            Ok(request) if matches!(request.message_type, DiscoveryMessageType::ClientRequest)
+                && request.service_name == announcement.service_name => request,
+            Ok(message) => {
+                debug!("Ignoring discovery message type: {:?}", message.message_type);
+                return Ok(());
+            },
+            Err(_) => return Ok(()),
+        };
+        debug!("Received discovery request {} from {}", request.server_id, addr);
 
-    /// Broadcast server announcement
-    async fn broadcast_announcement(socket: &UdpSocket, message: &DiscoveryMessage) -> Result<()> {
-        let message_data = serde_json::to_vec(message)
+        let mut response = DiscoveryMessage {
+            message_type: DiscoveryMessageType::ServerResponse,
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            ..announcement.clone()
+        };
+        response.sign(signing_key)?;
+        let response_data = serde_json::to_vec(&response)
             .map_err(|e| MessengerError::Serialization(e))?;
 
-        let broadcast_addr = SocketAddr::from((Ipv4Addr::BROADCAST, 9000));
-        socket.send_to(&message_data, broadcast_addr)
+        socket.send_to(&response_data, addr).await
             .map_err(|e| MessengerError::Network(e))?;
 
-        debug!("Broadcasted server announcement");
+        debug!("Sent discovery response to {}", addr);
+        Ok(())
+    }
+
+    /// Bind a discovery socket for `family`, returning the family actually bound since
+    /// dual-stack falls back to IPv4 when IPv6 is unavailable
+    fn bind_shared(family: AddressFamily, port: u16) -> std::io::Result<(UdpSocket, AddressFamily)> {
+        match family {
+            AddressFamily::Ipv4 => Self::bind_v4(port).map(|socket| (socket, AddressFamily::Ipv4)),
+            AddressFamily::Ipv6 => Self::bind_v6(port, true).map(|socket| (socket, AddressFamily::Ipv6)),
+            AddressFamily::DualStack => match Self::bind_v6(port, false) {
+                Ok(socket) => Ok((socket, AddressFamily::DualStack)),
+                Err(e) => {
+                    debug!("IPv6 discovery unavailable, using IPv4 only: {}", e);
+                    Self::bind_v4(port).map(|socket| (socket, AddressFamily::Ipv4))
+                },
+            },
+        }
+    }
+
+    /// Bind the discovery port for broadcasting, letting other listeners on this host
+    /// (such as a local client) share it
+    fn bind_v4(port: u16) -> std::io::Result<UdpSocket> {
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.set_broadcast(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+        Ok(socket.into())
+    }
+
+    /// Bind the discovery port over IPv6 and join the discovery multicast group. Unless
+    /// `only_v6` is set the socket also carries IPv4 traffic as mapped addresses.
+    fn bind_v6(port: u16, only_v6: bool) -> std::io::Result<UdpSocket> {
+        let socket = socket2::Socket::new(socket2::Domain::IPV6, socket2::Type::DGRAM, Some(socket2::Protocol::UDP))?;
+        socket.set_only_v6(only_v6)?;
+        socket.set_reuse_address(true)?;
+        if !only_v6 {
+            socket.set_broadcast(true)?;
+        }
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.join_multicast_v6(&DISCOVERY_MULTICAST_V6, 0)?;
+        socket.set_multicast_loop_v6(true)?;
+        Ok(socket.into())
+    }
+
+    /// Addresses discovery traffic is sent to on a socket bound for `family`
+    fn targets(family: AddressFamily, port: u16) -> Vec<SocketAddr> {
+        let multicast = SocketAddr::V6(SocketAddrV6::new(DISCOVERY_MULTICAST_V6, port, 0, 0));
+        match family {
+            AddressFamily::Ipv4 => vec![SocketAddr::from((Ipv4Addr::BROADCAST, port))],
+            AddressFamily::Ipv6 => vec![multicast],
+            AddressFamily::DualStack => vec![SocketAddr::from((Ipv4Addr::BROADCAST.to_ipv6_mapped(), port)), multicast],
+        }
+    }
+
+    /// Broadcast server announcement to each target
+    async fn broadcast_announcement(
+        socket: &tokio::net::UdpSocket,
+        message: &DiscoveryMessage,
+        signing_key: &SigningKey,
+        targets: &[SocketAddr],
+    ) -> Result<()> {
+        let mut message = DiscoveryMessage {
+            timestamp: chrono::Utc::now().timestamp() as u64,
+            ..message.clone()
+        };
+        message.sign(signing_key)?;
+        let message_data = serde_json::to_vec(&message)
+            .map_err(|e| MessengerError::Serialization(e))?;
+
+        // One IP version being unreachable shouldn't silence the other
+        for target in targets {
+            match socket.send_to(&message_data, target).await {
+                Ok(_) => debug!("Broadcasted server announcement to {}", target),
+                Err(e) => warn!("Failed to broadcast announcement to {}: {}", target, e),
+            }
+        }
         Ok(())
     }
 
-    /// Stop the discovery service
-    pub fn stop(&mut self) {
+    /// Stop the discovery service, waiting for the announcement loop so the port is free again
+    pub async fn stop(&mut self) {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(true);
+        }
+        if let Some(announcement_task) = self.announcement_task.take() {
+            if let Err(e) = announcement_task.await {
+                warn!("Announcement loop ended abnormally: {}", e);
+            }
+        }
         self.socket = None;
+        self.announcement = None;
         info!("Network discovery service stopped");
     }
 }
 
+/// Merge freshly discovered servers into a cache. Servers already cached keep their
+/// `discovered_at` and take everything else, including `last_seen`, from the new sighting.
+pub fn merge_discovered_servers(cache: &mut HashMap<Uuid, DiscoveredServer>, servers: Vec<DiscoveredServer>) {
+    for server in servers {
+        match cache.get_mut(&server.id) {
+            Some(cached) => {
+                if cached.public_key != server.public_key {
+                    warn!("Server {} is announcing with a different key than before", server.id);
+                }
+                *cached = DiscoveredServer {
+                    discovered_at: cached.discovered_at,
+                    ..server
+                };
+            },
+            None => {
+                cache.insert(server.id, server);
+            },
+        }
+    }
+}
+
+/// Drop cached servers not seen within `ttl` of `now` (Unix seconds). Returns the number removed.
+pub fn expire_discovered_servers(cache: &mut HashMap<Uuid, DiscoveredServer>, now: u64, ttl: Duration) -> usize {
+    let before = cache.len();
+    cache.retain(|_, server| server.last_seen.saturating_add(ttl.as_secs()) >= now);
+    before - cache.len()
+}
+
+impl Drop for NetworkDiscovery {
+    fn drop(&mut self) {
+        // Without an async context the loop can only be signalled, not awaited
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(true);
+        }
+        if let Some(announcement_task) = self.announcement_task.take() {
+            announcement_task.abort();
+        }
+    }
+}
+
+/// Load the discovery signing key saved at `path`, generating and saving one on first use
+pub fn load_or_create_signing_key(path: &Path) -> Result<SigningKey> {
+    if path.exists() {
+        let encoded = std::fs::read_to_string(path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to read discovery key: {}", e)))?;
+        let bytes: [u8; 32] = STANDARD.decode(encoded.trim()).ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| MessengerError::Encryption("Invalid discovery key".to_string()))?;
+        return Ok(SigningKey::from_bytes(&bytes));
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create discovery key directory: {}", e)))?;
+    }
+
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let temp_path = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&temp_path)
+        .map_err(|e| MessengerError::Storage(format!("Failed to save discovery key: {}", e)))?;
+    std::io::Write::write_all(&mut file, STANDARD.encode(key.to_bytes()).as_bytes())
+        .and_then(|_| file.sync_all())
+        .and_then(|_| std::fs::rename(&temp_path, path))
+        .map_err(|e| MessengerError::Storage(format!("Failed to save discovery key: {}", e)))?;
+    Ok(key)
+}
+
 impl Default for NetworkDiscovery {
     fn default() -> Self {
         Self {
             broadcast_port: 9000,
             service_name: "tcp-messenger".to_string(),
             timeout: Duration::from_secs(5),
+            announce_interval: DEFAULT_ANNOUNCE_INTERVAL,
+            address_family: AddressFamily::default(),
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+            announcement: None,
             socket: None,
+            shutdown: None,
+            announcement_task: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_stop_releases_port() {
+        // Find a free port to announce on
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let mut discovery = NetworkDiscovery::new(port, "test".to_string(), Duration::from_secs(1));
+        discovery.start_server_announcement(Uuid::new_v4(), "Test Server".to_string(), 8080).await.unwrap();
+        assert!(UdpSocket::bind(("0.0.0.0", port)).is_err());
+
+        discovery.stop().await;
+        assert!(discovery.announcement_task.is_none());
+        UdpSocket::bind(("0.0.0.0", port)).expect("discovery port should be free after stop");
+    }
+
+    // The listener blocks on a plain socket, so the announcement loop needs its own worker
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_no_announcements_after_stop() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let listener = NetworkDiscovery::bind_v4(port).unwrap();
+        listener.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+
+        let mut discovery = NetworkDiscovery::new(port, "test".to_string(), Duration::from_secs(1));
+        discovery.announce_interval = Duration::from_millis(50);
+        discovery.start_server_announcement(Uuid::new_v4(), "Test Server".to_string(), 8080).await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let (size, _) = listener.recv_from(&mut buffer).expect("announcements should arrive while running");
+        let announcement: DiscoveryMessage = serde_json::from_slice(&buffer[..size]).unwrap();
+        assert!(matches!(announcement.message_type, DiscoveryMessageType::ServerAnnounce));
+
+        discovery.stop().await;
+        // Drain anything sent before the stop, then expect silence across several intervals
+        listener.set_nonblocking(true).unwrap();
+        while listener.recv_from(&mut buffer).is_ok() {}
+        listener.set_nonblocking(false).unwrap();
+        let error = listener.recv_from(&mut buffer).unwrap_err();
+        assert!(matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_announcements_go_to_configured_port() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert_ne!(port, 9000);
+        let listener = NetworkDiscovery::bind_v4(port).unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let config = DiscoveryConfig {
+            listen_port: port,
+            ..Default::default()
+        };
+        let mut discovery = NetworkDiscovery::from_config(&config);
+        let server_id = Uuid::new_v4();
+        discovery.start_server_announcement(server_id, "Test Server".to_string(), 8080).await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let (size, _) = listener.recv_from(&mut buffer).expect("announcement should arrive on the configured port");
+        discovery.stop().await;
+
+        let announcement: DiscoveryMessage = serde_json::from_slice(&buffer[..size]).unwrap();
+        assert!(matches!(announcement.message_type, DiscoveryMessageType::ServerAnnounce));
+        assert_eq!(announcement.server_id, server_id);
+    }
+
+    #[tokio::test]
+    async fn test_client_request_is_answered_with_server_identity() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let server_id = Uuid::new_v4();
+
+        let mut server = NetworkDiscovery::new(port, "test".to_string(), Duration::from_secs(1));
+        let public_key = server.public_key();
+        server.start_server_announcement(server_id, "Office Server".to_string(), 8123).await.unwrap();
+
+        // The client listens on its own port, so it only hears the direct response
+        let mut client = NetworkDiscovery::new(port, "test".to_string(), Duration::from_secs(1));
+        let servers = client.discover_servers().await.unwrap();
+        server.stop().await;
+
+        let found = servers.iter().find(|s| s.id == server_id).expect("server should answer the request");
+        assert_eq!(found.name, "Office Server");
+        assert_eq!(found.port, 8123);
+        assert_eq!(found.public_key, public_key);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an IPv6 interface with multicast; run with --ignored"]
+    async fn test_ipv6_multicast_discovery() {
+        // Multicast loops back to this host, so both ends can run here
+        let probe = NetworkDiscovery::bind_v6(0, true).expect("IPv6 multicast should be available");
+        let port = probe.local_addr().unwrap().port();
+        drop(probe);
+        let server_id = Uuid::new_v4();
+
+        let mut server = NetworkDiscovery::new(port, "test".to_string(), Duration::from_secs(1));
+        server.address_family = AddressFamily::Ipv6;
+        server.start_server_announcement(server_id, "IPv6 Server".to_string(), 8123).await.unwrap();
+
+        let mut client = NetworkDiscovery::new(port, "test".to_string(), Duration::from_secs(1));
+        client.address_family = AddressFamily::Ipv6;
+        let servers = client.discover_servers().await.unwrap();
+        server.stop().await;
+
+        let found = servers.iter().find(|s| s.id == server_id).expect("server should answer over IPv6");
+        assert!(found.address.parse::<Ipv6Addr>().is_ok(), "expected an IPv6 address, got {}", found.address);
+        assert_eq!(found.port, 8123);
+    }
+
+    #[tokio::test]
+    async fn test_discovery_does_not_block_other_tasks() {
+        // A single-threaded runtime only makes progress elsewhere if discovery yields
+        let ticks = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = ticks.clone();
+        let ticker = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = NetworkDiscovery::new(port, "test".to_string(), Duration::from_millis(500));
+        client.discover_servers().await.unwrap();
+        ticker.abort();
+
+        assert!(ticks.load(std::sync::atomic::Ordering::Relaxed) >= 10);
+    }
+
+    #[tokio::test]
+    async fn test_services_with_different_names_ignore_each_other() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let mut server = NetworkDiscovery::new(port, "other-app".to_string(), Duration::from_secs(1));
+        server.start_server_announcement(Uuid::new_v4(), "Other Server".to_string(), 8123).await.unwrap();
+
+        let mut client = NetworkDiscovery::new(port, "tcp-messenger".to_string(), Duration::from_secs(1));
+        let servers = client.discover_servers().await.unwrap();
+        server.stop().await;
+
+        assert!(servers.is_empty());
+    }
+
+    #[test]
+    fn test_tampered_announcement_is_rejected() {
+        let key = SigningKey::generate(&mut rand::rngs::OsRng);
+        let mut announcement = DiscoveryMessage {
+            message_type: DiscoveryMessageType::ServerAnnounce,
+            service_name: "tcp-messenger".to_string(),
+            server_id: Uuid::new_v4(),
+            server_name: "Office Server".to_string(),
+            server_port: 8080,
+            timestamp: 1_700_000_000,
+            public_key: None,
+            signature: None,
+        };
+        announcement.sign(&key).unwrap();
+        assert_eq!(announcement.verify().unwrap(), STANDARD.encode(key.verifying_key().as_bytes()));
+
+        // Redirecting clients to another port invalidates the signature
+        let mut redirected = announcement.clone();
+        redirected.server_port = 6666;
+        assert!(redirected.verify().is_err());
+
+        // So does re-signing under another key while keeping the original key on the message
+        let mut forged = announcement.clone();
+        forged.sign(&SigningKey::generate(&mut rand::rngs::OsRng)).unwrap();
+        forged.public_key = announcement.public_key.clone();
+        assert!(forged.verify().is_err());
+
+        let unsigned = DiscoveryMessage { signature: None, ..announcement };
+        assert!(unsigned.verify().is_err());
+    }
+
+    #[test]
+    fn test_reannounced_server_is_not_expired() {
+        let ttl = Duration::from_secs(90);
+        let now = 10_000;
+        let server = |id, last_seen| DiscoveredServer {
+            id,
+            name: "Server".to_string(),
+            address: "192.168.1.10".to_string(),
+            port: 8080,
+            discovered_at: last_seen,
+            last_seen,
+            public_key: "key".to_string(),
+        };
+
+        let returning = Uuid::new_v4();
+        let gone = Uuid::new_v4();
+        let mut cache = HashMap::new();
+        merge_discovered_servers(&mut cache, vec![server(returning, now - 500), server(gone, now - 500)]);
+
+        // Seeing the server again refreshes last_seen but keeps when it was first found
+        merge_discovered_servers(&mut cache, vec![server(returning, now)]);
+        assert_eq!(expire_discovered_servers(&mut cache, now, ttl), 1);
+        assert_eq!(cache[&returning].last_seen, now);
+        assert_eq!(cache[&returning].discovered_at, now - 500);
+        assert!(!cache.contains_key(&gone));
+    }
+}
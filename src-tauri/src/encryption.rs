@@ -1,19 +1,104 @@
 use crate::{encryption_error, error::{MessengerError, Result}};
-use aes_gcm::{Aes256Gcm, Key, Nonce, aead::{Aead, KeyInit}};
-use p256::{ecdh::EphemeralSecret, PublicKey, elliptic_curve::sec1::ToEncodedPoint};
+use aes_gcm::{Aes256Gcm, Nonce, aead::{Aead, KeyInit, Payload}};
+use chacha20poly1305::ChaCha20Poly1305;
+use p256::{ecdh::EphemeralSecret, PublicKey, SecretKey, elliptic_curve::sec1::ToEncodedPoint};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
 use rand::Rng;
-use sha2::{Sha256, Digest};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-/// Encryption engine for secure message handling
+/// Bytes added to each payload by encryption (4-byte length, 12-byte nonce, 16-byte GCM tag, 32-byte MAC)
+pub const ENCRYPTION_OVERHEAD: usize = 4 + 12 + 16 + 32;
+
+/// Fixed HKDF salt for session keys derived from an ECDH exchange
+const KEY_DERIVATION_SALT: &[u8] = b"tcp-messenger-v1";
+
+/// Highest nonce counter value; encrypting past it would repeat a nonce
+pub const MAX_NONCE_COUNTER: u64 = u64::MAX;
+
+/// How close the nonce counter may get to its limit before key rotation is due
+const NONCE_COUNTER_HEADROOM: u64 = 1 << 20;
+
+/// Default number of sequence numbers tracked for replay protection
+pub const DEFAULT_REPLAY_WINDOW: u64 = 1024;
+
+/// AEAD cipher used for message bodies. Both peers must be configured with the same suite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherSuite {
+    /// AES-256-GCM, fastest on CPUs with AES instructions
+    #[default]
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, faster on devices without AES acceleration
+    ChaCha20Poly1305,
+}
+
+/// AEAD instance for the selected suite
+enum Cipher {
+    // Boxed because the expanded AES key schedule dwarfs the ChaCha20 state
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl Cipher {
+    fn new(suite: CipherSuite, key: &[u8; 32]) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => Self::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            CipherSuite::ChaCha20Poly1305 => Self::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into())),
+        }
+    }
+
+    fn suite(&self) -> CipherSuite {
+        match self {
+            Self::Aes256Gcm(_) => CipherSuite::Aes256Gcm,
+            Self::ChaCha20Poly1305(_) => CipherSuite::ChaCha20Poly1305,
+        }
+    }
+
+    // Both suites take a 96-bit nonce, so the nonce-prepended wire format is the same
+    fn encrypt(&self, nonce: &chacha20poly1305::Nonce, payload: Payload) -> aes_gcm::aead::Result<Vec<u8>> {
+        match self {
+            Self::Aes256Gcm(cipher) => cipher.encrypt(nonce, payload),
+            Self::ChaCha20Poly1305(cipher) => cipher.encrypt(nonce, payload),
+        }
+    }
+
+    fn decrypt(&self, nonce: &chacha20poly1305::Nonce, payload: Payload) -> aes_gcm::aead::Result<Vec<u8>> {
+        match self {
+            Self::Aes256Gcm(cipher) => cipher.decrypt(nonce, payload),
+            Self::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce, payload),
+        }
+    }
+}
+
+/// Encryption engine for secure message handling.
+/// Nonces are a random 4-byte salt chosen with the key followed by an 8-byte message counter,
+/// so they never repeat under one key.
 pub struct EncryptionEngine {
-    cipher: Aes256Gcm,
-    nonce: [u8; 12],
+    cipher: Cipher,
+    nonce_salt: [u8; 4],
+    nonce_counter: u64,
     key_rotation_counter: u32,
     max_messages_per_key: u32,
 }
 
+impl Debug for EncryptionEngine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptionEngine")
+            .field("cipher", &self.cipher.suite())
+            .field("nonce_counter", &self.nonce_counter)
+            .field("key_rotation_counter", &self.key_rotation_counter)
+            .finish()
+    }
+}
+
 /// Key pair for ECDH key exchange
 pub struct KeyPair {
     pub private_key: EphemeralSecret,
@@ -28,81 +113,107 @@ impl Debug for KeyPair {
     }
 }
 
-impl Clone for KeyPair {
-    fn clone(&self) -> Self {
-        // Generate a new key pair since EphemeralSecret cannot be cloned
-        // This creates a new private key but maintains the same public key concept
-        let new_private_key = EphemeralSecret::random(&mut rand::thread_rng());
-        let new_public_key = PublicKey::from(&new_private_key);
-        
-        Self {
-            private_key: new_private_key,
-            public_key: new_public_key,
-        }
-    }
+/// Which end of a connection a session belongs to. Each direction has its own keys, so
+/// frames a node sent can never be opened by that node if they are reflected back to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionRole {
+    /// The client, which opened the connection
+    Initiator,
+    /// The server, which accepted it
+    Responder,
 }
 
-/// Shared secret derived from ECDH
-#[derive(Debug, Clone)]
-pub struct SharedSecret {
+/// Encryption and MAC keys protecting one direction of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectionKeys {
     pub encryption_key: [u8; 32],
     pub mac_key: [u8; 32],
+}
+
+/// Shared secret derived from ECDH.
+/// Clones belong to the same session and share its encryption engines, sequence counter and replay window.
+#[derive(Debug, Clone)]
+pub struct SharedSecret {
+    /// Keys for frames this side sends
+    pub sending: DirectionKeys,
+    /// Keys for frames the peer sends
+    pub receiving: DirectionKeys,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub cipher: CipherSuite,
+    send_engine: Arc<Mutex<EncryptionEngine>>,
+    receive_engine: Arc<Mutex<EncryptionEngine>>,
+    next_sequence: Arc<AtomicU64>,
+    replay_window: Arc<Mutex<ReplayWindow>>,
+}
+
+/// Sliding window of received sequence numbers, rejecting duplicates and ones too far behind
+#[derive(Debug)]
+pub struct ReplayWindow {
+    size: u64,
+    highest: Option<u64>,
+    seen: BTreeSet<u64>,
 }
 
 /// Key exchange manager
 #[derive(Debug)]
 pub struct KeyExchangeManager {
+    identity: Option<SecretKey>,
     key_pairs: HashMap<uuid::Uuid, KeyPair>,
     shared_secrets: HashMap<uuid::Uuid, SharedSecret>,
     key_rotation_interval: u32,
+    replay_window: u64,
+    cipher: CipherSuite,
 }
 
 impl EncryptionEngine {
     /// Create a new encryption engine with a random key
-    pub fn new() -> Result<Self> {
+    pub fn new(suite: CipherSuite) -> Result<Self> {
         let mut key_bytes = [0u8; 32];
         rand::thread_rng().fill(&mut key_bytes);
         
-        let key = Key::<aes_gcm::Aes256Gcm>::from_slice(&key_bytes);
-        let cipher = Aes256Gcm::new(key);
+        Self::from_key(&key_bytes, suite)
+    }
 
-        let mut nonce_bytes = [0u8; 12];
-        rand::thread_rng().fill(&mut nonce_bytes);
+    /// Create encryption engine from existing key
+    pub fn from_key(key: &[u8; 32], suite: CipherSuite) -> Result<Self> {
+        Ok(Self::with_key(key, suite))
+    }
 
-        Ok(Self {
-            cipher,
-            nonce: nonce_bytes,
+    fn with_key(key: &[u8; 32], suite: CipherSuite) -> Self {
+        Self {
+            cipher: Cipher::new(suite, key),
+            nonce_salt: rand::thread_rng().gen(),
+            nonce_counter: 0,
             key_rotation_counter: 0,
             max_messages_per_key: 100,
-        })
+        }
     }
 
-    /// Create encryption engine from existing key
-    pub fn from_key(key: &[u8; 32]) -> Result<Self> {
-        let key = Key::<aes_gcm::Aes256Gcm>::from_slice(key);
-        let cipher = Aes256Gcm::new(key);
-
-        let mut nonce_bytes = [0u8; 12];
-        rand::thread_rng().fill(&mut nonce_bytes);
+    /// Take the next nonce, refusing once the counter is exhausted
+    fn next_nonce(&mut self) -> Result<[u8; 12]> {
+        if self.nonce_counter == MAX_NONCE_COUNTER {
+            return Err(encryption_error!("Nonce counter exhausted, the key must be rotated"));
+        }
 
-        Ok(Self {
-            cipher,
-            nonce: nonce_bytes,
-            key_rotation_counter: 0,
-            max_messages_per_key: 100,
-        })
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.nonce_salt);
+        nonce[4..].copy_from_slice(&self.nonce_counter.to_be_bytes());
+        self.nonce_counter += 1;
+        Ok(nonce)
     }
 
     /// Encrypt a message
     pub fn encrypt_message(&mut self, message: &[u8]) -> Result<Vec<u8>> {
-        // Generate a new nonce for each message
-        let mut nonce_bytes = [0u8; 12];
-        rand::thread_rng().fill(&mut nonce_bytes);
+        self.encrypt_message_with_aad(message, &[])
+    }
+
+    /// Encrypt a message, authenticating `associated_data` alongside it without encrypting it
+    pub fn encrypt_message_with_aad(&mut self, message: &[u8], associated_data: &[u8]) -> Result<Vec<u8>> {
+        let nonce_bytes = self.next_nonce()?;
         let nonce = Nonce::from_slice(&nonce_bytes);
 
         // Encrypt the message
-        let ciphertext = self.cipher.encrypt(nonce, message)
+        let ciphertext = self.cipher.encrypt(nonce, Payload { msg: message, aad: associated_data })
             .map_err(|e| encryption_error!("Failed to encrypt message: {}", e))?;
 
         // Prepend nonce to ciphertext
@@ -116,6 +227,11 @@ impl EncryptionEngine {
 
     /// Decrypt a message
     pub fn decrypt_message(&self, encrypted_data: &[u8]) -> Result<Vec<u8>> {
+        self.decrypt_message_with_aad(encrypted_data, &[])
+    }
+
+    /// Decrypt a message, failing unless `associated_data` matches what it was encrypted with
+    pub fn decrypt_message_with_aad(&self, encrypted_data: &[u8], associated_data: &[u8]) -> Result<Vec<u8>> {
         if encrypted_data.len() < 12 {
             return Err(encryption_error!("Invalid encrypted data length"));
         }
@@ -125,7 +241,7 @@ impl EncryptionEngine {
         let ciphertext = &encrypted_data[12..];
 
         // Decrypt the message
-        let plaintext = self.cipher.decrypt(nonce, ciphertext)
+        let plaintext = self.cipher.decrypt(nonce, Payload { msg: ciphertext, aad: associated_data })
             .map_err(|e| encryption_error!("Failed to decrypt message: {}", e))?;
 
         Ok(plaintext)
@@ -134,6 +250,7 @@ impl EncryptionEngine {
     /// Check if key rotation is needed
     pub fn should_rotate_key(&self) -> bool {
         self.key_rotation_counter >= self.max_messages_per_key
+            || self.nonce_counter >= MAX_NONCE_COUNTER - NONCE_COUNTER_HEADROOM
     }
 
     /// Rotate encryption key
@@ -141,8 +258,9 @@ impl EncryptionEngine {
         let mut key_bytes = [0u8; 32];
         rand::thread_rng().fill(&mut key_bytes);
         
-        let key = Key::<aes_gcm::Aes256Gcm>::from_slice(&key_bytes);
-        self.cipher = Aes256Gcm::new(key);
+        self.cipher = Cipher::new(self.cipher.suite(), &key_bytes);
+        self.nonce_salt = rand::thread_rng().gen();
+        self.nonce_counter = 0;
 
         self.key_rotation_counter = 0;
         Ok(())
@@ -152,6 +270,11 @@ impl EncryptionEngine {
     pub fn key_rotation_counter(&self) -> u32 {
         self.key_rotation_counter
     }
+
+    /// Cipher suite this engine encrypts with
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher.suite()
+    }
 }
 
 impl KeyPair {
@@ -171,51 +294,131 @@ impl KeyPair {
         self.public_key.to_encoded_point(false).as_bytes().to_vec()
     }
 
-    /// Perform ECDH key exchange
-    pub fn perform_key_exchange(&self, peer_public_key: &PublicKey) -> Result<SharedSecret> {
+    /// Perform ECDH key exchange, taking the keys for our `role`'s side of the session
+    pub fn perform_key_exchange(&self, peer_public_key: &PublicKey, role: SessionRole) -> Result<SharedSecret> {
         let shared_secret = self.private_key.diffie_hellman(peer_public_key);
-        let shared_secret_bytes = shared_secret.raw_secret_bytes();
+        let initiator = Self::derive_keys(shared_secret.raw_secret_bytes(), b"initiator")?;
+        let responder = Self::derive_keys(shared_secret.raw_secret_bytes(), b"responder")?;
+        Ok(match role {
+            SessionRole::Initiator => SharedSecret::new(initiator, responder),
+            SessionRole::Responder => SharedSecret::new(responder, initiator),
+        })
+    }
 
-        // Derive encryption and MAC keys using HKDF
-        let encryption_key = Self::derive_key(&shared_secret_bytes, b"encryption")?;
-        let mac_key = Self::derive_key(&shared_secret_bytes, b"mac")?;
+    /// Expand independent encryption and MAC keys for frames sent by `sender` from the raw
+    /// ECDH secret with HKDF-SHA256
+    fn derive_keys(shared_secret: &[u8], sender: &[u8]) -> Result<DirectionKeys> {
+        let hkdf = Hkdf::<Sha256>::new(Some(KEY_DERIVATION_SALT), shared_secret);
 
-        Ok(SharedSecret {
-            encryption_key,
-            mac_key,
-            created_at: chrono::Utc::now(),
-        })
+        let mut encryption_key = [0u8; 32];
+        hkdf.expand(&[sender, b" encryption"].concat(), &mut encryption_key)
+            .map_err(|e| encryption_error!("Failed to derive encryption key: {}", e))?;
+        let mut mac_key = [0u8; 32];
+        hkdf.expand(&[sender, b" mac"].concat(), &mut mac_key)
+            .map_err(|e| encryption_error!("Failed to derive MAC key: {}", e))?;
+
+        Ok(DirectionKeys { encryption_key, mac_key })
     }
+}
 
-    /// Derive a key using HKDF
-    fn derive_key(shared_secret: &[u8], info: &[u8]) -> Result<[u8; 32]> {
-        let mut hasher = Sha256::new();
-        hasher.update(shared_secret);
-        hasher.update(info);
-        hasher.update(b"tcp-messenger-v1");
-        
-        let result = hasher.finalize();
-        let mut key = [0u8; 32];
-        key.copy_from_slice(&result);
-        Ok(key)
+impl ReplayWindow {
+    pub fn new(size: u64) -> Self {
+        Self {
+            size: size.max(1),
+            highest: None,
+            seen: BTreeSet::new(),
+        }
+    }
+
+    /// Record a sequence number, returning false if it is a replay or older than the window
+    pub fn accept(&mut self, sequence: u64) -> bool {
+        if let Some(highest) = self.highest {
+            if sequence.saturating_add(self.size) <= highest {
+                return false;
+            }
+        }
+        if !self.seen.insert(sequence) {
+            return false;
+        }
+
+        if self.highest.is_none_or(|highest| sequence > highest) {
+            self.highest = Some(sequence);
+            // Forget everything that has slid out of the window
+            self.seen = self.seen.split_off(&sequence.saturating_sub(self.size - 1));
+        }
+        true
     }
 }
 
 impl SharedSecret {
+    /// Start a session from derived keys for each direction
+    pub fn new(sending: DirectionKeys, receiving: DirectionKeys) -> Self {
+        Self {
+            send_engine: Arc::new(Mutex::new(EncryptionEngine::with_key(&sending.encryption_key, CipherSuite::default()))),
+            receive_engine: Arc::new(Mutex::new(EncryptionEngine::with_key(&receiving.encryption_key, CipherSuite::default()))),
+            sending,
+            receiving,
+            created_at: chrono::Utc::now(),
+            cipher: CipherSuite::default(),
+            next_sequence: Arc::new(AtomicU64::new(0)),
+            replay_window: Arc::new(Mutex::new(ReplayWindow::new(DEFAULT_REPLAY_WINDOW))),
+        }
+    }
+
+    /// Track the given number of recent sequence numbers for replay protection
+    pub fn with_replay_window(self, size: u64) -> Self {
+        Self {
+            replay_window: Arc::new(Mutex::new(ReplayWindow::new(size))),
+            ..self
+        }
+    }
+
+    /// Encrypt this session's messages with the given suite
+    pub fn with_cipher(self, cipher: CipherSuite) -> Self {
+        Self {
+            cipher,
+            send_engine: Arc::new(Mutex::new(EncryptionEngine::with_key(&self.sending.encryption_key, cipher))),
+            receive_engine: Arc::new(Mutex::new(EncryptionEngine::with_key(&self.receiving.encryption_key, cipher))),
+            ..self
+        }
+    }
+
+    /// Encrypt and authenticate with this session's sending keys, so nonces never repeat within the session
+    pub fn seal(&self, plaintext: &[u8], associated_data: &[u8]) -> Result<SecureMessage> {
+        let mut engine = self.send_engine.lock().unwrap_or_else(|e| e.into_inner());
+        SecureMessage::encrypt(plaintext, associated_data, &mut engine, &self.sending.mac_key)
+    }
+
+    /// Verify and decrypt a message sealed by the peer's half of this session
+    pub fn open(&self, secure: &SecureMessage, associated_data: &[u8]) -> Result<Vec<u8>> {
+        let engine = self.receive_engine.lock().unwrap_or_else(|e| e.into_inner());
+        secure.decrypt(associated_data, &engine, &self.receiving.mac_key)
+    }
+
+    /// Sequence number for the next outgoing message in this session
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Check an incoming sequence number against the replay window
+    pub fn accept_sequence(&self, sequence: u64) -> bool {
+        self.replay_window.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .accept(sequence)
+    }
+
     /// Check if the shared secret is expired
     pub fn is_expired(&self, max_age_seconds: u64) -> bool {
         let age = chrono::Utc::now() - self.created_at;
         age.num_seconds() > max_age_seconds as i64
     }
 
-    /// Get the encryption key
-    pub fn encryption_key(&self) -> &[u8; 32] {
-        &self.encryption_key
-    }
-
-    /// Get the MAC key
-    pub fn mac_key(&self) -> &[u8; 32] {
-        &self.mac_key
+    /// Build both ends of a session directly from keys, for exercising the protocol in tests
+    #[cfg(test)]
+    pub fn test_pair(seed: u8) -> (Self, Self) {
+        let initiator = DirectionKeys { encryption_key: [seed; 32], mac_key: [seed.wrapping_add(1); 32] };
+        let responder = DirectionKeys { encryption_key: [seed.wrapping_add(2); 32], mac_key: [seed.wrapping_add(3); 32] };
+        (Self::new(initiator.clone(), responder.clone()), Self::new(responder, initiator))
     }
 }
 
@@ -223,17 +426,84 @@ impl KeyExchangeManager {
     /// Create a new key exchange manager
     pub fn new(key_rotation_interval: u32) -> Self {
         Self {
+            identity: None,
             key_pairs: HashMap::new(),
             shared_secrets: HashMap::new(),
             key_rotation_interval,
+            replay_window: DEFAULT_REPLAY_WINDOW,
+            cipher: CipherSuite::default(),
         }
     }
 
-    /// Generate a new key pair for a peer
-    pub fn generate_key_pair(&mut self, peer_id: uuid::Uuid) -> Result<KeyPair> {
+    /// Load the long-term identity key saved at `path`, generating and saving one on first use
+    pub fn load_or_create_identity(&mut self, path: &Path) -> Result<()> {
+        let identity = if path.exists() {
+            let encoded = std::fs::read_to_string(path)
+                .map_err(|e| MessengerError::Storage(format!("Failed to read identity key: {}", e)))?;
+            let bytes = STANDARD.decode(encoded.trim())
+                .map_err(|e| encryption_error!("Failed to decode identity key: {}", e))?;
+            SecretKey::from_slice(&bytes)
+                .map_err(|e| encryption_error!("Invalid identity key: {}", e))?
+        } else {
+            let identity = SecretKey::random(&mut rand::thread_rng());
+            Self::save_identity(path, &identity)?;
+            identity
+        };
+
+        self.identity = Some(identity);
+        Ok(())
+    }
+
+    /// Write the identity key through a temporary file so a crash never leaves a truncated key
+    fn save_identity(path: &Path, identity: &SecretKey) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| MessengerError::Storage(format!("Failed to create identity directory: {}", e)))?;
+        }
+
+        let temp_path = path.with_extension("tmp");
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&temp_path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to save identity key: {}", e)))?;
+        std::io::Write::write_all(&mut file, STANDARD.encode(identity.to_bytes()).as_bytes())
+            .and_then(|_| file.sync_all())
+            .and_then(|_| std::fs::rename(&temp_path, path))
+            .map_err(|e| MessengerError::Storage(format!("Failed to save identity key: {}", e)))
+    }
+
+    /// Encoded public half of the identity key
+    pub fn identity_public_key(&self) -> Result<Vec<u8>> {
+        self.identity.as_ref()
+            .map(|identity| identity.public_key().to_encoded_point(false).as_bytes().to_vec())
+            .ok_or_else(|| encryption_error!("No identity key loaded"))
+    }
+
+    /// SHA-256 of the identity public key as lowercase hex, for peers to recognize this node
+    pub fn fingerprint(&self) -> Result<String> {
+        let digest = Sha256::digest(self.identity_public_key()?);
+        Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    /// Set the replay window size used for new sessions
+    pub fn set_replay_window(&mut self, size: u64) {
+        self.replay_window = size;
+    }
+
+    /// Set the cipher suite used for new sessions
+    pub fn set_cipher_suite(&mut self, cipher: CipherSuite) {
+        self.cipher = cipher;
+    }
+
+    /// Generate a new key pair for a peer, keeping the private key and returning the encoded public key
+    pub fn generate_key_pair(&mut self, peer_id: uuid::Uuid) -> Result<Vec<u8>> {
         let key_pair = KeyPair::generate();
-        self.key_pairs.insert(peer_id, key_pair.clone());
-        Ok(key_pair)
+        let public_key = key_pair.public_key_bytes();
+        self.key_pairs.insert(peer_id, key_pair);
+        Ok(public_key)
     }
 
     /// Get the public key for a peer
@@ -243,16 +513,31 @@ impl KeyExchangeManager {
             .ok_or_else(|| encryption_error!("No key pair found for peer: {}", peer_id))
     }
 
-    /// Perform key exchange with a peer
+    /// Encoded public key for a peer, as sent in a `KeyExchange` message
+    pub fn public_key_bytes(&self, peer_id: &uuid::Uuid) -> Result<Vec<u8>> {
+        self.get_public_key(peer_id)
+            .map(|key| key.to_encoded_point(false).as_bytes().to_vec())
+    }
+
+    /// Decode a public key received from a peer
+    pub fn decode_public_key(bytes: &[u8]) -> Result<PublicKey> {
+        PublicKey::from_sec1_bytes(bytes)
+            .map_err(|e| MessengerError::KeyExchangeFailed(format!("Invalid public key: {}", e)))
+    }
+
+    /// Perform key exchange with a peer, taking our `role`'s side of the session
     pub fn perform_key_exchange(
         &mut self,
         peer_id: uuid::Uuid,
         peer_public_key: &PublicKey,
+        role: SessionRole,
     ) -> Result<SharedSecret> {
         let key_pair = self.key_pairs.get(&peer_id)
             .ok_or_else(|| encryption_error!("No key pair found for peer: {}", peer_id))?;
 
-        let shared_secret = key_pair.perform_key_exchange(peer_public_key)?;
+        let shared_secret = key_pair.perform_key_exchange(peer_public_key, role)?
+            .with_replay_window(self.replay_window)
+            .with_cipher(self.cipher);
         self.shared_secrets.insert(peer_id, shared_secret.clone());
         Ok(shared_secret)
     }
@@ -291,23 +576,26 @@ impl KeyExchangeManager {
 pub struct MessageAuthenticator;
 
 impl MessageAuthenticator {
-    /// Create a MAC for a message
+    /// Create an HMAC-SHA256 tag for a message
     pub fn create_mac(key: &[u8; 32], message: &[u8]) -> Result<[u8; 32]> {
-        let mut hasher = Sha256::new();
-        hasher.update(key);
-        hasher.update(message);
-        hasher.update(b"tcp-messenger-mac");
-        
-        let result = hasher.finalize();
-        let mut mac = [0u8; 32];
-        mac.copy_from_slice(&result);
-        Ok(mac)
+        let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(key)
+            .map_err(|e| encryption_error!("Failed to initialize HMAC: {}", e))?;
+        hmac.update(message);
+        Ok(hmac.finalize().into_bytes().into())
     }
 
-    /// Verify a MAC for a message
+    /// Verify a MAC for a message in constant time. Any failure to compute the
+    /// expected tag counts as a verification failure.
     pub fn verify_mac(key: &[u8; 32], message: &[u8], mac: &[u8; 32]) -> bool {
-        let expected_mac = Self::create_mac(key, message).unwrap_or([0u8; 32]);
-        expected_mac == *mac
+        match Self::create_mac(key, message) {
+            Ok(expected_mac) => Self::tags_match(&expected_mac, mac),
+            Err(_) => false,
+        }
+    }
+
+    /// Compare two tags without short-circuiting on the first differing byte
+    fn tags_match(expected: &[u8; 32], actual: &[u8; 32]) -> bool {
+        expected.ct_eq(actual).into()
     }
 }
 
@@ -318,17 +606,16 @@ pub struct SecureMessage {
 }
 
 impl SecureMessage {
-    /// Create a secure message from plaintext
+    /// Create a secure message from plaintext. `associated_data` is authenticated but not
+    /// encrypted, and must be given again to decrypt.
     pub fn encrypt(
         plaintext: &[u8],
-        encryption_key: &[u8; 32],
+        associated_data: &[u8],
+        engine: &mut EncryptionEngine,
         mac_key: &[u8; 32],
     ) -> Result<Self> {
-        // Create encryption engine
-        let mut engine = EncryptionEngine::from_key(encryption_key)?;
-        
         // Encrypt the message
-        let encrypted_data = engine.encrypt_message(plaintext)?;
+        let encrypted_data = engine.encrypt_message_with_aad(plaintext, associated_data)?;
         
         // Create MAC
         let mac = MessageAuthenticator::create_mac(mac_key, &encrypted_data)?;
@@ -342,7 +629,8 @@ impl SecureMessage {
     /// Decrypt a secure message
     pub fn decrypt(
         &self,
-        encryption_key: &[u8; 32],
+        associated_data: &[u8],
+        engine: &EncryptionEngine,
         mac_key: &[u8; 32],
     ) -> Result<Vec<u8>> {
         // Verify MAC first
@@ -350,17 +638,14 @@ impl SecureMessage {
             return Err(encryption_error!("MAC verification failed"));
         }
 
-        // Create encryption engine
-        let engine = EncryptionEngine::from_key(encryption_key)?;
-        
         // Decrypt the message
-        engine.decrypt_message(&self.encrypted_data)
+        engine.decrypt_message_with_aad(&self.encrypted_data, associated_data)
     }
 
     /// Serialize to bytes
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
-        bytes.extend_from_slice(&self.encrypted_data.len().to_be_bytes());
+        bytes.extend_from_slice(&(self.encrypted_data.len() as u32).to_be_bytes());
         bytes.extend_from_slice(&self.encrypted_data);
         bytes.extend_from_slice(&self.mac);
         bytes
@@ -373,6 +658,10 @@ impl SecureMessage {
         }
 
         let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() != 4 + length + 32 {
+            return Err(encryption_error!("Invalid secure message length: {}", length));
+        }
+
         let encrypted_data = data[4..4 + length].to_vec();
         let mut mac = [0u8; 32];
         mac.copy_from_slice(&data[4 + length..4 + length + 32]);
@@ -390,13 +679,61 @@ mod tests {
 
     #[test]
     fn test_encryption_roundtrip() {
-        let mut engine = EncryptionEngine::new().unwrap();
-        let message = b"Hello, World!";
-        
-        let encrypted = engine.encrypt_message(message).unwrap();
-        let decrypted = engine.decrypt_message(&encrypted).unwrap();
-        
-        assert_eq!(message, &decrypted[..]);
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305] {
+            let mut engine = EncryptionEngine::new(suite).unwrap();
+            let message = b"Hello, World!";
+
+            let encrypted = engine.encrypt_message(message).unwrap();
+            let decrypted = engine.decrypt_message(&encrypted).unwrap();
+
+            assert_eq!(message, &decrypted[..]);
+            // Nonce prepended, 16-byte tag appended, whichever the suite
+            assert_eq!(encrypted.len(), 12 + message.len() + 16);
+
+            engine.rotate_key().unwrap();
+            assert_eq!(engine.cipher_suite(), suite);
+        }
+    }
+
+    #[test]
+    fn test_nonces_come_from_a_counter() {
+        let mut engine = EncryptionEngine::from_key(&[3u8; 32], CipherSuite::Aes256Gcm).unwrap();
+        let first = engine.encrypt_message(b"same plaintext").unwrap();
+        let second = engine.encrypt_message(b"same plaintext").unwrap();
+
+        // Same salt, consecutive counters
+        assert_ne!(first[..12], second[..12]);
+        assert_eq!(first[..4], second[..4]);
+        assert_eq!(first[4..12], 0u64.to_be_bytes());
+        assert_eq!(second[4..12], 1u64.to_be_bytes());
+        assert_eq!(engine.decrypt_message(&second).unwrap(), b"same plaintext");
+
+        // Rotation is due well before the counter runs out, and encryption stops at the limit
+        engine.nonce_counter = MAX_NONCE_COUNTER - NONCE_COUNTER_HEADROOM;
+        assert!(engine.should_rotate_key());
+        engine.nonce_counter = MAX_NONCE_COUNTER - 1;
+        assert!(engine.encrypt_message(b"last one").is_ok());
+        assert!(engine.encrypt_message(b"one too many").is_err());
+
+        engine.rotate_key().unwrap();
+        assert!(!engine.should_rotate_key());
+        assert_eq!(engine.encrypt_message(b"fresh key").unwrap()[4..12], 0u64.to_be_bytes());
+    }
+
+    #[test]
+    fn test_cipher_suites_are_not_interchangeable() {
+        let key = [7u8; 32];
+        let mut aes = EncryptionEngine::from_key(&key, CipherSuite::Aes256Gcm).unwrap();
+        let mut chacha = EncryptionEngine::from_key(&key, CipherSuite::ChaCha20Poly1305).unwrap();
+
+        let aes_encrypted = aes.encrypt_message(b"Hello, World!").unwrap();
+        let chacha_encrypted = chacha.encrypt_message(b"Hello, World!").unwrap();
+        assert!(chacha.decrypt_message(&aes_encrypted).is_err());
+        assert!(aes.decrypt_message(&chacha_encrypted).is_err());
+
+        let secure_msg = SecureMessage::encrypt(b"Secure message", &[], &mut chacha, &[2u8; 32]).unwrap();
+        assert_eq!(secure_msg.decrypt(&[], &chacha, &[2u8; 32]).unwrap(), b"Secure message");
+        assert!(secure_msg.decrypt(&[], &aes, &[2u8; 32]).is_err());
     }
 
     #[test]
@@ -404,11 +741,94 @@ mod tests {
         let key_pair1 = KeyPair::generate();
         let key_pair2 = KeyPair::generate();
         
-        let shared_secret1 = key_pair1.perform_key_exchange(&key_pair2.public_key).unwrap();
-        let shared_secret2 = key_pair2.perform_key_exchange(&key_pair1.public_key).unwrap();
+        let shared_secret1 = key_pair1.perform_key_exchange(&key_pair2.public_key, SessionRole::Initiator).unwrap();
+        let shared_secret2 = key_pair2.perform_key_exchange(&key_pair1.public_key, SessionRole::Responder).unwrap();
         
-        assert_eq!(shared_secret1.encryption_key, shared_secret2.encryption_key);
-        assert_eq!(shared_secret1.mac_key, shared_secret2.mac_key);
+        // What one side sends with, the other receives with
+        assert_eq!(shared_secret1.sending, shared_secret2.receiving);
+        assert_eq!(shared_secret1.receiving, shared_secret2.sending);
+        assert_ne!(shared_secret1.sending, shared_secret1.receiving);
+        assert_ne!(shared_secret1.sending.encryption_key, shared_secret1.sending.mac_key);
+    }
+
+    #[test]
+    fn test_reflected_frames_do_not_open() {
+        let key_pair1 = KeyPair::generate();
+        let key_pair2 = KeyPair::generate();
+        let client = key_pair1.perform_key_exchange(&key_pair2.public_key, SessionRole::Initiator).unwrap();
+        let server = key_pair2.perform_key_exchange(&key_pair1.public_key, SessionRole::Responder).unwrap();
+
+        let sealed = client.seal(b"hello", b"header").unwrap();
+        assert_eq!(server.open(&sealed, b"header").unwrap(), b"hello");
+        // Sent back to its sender, the frame fails to verify instead of passing as the peer's
+        assert!(client.open(&sealed, b"header").is_err());
+    }
+
+    #[test]
+    fn test_derived_keys_match_hkdf() {
+        let DirectionKeys { encryption_key, mac_key } = KeyPair::derive_keys(&[5u8; 32], b"initiator").unwrap();
+        assert_ne!(encryption_key, mac_key);
+
+        // Same as expanding the raw secret with HKDF-SHA256 directly
+        let hkdf = Hkdf::<Sha256>::new(Some(KEY_DERIVATION_SALT), &[5u8; 32]);
+        let mut expected = [0u8; 32];
+        hkdf.expand(b"initiator encryption", &mut expected).unwrap();
+        assert_eq!(encryption_key, expected);
+        hkdf.expand(b"initiator mac", &mut expected).unwrap();
+        assert_eq!(mac_key, expected);
+        assert_ne!(KeyPair::derive_keys(&[5u8; 32], b"responder").unwrap().encryption_key, encryption_key);
+
+        // Not the old hash construction
+        let mut hasher = Sha256::new();
+        hasher.update([5u8; 32]);
+        hasher.update(b"encryption");
+        hasher.update(KEY_DERIVATION_SALT);
+        assert_ne!(encryption_key.as_slice(), hasher.finalize().as_slice());
+    }
+
+    #[test]
+    fn test_manager_key_pair_matches_returned_public_key() {
+        let mut manager = KeyExchangeManager::new(100);
+        let peer_id = uuid::Uuid::new_v4();
+        let manager_public_key = manager.generate_key_pair(peer_id).unwrap();
+        assert_eq!(manager_public_key, manager.public_key_bytes(&peer_id).unwrap());
+
+        // A peer who only saw the returned public key derives the same secret as the manager
+        let peer = KeyPair::generate();
+        let peer_secret = peer.perform_key_exchange(&KeyExchangeManager::decode_public_key(&manager_public_key).unwrap(), SessionRole::Initiator).unwrap();
+        let manager_secret = manager.perform_key_exchange(peer_id, &peer.public_key, SessionRole::Responder).unwrap();
+
+        assert_eq!(manager_secret.receiving, peer_secret.sending);
+        assert_eq!(manager_secret.sending, peer_secret.receiving);
+    }
+
+    #[test]
+    fn test_identity_persists_across_loads() {
+        let dir = std::env::temp_dir().join(format!("tcp-messenger-identity-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("identity.key");
+
+        let mut first = KeyExchangeManager::new(100);
+        assert!(first.fingerprint().is_err());
+        first.load_or_create_identity(&path).unwrap();
+        let fingerprint = first.fingerprint().unwrap();
+        assert_eq!(fingerprint.len(), 64);
+        assert!(path.exists());
+
+        let mut second = KeyExchangeManager::new(100);
+        second.load_or_create_identity(&path).unwrap();
+        assert_eq!(second.fingerprint().unwrap(), fingerprint);
+        assert_eq!(second.identity_public_key().unwrap(), first.identity_public_key().unwrap());
+
+        // A different path gets its own identity
+        let mut other = KeyExchangeManager::new(100);
+        other.load_or_create_identity(&dir.join("other.key")).unwrap();
+        assert_ne!(other.fingerprint().unwrap(), fingerprint);
+
+        // A corrupt key file is an error rather than silently replaced
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(KeyExchangeManager::new(100).load_or_create_identity(&path).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
     #[test]
@@ -417,9 +837,65 @@ mod tests {
         let mac_key = [2u8; 32];
         let message = b"Secure message";
         
-        let secure_msg = SecureMessage::encrypt(message, &encryption_key, &mac_key).unwrap();
-        let decrypted = secure_msg.decrypt(&encryption_key, &mac_key).unwrap();
+        let mut engine = EncryptionEngine::from_key(&encryption_key, CipherSuite::Aes256Gcm).unwrap();
+        let secure_msg = SecureMessage::encrypt(message, &[], &mut engine, &mac_key).unwrap();
+        let decrypted = secure_msg.decrypt(&[], &engine, &mac_key).unwrap();
         
         assert_eq!(message, &decrypted[..]);
+
+        // Survives serialization, and the serialized size matches the advertised overhead
+        let bytes = secure_msg.to_bytes();
+        assert_eq!(bytes.len(), message.len() + ENCRYPTION_OVERHEAD);
+        let parsed = SecureMessage::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.decrypt(&[], &engine, &mac_key).unwrap(), message);
+        assert!(SecureMessage::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_tampered_ciphertext_fails_verification() {
+        let encryption_key = [1u8; 32];
+        let mac_key = [2u8; 32];
+        let mut engine = EncryptionEngine::from_key(&encryption_key, CipherSuite::Aes256Gcm).unwrap();
+        let secure_msg = SecureMessage::encrypt(b"Secure message", &[], &mut engine, &mac_key).unwrap();
+
+        // The tag is a standard HMAC-SHA256 over the ciphertext
+        let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(&mac_key).unwrap();
+        hmac.update(&secure_msg.encrypted_data);
+        assert_eq!(secure_msg.mac, <[u8; 32]>::from(hmac.finalize().into_bytes()));
+
+        let mut tampered = SecureMessage::from_bytes(&secure_msg.to_bytes()).unwrap();
+        let last = tampered.encrypted_data.len() - 1;
+        tampered.encrypted_data[last] ^= 0x01;
+        assert!(!MessageAuthenticator::verify_mac(&mac_key, &tampered.encrypted_data, &tampered.mac));
+        assert!(tampered.decrypt(&[], &engine, &mac_key).is_err());
+
+        // Verification goes through the constant-time comparison
+        let mut wrong_tag = secure_msg.mac;
+        wrong_tag[31] ^= 0x01;
+        assert!(MessageAuthenticator::tags_match(&secure_msg.mac, &secure_msg.mac));
+        assert!(!MessageAuthenticator::tags_match(&secure_msg.mac, &wrong_tag));
+        assert_eq!(
+            MessageAuthenticator::tags_match(&secure_msg.mac, &wrong_tag),
+            bool::from(secure_msg.mac.ct_eq(&wrong_tag))
+        );
+        assert!(!MessageAuthenticator::verify_mac(&mac_key, &secure_msg.encrypted_data, &wrong_tag));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new(4);
+        assert!(window.accept(0));
+        assert!(window.accept(2));
+        assert!(!window.accept(2));
+
+        // Out of order but still inside the window
+        assert!(window.accept(1));
+        assert!(window.accept(5));
+        assert!(window.accept(3));
+
+        // Slid out of the window
+        assert!(!window.accept(1));
+        assert!(!window.accept(0));
+        assert!(window.seen.len() <= 4);
     }
 }
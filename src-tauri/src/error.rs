@@ -34,6 +34,9 @@ pub enum MessengerError {
     #[error("Invalid input: {0}")]
     InvalidInput(String),
 
+    #[error("Invalid {field}: {reason}")]
+    InvalidField { field: String, reason: String },
+
     #[error("Connection timeout")]
     ConnectionTimeout,
 
@@ -55,6 +58,15 @@ pub enum MessengerError {
     #[error("Message too large: {size} bytes (max: {max})")]
     MessageTooLarge { size: usize, max: usize },
 
+    #[error("File too large: {size} bytes (max: {max})")]
+    FileTooLarge { size: u64, max: u64 },
+
+    #[error("Too many pending acknowledgments (max: {max})")]
+    TooManyPendingAcknowledgments { max: usize },
+
+    #[error("Outbox is full (max: {max} messages)")]
+    OutboxFull { max: usize },
+
     #[error("Invalid message type: {0}")]
     InvalidMessageType(String),
 
@@ -83,6 +95,16 @@ pub enum MessengerError {
 /// Result type alias for the application
 pub type Result<T> = std::result::Result<T, MessengerError>;
 
+impl MessengerError {
+    /// Create an invalid input error naming the offending field
+    pub fn invalid_field(field: &str, reason: impl Into<String>) -> Self {
+        MessengerError::InvalidField {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
 impl From<MessengerError> for String {
     fn from(err: MessengerError) -> Self {
         err.to_string()
@@ -91,7 +113,15 @@ impl From<MessengerError> for String {
 
 impl From<MessengerError> for InvokeError {
     fn from(err: MessengerError) -> Self {
-        InvokeError::from(err.to_string())
+        match &err {
+            // Keep the field name so the frontend can highlight the offending input
+            MessengerError::InvalidField { field, reason } => InvokeError::from(serde_json::json!({
+                "message": err.to_string(),
+                "field": field,
+                "reason": reason,
+            })),
+            _ => InvokeError::from(err.to_string()),
+        }
     }
 }
 
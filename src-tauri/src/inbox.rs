@@ -0,0 +1,326 @@
+use crate::error::Result;
+use crate::network::NetworkManager;
+use crate::storage::StorageBackend;
+use crate::types::{Message, MessageStatus, MessageStatusEvent, MessageType, TypingEvent};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Something the UI should hear about once an incoming message is handled. Serializes as
+/// its payload alone; `name` gives the event to emit it as.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum InboxEvent {
+    /// A message from a peer was stored
+    Received(Box<Message>),
+    /// A peer edited one of their messages; carries the message as edited
+    Edited(Box<Message>),
+    /// A peer reported on one of our messages
+    StatusChanged(MessageStatusEvent),
+    /// A peer started or stopped typing
+    Typing(TypingEvent),
+}
+
+impl InboxEvent {
+    /// Name of the Tauri event the frontend listens for
+    pub fn name(&self) -> &'static str {
+        match self {
+            InboxEvent::Received(_) => "message-received",
+            InboxEvent::Edited(_) => "message-edited",
+            InboxEvent::StatusChanged(_) => "message-status-changed",
+            InboxEvent::Typing(_) => "peer-typing",
+        }
+    }
+}
+
+/// Apply one message from the network to local storage
+pub async fn handle_incoming(storage: &RwLock<Box<dyn StorageBackend>>, local_id: Uuid, message: Message) -> Result<Option<InboxEvent>> {
+    match &message.message_type {
+        MessageType::ReadReceipt { message_id } | MessageType::Acknowledgment { message_id } => {
+            let mut storage = storage.write().await;
+            // Servers broadcast receipts, so skip those for messages that aren't ours
+            if storage.get_message(message_id)?.is_none_or(|sent| sent.sender_id != local_id) {
+                debug!("Ignoring receipt for message {}", message_id);
+                return Ok(None);
+            }
+
+            let changed = storage.set_message_status(message_id, MessageStatus::Acknowledged).await?;
+            Ok(changed.then_some(InboxEvent::StatusChanged(MessageStatusEvent {
+                message_id: *message_id,
+                status: MessageStatus::Acknowledged,
+            })))
+        },
+        MessageType::Edit { target_id, .. } => {
+            let mut storage = storage.write().await;
+            storage.apply_edit(&message).await?;
+            Ok(storage.get_message(target_id)?.map(|edited| InboxEvent::Edited(Box::new(edited))))
+        },
+        MessageType::Typing { is_typing } => Ok(Some(InboxEvent::Typing(TypingEvent {
+            sender_id: message.sender_id,
+            is_typing: *is_typing,
+        }))),
+        MessageType::Text { .. } | MessageType::File { .. } | MessageType::System { .. } | MessageType::Reaction { .. } => {
+            storage.write().await.store_message(message.clone()).await?;
+            Ok(Some(InboxEvent::Received(Box::new(message))))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Mark a message read and, if a peer sent it, send them a read receipt. The receipt is
+/// best effort: without a connection the message is still marked read.
+pub async fn mark_read(
+    storage: &RwLock<Box<dyn StorageBackend>>,
+    network_manager: &RwLock<Option<NetworkManager>>,
+    local_id: Uuid,
+    message_id: &Uuid,
+) -> Result<()> {
+    let needs_receipt = {
+        let mut storage = storage.write().await;
+        let needs_receipt = storage.get_message(message_id)?
+            .is_some_and(|message| !message.read && message.sender_id != local_id);
+        storage.mark_message_read(message_id).await?;
+        needs_receipt
+    };
+    if !needs_receipt {
+        return Ok(());
+    }
+
+    match network_manager.read().await.as_ref() {
+        Some(manager) => {
+            if let Err(e) = manager.send_message(Message::new_read_receipt(*message_id, local_id)).await {
+                warn!("Failed to send read receipt for message {}: {}", message_id, e);
+            }
+        },
+        None => debug!("Not connected, skipping read receipt for message {}", message_id),
+    }
+    Ok(())
+}
+
+/// Feed messages from the network through `handle_incoming` until the channel closes
+pub async fn run(
+    storage: Arc<RwLock<Box<dyn StorageBackend>>>,
+    local_id: Uuid,
+    mut receiver: mpsc::Receiver<Message>,
+    mut on_event: impl FnMut(InboxEvent),
+) {
+    while let Some(message) = receiver.recv().await {
+        match handle_incoming(&storage, local_id, message).await {
+            Ok(Some(event)) => on_event(event),
+            Ok(None) => {},
+            Err(e) => warn!("Failed to handle incoming message: {}", e),
+        }
+    }
+    debug!("Inbox closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{open_backend, StorageConfig};
+    use crate::types::MessageFilter;
+    use std::time::Duration;
+
+    async fn temp_storage() -> (Arc<RwLock<Box<dyn StorageBackend>>>, std::path::PathBuf) {
+        let config = StorageConfig::temp(|_| {});
+        let storage = open_backend(&config).await.unwrap();
+        (Arc::new(RwLock::new(storage)), config.data_directory)
+    }
+
+    #[tokio::test]
+    async fn test_read_on_one_side_acknowledges_on_the_other() {
+        let (server_storage, server_dir) = temp_storage().await;
+        let (client_storage, client_dir) = temp_storage().await;
+        let server_id = Uuid::new_v4();
+        let client_id = Uuid::new_v4();
+
+        let (mut server, _sender) = NetworkManager::new();
+        let mut server_inbox = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let server = RwLock::new(Some(server));
+
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        let mut client_inbox = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        let client = RwLock::new(Some(client));
+
+        // The client sends a message and the server stores it on arrival
+        let message = Message::new_text("Did you get this?".to_string(), client_id);
+        client_storage.write().await.store_message(message.clone()).await.unwrap();
+        client.read().await.as_ref().unwrap().send_message(message.clone()).await.unwrap();
+        let arrived = tokio::time::timeout(Duration::from_secs(2), server_inbox.recv()).await.unwrap().unwrap();
+        let event = handle_incoming(&server_storage, server_id, arrived).await.unwrap();
+        assert!(matches!(event, Some(InboxEvent::Received(ref received)) if received.id == message.id));
+
+        // Leave the delivery ack unhandled so only the receipt can change the client's copy
+        let ack = tokio::time::timeout(Duration::from_secs(2), client_inbox.recv()).await.unwrap().unwrap();
+        assert_eq!(ack.message_type, MessageType::Acknowledgment { message_id: message.id });
+
+        // Reading it on the server flips the client's copy to acknowledged
+        mark_read(&server_storage, &server, server_id, &message.id).await.unwrap();
+        assert!(server_storage.read().await.get_message(&message.id).unwrap().unwrap().read);
+
+        let receipt = tokio::time::timeout(Duration::from_secs(2), client_inbox.recv()).await.unwrap().unwrap();
+        assert_eq!(receipt.message_type, MessageType::ReadReceipt { message_id: message.id });
+        let event = handle_incoming(&client_storage, client_id, receipt).await.unwrap();
+        assert_eq!(event, Some(InboxEvent::StatusChanged(MessageStatusEvent {
+            message_id: message.id,
+            status: MessageStatus::Acknowledged,
+        })));
+        assert_eq!(client_storage.read().await.get_message(&message.id).unwrap().unwrap().status, MessageStatus::Acknowledged);
+
+        client.write().await.take().unwrap().disconnect().await.unwrap();
+        server.write().await.take().unwrap().stop_server().await.unwrap();
+        std::fs::remove_dir_all(&server_dir).unwrap();
+        std::fs::remove_dir_all(&client_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_received_messages_are_stored_and_emitted() {
+        let (storage, dir) = temp_storage().await;
+        let client_id = Uuid::new_v4();
+
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        let client_inbox = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        for _ in 0..50 {
+            if !server.clients.read().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        // Stands in for the app handle, recording what would be emitted
+        let (emitted_tx, mut emitted) = mpsc::unbounded_channel();
+        let client_loop = tokio::spawn(run(storage.clone(), client_id, client_inbox, move |event| {
+            let _ = emitted_tx.send((event.name(), serde_json::to_value(&event).unwrap()));
+        }));
+
+        let message = Message::new_text("Hello from the server".to_string(), info.id);
+        server.send_message(message.clone()).await.unwrap();
+
+        let (name, payload) = tokio::time::timeout(Duration::from_secs(2), emitted.recv()).await.unwrap().unwrap();
+        assert_eq!(name, "message-received");
+        let stored = storage.read().await.get_message(&message.id).unwrap().unwrap();
+        assert_eq!(stored.message_type, message.message_type);
+        assert_eq!(payload, serde_json::to_value(&stored).unwrap());
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+        client_loop.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delivered_messages_are_acknowledged() {
+        let (server_storage, server_dir) = temp_storage().await;
+        let (client_storage, client_dir) = temp_storage().await;
+        let server_id = Uuid::new_v4();
+        let client_id = Uuid::new_v4();
+
+        let (mut server, _sender) = NetworkManager::new();
+        let server_inbox = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        let client_inbox = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+
+        let server_loop = tokio::spawn(run(server_storage.clone(), server_id, server_inbox, |_| {}));
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let client_loop = tokio::spawn(run(client_storage.clone(), client_id, client_inbox, move |event| {
+            let _ = events_tx.send(event);
+        }));
+
+        // The server acknowledges on receipt, without anyone reading the message
+        let message = Message::new_text("Delivered?".to_string(), client_id);
+        client_storage.write().await.store_message(message.clone()).await.unwrap();
+        client.send_message(message.clone()).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event, InboxEvent::StatusChanged(MessageStatusEvent {
+            message_id: message.id,
+            status: MessageStatus::Acknowledged,
+        }));
+        assert_eq!(client_storage.read().await.get_message(&message.id).unwrap().unwrap().status, MessageStatus::Acknowledged);
+        assert!(!server_storage.read().await.get_message(&message.id).unwrap().unwrap().read);
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+        server_loop.abort();
+        client_loop.abort();
+        std::fs::remove_dir_all(&server_dir).unwrap();
+        std::fs::remove_dir_all(&client_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_typing_is_forwarded_but_never_stored() {
+        let (storage, dir) = temp_storage().await;
+        let client_id = Uuid::new_v4();
+
+        let (mut server, _sender) = NetworkManager::new();
+        let mut server_inbox = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+
+        let typing = Message::new_typing(true, client_id);
+        client.send_message(typing.clone()).await.unwrap();
+        let arrived = tokio::time::timeout(Duration::from_secs(2), server_inbox.recv()).await.unwrap().unwrap();
+        assert_eq!(arrived.message_type, MessageType::Typing { is_typing: true });
+
+        let event = handle_incoming(&storage, Uuid::new_v4(), arrived).await.unwrap();
+        assert_eq!(event, Some(InboxEvent::Typing(TypingEvent { sender_id: client_id, is_typing: true })));
+        storage.write().await.store_message(typing).await.unwrap();
+        assert!(storage.read().await.get_messages_with_filter(&MessageFilter::default()).unwrap().is_empty());
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_receipts_for_other_senders_are_ignored() {
+        let (storage, dir) = temp_storage().await;
+        let local_id = Uuid::new_v4();
+        let theirs = Message::new_text("Not mine".to_string(), Uuid::new_v4());
+        storage.write().await.store_message(theirs.clone()).await.unwrap();
+
+        let receipt = Message::new_read_receipt(theirs.id, Uuid::new_v4());
+        assert_eq!(handle_incoming(&storage, local_id, receipt).await.unwrap(), None);
+        let unknown = Message::new_read_receipt(Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(handle_incoming(&storage, local_id, unknown).await.unwrap(), None);
+        assert_eq!(storage.read().await.get_message(&theirs.id).unwrap().unwrap().status, theirs.status);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_edits_update_the_stored_message() {
+        let (storage, dir) = temp_storage().await;
+        let peer_id = Uuid::new_v4();
+        let original = Message::new_text("See you at 5".to_string(), peer_id);
+        handle_incoming(&storage, Uuid::new_v4(), original.clone()).await.unwrap();
+
+        let edit = Message::new_edit(original.id, "See you at 6".to_string(), peer_id);
+        let Some(InboxEvent::Edited(edited)) = handle_incoming(&storage, Uuid::new_v4(), edit).await.unwrap() else {
+            panic!("expected an edited event");
+        };
+        assert_eq!(edited.message_type, MessageType::Text { content: "See you at 6".to_string() });
+        assert_eq!(storage.read().await.get_message(&original.id).unwrap(), Some(*edited));
+
+        // Only the sender may edit, and the edit itself is never stored
+        let impostor = Message::new_edit(original.id, "Changed".to_string(), Uuid::new_v4());
+        assert!(handle_incoming(&storage, Uuid::new_v4(), impostor).await.is_err());
+        assert_eq!(storage.read().await.get_messages_with_filter(&MessageFilter::default()).unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use crate::error::{MessengerError, Result};
+use crate::protocol::ProtocolMessage;
+use crate::types::Message;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Direction of a journaled protocol message
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub enum JournalDirection {
+    Inbound,
+    Outbound,
+}
+
+/// A single journaled protocol message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub direction: JournalDirection,
+    pub timestamp: DateTime<Utc>,
+    /// Raw frame bytes (header + data), base64 encoded
+    pub frame: String,
+}
+
+impl JournalEntry {
+    /// Decode the raw frame bytes
+    pub fn frame_bytes(&self) -> Result<Vec<u8>> {
+        STANDARD.decode(&self.frame)
+            .map_err(|e| MessengerError::Storage(format!("Failed to decode journal frame: {}", e)))
+    }
+}
+
+/// Capped on-disk journal of every protocol message sent or received, for debugging
+#[derive(Debug)]
+pub struct MessageJournal {
+    path: PathBuf,
+    entries: VecDeque<JournalEntry>,
+    max_entries: usize,
+    /// Entries in the on-disk log, which holds up to twice `max_entries` between truncations
+    on_disk: usize,
+}
+
+impl MessageJournal {
+    /// Open a journal, loading any entries already on disk
+    pub fn open(path: PathBuf, max_entries: usize) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| MessengerError::Storage(format!("Failed to create journal directory: {}", e)))?;
+        }
+
+        let mut entries: VecDeque<JournalEntry> = Self::load(&path)?.into();
+        let on_disk = entries.len();
+        while entries.len() > max_entries {
+            entries.pop_front();
+        }
+
+        Ok(Self {
+            path,
+            entries,
+            max_entries,
+            on_disk,
+        })
+    }
+
+    /// Record a protocol message. The file is written asynchronously so callers on the
+    /// network tasks never block a runtime thread on disk I/O.
+    pub async fn record(&mut self, direction: JournalDirection, message: &ProtocolMessage) -> Result<()> {
+        let entry = JournalEntry {
+            direction,
+            timestamp: Utc::now(),
+            frame: STANDARD.encode(message.to_bytes()),
+        };
+
+        self.append(&entry).await?;
+        self.on_disk += 1;
+        self.entries.push_back(entry);
+        while self.entries.len() > self.max_entries {
+            self.entries.pop_front();
+        }
+
+        // Drop the oldest entries from the log in batches rather than rewriting it per frame
+        if self.on_disk > self.max_entries.saturating_mul(2) {
+            self.rewrite().await?;
+            self.on_disk = self.entries.len();
+        }
+
+        debug!("Journaled {:?} message", direction);
+        Ok(())
+    }
+
+    /// Get the newest `max_entries` journaled entries, oldest first
+    pub fn entries(&self) -> Vec<JournalEntry> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// Path of the on-disk log
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load journal entries from disk
+    pub fn load(path: &Path) -> Result<Vec<JournalEntry>> {
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to read journal: {}", e)))?;
+
+        content.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                serde_json::from_str(line)
+                    .map_err(|e| MessengerError::Storage(format!("Failed to parse journal entry: {}", e)))
+            })
+            .collect()
+    }
+
+    async fn append(&self, entry: &JournalEntry) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(|e| MessengerError::Storage(format!("Failed to open journal: {}", e)))?;
+
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes()).await
+            .map_err(|e| MessengerError::Storage(format!("Failed to write journal: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Replace the log with the retained entries. The new log is written beside the old one
+    /// and renamed over it, so a crash mid-write leaves the old log intact.
+    async fn rewrite(&self) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let mut content = String::new();
+        for entry in &self.entries {
+            content.push_str(&serde_json::to_string(entry)?);
+            content.push('\n');
+        }
+
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_name);
+        let mut file = tokio::fs::File::create(&temp_path).await
+            .map_err(|e| MessengerError::Storage(format!("Failed to rewrite journal: {}", e)))?;
+        file.write_all(content.as_bytes()).await
+            .map_err(|e| MessengerError::Storage(format!("Failed to rewrite journal: {}", e)))?;
+        file.sync_all().await
+            .map_err(|e| MessengerError::Storage(format!("Failed to rewrite journal: {}", e)))?;
+        tokio::fs::rename(&temp_path, &self.path).await
+            .map_err(|e| MessengerError::Storage(format!("Failed to replace journal: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+/// Re-feed recorded frames through the protocol parser
+pub fn replay_journal(entries: &[JournalEntry]) -> Result<Vec<(JournalDirection, Message)>> {
+    entries.iter()
+        .map(|entry| {
+            let frame = entry.frame_bytes()?;
+            let message = ProtocolMessage::from_bytes(&frame)?.to_message()?;
+            Ok((entry.direction, message))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_journal_path() -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("tcp-messenger-test-{}", Uuid::new_v4()))
+            .join("journal.jsonl")
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = temp_journal_path();
+        let mut journal = MessageJournal::open(path.clone(), 100).unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let messages = vec![
+            Message::new_text("first".to_string(), sender_id),
+            Message::new_heartbeat(sender_id),
+            Message::new_text("third".to_string(), sender_id),
+        ];
+
+        journal.record(JournalDirection::Outbound, &ProtocolMessage::new(&messages[0]).unwrap()).await.unwrap();
+        journal.record(JournalDirection::Inbound, &ProtocolMessage::new(&messages[1]).unwrap()).await.unwrap();
+        journal.record(JournalDirection::Inbound, &ProtocolMessage::new(&messages[2]).unwrap()).await.unwrap();
+
+        let replayed = replay_journal(&MessageJournal::load(&path).unwrap()).unwrap();
+        assert_eq!(replayed.len(), 3);
+        assert_eq!(replayed[0], (JournalDirection::Outbound, messages[0].clone()));
+        assert_eq!(replayed[1], (JournalDirection::Inbound, messages[1].clone()));
+        assert_eq!(replayed[2], (JournalDirection::Inbound, messages[2].clone()));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_journal_is_capped() {
+        let path = temp_journal_path();
+        let mut journal = MessageJournal::open(path.clone(), 2).unwrap();
+
+        let contents = |entries: &[JournalEntry]| -> Vec<String> {
+            replay_journal(entries).unwrap().iter()
+                .map(|(_, message)| match &message.message_type {
+                    crate::types::MessageType::Text { content } => content.clone(),
+                    _ => String::new(),
+                })
+                .collect()
+        };
+
+        let sender_id = Uuid::new_v4();
+        let mut record = async |content: &str| {
+            let message = Message::new_text(content.to_string(), sender_id);
+            journal.record(JournalDirection::Outbound, &ProtocolMessage::new(&message).unwrap()).await.unwrap();
+        };
+        for content in ["a", "b", "c"] {
+            record(content).await;
+        }
+
+        // The log is only cut back once it holds twice the cap
+        assert_eq!(contents(&MessageJournal::load(&path).unwrap()), vec!["a", "b", "c"]);
+        for content in ["d", "e"] {
+            record(content).await;
+        }
+        assert_eq!(contents(&MessageJournal::load(&path).unwrap()), vec!["d", "e"]);
+        assert_eq!(contents(&journal.entries()), vec!["d", "e"]);
+        assert_eq!(contents(&MessageJournal::open(path.clone(), 2).unwrap().entries()), vec!["d", "e"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -6,7 +6,12 @@ pub mod protocol;
 pub mod encryption;
 pub mod network;
 pub mod storage;
+pub mod sqlite_storage;
 pub mod discovery;
+pub mod journal;
+pub mod supervisor;
+pub mod transfer;
+pub mod inbox;
 pub mod commands;
 
 // Re-exports for easier access
@@ -14,25 +19,133 @@ pub use error::{MessengerError, Result};
 pub use types::*;
 
 use std::sync::Arc;
+use storage::StorageBackend;
 use tokio::sync::RwLock;
-use tracing::{info, error};
+use tracing::{info, warn, error};
 
 // Application state
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct AppState {
     pub config: Arc<RwLock<config::AppConfig>>,
+    /// File the configuration is loaded from and saved to
+    pub config_path: std::path::PathBuf,
     pub network_manager: Arc<RwLock<Option<network::NetworkManager>>>,
-    pub storage: Arc<RwLock<storage::MessageStorage>>,
+    /// Message store of the configured backend
+    pub storage: Arc<RwLock<Box<dyn StorageBackend>>>,
+    pub local_id: uuid::Uuid,
+    pub discovery: Arc<RwLock<Option<discovery::NetworkDiscovery>>>,
+    /// Servers found by discovery, kept between scans
+    pub discovered_servers: Arc<RwLock<std::collections::HashMap<uuid::Uuid, discovery::DiscoveredServer>>>,
+    /// Cancellation flags for streaming searches in progress
+    pub searches: Arc<RwLock<std::collections::HashMap<uuid::Uuid, Arc<std::sync::atomic::AtomicBool>>>>,
 }
 
 impl AppState {
     pub fn new() -> Self {
+        let config = config::AppConfig::default();
         Self {
-            config: Arc::new(RwLock::new(config::AppConfig::default())),
+            storage: Arc::new(RwLock::new(storage::create_backend(&(&config.storage).into()))),
+            config: Arc::new(RwLock::new(config)),
+            config_path: config::AppConfig::default_config_path(),
             network_manager: Arc::new(RwLock::new(None)),
-            storage: Arc::new(RwLock::new(storage::MessageStorage::new())),
+            local_id: uuid::Uuid::new_v4(),
+            discovery: Arc::new(RwLock::new(None)),
+            discovered_servers: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            searches: Arc::new(RwLock::new(std::collections::HashMap::new())),
         }
     }
+
+    /// Create state seeded from the config file at the default location
+    pub fn with_loaded_config() -> Self {
+        Self::with_config_file(&config::AppConfig::default_config_path())
+    }
+
+    /// Create state seeded from a config file, falling back to defaults if it is unusable
+    pub fn with_config_file(path: &std::path::PathBuf) -> Self {
+        let config = match config::AppConfig::load_from_file(path).and_then(|mut config| {
+            config.apply_env_overrides(std::env::vars())?;
+            config.validate()?;
+            Ok(config)
+        }) {
+            Ok(config) => {
+                info!("Loaded configuration from {:?}", path);
+                config
+            },
+            Err(e) => {
+                warn!("Ignoring configuration at {:?}, using defaults: {}", path, e);
+                config::AppConfig::default()
+            },
+        };
+
+        // Keep the same id across launches so our own earlier messages are still recognised
+        let local_id = Self::load_or_create_local_id(&config.storage.local_id_path()).unwrap_or_else(|e| {
+            warn!("Using a temporary local id: {}", e);
+            uuid::Uuid::new_v4()
+        });
+
+        Self {
+            storage: Arc::new(RwLock::new(storage::create_backend(&(&config.storage).into()))),
+            config: Arc::new(RwLock::new(config)),
+            config_path: path.clone(),
+            local_id,
+            ..Self::new()
+        }
+    }
+
+    /// Load the local user id saved at `path`, generating and saving one on first use
+    fn load_or_create_local_id(path: &std::path::Path) -> Result<uuid::Uuid> {
+        if path.exists() {
+            let saved = std::fs::read_to_string(path)
+                .map_err(|e| MessengerError::Storage(format!("Failed to read local id: {}", e)))?;
+            return uuid::Uuid::parse_str(saved.trim())
+                .map_err(|e| MessengerError::Storage(format!("Invalid local id: {}", e)));
+        }
+
+        let local_id = uuid::Uuid::new_v4();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| MessengerError::Storage(format!("Failed to create data directory: {}", e)))?;
+        }
+        std::fs::write(path, local_id.to_string())
+            .map_err(|e| MessengerError::Storage(format!("Failed to save local id: {}", e)))?;
+        Ok(local_id)
+    }
+
+    /// Shut down in order: close connections with a disconnect notice, stop discovery and
+    /// flush storage. Network and discovery get at most `grace` to finish before they are
+    /// abandoned; storage is flushed either way.
+    pub async fn shutdown(&self, grace: std::time::Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + grace;
+        info!("Shutting down with a {:?} grace period", grace);
+
+        for cancelled in self.searches.write().await.drain().map(|(_, cancelled)| cancelled) {
+            cancelled.store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        if let Some(mut manager) = self.network_manager.write().await.take() {
+            match tokio::time::timeout_at(deadline, manager.disconnect()).await {
+                Ok(Ok(())) => {},
+                Ok(Err(e)) => warn!("Network did not shut down cleanly: {}", e),
+                Err(_) => warn!("Network shutdown exceeded the grace period, abandoning open connections"),
+            }
+        }
+
+        if let Some(mut discovery) = self.discovery.write().await.take() {
+            if tokio::time::timeout_at(deadline, discovery.stop()).await.is_err() {
+                warn!("Discovery shutdown exceeded the grace period");
+            }
+        }
+
+        self.storage.write().await.compact().await?;
+        info!("Shutdown complete");
+        Ok(())
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -44,26 +157,71 @@ pub fn run() {
 
     info!("Starting TCP Messenger application");
 
-    let app_state = AppState::new();
+    let app_state = AppState::with_loaded_config();
+    let backups = {
+        let config = app_state.config.blocking_read();
+        config.storage.backup_enabled
+            .then(|| std::time::Duration::from_secs(config.storage.backup_interval.max(1) * 3600))
+    };
+    let backup_storage = app_state.storage.clone();
+    let storage = app_state.storage.clone();
 
     tauri::Builder::default()
         .manage(app_state)
+        .setup(move |_app| {
+            // Load stored messages, dropping any past the retention period, before commands can use them
+            tauri::async_runtime::block_on(async { storage.write().await.initialize().await })?;
+            if let Some(interval) = backups {
+                tauri::async_runtime::spawn(storage::run_backups(backup_storage, interval));
+            }
+            Ok(())
+        })
         .invoke_handler(tauri::generate_handler![
             commands::server::start_server,
             commands::server::stop_server,
             commands::server::get_server_status,
+            commands::server::get_peer_stats,
+            commands::server::get_connection_audit,
+            commands::server::get_identity_fingerprint,
             commands::client::connect_to_server,
             commands::client::disconnect,
             commands::client::get_connection_status,
+            commands::client::test_connection,
+            commands::client::benchmark_peer,
             commands::message::send_message,
+            commands::message::send_direct_message,
+            commands::message::preview_message,
             commands::message::get_messages,
+            commands::message::get_messages_with_filter,
+            commands::message::get_reactions,
+            commands::message::search_messages,
+            commands::message::start_message_search,
+            commands::message::cancel_message_search,
+            commands::message::rebuild_index,
+            commands::message::delete_message,
+            commands::message::clear_all_messages,
+            commands::message::export_messages,
+            commands::message::export_messages_to_bytes,
+            commands::message::import_messages,
+            commands::message::get_message_stats,
+            commands::message::get_message_histogram,
+            commands::message::mark_message_read,
+            commands::message::send_typing,
+            commands::message::set_message_pinned,
+            commands::message::trim_history,
+            commands::message::cleanup_expired_messages,
+            commands::message::get_unread_count,
             commands::message::send_file,
             commands::config::get_config,
             commands::config::update_config,
+            commands::config::export_config_profile,
+            commands::config::import_config_profile,
+            commands::config::get_effective_config,
             commands::discovery::discover_servers,
             commands::discovery::get_discovered_servers,
             commands::discovery::start_server_announcement,
             commands::discovery::stop_server_announcement,
+            commands::app::shutdown,
         ])
         .run(tauri::generate_context!())
         .unwrap_or_else(|e| {
@@ -71,3 +229,110 @@ pub fn run() {
             std::process::exit(1);
         });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::ProtocolHandler;
+
+    #[tokio::test]
+    async fn test_state_loads_config_file() {
+        let dir = std::env::temp_dir().join(format!("tcp-messenger-state-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.json");
+
+        let mut saved = config::AppConfig::default();
+        saved.network.server.max_clients = 6;
+        saved.storage.data_directory = dir.join("data");
+        saved.storage.message_retention_days = 1;
+        saved.save_to_file(&path).unwrap();
+
+        let state = AppState::with_config_file(&path);
+        let loaded = state.config.read().await;
+        assert_eq!(loaded.network.server.max_clients, 6);
+
+        // Storage follows the configured retention period rather than the default
+        let mut storage = state.storage.write().await;
+        storage.initialize().await.unwrap();
+        let mut old = Message::new_text("two days old".to_string(), state.local_id);
+        old.timestamp = chrono::Utc::now() - chrono::Duration::days(2);
+        storage.store_message(old).await.unwrap();
+        assert_eq!(storage.cleanup_expired_messages().await.unwrap(), 1);
+        drop(storage);
+
+        // The local id is saved on first launch and reused after a restart
+        let restarted = AppState::with_config_file(&path);
+        assert_eq!(restarted.local_id, state.local_id);
+        assert!(dir.join("data").join("local_id").exists());
+
+        // An unparseable file falls back to defaults
+        std::fs::write(&path, "not json").unwrap();
+        let state = AppState::with_config_file(&path);
+        assert_eq!(
+            state.config.read().await.network.server.max_clients,
+            config::AppConfig::default().network.server.max_clients
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_uses_configured_backend() {
+        let dir = std::env::temp_dir().join(format!("tcp-messenger-state-{}", uuid::Uuid::new_v4()));
+        let path = dir.join("config.json");
+        let mut saved = config::AppConfig::default();
+        saved.storage.data_directory = dir.join("data");
+        saved.storage.backend = storage::StorageBackendKind::Sqlite;
+        saved.save_to_file(&path).unwrap();
+
+        let state = AppState::with_config_file(&path);
+        state.storage.write().await.initialize().await.unwrap();
+        let message = Message::new_text("in the database".to_string(), state.local_id);
+        state.storage.write().await.store_message(message.clone()).await.unwrap();
+        assert_eq!(state.storage.read().await.get_message(&message.id).unwrap(), Some(message));
+        assert!(dir.join("data").join("messages").join("messages.db").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_notifies_peers_and_flushes_storage() {
+        let storage_config = storage::StorageConfig::temp(|_| {});
+        let dir = storage_config.data_directory.clone();
+        let mut storage = storage::MessageStorage::with_config(&storage_config);
+        storage.initialize().await.unwrap();
+        let message = Message::new_text("keep me".to_string(), uuid::Uuid::new_v4());
+        storage.store_message(message.clone()).await.unwrap();
+        // Lose the log so only the flush on shutdown can bring the message back
+        std::fs::remove_file(dir.join("messages").join("messages.log")).unwrap();
+
+        let state = AppState {
+            storage: Arc::new(RwLock::new(Box::new(storage))),
+            ..AppState::new()
+        };
+        let (mut manager, _sender) = network::NetworkManager::new();
+        manager.config.close_linger = 200;
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        *state.network_manager.write().await = Some(manager);
+
+        let mut peer = tokio::net::TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        for _ in 0..50 {
+            if !state.network_manager.read().await.as_ref().unwrap().clients.read().await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        state.shutdown(std::time::Duration::from_millis(500)).await.unwrap();
+
+        let goodbye = ProtocolHandler::receive_message(&mut peer).await.unwrap();
+        assert!(matches!(goodbye.message_type, MessageType::Disconnect { .. }));
+        assert!(state.network_manager.read().await.is_none());
+        assert!(tokio::net::TcpStream::connect(("127.0.0.1", info.port)).await.is_err());
+
+        let mut reloaded = storage::MessageStorage::with_config(&storage_config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_message(&message.id).unwrap().id, message.id);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,27 @@
 use crate::error::{MessengerError, Result};
-use crate::types::{Message, ConnectionStatus, ServerInfo, ClientInfo, NetworkStats};
-use crate::protocol::{ProtocolHandler, HeartbeatHandler};
-use crate::encryption::{KeyExchangeManager, SharedSecret};
-use std::collections::HashMap;
+use crate::types::{Message, MessageStatus, MessageType, SystemMessageLevel, ConnectionStatus, ServerInfo, ClientInfo, NetworkStats, PeerStats, ConnectionAttempt, ConnectionOutcome, BenchmarkReport};
+use crate::protocol::{AcknowledgmentHandler, ProtocolHandler, ProtocolMessage, HeartbeatHandler, PendingAcknowledgments, RecentMessages, ControlChannel, ControlState};
+use crate::config::{AcknowledgmentConfig, AppConfig, ClientConfig, NetworkConfig, SecurityConfig, ServerConfig};
+use crate::encryption::{KeyExchangeManager, SessionRole, SharedSecret};
+use crate::journal::{JournalDirection, MessageJournal};
+use crate::supervisor;
+use std::collections::{HashMap, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use tokio::net::{TcpStream, TcpListener};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::task::JoinHandle;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::{mpsc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
 use uuid::Uuid;
-use tracing::{info, error};
+use tracing::{info, warn, error, debug};
+
+/// Maximum number of connection attempts kept in the audit trail
+pub const MAX_AUDIT_ENTRIES: usize = 1000;
+
+/// How long received message ids are remembered for deduplication
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(10 * 60);
 
 /// Network manager that handles both server and client connections
 #[derive(Debug)]
@@ -18,11 +30,22 @@ pub struct NetworkManager {
     pub server_info: Option<ServerInfo>,
     pub client_info: Option<ClientInfo>,
     pub stats: Arc<RwLock<NetworkStats>>,
+    pub clients: Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
+    pub audit: Arc<RwLock<ConnectionAudit>>,
+    pub recent_messages: Arc<RwLock<RecentMessages>>,
+    pub journal: Option<Arc<RwLock<MessageJournal>>>,
+    pub config: NetworkConfig,
+    pub security: SecurityConfig,
     pub message_sender: mpsc::Sender<Message>,
     pub message_receiver: Arc<RwLock<Option<mpsc::Receiver<Message>>>>,
     pub key_manager: Arc<RwLock<KeyExchangeManager>>,
     pub heartbeat_handler: Arc<RwLock<HeartbeatHandler>>,
+    pub control_state: Arc<RwLock<ControlState>>,
     pub connection_start_time: Option<Instant>,
+    /// Messages waiting for the client connection to come (back) up
+    pub outbox: Arc<Outbox>,
+    server: Option<TcpServer>,
+    client: Option<TcpClient>,
 }
 
 /// Connection type
@@ -33,35 +56,383 @@ pub enum ConnectionType {
 }
 
 /// Server implementation
+#[derive(Debug)]
 pub struct TcpServer {
-    listener: Option<TcpListener>,
+    listeners: Vec<TcpListener>,
+    accept_tasks: Vec<JoinHandle<()>>,
+    status: Arc<std::sync::RwLock<ConnectionStatus>>,
+    reaper_task: Option<JoinHandle<()>>,
+    control_task: Option<JoinHandle<()>>,
+    bound_addresses: Vec<SocketAddr>,
+    connectable_addresses: Vec<SocketAddr>,
     clients: Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
+    audit: Arc<RwLock<ConnectionAudit>>,
+    recent_messages: Arc<RwLock<RecentMessages>>,
+    journal: Option<Arc<RwLock<MessageJournal>>>,
+    ack_config: AcknowledgmentConfig,
+    config: ServerConfig,
+    max_message_size: usize,
     message_sender: mpsc::Sender<Message>,
     key_manager: Arc<RwLock<KeyExchangeManager>>,
     heartbeat_handler: Arc<RwLock<HeartbeatHandler>>,
+    control: ControlChannel,
+    stats: Arc<RwLock<NetworkStats>>,
+    server_id: Uuid,
+    /// Set to true to make the accept loops return and release their listeners
+    stopping: watch::Sender<bool>,
+}
+
+/// Shared state each of the server's accept loops runs against
+#[derive(Clone)]
+struct AcceptContext {
+    clients: Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
+    audit: Arc<RwLock<ConnectionAudit>>,
+    recent_messages: Arc<RwLock<RecentMessages>>,
+    journal: Option<Arc<RwLock<MessageJournal>>>,
+    ack_config: AcknowledgmentConfig,
+    max_clients: usize,
+    max_message_size: usize,
+    message_sender: mpsc::Sender<Message>,
+    key_manager: Arc<RwLock<KeyExchangeManager>>,
+    control: ControlChannel,
     stats: Arc<RwLock<NetworkStats>>,
+    status: Arc<std::sync::RwLock<ConnectionStatus>>,
     server_id: Uuid,
+    stopping: watch::Receiver<bool>,
+}
+
+/// Record a frame in the journal, if journaling is enabled
+async fn journal_frame(journal: Option<&RwLock<MessageJournal>>, direction: JournalDirection, protocol_msg: &ProtocolMessage) {
+    if let Some(journal) = journal {
+        if let Err(e) = journal.write().await.record(direction, protocol_msg).await {
+            error!("Failed to journal message: {}", e);
+        }
+    }
+}
+
+/// Expand wildcard binds such as 0.0.0.0 into the concrete interface addresses they listen on.
+/// Loopback addresses are skipped unless nothing else is available.
+fn connectable_addresses(bound: &[SocketAddr]) -> Vec<SocketAddr> {
+    let interfaces: Vec<IpAddr> = match get_if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces.into_iter().map(|interface| interface.ip()).collect(),
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            Vec::new()
+        }
+    };
+
+    let mut addresses = Vec::new();
+    for addr in bound {
+        if !addr.ip().is_unspecified() {
+            addresses.push(*addr);
+            continue;
+        }
+
+        let same_family: Vec<IpAddr> = interfaces.iter()
+            .copied()
+            .filter(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .collect();
+        let mut reachable: Vec<IpAddr> = same_family.iter().copied().filter(|ip| !ip.is_loopback()).collect();
+        if reachable.is_empty() {
+            reachable = same_family;
+        }
+        if reachable.is_empty() {
+            reachable.push(if addr.is_ipv4() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { IpAddr::V6(std::net::Ipv6Addr::LOCALHOST) });
+        }
+
+        addresses.extend(reachable.into_iter().map(|ip| SocketAddr::new(ip, addr.port())));
+    }
+    addresses
+}
+
+/// Validated host and port to connect to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionTarget {
+    host: String,
     port: u16,
 }
 
+impl ConnectionTarget {
+    /// Validate a host name or IP address and port
+    pub fn parse(address: &str, port: u16) -> Result<Self> {
+        let host = address.trim();
+        // Accept bracketed IPv6 literals such as "[::1]"
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+
+        if host.is_empty() {
+            return Err(MessengerError::invalid_field("address", "server address is empty"));
+        }
+        if host.contains(|c: char| c.is_whitespace() || c == '/' || c == '@') {
+            return Err(MessengerError::invalid_field("address", format!("'{}' is not a valid host name or IP address", address)));
+        }
+        if port == 0 {
+            return Err(MessengerError::invalid_field("port", "must be between 1 and 65535"));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+
+    /// Validate a "host:port" peer, e.g. "192.168.1.5:8000" or "[::1]:8000"
+    pub fn parse_peer(peer: &str) -> Result<Self> {
+        let (host, port) = peer.trim().rsplit_once(':')
+            .ok_or_else(|| MessengerError::invalid_field("peer", format!("'{}' is not in host:port form", peer)))?;
+        let port = port.parse::<u16>()
+            .map_err(|_| MessengerError::invalid_field("port", format!("'{}' does not end in a valid port", peer)))?;
+        Self::parse(host, port)
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Resolve the target to concrete socket addresses, looking up host names if needed
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        if let Ok(ip) = self.host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, self.port)]);
+        }
+
+        let addresses: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), self.port)).await
+            .map_err(|e| MessengerError::invalid_field("address", format!("failed to resolve '{}': {}", self.host, e)))?
+            .collect();
+
+        if addresses.is_empty() {
+            return Err(MessengerError::invalid_field("address", format!("no addresses found for '{}'", self.host)));
+        }
+        Ok(addresses)
+    }
+}
+
 /// Client implementation
+#[derive(Debug)]
 pub struct TcpClient {
-    stream: Option<TcpStream>,
+    writer: Arc<Mutex<Option<OwnedWriteHalf>>>,
+    reader_task: Option<JoinHandle<()>>,
+    heartbeat_task: Option<JoinHandle<()>>,
+    control_task: Option<JoinHandle<()>>,
+    status: Arc<std::sync::RwLock<ConnectionStatus>>,
+    closing: Arc<AtomicBool>,
+    shared_secret: Arc<RwLock<Option<SharedSecret>>>,
+    target: ConnectionTarget,
+    config: ClientConfig,
+    security: SecurityConfig,
     server_address: String,
     server_port: u16,
     message_sender: mpsc::Sender<Message>,
     key_manager: Arc<RwLock<KeyExchangeManager>>,
     heartbeat_handler: Arc<RwLock<HeartbeatHandler>>,
     stats: Arc<RwLock<NetworkStats>>,
+    outbox: Arc<Outbox>,
+    journal: Option<Arc<RwLock<MessageJournal>>>,
+    benchmark_replies: BenchmarkReplies,
     client_id: Uuid,
     connection_start_time: Option<Instant>,
 }
 
+/// Where the receive loop hands echoed benchmark sequence numbers while a benchmark runs
+type BenchmarkReplies = Arc<std::sync::Mutex<Option<mpsc::UnboundedSender<u64>>>>;
+
+/// What a client needs to agree a session key with the server on each connection
+#[derive(Clone)]
+struct ClientHandshake {
+    client_id: Uuid,
+    encryption_enabled: bool,
+    max_message_size: usize,
+    timeout: Duration,
+    key_manager: Arc<RwLock<KeyExchangeManager>>,
+    message_sender: mpsc::Sender<Message>,
+}
+
+/// Shared state the client's receive loop runs against, cloned into each restart
+#[derive(Clone)]
+struct ClientSession {
+    writer: Arc<Mutex<Option<OwnedWriteHalf>>>,
+    status: Arc<std::sync::RwLock<ConnectionStatus>>,
+    closing: Arc<AtomicBool>,
+    shared_secret: Arc<RwLock<Option<SharedSecret>>>,
+    stats: Arc<RwLock<NetworkStats>>,
+    outbox: Arc<Outbox>,
+    journal: Option<Arc<RwLock<MessageJournal>>>,
+    benchmark_replies: BenchmarkReplies,
+    control: ControlChannel,
+    target: ConnectionTarget,
+    config: ClientConfig,
+    handshake: ClientHandshake,
+}
+
+/// Outgoing messages held while the client is offline. Once the connection is up the
+/// queue is flushed in order and sends go straight out until it drops again; after the
+/// client gives up on the server the outbox is closed and refuses new messages.
+#[derive(Debug)]
+pub struct Outbox {
+    state: Mutex<OutboxState>,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+struct OutboxState {
+    queue: VecDeque<Message>,
+    mode: OutboxMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutboxMode {
+    Queueing,
+    Online,
+    Closed,
+}
+
+impl Outbox {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            state: Mutex::new(OutboxState { queue: VecDeque::new(), mode: OutboxMode::Queueing }),
+            capacity,
+        }
+    }
+
+    /// Queue a copy of the message if the connection is down. Returns false if the
+    /// connection is up and the message should be sent directly.
+    pub async fn queue_if_offline(&self, message: &Message) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        match state.mode {
+            OutboxMode::Online => Ok(false),
+            OutboxMode::Closed => Err(MessengerError::NotConnected),
+            OutboxMode::Queueing if state.queue.len() >= self.capacity => Err(MessengerError::OutboxFull { max: self.capacity }),
+            OutboxMode::Queueing => {
+                state.queue.push_back(message.clone());
+                Ok(true)
+            },
+        }
+    }
+
+    /// Number of messages waiting to go out
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.queue.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Hold new messages until the next flush
+    async fn go_offline(&self) {
+        let mut state = self.state.lock().await;
+        if state.mode == OutboxMode::Online {
+            state.mode = OutboxMode::Queueing;
+        }
+    }
+
+    /// Refuse new messages; anything already queued waits for a later connection
+    async fn close(&self) {
+        self.state.lock().await.mode = OutboxMode::Closed;
+    }
+
+    /// Send everything queued, oldest first, then let sends go straight out. Holding the
+    /// lock throughout keeps new messages from overtaking queued ones. If a send fails the
+    /// rest stay queued for the next connection.
+    async fn flush(
+        &self,
+        writer: &Mutex<Option<OwnedWriteHalf>>,
+        shared_secret: &RwLock<Option<SharedSecret>>,
+        stats: &RwLock<NetworkStats>,
+        journal: Option<&RwLock<MessageJournal>>,
+        max_message_size: usize,
+    ) {
+        let mut state = self.state.lock().await;
+        let queued = state.queue.len();
+        while let Some(message) = state.queue.front() {
+            if let Err(e) = TcpClient::send_over(writer, shared_secret, stats, journal, max_message_size, message).await {
+                warn!("Failed to flush outbox, keeping {} messages queued: {}", state.queue.len(), e);
+                state.mode = OutboxMode::Queueing;
+                return;
+            }
+            state.queue.pop_front();
+        }
+        state.mode = OutboxMode::Online;
+        if queued > 0 {
+            info!("Flushed {} queued messages", queued);
+        }
+    }
+}
+
 /// Client connection on the server side
+#[derive(Debug)]
 pub struct ClientConnection {
     pub id: Uuid,
+    pub connected_at: Instant,
     pub last_heartbeat: Instant,
     pub shared_secret: Option<SharedSecret>,
+    pub stats: PeerStats,
+    pub pending_acks: PendingAcknowledgments,
+    pub writer: Arc<Mutex<OwnedWriteHalf>>,
+    reader_task: Option<JoinHandle<()>>,
+}
+
+impl ClientConnection {
+    pub fn new(id: Uuid, writer: OwnedWriteHalf, ack_config: &AcknowledgmentConfig) -> Self {
+        Self {
+            id,
+            connected_at: Instant::now(),
+            last_heartbeat: Instant::now(),
+            shared_secret: None,
+            stats: PeerStats::new(id),
+            pending_acks: PendingAcknowledgments::new(ack_config),
+            writer: Arc::new(Mutex::new(writer)),
+            reader_task: None,
+        }
+    }
+
+    /// Get a snapshot of this peer's statistics
+    pub fn peer_stats(&self) -> PeerStats {
+        let mut stats = self.stats.clone();
+        stats.connected_duration = self.connected_at.elapsed().as_secs();
+        stats.pending_acks = self.pending_acks.len();
+        stats
+    }
+}
+
+/// Bounded audit trail of incoming connection attempts
+#[derive(Debug)]
+pub struct ConnectionAudit {
+    entries: VecDeque<ConnectionAttempt>,
+    capacity: usize,
+}
+
+impl ConnectionAudit {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Record a connection attempt, dropping the oldest entry when full
+    pub fn record(&mut self, address: SocketAddr, outcome: ConnectionOutcome) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        while self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(ConnectionAttempt {
+            address: address.to_string(),
+            timestamp: chrono::Utc::now(),
+            outcome,
+        });
+    }
+
+    /// Get all recorded attempts, oldest first
+    pub fn entries(&self) -> Vec<ConnectionAttempt> {
+        self.entries.iter().cloned().collect()
+    }
 }
 
 impl NetworkManager {
@@ -73,36 +444,84 @@ impl NetworkManager {
             server_info: None,
             client_info: None,
             stats: Arc::new(RwLock::new(NetworkStats::default())),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            audit: Arc::new(RwLock::new(ConnectionAudit::new(MAX_AUDIT_ENTRIES))),
+            recent_messages: Arc::new(RwLock::new(RecentMessages::new(DEDUP_WINDOW))),
+            journal: None,
+            config: NetworkConfig::default(),
+            security: SecurityConfig::default(),
             message_sender: message_sender.clone(),
             message_receiver: Arc::new(RwLock::new(Some(message_receiver))),
             key_manager: Arc::new(RwLock::new(KeyExchangeManager::new(100))),
             heartbeat_handler: Arc::new(RwLock::new(HeartbeatHandler::new(30))),
+            control_state: Arc::new(RwLock::new(ControlState::default())),
             connection_start_time: None,
+            outbox: Arc::new(Outbox::new(ClientConfig::default().outbox_capacity)),
+            server: None,
+            client: None,
         };
 
         (manager, message_sender)
     }
 
+    /// Record every protocol message to the given journal
+    pub fn enable_journal(&mut self, journal: MessageJournal) {
+        info!("Message journal enabled at {:?}", journal.path());
+        self.journal = Some(Arc::new(RwLock::new(journal)));
+    }
+
+    /// Set the network and security configuration used for new connections
+    pub fn set_config(&mut self, config: &AppConfig) {
+        self.config = config.network.clone();
+        self.security = config.security.clone();
+        self.outbox = Arc::new(Outbox::new(config.network.client.outbox_capacity));
+        let mut key_manager = KeyExchangeManager::new(config.security.key_rotation_interval);
+        key_manager.set_replay_window(config.security.replay_window);
+        key_manager.set_cipher_suite(config.security.cipher);
+        self.key_manager = Arc::new(RwLock::new(key_manager));
+        self.heartbeat_handler = Arc::new(RwLock::new(HeartbeatHandler::new(config.network.server.heartbeat_interval)));
+    }
+
+    /// How long to wait for outbound data to drain when closing a connection
+    pub fn close_linger(&self) -> Duration {
+        Duration::from_millis(self.config.close_linger)
+    }
+
     /// Start a TCP server
     pub async fn start_server(&mut self, port: Option<u16>) -> Result<ServerInfo> {
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port.unwrap_or(8000));
+        self.start_server_on(vec![addr]).await
+    }
+
+    /// Start a TCP server listening on several addresses at once
+    pub async fn start_server_on(&mut self, addresses: Vec<SocketAddr>) -> Result<ServerInfo> {
         if self.connection_type.is_some() {
             return Err(MessengerError::AlreadyConnected);
         }
 
         let server = TcpServer::new(
-            port,
+            addresses,
+            self.clients.clone(),
+            self.audit.clone(),
+            self.recent_messages.clone(),
+            self.journal.clone(),
+            self.config.acknowledgment.clone(),
+            self.config.server.clone(),
+            self.security.max_message_size,
             self.message_sender.clone(),
             self.key_manager.clone(),
             self.heartbeat_handler.clone(),
+            self.control_state.clone(),
             self.stats.clone(),
         ).await?;
 
-        let server_info = server.get_info();
+        let server_info = server.get_info().await;
+        self.server = Some(server);
         self.server_info = Some(server_info.clone());
         self.connection_type = Some(ConnectionType::Server);
         self.connection_start_time = Some(Instant::now());
 
-        info!("TCP server started on port {}", server_info.port);
+        info!("TCP server started on {}", server_info.addresses.join(", "));
         Ok(server_info)
     }
 
@@ -112,16 +531,23 @@ impl NetworkManager {
             return Err(MessengerError::AlreadyConnected);
         }
 
+        let target = ConnectionTarget::parse(&address, port)?;
+
         let client = TcpClient::new(
-            address.clone(),
-            port,
+            target,
+            self.config.client.clone(),
+            self.security.clone(),
             self.message_sender.clone(),
             self.key_manager.clone(),
             self.heartbeat_handler.clone(),
+            self.control_state.clone(),
             self.stats.clone(),
+            self.outbox.clone(),
+            self.journal.clone(),
         ).await?;
 
         let client_info = client.get_info();
+        self.client = Some(client);
         self.client_info = Some(client_info.clone());
         self.connection_type = Some(ConnectionType::Client);
         self.connection_start_time = Some(Instant::now());
@@ -135,6 +561,9 @@ impl NetworkManager {
         match self.connection_type {
             Some(ConnectionType::Server) => {
                 info!("Stopping TCP server");
+                if let Some(mut server) = self.server.take() {
+                    server.shutdown(self.close_linger()).await;
+                }
                 self.server_info = None;
                 self.connection_type = None;
                 self.connection_start_time = None;
@@ -152,6 +581,12 @@ impl NetworkManager {
             },
             Some(ConnectionType::Client) => {
                 info!("Disconnecting from server");
+                if let Some(mut client) = self.client.take() {
+                    if let Err(e) = client.close("Client disconnected", self.close_linger()).await {
+                        warn!("Connection did not close cleanly: {}", e);
+                    }
+                }
+                self.outbox.close().await;
                 self.client_info = None;
                 self.connection_type = None;
                 self.connection_start_time = None;
@@ -165,255 +600,2163 @@ impl NetworkManager {
     pub async fn get_connection_status(&self) -> ConnectionStatus {
         match &self.connection_type {
             Some(ConnectionType::Server) => {
-                if self.server_info.is_some() {
-                    ConnectionStatus::Connected
-                } else {
-                    ConnectionStatus::Disconnected
-                }
+                self.server.as_ref()
+                    .map(|server| server.status())
+                    .unwrap_or(ConnectionStatus::Disconnected)
             },
             Some(ConnectionType::Client) => {
-                if self.client_info.is_some() {
-                    ConnectionStatus::Connected
-                } else {
-                    ConnectionStatus::Disconnected
-                }
+                self.client.as_ref()
+                    .map(|client| client.status())
+                    .unwrap_or(ConnectionStatus::Disconnected)
             },
             None => ConnectionStatus::Disconnected,
         }
     }
 
-    /// Send a message
+    /// Send a message; when running a server it is broadcast to every connected client,
+    /// unless it has a `recipient_id`, in which case only that client gets it
     pub async fn send_message(&self, message: Message) -> Result<()> {
-        self.message_sender.send(message).await
-            .map_err(|e| MessengerError::Internal(format!("Failed to send message: {}", e)))?;
-        Ok(())
+        if let Some(server) = &self.server {
+            match message.recipient_id {
+                Some(recipient_id) => server.send_to_client(&recipient_id, &message, self.security.encryption_enabled).await?,
+                None => {
+                    server.broadcast_message(&message, self.security.encryption_enabled).await?;
+                },
+            }
+            return Ok(());
+        }
+
+        match &self.client {
+            Some(client) => client.send_message(&message).await,
+            None => Err(MessengerError::NotConnected),
+        }
+    }
+
+    /// Send a message, resending up to the server or client `retry_attempts` times with
+    /// `retry_delay` between tries. Each resend bumps `retry_count`; the message ends up `Sent`,
+    /// or `Failed` once the retries run out. Oversized messages and ones for a peer that isn't
+    /// connected are failed without retrying.
+    ///
+    /// A client that hasn't connected yet or is reconnecting queues the message in the outbox
+    /// instead, leaving it `Sending`; the queue is flushed in order once the connection is up.
+    pub async fn send_message_with_retry(&self, message: &mut Message) -> Result<()> {
+        let (retry_attempts, retry_delay) = if self.server.is_some() {
+            (self.config.server.retry_attempts, self.config.server.retry_delay)
+        } else {
+            (self.config.client.retry_attempts, self.config.client.retry_delay)
+        };
+
+        loop {
+            let sent = match self.server {
+                Some(_) => self.send_message(message.clone()).await,
+                None => match self.outbox.queue_if_offline(message).await {
+                    Ok(true) => {
+                        message.status = MessageStatus::Sending;
+                        info!("Queued message {} until the connection is up", message.id);
+                        return Ok(());
+                    },
+                    Ok(false) => self.send_message(message.clone()).await,
+                    Err(e) => Err(e),
+                },
+            };
+
+            match sent {
+                Ok(()) => {
+                    message.status = MessageStatus::Sent;
+                    return Ok(());
+                },
+                Err(e) if message.retry_count < retry_attempts && !matches!(e, MessengerError::MessageTooLarge { .. } | MessengerError::ResourceNotFound(_) | MessengerError::OutboxFull { .. }) => {
+                    message.retry_count += 1;
+                    warn!("Failed to send message {}, retrying ({}/{}): {}", message.id, message.retry_count, retry_attempts, e);
+                    tokio::time::sleep(Duration::from_millis(retry_delay)).await;
+                },
+                Err(e) => {
+                    error!("Giving up on message {} after {} retries: {}", message.id, message.retry_count, e);
+                    message.status = MessageStatus::Failed;
+                    return Err(e);
+                },
+            }
+        }
+    }
+
+    /// Send a message to a single client connected to the server
+    pub async fn send_to_client(&self, peer_id: &Uuid, message: &Message) -> Result<()> {
+        let server = self.server.as_ref().ok_or(MessengerError::ServerNotRunning)?;
+        server.send_to_client(peer_id, message, self.security.encryption_enabled).await
+    }
+
+    /// Disconnect a single client from the server, telling it why
+    pub async fn disconnect_client(&self, peer_id: &Uuid, reason: &str) -> Result<()> {
+        let server = self.server.as_ref().ok_or(MessengerError::ServerNotRunning)?;
+        server.disconnect_client(peer_id, reason, self.close_linger()).await
+    }
+
+    /// Benchmark the link to `peer`, which must be the server this client is connected to
+    pub async fn benchmark(&self, peer: &ConnectionTarget, duration: Duration, payload_size: usize) -> Result<BenchmarkReport> {
+        let client = self.client.as_ref().ok_or(MessengerError::NotConnected)?;
+        if !client.is_connected_to(peer).await? {
+            return Err(MessengerError::invalid_field("peer", format!("not connected to {}:{}", peer.host(), peer.port())));
+        }
+        client.benchmark(duration, payload_size).await
+    }
+
+    /// Get current server details, including the live client count
+    pub async fn get_server_info(&self) -> Option<ServerInfo> {
+        match &self.server {
+            Some(server) => Some(server.get_info().await),
+            None => None,
+        }
     }
 
     /// Get network statistics
     pub async fn get_stats(&self) -> NetworkStats {
-        self.stats.read().await.clone()
+        let mut stats = self.stats.read().await.clone();
+        stats.connection_uptime = self.connection_start_time
+            .map_or(0, |start| start.elapsed().as_secs());
+        stats
+    }
+
+    /// Get the audit trail of incoming connection attempts
+    pub async fn get_connection_audit(&self) -> Vec<ConnectionAttempt> {
+        self.audit.read().await.entries()
+    }
+
+    /// Get statistics for a single connected peer
+    pub async fn get_peer_stats(&self, peer_id: &Uuid) -> Result<PeerStats> {
+        let clients = self.clients.read().await;
+        clients.get(peer_id)
+            .map(|client| client.peer_stats())
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Peer not found: {}", peer_id)))
+    }
+
+    /// Get statistics for every connected peer
+    pub async fn get_all_peer_stats(&self) -> Vec<PeerStats> {
+        let clients = self.clients.read().await;
+        clients.values().map(|client| client.peer_stats()).collect()
     }
 }
 
 impl TcpServer {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        port: Option<u16>,
+        addresses: Vec<SocketAddr>,
+        clients: Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
+        audit: Arc<RwLock<ConnectionAudit>>,
+        recent_messages: Arc<RwLock<RecentMessages>>,
+        journal: Option<Arc<RwLock<MessageJournal>>>,
+        ack_config: AcknowledgmentConfig,
+        config: ServerConfig,
+        max_message_size: usize,
         message_sender: mpsc::Sender<Message>,
         key_manager: Arc<RwLock<KeyExchangeManager>>,
         heartbeat_handler: Arc<RwLock<HeartbeatHandler>>,
+        control_state: Arc<RwLock<ControlState>>,
         stats: Arc<RwLock<NetworkStats>>,
     ) -> Result<Self> {
-        let port = port.unwrap_or(8000);
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-        
-        let listener = TcpListener::bind(addr).await
-            .map_err(|e| MessengerError::Network(e))?;
+        if addresses.is_empty() {
+            return Err(MessengerError::invalid_field("addresses", "no listen addresses given"));
+        }
+
+        let mut listeners = Vec::new();
+        let mut bound_addresses = Vec::new();
+        for addr in addresses {
+            let listener = TcpListener::bind(addr).await
+                .map_err(|e| MessengerError::Network(e))?;
+            bound_addresses.push(listener.local_addr()?);
+            listeners.push(listener);
+        }
 
         let server_id = Uuid::new_v4();
+        let connectable_addresses = connectable_addresses(&bound_addresses);
+        let (control, control_task) = ControlChannel::spawn(control_state);
         
         let mut server = Self {
-            listener: Some(listener),
-            clients: Arc::new(RwLock::new(HashMap::new())),
+            listeners,
+            accept_tasks: Vec::new(),
+            status: Arc::new(std::sync::RwLock::new(ConnectionStatus::Connected)),
+            reaper_task: None,
+            control_task: Some(control_task),
+            bound_addresses,
+            connectable_addresses,
+            clients,
+            audit,
+            recent_messages,
+            journal,
+            ack_config,
+            config,
+            max_message_size,
             message_sender,
             key_manager,
             heartbeat_handler,
+            control,
             stats,
             server_id,
-            port,
+            stopping: watch::channel(false).0,
         };
 
         // Start accepting connections
         server.start_accepting_connections().await?;
+        server.start_reaping_idle_clients();
         
         Ok(server)
     }
 
     async fn start_accepting_connections(&mut self) -> Result<()> {
-        let listener = self.listener.take().unwrap();
-        let clients = self.clients.clone();
-        let message_sender = self.message_sender.clone();
-        let key_manager = self.key_manager.clone();
-        let stats = self.stats.clone();
+        let context = AcceptContext {
+            clients: self.clients.clone(),
+            audit: self.audit.clone(),
+            recent_messages: self.recent_messages.clone(),
+            journal: self.journal.clone(),
+            ack_config: self.ack_config.clone(),
+            max_clients: self.config.max_clients as usize,
+            max_message_size: self.max_message_size,
+            message_sender: self.message_sender.clone(),
+            key_manager: self.key_manager.clone(),
+            control: self.control.clone(),
+            stats: self.stats.clone(),
+            status: self.status.clone(),
+            server_id: self.server_id,
+            stopping: self.stopping.subscribe(),
+        };
 
-        tokio::spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((stream, _)) => {
-                        let client_id = Uuid::new_v4();
-                        info!("New client connected: {}", client_id);
-
-                        let client_connection = ClientConnection {
-                            id: client_id,
-                            last_heartbeat: Instant::now(),
-                            shared_secret: None,
+        // Every listener feeds the same client map and message dispatch. The loops are
+        // supervised so a listener keeps accepting even if its loop panics.
+        for listener in std::mem::take(&mut self.listeners) {
+            let listener = Arc::new(listener);
+            let address = listener.local_addr()?;
+            let accept_context = context.clone();
+            let failure_context = context.clone();
+
+            let accept_task = supervisor::supervise(
+                "accept loop",
+                move || Self::accept_connections(listener.clone(), accept_context.clone()),
+                move |failure| {
+                    let context = failure_context.clone();
+                    async move {
+                        let (status, notice) = if failure.restarting {
+                            (ConnectionStatus::Reconnecting, format!("Listener on {} failed and is restarting", address))
+                        } else {
+                            (ConnectionStatus::Error(format!("Listener on {} stopped", address)), format!("Listener on {} failed and was stopped", address))
                         };
+                        *context.status.write().unwrap_or_else(|e| e.into_inner()) = status;
 
-                        // Add client to the list
-                        {
-                            let mut clients = clients.write().await;
-                            clients.insert(client_id, client_connection);
+                        let notice = Message::new_system(notice, SystemMessageLevel::Error, context.server_id);
+                        if let Err(e) = context.message_sender.send(notice).await {
+                            error!("Failed to send message to application: {}", e);
                         }
+                    }
+                },
+            );
+            self.accept_tasks.push(accept_task);
+        }
 
-                        // Handle client messages
-                        Self::handle_client_messages(
-                            client_id,
-                            stream,
-                            clients.clone(),
-                            message_sender.clone(),
-                            key_manager.clone(),
-                            stats.clone(),
-                        ).await;
-                    },
-                    Err(e) => {
-                        error!("Failed to accept connection: {}", e);
+        Ok(())
+    }
+
+    async fn accept_connections(listener: Arc<TcpListener>, context: AcceptContext) {
+        *context.status.write().unwrap_or_else(|e| e.into_inner()) = ConnectionStatus::Connected;
+        let AcceptContext { clients, audit, recent_messages, journal, ack_config, max_clients, max_message_size, message_sender, key_manager, control, stats, server_id, mut stopping, .. } = context;
+
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = stopping.wait_for(|stopping| *stopping) => return,
+            };
+
+            match accepted {
+                Ok((stream, addr)) => {
+                    // Check and insert under one lock so two listeners cannot both take the last slot
+                    let mut clients_guard = clients.write().await;
+                    if clients_guard.len() >= max_clients {
+                        drop(clients_guard);
+                        warn!("Rejecting connection from {}: server is full ({} clients)", addr, max_clients);
+                        audit.write().await.record(addr, ConnectionOutcome::OverCapacity);
+                        tokio::spawn(Self::reject_connection(stream, server_id, max_clients));
+                        continue;
                     }
+
+                    let client_id = Uuid::new_v4();
+                    info!("New client connected: {} from {}", client_id, addr);
+
+                    // Reads happen on the client's task, writes go through the shared write half
+                    let (reader, writer) = stream.into_split();
+                    let mut client_connection = ClientConnection::new(client_id, writer, &ack_config);
+
+                    // The read loop owns the read half. It starts once the lock is released,
+                    // by which point the entry it updates is already in the map.
+                    client_connection.reader_task = Some(tokio::spawn(Self::read_client_messages(
+                        client_id,
+                        reader,
+                        clients.clone(),
+                        recent_messages.clone(),
+                        journal.clone(),
+                        max_message_size,
+                        server_id,
+                        message_sender.clone(),
+                        key_manager.clone(),
+                        control.clone(),
+                        stats.clone(),
+                    )));
+                    clients_guard.insert(client_id, client_connection);
+                    drop(clients_guard);
+
+                    audit.write().await.record(addr, ConnectionOutcome::Accepted);
+                },
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
                 }
             }
-        });
+        }
+    }
 
-        Ok(())
+    /// Tell a peer the server is full and close the connection without tracking it
+    async fn reject_connection(mut stream: TcpStream, server_id: Uuid, max_clients: usize) {
+        let goodbye = Message::new_disconnect(format!("Server is full ({} clients)", max_clients), server_id);
+        if let Err(e) = ProtocolHandler::close_gracefully(&mut stream, &goodbye, Duration::from_millis(500)).await {
+            debug!("Rejected connection did not close cleanly: {}", e);
+        }
     }
 
-    async fn handle_client_messages(
-        client_id: Uuid,
-        mut stream: TcpStream,
-        clients: Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
-        message_sender: mpsc::Sender<Message>,
-        _key_manager: Arc<RwLock<KeyExchangeManager>>,
-        stats: Arc<RwLock<NetworkStats>>,
-    ) {
-        tokio::spawn(async move {
+    /// Periodically evict clients that have been silent for longer than `connection_timeout`
+    fn start_reaping_idle_clients(&mut self) {
+        let idle_timeout = Duration::from_secs(self.config.connection_timeout);
+        let clients = self.clients.clone();
+        let message_sender = self.message_sender.clone();
+        let server_id = self.server_id;
+
+        let reaper_task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval((idle_timeout / 2).clamp(Duration::from_millis(100), Duration::from_secs(5)));
             loop {
-                // Get client connection
-                let client_connection = {
+                ticker.tick().await;
+
+                // Removing under the write lock is what stops the client's read loop from
+                // touching the entry again; its own cleanup just finds nothing to remove
+                let stale: Vec<ClientConnection> = {
                     let mut clients = clients.write().await;
-                    clients.remove(&client_id)
+                    let stale_ids: Vec<Uuid> = clients.iter()
+                        .filter(|(_, client)| client.last_heartbeat.elapsed() > idle_timeout)
+                        .map(|(id, _)| *id)
+                        .collect();
+                    stale_ids.iter().filter_map(|id| clients.remove(id)).collect()
                 };
 
-                if let Some(mut client) = client_connection {
-                    // Try to receive a message
-                    // Note: stream is now handled separately in the handler function
-                    match ProtocolHandler::receive_message(&mut stream).await {
-                        Ok(message) => {
-                            // Update heartbeat
-                            client.last_heartbeat = Instant::now();
-
-                            // Send message to application
-                            if let Err(e) = message_sender.send(message).await {
-                                error!("Failed to send message to application: {}", e);
-                                break;
-                            }
-
-                            // Update stats
-                            {
-                                let mut stats = stats.write().await;
-                                stats.messages_received += 1;
-                                stats.last_activity = Some(chrono::Utc::now());
-                            }
-
-                            // Re-insert client
-                            {
-                                let mut clients = clients.write().await;
-                                clients.insert(client_id, client);
-                            }
-                        },
-                        Err(e) => {
-                            error!("Failed to receive message from client {}: {}", client_id, e);
-                            break;
-                        }
+                for mut client in stale {
+                    warn!("Evicting client {} after {:?} without activity", client.id, idle_timeout);
+                    if let Some(reader_task) = client.reader_task.take() {
+                        reader_task.abort();
                     }
-                } else {
-                    // Client disconnected
-                    break;
-                }
-            }
 
-            // Remove client from list
-            {
-                let mut clients = clients.write().await;
-                clients.remove(&client_id);
-            }
+                    let goodbye = Message::new_disconnect("Idle timeout".to_string(), server_id);
+                    if let Err(e) = ProtocolHandler::close_gracefully(&mut *client.writer.lock().await, &goodbye, Duration::from_millis(500)).await {
+                        debug!("Idle client {} did not close cleanly: {}", client.id, e);
+                    }
 
-            info!("Client {} disconnected", client_id);
+                    let notice = Message::new_system(
+                        format!("Client {} disconnected after being idle", client.id),
+                        SystemMessageLevel::Warning,
+                        server_id,
+                    );
+                    if let Err(e) = message_sender.send(notice).await {
+                        error!("Failed to send message to application: {}", e);
+                    }
+                }
+            }
         });
-    }
 
-    pub fn get_info(&self) -> ServerInfo {
-        ServerInfo {
-            id: self.server_id,
-            address: "0.0.0.0".to_string(),
-            port: self.port,
-            status: ConnectionStatus::Connected,
-            started_at: chrono::Utc::now(),
-            client_count: 0, // Will be updated by the connection handler
-            max_clients: 1,
-        }
+        self.reaper_task = Some(reaper_task);
     }
-}
 
-impl TcpClient {
-    pub async fn new(
-        address: String,
-        port: u16,
-        message_sender: mpsc::Sender<Message>,
-        key_manager: Arc<RwLock<KeyExchangeManager>>,
-        heartbeat_handler: Arc<RwLock<HeartbeatHandler>>,
-        stats: Arc<RwLock<NetworkStats>>,
-    ) -> Result<Self> {
-        let addr = SocketAddr::new(address.parse().unwrap(), port);
-        let stream = TcpStream::connect(addr).await
-            .map_err(|e| MessengerError::Network(e))?;
+    /// Send a message to one connected client, encrypted if enabled and a shared secret exists
+    pub async fn send_to_client(&self, peer_id: &Uuid, message: &Message, encrypt: bool) -> Result<()> {
+        let (writer, secret) = self.clients.read().await.get(peer_id)
+            .map(|client| (client.writer.clone(), client.shared_secret.clone()))
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Peer not found: {}", peer_id)))?;
 
-        let client_id = Uuid::new_v4();
-        
-        let client = Self {
-            stream: Some(stream),
-            server_address: address,
-            server_port: port,
-            message_sender,
-            key_manager,
-            heartbeat_handler,
-            stats,
-            client_id,
-            connection_start_time: Some(Instant::now()),
+        // Track before sending so a full queue refuses the message instead of losing its ack
+        if AcknowledgmentHandler::requires_acknowledgment(message) {
+            if let Some(client) = self.clients.write().await.get_mut(peer_id) {
+                if let Some(dropped) = client.pending_acks.track(message.id)? {
+                    warn!("Gave up waiting for client {} to acknowledge message {}", peer_id, dropped);
+                }
+            }
+        }
+
+        let protocol_msg = ProtocolMessage::new(message)?;
+        let protocol_msg = match secret.filter(|_| encrypt) {
+            Some(secret) => protocol_msg.encrypt(&secret)?,
+            None => protocol_msg,
         };
+        protocol_msg.check_size(self.max_message_size)?;
+        {
+            use tokio::io::AsyncWriteExt;
+            let mut writer = writer.lock().await;
+            writer.write_all(&protocol_msg.to_bytes()).await
+                .map_err(|e| MessengerError::Network(e))?;
+            writer.flush().await
+                .map_err(|e| MessengerError::Network(e))?;
+        }
 
-        // Start receiving messages
-        client.start_receiving_messages().await?;
-        
-        Ok(client)
-    }
+        journal_frame(self.journal.as_deref(), JournalDirection::Outbound, &protocol_msg).await;
+
+        if let Some(client) = self.clients.write().await.get_mut(peer_id) {
+            client.stats.record_sent(protocol_msg.wire_size());
+        }
 
-    async fn start_receiving_messages(&self) -> Result<()> {
-        // Note: This is a simplified implementation
-        // In a real application, you'd need to handle the stream ownership differently
-        // For now, we'll just return Ok to avoid compilation errors
+        let mut stats = self.stats.write().await;
+        stats.messages_sent += 1;
+        stats.bytes_sent += protocol_msg.wire_size() as u64;
+        stats.last_activity = Some(chrono::Utc::now());
         Ok(())
     }
 
-    pub fn get_info(&self) -> ClientInfo {
-        ClientInfo {
-            id: self.client_id,
-            server_address: self.server_address.clone(),
-            server_port: self.server_port,
-            status: ConnectionStatus::Connected,
-            connected_at: Some(chrono::Utc::now()),
-            last_heartbeat: Some(chrono::Utc::now()),
+    /// Send a message to every connected client, dropping clients whose write fails.
+    /// Returns the number of clients the message reached.
+    pub async fn broadcast_message(&self, message: &Message, encrypt: bool) -> Result<usize> {
+        // Refuse oversized messages up front rather than dropping every client over them.
+        // Text length is the sending command's concern; only the wire size matters here.
+        let preview = ProtocolMessage::preview(message, self.max_message_size, usize::MAX, encrypt)?;
+        if !preview.fits {
+            return Err(MessengerError::MessageTooLarge { size: preview.wire_size, max: self.max_message_size });
         }
-    }
-}
 
+        let peer_ids: Vec<Uuid> = self.clients.read().await.keys().copied().collect();
+        let mut delivered = 0;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        for peer_id in peer_ids {
+            match self.send_to_client(&peer_id, message, encrypt).await {
+                Ok(()) => delivered += 1,
+                Err(e) => {
+                    warn!("Dropping client {} after failed broadcast: {}", peer_id, e);
+                    if let Some(mut client) = self.clients.write().await.remove(&peer_id) {
+                        if let Some(reader_task) = client.reader_task.take() {
+                            reader_task.abort();
+                        }
+                    }
+                },
+            }
+        }
 
-    #[tokio::test]
+        debug!("Broadcast message {} to {} clients", message.id, delivered);
+        Ok(delivered)
+    }
+
+    /// Send a client a disconnect notice and close its connection
+    pub async fn disconnect_client(&self, peer_id: &Uuid, reason: &str, linger: Duration) -> Result<()> {
+        let writer = self.clients.read().await.get(peer_id)
+            .map(|client| client.writer.clone())
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Peer not found: {}", peer_id)))?;
+
+        let goodbye = Message::new_disconnect(reason.to_string(), self.server_id);
+        let result = ProtocolHandler::close_gracefully(&mut *writer.lock().await, &goodbye, linger).await;
+
+        // The client's read loop removes it once the peer closes its side
+        self.wait_for_removal(peer_id, linger).await;
+        if let Some(mut client) = self.clients.write().await.remove(peer_id) {
+            if let Some(reader_task) = client.reader_task.take() {
+                reader_task.abort();
+            }
+        }
+
+        info!("Disconnected client {}: {}", peer_id, reason);
+        result
+    }
+
+    /// Stop accepting connections and close every client connection. The listeners are
+    /// closed by the time this returns, so the port can be bound again straight away.
+    pub async fn shutdown(&mut self, linger: Duration) {
+        self.stopping.send_replace(true);
+        for mut accept_task in self.accept_tasks.drain(..) {
+            if tokio::time::timeout(linger, &mut accept_task).await.is_err() {
+                warn!("Accept loop did not stop within {:?}, aborting it", linger);
+                accept_task.abort();
+                let _ = accept_task.await;
+            }
+        }
+        for task in [self.reaper_task.take(), self.control_task.take()].into_iter().flatten() {
+            task.abort();
+            let _ = task.await;
+        }
+
+        let peer_ids: Vec<Uuid> = self.clients.read().await.keys().copied().collect();
+        let closing: Vec<_> = peer_ids.iter()
+            .map(|peer_id| self.disconnect_client(peer_id, "Server shutting down", linger))
+            .collect();
+        for (peer_id, close) in peer_ids.iter().zip(closing) {
+            if let Err(e) = close.await {
+                warn!("Connection to client {} did not close cleanly: {}", peer_id, e);
+            }
+        }
+    }
+
+    async fn wait_for_removal(&self, peer_id: &Uuid, linger: Duration) {
+        let deadline = Instant::now() + linger;
+        while Instant::now() < deadline && self.clients.read().await.contains_key(peer_id) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Read a client's frames until it disconnects. Its entry is updated in place and only removed at the end.
+    #[allow(clippy::too_many_arguments)]
+    async fn read_client_messages(
+        client_id: Uuid,
+        mut stream: tokio::net::tcp::OwnedReadHalf,
+        clients: Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
+        recent_messages: Arc<RwLock<RecentMessages>>,
+        journal: Option<Arc<RwLock<MessageJournal>>>,
+        max_message_size: usize,
+        server_id: Uuid,
+        message_sender: mpsc::Sender<Message>,
+        key_manager: Arc<RwLock<KeyExchangeManager>>,
+        control: ControlChannel,
+        stats: Arc<RwLock<NetworkStats>>,
+    ) {
+        loop {
+            // Client was removed from the list
+            if !clients.read().await.contains_key(&client_id) {
+                break;
+            }
+
+            let protocol_msg = match ProtocolHandler::receive_protocol_message(&mut stream, max_message_size).await {
+                Ok(protocol_msg) => protocol_msg,
+                Err(e) => {
+                    error!("Failed to receive message from client {}: {}", client_id, e);
+                    break;
+                }
+            };
+
+            journal_frame(journal.as_deref(), JournalDirection::Inbound, &protocol_msg).await;
+
+            let secret = clients.read().await.get(&client_id)
+                .and_then(|client| client.shared_secret.clone());
+            let message = match protocol_msg.open(secret.as_ref()) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Failed to decode message from client {}: {}", client_id, e);
+                    break;
+                }
+            };
+
+            // Update heartbeat and per-peer stats in place
+            let writer = {
+                let mut clients = clients.write().await;
+                let Some(client) = clients.get_mut(&client_id) else {
+                    break;
+                };
+                client.last_heartbeat = Instant::now();
+                client.stats.record_received(protocol_msg.wire_size());
+
+                if let MessageType::Acknowledgment { message_id } = &message.message_type {
+                    client.pending_acks.acknowledge(message_id);
+                }
+                client.writer.clone()
+            };
+
+            // Every frame counts towards traffic, including ones not passed to the application
+            {
+                let mut stats = stats.write().await;
+                stats.bytes_received += protocol_msg.wire_size() as u64;
+                stats.last_activity = Some(chrono::Utc::now());
+            }
+
+            // Heartbeats only refresh last_heartbeat above
+            if matches!(message.message_type, MessageType::Heartbeat) {
+                continue;
+            }
+
+            // Control messages go to the control handler and never reach the application
+            if let MessageType::Control { control: instruction } = message.message_type {
+                control.dispatch(client_id, instruction);
+                continue;
+            }
+
+            // Answer a key exchange with our own public key; the derived secret covers later frames
+            if let MessageType::KeyExchange { public_key } = &message.message_type {
+                if let Err(e) = Self::complete_key_exchange(client_id, public_key, server_id, &key_manager, &clients, &writer).await {
+                    error!("Key exchange with client {} failed: {}", client_id, e);
+                    break;
+                }
+                debug!("Established shared secret with client {}", client_id);
+                continue;
+            }
+
+            // Benchmark probes are echoed straight back on the same session and never reach the application
+            if message.is_benchmark() {
+                if let Err(e) = ProtocolHandler::send_message_with_key(&mut *writer.lock().await, &message, secret.as_ref(), max_message_size).await {
+                    error!("Failed to echo benchmark probe to client {}: {}", client_id, e);
+                    break;
+                }
+                continue;
+            }
+
+            // Drop messages that arrived too late to matter, e.g. queued across a reconnect
+            if message.is_expired() {
+                debug!("Dropping expired message {} from client {}", message.id, client_id);
+                continue;
+            }
+
+            // Acknowledge before the duplicate check, so a resend whose first ack was lost gets another
+            if AcknowledgmentHandler::requires_acknowledgment(&message) {
+                let ack = AcknowledgmentHandler::create_acknowledgment(message.id, server_id);
+                if let Err(e) = ProtocolHandler::send_message_with_key(&mut *writer.lock().await, &ack, secret.as_ref(), max_message_size).await {
+                    error!("Failed to acknowledge message {} from client {}: {}", message.id, client_id, e);
+                    break;
+                }
+            }
+
+            // Drop replays of messages already delivered, e.g. resent after a reconnect
+            if !recent_messages.write().await.insert(message.id) {
+                debug!("Dropping duplicate message {} from client {}", message.id, client_id);
+                continue;
+            }
+
+            // Send message to application
+            if let Err(e) = message_sender.send(message).await {
+                error!("Failed to send message to application: {}", e);
+                break;
+            }
+
+            // Update stats
+            {
+                let mut stats = stats.write().await;
+                stats.messages_received += 1;
+                stats.last_activity = Some(chrono::Utc::now());
+            }
+        }
+
+        // Remove client from list
+        {
+            let mut clients = clients.write().await;
+            clients.remove(&client_id);
+        }
+        key_manager.write().await.remove_peer(&client_id);
+        control.remove_peer(&client_id).await;
+
+        info!("Client {} disconnected", client_id);
+    }
+
+    /// Derive the session secret from a client's public key and reply with ours
+    async fn complete_key_exchange(
+        client_id: Uuid,
+        client_public_key: &[u8],
+        server_id: Uuid,
+        key_manager: &Arc<RwLock<KeyExchangeManager>>,
+        clients: &Arc<RwLock<HashMap<Uuid, ClientConnection>>>,
+        writer: &Arc<Mutex<OwnedWriteHalf>>,
+    ) -> Result<()> {
+        let client_public_key = KeyExchangeManager::decode_public_key(client_public_key)?;
+        let (reply, shared_secret) = {
+            let mut key_manager = key_manager.write().await;
+            let public_key = key_manager.generate_key_pair(client_id)?;
+            let shared_secret = key_manager.perform_key_exchange(client_id, &client_public_key, SessionRole::Responder)?;
+            (Message::new_key_exchange(public_key, server_id), shared_secret)
+        };
+
+        // Hold the writer until the secret is stored so no encrypted frame overtakes the reply
+        let mut writer = writer.lock().await;
+        ProtocolHandler::send_message(&mut *writer, &reply).await?;
+        if let Some(client) = clients.write().await.get_mut(&client_id) {
+            client.shared_secret = Some(shared_secret);
+        }
+        Ok(())
+    }
+
+    /// Whether the server is accepting connections
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Server details; addresses are ones peers can connect to rather than wildcard binds
+    pub async fn get_info(&self) -> ServerInfo {
+        let primary = self.connectable_addresses.first().unwrap_or(&self.bound_addresses[0]);
+
+        ServerInfo {
+            id: self.server_id,
+            address: primary.ip().to_string(),
+            port: primary.port(),
+            addresses: self.connectable_addresses.iter().map(|addr| addr.to_string()).collect(),
+            status: self.status(),
+            started_at: chrono::Utc::now(),
+            client_count: self.clients.read().await.len() as u32,
+            max_clients: self.config.max_clients,
+        }
+    }
+}
+
+impl ClientHandshake {
+    /// Open a connection to the target and, when encryption is enabled, agree a session key over it
+    async fn connect(&self, target: &ConnectionTarget) -> Result<(TcpStream, Option<SharedSecret>)> {
+        let mut stream = TcpClient::open_stream(target).await?;
+        if !self.encryption_enabled {
+            return Ok((stream, None));
+        }
+
+        let shared_secret = tokio::time::timeout(self.timeout, self.exchange_keys(&mut stream)).await
+            .map_err(|_| MessengerError::ConnectionTimeout)??;
+        Ok((stream, Some(shared_secret)))
+    }
+
+    /// Send our public key and derive the shared secret from the server's reply.
+    /// Anything the server sends before replying is passed on to the application.
+    async fn exchange_keys(&self, stream: &mut TcpStream) -> Result<SharedSecret> {
+        let public_key = self.key_manager.write().await.generate_key_pair(self.client_id)?;
+        ProtocolHandler::send_message(stream, &Message::new_key_exchange(public_key, self.client_id)).await?;
+
+        let server_public_key = loop {
+            let message = ProtocolHandler::receive_protocol_message(stream, self.max_message_size).await?.to_message()?;
+            match message.message_type {
+                MessageType::KeyExchange { public_key } => break public_key,
+                _ => {
+                    if let Err(e) = self.message_sender.send(message).await {
+                        error!("Failed to send message to application: {}", e);
+                    }
+                },
+            }
+        };
+
+        let server_public_key = KeyExchangeManager::decode_public_key(&server_public_key)?;
+        self.key_manager.write().await.perform_key_exchange(self.client_id, &server_public_key, SessionRole::Initiator)
+    }
+}
+
+impl TcpClient {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        target: ConnectionTarget,
+        config: ClientConfig,
+        security: SecurityConfig,
+        message_sender: mpsc::Sender<Message>,
+        key_manager: Arc<RwLock<KeyExchangeManager>>,
+        heartbeat_handler: Arc<RwLock<HeartbeatHandler>>,
+        control_state: Arc<RwLock<ControlState>>,
+        stats: Arc<RwLock<NetworkStats>>,
+        outbox: Arc<Outbox>,
+        journal: Option<Arc<RwLock<MessageJournal>>>,
+    ) -> Result<Self> {
+        let client_id = Uuid::new_v4();
+        let handshake = ClientHandshake {
+            client_id,
+            encryption_enabled: security.encryption_enabled,
+            max_message_size: security.max_message_size,
+            timeout: Duration::from_secs(config.connection_timeout),
+            key_manager: key_manager.clone(),
+            message_sender: message_sender.clone(),
+        };
+        let (stream, shared_secret) = handshake.connect(&target).await?;
+        let (reader, writer) = stream.into_split();
+        let (control, control_task) = ControlChannel::spawn(control_state);
+        
+        let mut client = Self {
+            writer: Arc::new(Mutex::new(Some(writer))),
+            reader_task: None,
+            heartbeat_task: None,
+            control_task: Some(control_task),
+            status: Arc::new(std::sync::RwLock::new(ConnectionStatus::Connected)),
+            closing: Arc::new(AtomicBool::new(false)),
+            shared_secret: Arc::new(RwLock::new(shared_secret)),
+            server_address: target.host().to_string(),
+            server_port: target.port(),
+            target,
+            config,
+            security,
+            message_sender,
+            key_manager,
+            heartbeat_handler,
+            stats,
+            outbox,
+            journal,
+            benchmark_replies: Arc::new(std::sync::Mutex::new(None)),
+            client_id,
+            connection_start_time: Some(Instant::now()),
+        };
+
+        // Start receiving messages
+        client.start_receiving_messages(reader, handshake, control).await?;
+        client.start_heartbeats().await;
+        client.outbox.flush(&client.writer, &client.shared_secret, &client.stats, client.journal.as_deref(), client.security.max_message_size).await;
+        
+        Ok(client)
+    }
+
+    /// Connect to the target, trying each resolved address in turn, e.g. IPv6 then IPv4 for "localhost"
+    async fn open_stream(target: &ConnectionTarget) -> Result<TcpStream> {
+        for addr in target.resolve().await? {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => debug!("Failed to connect to {}: {}", addr, e),
+            }
+        }
+        Err(MessengerError::ConnectionRefused)
+    }
+
+    async fn start_receiving_messages(&mut self, reader: OwnedReadHalf, handshake: ClientHandshake, control: ControlChannel) -> Result<()> {
+        let session = ClientSession {
+            writer: self.writer.clone(),
+            status: self.status.clone(),
+            closing: self.closing.clone(),
+            shared_secret: self.shared_secret.clone(),
+            stats: self.stats.clone(),
+            outbox: self.outbox.clone(),
+            journal: self.journal.clone(),
+            benchmark_replies: self.benchmark_replies.clone(),
+            control,
+            target: self.target.clone(),
+            config: self.config.clone(),
+            handshake,
+        };
+        let failure_session = session.clone();
+
+        // A restarted loop has lost its reader, so it starts by reconnecting
+        let mut initial_reader = Some(reader);
+        let reader_task = supervisor::supervise(
+            "client receive loop",
+            move || Self::run_connection(initial_reader.take(), session.clone()),
+            move |failure| {
+                let session = failure_session.clone();
+                async move {
+                    if failure.restarting {
+                        session.outbox.go_offline().await;
+                    } else {
+                        session.outbox.close().await;
+                    }
+                    let (status, notice) = if failure.restarting {
+                        (ConnectionStatus::Reconnecting, "Connection to server failed unexpectedly, reconnecting")
+                    } else {
+                        (ConnectionStatus::Error("Connection to server failed".to_string()), "Connection to server failed and could not be recovered")
+                    };
+                    Self::set_status(&session.status, status);
+
+                    let notice = Message::new_system(notice.to_string(), SystemMessageLevel::Error, session.handshake.client_id);
+                    if let Err(e) = session.handshake.message_sender.send(notice).await {
+                        error!("Failed to send message to application: {}", e);
+                    }
+                }
+            },
+        );
+
+        self.reader_task = Some(reader_task);
+        Ok(())
+    }
+
+    /// Forward messages from the server, reconnecting whenever the connection drops
+    async fn run_connection(reader: Option<OwnedReadHalf>, session: ClientSession) {
+        let mut reader = match reader {
+            Some(reader) => reader,
+            None => match Self::resume(&session).await {
+                Some(reader) => reader,
+                None => return session.outbox.close().await,
+            },
+        };
+
+        loop {
+            let app_listening = Self::receive_until_closed(&mut reader, &session).await;
+
+            // Hold new messages, and release our side too so the server can finish closing
+            session.outbox.go_offline().await;
+            session.writer.lock().await.take();
+
+            if !app_listening || session.closing.load(Ordering::SeqCst) || !session.config.auto_reconnect {
+                session.outbox.close().await;
+                Self::set_status(&session.status, ConnectionStatus::Disconnected);
+                info!("Disconnected from server");
+                return;
+            }
+
+            match Self::resume(&session).await {
+                Some(new_reader) => reader = new_reader,
+                None => return session.outbox.close().await,
+            }
+        }
+    }
+
+    /// Reconnect and swap the new connection into the session, returning its read half
+    async fn resume(session: &ClientSession) -> Option<OwnedReadHalf> {
+        let (stream, new_secret) = Self::reconnect(&session.target, &session.config, &session.status, &session.closing, &session.handshake).await?;
+        let (reader, writer) = stream.into_split();
+        *session.writer.lock().await = Some(writer);
+        // The old session's key does not carry over to the new connection
+        *session.shared_secret.write().await = new_secret;
+        session.outbox.flush(&session.writer, &session.shared_secret, &session.stats, session.journal.as_deref(), session.handshake.max_message_size).await;
+        Self::set_status(&session.status, ConnectionStatus::Connected);
+        info!("Reconnected to server at {}:{}", session.target.host(), session.target.port());
+        Some(reader)
+    }
+
+    /// Send keepalives while the connection is idle so NAT mappings are not dropped
+    async fn start_heartbeats(&mut self) {
+        let heartbeat_handler = self.heartbeat_handler.clone();
+        let writer = self.writer.clone();
+        let shared_secret = self.shared_secret.clone();
+        let closing = self.closing.clone();
+        let client_id = self.client_id;
+        let max_message_size = self.security.max_message_size;
+        let period = heartbeat_handler.read().await.interval();
+
+        let heartbeat_task = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(period);
+            ticker.tick().await;
+
+            while !closing.load(Ordering::SeqCst) {
+                ticker.tick().await;
+                if !heartbeat_handler.read().await.should_send_heartbeat() {
+                    continue;
+                }
+
+                // Nothing to do while disconnected or reconnecting
+                let mut writer = writer.lock().await;
+                let Some(stream) = writer.as_mut() else {
+                    continue;
+                };
+
+                let heartbeat = HeartbeatHandler::create_heartbeat(client_id);
+                let secret = shared_secret.read().await.clone();
+                match ProtocolHandler::send_message_with_key(stream, &heartbeat, secret.as_ref(), max_message_size).await {
+                    Ok(()) => heartbeat_handler.write().await.update_heartbeat(),
+                    Err(e) => debug!("Failed to send heartbeat: {}", e),
+                }
+            }
+        });
+
+        self.heartbeat_task = Some(heartbeat_task);
+    }
+
+    /// Send a message to the server over the current connection
+    pub async fn send_message(&self, message: &Message) -> Result<()> {
+        Self::send_over(&self.writer, &self.shared_secret, &self.stats, self.journal.as_deref(), self.security.max_message_size, message).await?;
+        Ok(())
+    }
+
+    /// Send on the current connection, if there is one, counting it in the stats.
+    /// Frames larger than `max_message_size` are refused. Returns the size of the frame on the wire.
+    async fn send_over(
+        writer: &Mutex<Option<OwnedWriteHalf>>,
+        shared_secret: &RwLock<Option<SharedSecret>>,
+        stats: &RwLock<NetworkStats>,
+        journal: Option<&RwLock<MessageJournal>>,
+        max_message_size: usize,
+        message: &Message,
+    ) -> Result<usize> {
+        let protocol_msg = {
+            let mut writer = writer.lock().await;
+            let stream = writer.as_mut().ok_or(MessengerError::NotConnected)?;
+            let protocol_msg = ProtocolMessage::new(message)?;
+            let protocol_msg = match shared_secret.read().await.as_ref() {
+                Some(secret) => protocol_msg.encrypt(secret)?,
+                None => protocol_msg,
+            };
+            ProtocolHandler::send_protocol_message(stream, &protocol_msg, max_message_size).await?;
+            protocol_msg
+        };
+        journal_frame(journal, JournalDirection::Outbound, &protocol_msg).await;
+
+        let mut stats = stats.write().await;
+        stats.messages_sent += 1;
+        stats.bytes_sent += protocol_msg.wire_size() as u64;
+        stats.last_activity = Some(chrono::Utc::now());
+        Ok(protocol_msg.wire_size())
+    }
+
+    /// Whether `peer` names the server this client is connected to, by name or by address
+    async fn is_connected_to(&self, peer: &ConnectionTarget) -> Result<bool> {
+        if *peer == self.target {
+            return Ok(true);
+        }
+        let connected = self.writer.lock().await.as_ref().ok_or(MessengerError::NotConnected)?.peer_addr()?;
+        Ok(peer.resolve().await?.contains(&connected))
+    }
+
+    /// Measure throughput and round-trip time to the server over the current session, encrypted
+    /// if the session is. Probes are echoed by the server one at a time and never reach the application.
+    pub async fn benchmark(&self, duration: Duration, payload_size: usize) -> Result<BenchmarkReport> {
+        let (reply_sender, mut replies) = mpsc::unbounded_channel();
+        *self.benchmark_replies.lock().unwrap_or_else(|e| e.into_inner()) = Some(reply_sender);
+        let result = self.run_benchmark(duration, payload_size, &mut replies).await;
+        self.benchmark_replies.lock().unwrap_or_else(|e| e.into_inner()).take();
+        result
+    }
+
+    async fn run_benchmark(&self, duration: Duration, payload_size: usize, replies: &mut mpsc::UnboundedReceiver<u64>) -> Result<BenchmarkReport> {
+        let reply_timeout = Duration::from_secs(self.config.connection_timeout);
+        let started = Instant::now();
+        let mut round_trips = Vec::new();
+        let mut bytes_sent = 0u64;
+
+        while started.elapsed() < duration {
+            let sequence = round_trips.len() as u64;
+            let probe = Message::new_benchmark(sequence, payload_size, self.client_id);
+
+            let sent_at = Instant::now();
+            let size = Self::send_over(&self.writer, &self.shared_secret, &self.stats, self.journal.as_deref(), self.security.max_message_size, &probe).await?;
+            loop {
+                match tokio::time::timeout(reply_timeout, replies.recv()).await {
+                    Ok(Some(echoed)) if echoed == sequence => break,
+                    Ok(Some(_)) => continue,
+                    Ok(None) | Err(_) => return Err(MessengerError::ConnectionTimeout),
+                }
+            }
+
+            round_trips.push(sent_at.elapsed());
+            bytes_sent += size as u64;
+        }
+
+        let elapsed = started.elapsed();
+
+        round_trips.sort();
+        let percentile = |p: f64| -> f64 {
+            if round_trips.is_empty() {
+                return 0.0;
+            }
+            let rank = ((p * round_trips.len() as f64).ceil() as usize).clamp(1, round_trips.len());
+            round_trips[rank - 1].as_secs_f64() * 1000.0
+        };
+
+        let seconds = elapsed.as_secs_f64();
+        Ok(BenchmarkReport {
+            duration_ms: elapsed.as_millis() as u64,
+            messages: round_trips.len() as u64,
+            bytes_sent,
+            throughput_bytes_per_sec: bytes_sent as f64 / seconds,
+            messages_per_sec: round_trips.len() as f64 / seconds,
+            rtt_p50_ms: percentile(0.50),
+            rtt_p90_ms: percentile(0.90),
+            rtt_p99_ms: percentile(0.99),
+        })
+    }
+
+    /// Forward messages from the server until the connection ends.
+    /// Returns false if the application stopped listening.
+    async fn receive_until_closed(reader: &mut OwnedReadHalf, session: &ClientSession) -> bool {
+        loop {
+            let protocol_msg = match ProtocolHandler::receive_protocol_message(reader, session.handshake.max_message_size).await {
+                Ok(protocol_msg) => protocol_msg,
+                Err(e) => {
+                    debug!("Connection to server closed: {}", e);
+                    return true;
+                }
+            };
+            let size = protocol_msg.wire_size();
+            journal_frame(session.journal.as_deref(), JournalDirection::Inbound, &protocol_msg).await;
+
+            let secret = session.shared_secret.read().await.clone();
+            let message = match protocol_msg.open(secret.as_ref()) {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("Failed to decode message from server: {}", e);
+                    return true;
+                }
+            };
+
+            // Update stats
+            {
+                let mut stats = session.stats.write().await;
+                stats.messages_received += 1;
+                stats.bytes_received += size as u64;
+                stats.last_activity = Some(chrono::Utc::now());
+            }
+
+            if message.is_expired() {
+                debug!("Dropping expired message {} from server", message.id);
+                continue;
+            }
+
+            // Control messages go to the control handler and never reach the application
+            if let MessageType::Control { control: instruction } = message.message_type {
+                session.control.dispatch(message.sender_id, instruction);
+                continue;
+            }
+
+            // Echoed benchmark probes go to the running benchmark, if any
+            if let MessageType::Benchmark { sequence, .. } = message.message_type {
+                if let Some(replies) = session.benchmark_replies.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                    let _ = replies.send(sequence);
+                }
+                continue;
+            }
+
+            if AcknowledgmentHandler::requires_acknowledgment(&message) {
+                let ack = AcknowledgmentHandler::create_acknowledgment(message.id, session.handshake.client_id);
+                if let Some(writer) = session.writer.lock().await.as_mut() {
+                    if let Err(e) = ProtocolHandler::send_message_with_key(writer, &ack, secret.as_ref(), session.handshake.max_message_size).await {
+                        warn!("Failed to acknowledge message {} from server: {}", message.id, e);
+                    }
+                }
+            }
+
+            // Send message to application
+            if let Err(e) = session.handshake.message_sender.send(message).await {
+                error!("Failed to send message to application: {}", e);
+                return false;
+            }
+        }
+    }
+
+    /// Retry the connection with exponential backoff, giving up after `retry_attempts`
+    async fn reconnect(
+        target: &ConnectionTarget,
+        config: &ClientConfig,
+        status: &std::sync::RwLock<ConnectionStatus>,
+        closing: &AtomicBool,
+        handshake: &ClientHandshake,
+    ) -> Option<(TcpStream, Option<SharedSecret>)> {
+        Self::set_status(status, ConnectionStatus::Reconnecting);
+        warn!("Lost connection to server, reconnecting to {}:{}", target.host(), target.port());
+        tokio::time::sleep(Duration::from_secs(config.reconnect_delay)).await;
+
+        for attempt in 0..config.retry_attempts {
+            if closing.load(Ordering::SeqCst) {
+                Self::set_status(status, ConnectionStatus::Disconnected);
+                return None;
+            }
+
+            match handshake.connect(target).await {
+                Ok(connection) => return Some(connection),
+                Err(e) => debug!("Reconnection attempt {} failed: {}", attempt + 1, e),
+            }
+
+            let backoff = config.retry_delay.saturating_mul(1 << attempt.min(16));
+            tokio::time::sleep(Duration::from_millis(backoff)).await;
+        }
+
+        error!("Giving up on server after {} reconnection attempts", config.retry_attempts);
+        Self::set_status(status, ConnectionStatus::Error(format!(
+            "Failed to reconnect after {} attempts", config.retry_attempts
+        )));
+        None
+    }
+
+    fn set_status(status: &std::sync::RwLock<ConnectionStatus>, new_status: ConnectionStatus) {
+        *status.write().unwrap_or_else(|e| e.into_inner()) = new_status;
+    }
+
+    /// Current state of the connection to the server
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Check if the connection to the server is still open
+    pub fn is_connected(&self) -> bool {
+        self.status() == ConnectionStatus::Connected
+    }
+
+    /// Send a disconnect notice, close the write side and wait for the server to close its side
+    pub async fn close(&mut self, reason: &str, linger: Duration) -> Result<()> {
+        self.closing.store(true, Ordering::SeqCst);
+        if let Some(heartbeat_task) = self.heartbeat_task.take() {
+            heartbeat_task.abort();
+        }
+        if let Some(control_task) = self.control_task.take() {
+            control_task.abort();
+        }
+        let Some(mut writer) = self.writer.lock().await.take() else {
+            // Not connected, possibly waiting to reconnect
+            if let Some(reader_task) = self.reader_task.take() {
+                reader_task.abort();
+            }
+            Self::set_status(&self.status, ConnectionStatus::Disconnected);
+            return Ok(());
+        };
+
+        let goodbye = Message::new_disconnect(reason.to_string(), self.client_id);
+        let result = ProtocolHandler::close_gracefully(&mut writer, &goodbye, linger).await;
+
+        // The receive loop keeps draining until the server closes its side
+        if let Some(mut reader_task) = self.reader_task.take() {
+            if tokio::time::timeout(linger, &mut reader_task).await.is_err() {
+                reader_task.abort();
+                Self::set_status(&self.status, ConnectionStatus::Disconnected);
+                return Err(MessengerError::ConnectionTimeout);
+            }
+        }
+        result
+    }
+
+    pub fn get_info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.client_id,
+            server_address: self.server_address.clone(),
+            server_port: self.server_port,
+            status: self.status(),
+            connected_at: Some(chrono::Utc::now()),
+            last_heartbeat: Some(chrono::Utc::now()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DEFAULT_MAX_MESSAGE_SIZE;
+    use crate::types::ControlMessage;
+
+    #[tokio::test]
     async fn test_network_manager_creation() {
         let (manager, _receiver) = NetworkManager::new();
         assert!(manager.connection_type.is_none());
-        assert!(manager.server_info.is_none());
-        assert!(manager.client_info.is_none());
+        assert!(manager.server_info.is_none());
+        assert!(manager.client_info.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_per_peer_stats() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 2;
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut first = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        let mut second = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+
+        ProtocolHandler::send_message(&mut first, &Message::new_text("one".to_string(), Uuid::new_v4())).await.unwrap();
+        for _ in 0..2 {
+            ProtocolHandler::send_message(&mut second, &Message::new_text("two".to_string(), Uuid::new_v4())).await.unwrap();
+        }
+
+        // Wait for the server to process all three messages
+        for _ in 0..50 {
+            let total: u64 = manager.get_all_peer_stats().await.iter().map(|s| s.messages_received).sum();
+            if total == 3 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        let mut peer_stats = manager.get_all_peer_stats().await;
+        peer_stats.sort_by_key(|s| s.messages_received);
+        assert_eq!(peer_stats.len(), 2);
+        assert_eq!(peer_stats[0].messages_received, 1);
+        assert_eq!(peer_stats[1].messages_received, 2);
+        assert!(peer_stats[1].bytes_received > peer_stats[0].bytes_received);
+
+        let single = manager.get_peer_stats(&peer_stats[0].peer_id).await.unwrap();
+        assert_eq!(single.messages_received, 1);
+        assert!(manager.get_peer_stats(&Uuid::new_v4()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_messages_are_dropped() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 2;
+        let mut receiver = manager.message_receiver.write().await.take().unwrap();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let message = Message::new_text("once".to_string(), Uuid::new_v4());
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        ProtocolHandler::send_message(&mut stream, &message).await.unwrap();
+
+        // Resend the same message over a new connection, as after a reconnect
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        ProtocolHandler::send_message(&mut stream, &message).await.unwrap();
+        let next = Message::new_text("next".to_string(), Uuid::new_v4());
+        ProtocolHandler::send_message(&mut stream, &next).await.unwrap();
+
+        let timeout = std::time::Duration::from_secs(2);
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            received.push(tokio::time::timeout(timeout, receiver.recv()).await.unwrap().unwrap().id);
+        }
+        // Connections are served concurrently, so only the set of ids is fixed
+        received.sort();
+        let mut expected = vec![message.id, next.id];
+        expected.sort();
+        assert_eq!(received, expected);
+
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(receiver.try_recv().is_err());
+    }
+
+    async fn wait_for_clients(manager: &NetworkManager, count: usize) {
+        for _ in 0..50 {
+            if manager.clients.read().await.len() == count {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(manager.clients.read().await.len(), count);
+    }
+
+    #[tokio::test]
+    async fn test_idle_clients_are_evicted() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.connection_timeout = 1;
+        let mut receiver = manager.message_receiver.write().await.take().unwrap();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        // Connect and then stay silent
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+
+        let notice = tokio::time::timeout(Duration::from_secs(3), receiver.recv()).await.unwrap().unwrap();
+        assert!(matches!(notice.message_type, MessageType::System { level: SystemMessageLevel::Warning, .. }));
+        assert!(manager.clients.read().await.is_empty());
+
+        let goodbye = ProtocolHandler::receive_message(&mut stream).await.unwrap();
+        assert!(matches!(goodbye.message_type, MessageType::Disconnect { .. }));
+        assert!(ProtocolHandler::receive_message(&mut stream).await.is_err());
+
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_byte_counters_advance() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 2;
+        let mut receiver = manager.message_receiver.write().await.take().unwrap();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        let peer_id = *manager.clients.read().await.keys().next().unwrap();
+
+        let payload = "x".repeat(500);
+        ProtocolHandler::send_message(&mut stream, &Message::new_text(payload.clone(), Uuid::new_v4())).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+
+        let stats = manager.get_stats().await;
+        assert_eq!(stats.messages_received, 1);
+        assert!(stats.bytes_received >= payload.len() as u64);
+        assert!(stats.last_activity.is_some());
+
+        manager.send_to_client(&peer_id, &Message::new_text(payload.clone(), info.id)).await.unwrap();
+        let stats = manager.get_stats().await;
+        assert_eq!(stats.messages_sent, 1);
+        assert!(stats.bytes_sent >= payload.len() as u64);
+
+        // The client side counts its own traffic too
+        let (mut client, _sender) = NetworkManager::new();
+        let mut client_receiver = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&manager, 2).await;
+        client.send_message(Message::new_text(payload.clone(), Uuid::new_v4())).await.unwrap();
+        let stats = client.get_stats().await;
+        assert_eq!(stats.messages_sent, 1);
+        assert!(stats.bytes_sent >= payload.len() as u64);
+
+        let client_id = *manager.clients.read().await.keys().find(|id| **id != peer_id).unwrap();
+        let incoming = Message::new_text(payload.clone(), info.id);
+        manager.send_to_client(&client_id, &incoming).await.unwrap();
+        while tokio::time::timeout(Duration::from_secs(2), client_receiver.recv()).await.unwrap().unwrap().id != incoming.id {}
+        assert!(client.get_stats().await.bytes_received >= payload.len() as u64);
+
+        client.disconnect().await.unwrap();
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sent_messages_are_pending_until_acknowledged() {
+        let (mut manager, _sender) = NetworkManager::new();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        let peer_id = *manager.clients.read().await.keys().next().unwrap();
+
+        // Heartbeats are never acknowledged, so they are not tracked
+        manager.send_to_client(&peer_id, &Message::new_heartbeat(info.id)).await.unwrap();
+        let message = Message::new_text("ack me".to_string(), info.id);
+        manager.send_to_client(&peer_id, &message).await.unwrap();
+        assert_eq!(manager.get_peer_stats(&peer_id).await.unwrap().pending_acks, 1);
+
+        ProtocolHandler::receive_message(&mut peer).await.unwrap();
+        let received = ProtocolHandler::receive_message(&mut peer).await.unwrap();
+        assert_eq!(received.id, message.id);
+        ProtocolHandler::send_message(&mut peer, &AcknowledgmentHandler::create_acknowledgment(message.id, Uuid::new_v4())).await.unwrap();
+
+        for _ in 0..50 {
+            if manager.get_peer_stats(&peer_id).await.unwrap().pending_acks == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(manager.get_peer_stats(&peer_id).await.unwrap().pending_acks, 0);
+
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_journals_both_directions() {
+        let (mut server, _sender) = NetworkManager::new();
+        server.security.encryption_enabled = false;
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let path = std::env::temp_dir()
+            .join(format!("tcp-messenger-client-journal-{}", Uuid::new_v4()))
+            .join("journal.jsonl");
+        let (mut client, _sender) = NetworkManager::new();
+        client.security.encryption_enabled = false;
+        client.enable_journal(MessageJournal::open(path.clone(), 100).unwrap());
+        let mut receiver = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&server, 1).await;
+
+        let outgoing = Message::new_text("from the client".to_string(), Uuid::new_v4());
+        client.send_message(outgoing.clone()).await.unwrap();
+        let peer_id = *server.clients.read().await.keys().next().unwrap();
+        let incoming = Message::new_text("from the server".to_string(), info.id);
+        server.send_to_client(&peer_id, &incoming).await.unwrap();
+        // Skip anything delivered ahead of it, such as the connection notice
+        while tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap().id != incoming.id {}
+
+        let replayed = crate::journal::replay_journal(&MessageJournal::load(&path).unwrap()).unwrap();
+        assert!(replayed.iter().any(|(direction, message)| *direction == JournalDirection::Outbound && message.id == outgoing.id));
+        assert!(replayed.iter().any(|(direction, message)| *direction == JournalDirection::Inbound && message.id == incoming.id));
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connections_beyond_max_clients_are_rejected() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 1;
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        assert_eq!(info.max_clients, 1);
+
+        let _first = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+
+        let mut second = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        let goodbye = tokio::time::timeout(Duration::from_secs(2), ProtocolHandler::receive_message(&mut second)).await.unwrap().unwrap();
+        match goodbye.message_type {
+            MessageType::Disconnect { reason } => assert!(reason.contains("full")),
+            other => panic!("expected a disconnect, got {:?}", other),
+        }
+
+        assert_eq!(manager.clients.read().await.len(), 1);
+        let audit = manager.get_connection_audit().await;
+        assert_eq!(audit.len(), 2);
+        assert_eq!(audit[1].outcome, ConnectionOutcome::OverCapacity);
+
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_message_size_is_enforced() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.security.max_message_size = 1024;
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        let peer_id = *manager.clients.read().await.keys().next().unwrap();
+
+        // Oversized outbound messages are refused without dropping the client
+        let content: String = (0..128).map(|_| Uuid::new_v4().simple().to_string()).collect();
+        let large = Message::new_text(content, info.id);
+        assert!(matches!(manager.send_to_client(&peer_id, &large).await, Err(MessengerError::MessageTooLarge { .. })));
+        assert!(matches!(manager.send_message(large.clone()).await, Err(MessengerError::MessageTooLarge { .. })));
+        assert_eq!(manager.clients.read().await.len(), 1);
+
+        // A header claiming more than the limit gets the peer disconnected
+        let header = crate::protocol::MessageHeader::new(0x01, 1024 * 1024 * 1024, crate::types::MessageFlags::NONE);
+        tokio::io::AsyncWriteExt::write_all(&mut stream, &header.to_bytes()).await.unwrap();
+        wait_for_clients(&manager, 0).await;
+        assert!(manager.clients.read().await.is_empty());
+
+        // A client holds its messages to the same limit rather than the default one
+        let mut client = NetworkManager::new().0;
+        client.security.max_message_size = 1024;
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        assert!(matches!(client.send_message(large).await, Err(MessengerError::MessageTooLarge { .. })));
+        assert_eq!(manager.clients.read().await.len(), 1);
+        client.disconnect().await.unwrap();
+        manager.stop_server().await.unwrap();
+
+        // ...and may send past the default when both ends allow it
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.security.max_message_size = 4 * DEFAULT_MAX_MESSAGE_SIZE;
+        let mut receiver = manager.message_receiver.write().await.take().unwrap();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let mut client = NetworkManager::new().0;
+        client.security.max_message_size = 4 * DEFAULT_MAX_MESSAGE_SIZE;
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        let content = "x".repeat(2 * DEFAULT_MAX_MESSAGE_SIZE);
+        client.send_message(Message::new_text(content.clone(), info.id)).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+        assert!(matches!(received.message_type, MessageType::Text { content: received } if received == content));
+
+        client.disconnect().await.unwrap();
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_control_messages_are_handled_out_of_band() {
+        let (mut manager, _sender) = NetworkManager::new();
+        let mut receiver = manager.message_receiver.write().await.take().unwrap();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        let peer_id = *manager.clients.read().await.keys().next().unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let capabilities = ControlMessage::Capabilities { features: vec!["compression".to_string()] };
+        for message in [
+            Message::new_text("first".to_string(), sender_id),
+            Message::new_control(capabilities, sender_id),
+            Message::new_text("second".to_string(), sender_id),
+            Message::new_control(ControlMessage::FlowControl { paused: true }, sender_id),
+        ] {
+            ProtocolHandler::send_message(&mut stream, &message).await.unwrap();
+        }
+
+        // Data arrives in order with the control messages filtered out
+        for expected in ["first", "second"] {
+            let received = tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+            assert_eq!(received.message_type, MessageType::Text { content: expected.to_string() });
+        }
+
+        // The control handler applied both to the peer's state
+        for _ in 0..50 {
+            if manager.control_state.read().await.is_paused(&peer_id) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        {
+            let control_state = manager.control_state.read().await;
+            assert!(control_state.is_paused(&peer_id));
+            assert_eq!(control_state.capabilities(&peer_id), Some(&["compression".to_string()][..]));
+        }
+
+        // Nothing else reached the application, so there is nothing for storage to persist
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(manager.get_peer_stats(&peer_id).await.unwrap().messages_received, 4);
+        assert_eq!(manager.get_stats().await.messages_received, 2);
+
+        // Control state is dropped with the peer
+        drop(stream);
+        for _ in 0..50 {
+            if manager.control_state.read().await.capabilities(&peer_id).is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(manager.control_state.read().await.capabilities(&peer_id).is_none());
+        assert!(!manager.control_state.read().await.is_paused(&peer_id));
+
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_info_tracks_client_count() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 2;
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        assert_eq!(info.client_count, 0);
+
+        let first = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        let _second = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 2).await;
+        assert_eq!(manager.get_server_info().await.unwrap().client_count, 2);
+
+        drop(first);
+        wait_for_clients(&manager, 1).await;
+        assert_eq!(manager.get_server_info().await.unwrap().client_count, 1);
+
+        manager.stop_server().await.unwrap();
+        assert!(manager.get_server_info().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_stays_listed_while_reading() {
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let mut peer = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&server, 1).await;
+        let client_id = *server.clients.read().await.keys().next().unwrap();
+
+        // Watch the client map from another task while the read loop handles traffic and idles
+        let clients = server.clients.clone();
+        let watching = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let still_watching = watching.clone();
+        let watcher = tokio::spawn(async move {
+            let mut misses = 0;
+            while still_watching.load(std::sync::atomic::Ordering::SeqCst) {
+                if !clients.read().await.contains_key(&client_id) {
+                    misses += 1;
+                }
+                tokio::task::yield_now().await;
+            }
+            misses
+        });
+
+        for round in 0..20 {
+            ProtocolHandler::send_message(&mut peer, &Message::new_heartbeat(info.id)).await.unwrap();
+            // Leave the loop waiting on a message that is slow to come every few rounds
+            let pause = if round % 5 == 0 { 50 } else { 5 };
+            tokio::time::sleep(std::time::Duration::from_millis(pause)).await;
+        }
+
+        watching.store(false, std::sync::atomic::Ordering::SeqCst);
+        assert_eq!(watcher.await.unwrap(), 0);
+        assert_eq!(server.get_server_info().await.unwrap().client_count, 1);
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_uptime() {
+        let (mut manager, _sender) = NetworkManager::new();
+        assert_eq!(manager.get_stats().await.connection_uptime, 0);
+
+        manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(manager.get_stats().await.connection_uptime >= 1);
+
+        manager.stop_server().await.unwrap();
+        assert_eq!(manager.get_stats().await.connection_uptime, 0);
+    }
+
+    #[tokio::test]
+    async fn test_expired_messages_are_dropped() {
+        let (mut manager, _sender) = NetworkManager::new();
+        let mut receiver = manager.message_receiver.write().await.take().unwrap();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut stream = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+
+        // Sent five seconds ago with a one second TTL
+        let mut stale = Message::new_text("typing...".to_string(), Uuid::new_v4())
+            .with_ttl(Duration::from_secs(1));
+        stale.timestamp = chrono::Utc::now() - chrono::Duration::seconds(5);
+        let fresh = Message::new_text("still relevant".to_string(), Uuid::new_v4())
+            .with_ttl(Duration::from_secs(60));
+
+        ProtocolHandler::send_message(&mut stream, &stale).await.unwrap();
+        ProtocolHandler::send_message(&mut stream, &fresh).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(received.id, fresh.id);
+        assert_eq!(received.ttl, Some(60_000));
+        assert!(receiver.try_recv().is_err());
+
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_close_delivers_final_message() {
+        use tokio::io::AsyncReadExt;
+
+        let (mut manager, _sender) = NetworkManager::new();
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut kicked = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        let peer_id = *manager.clients.read().await.keys().next().unwrap();
+
+        // Read the final message concurrently, closing our side once the server has
+        let peer = tokio::spawn(async move {
+            let message = ProtocolHandler::receive_message(&mut kicked).await.unwrap();
+            assert_eq!(kicked.read(&mut [0u8; 16]).await.unwrap(), 0);
+            message
+        });
+        manager.disconnect_client(&peer_id, "kicked").await.unwrap();
+        match peer.await.unwrap().message_type {
+            MessageType::Disconnect { reason } => assert_eq!(reason, "kicked"),
+            other => panic!("Expected Disconnect, got {:?}", other),
+        }
+        assert!(manager.clients.read().await.is_empty());
+
+        // Shutting down notifies every remaining client
+        let mut remaining = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        let peer = tokio::spawn(async move {
+            ProtocolHandler::receive_message(&mut remaining).await.unwrap()
+        });
+        manager.stop_server().await.unwrap();
+        assert!(matches!(peer.await.unwrap().message_type, MessageType::Disconnect { .. }));
+        assert!(manager.clients.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_close_delivers_final_message() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // The bare listener does not answer key exchanges
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.security.encryption_enabled = false;
+        manager.connect_to_server("127.0.0.1".to_string(), port).await.unwrap();
+        let (mut server_side, _) = listener.accept().await.unwrap();
+
+        let peer = tokio::spawn(async move {
+            let message = ProtocolHandler::receive_message(&mut server_side).await.unwrap();
+            assert_eq!(server_side.read(&mut [0u8; 16]).await.unwrap(), 0);
+            message
+        });
+        manager.disconnect().await.unwrap();
+
+        assert!(matches!(peer.await.unwrap().message_type, MessageType::Disconnect { .. }));
+        assert!(manager.connection_type.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_receives_server_message() {
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        let mut receiver = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&server, 1).await;
+        let peer_id = *server.clients.read().await.keys().next().unwrap();
+
+        let message = Message::new_text("hello client".to_string(), info.id);
+        server.send_to_client(&peer_id, &message).await.unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(received, Message { encrypted: true, ..message });
+
+        let stats = client.get_stats().await;
+        assert_eq!(stats.messages_received, 1);
+        assert!(stats.bytes_received > 0);
+        assert_eq!(server.get_stats().await.messages_sent, 1);
+
+        // Losing the server marks the client disconnected
+        server.stop_server().await.unwrap();
+        for _ in 0..50 {
+            if client.get_connection_status().await == ConnectionStatus::Disconnected {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(client.get_connection_status().await, ConnectionStatus::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange_on_connect() {
+        let (mut server, _sender) = NetworkManager::new();
+        let mut server_receiver = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&server, 1).await;
+
+        // The server stores its secret before replying, so both sides are ready once connect returns
+        let server_secret = server.clients.read().await.values().next().unwrap().shared_secret.clone().unwrap();
+        let client_secret = client.client.as_ref().unwrap().shared_secret.read().await.clone().unwrap();
+        assert_eq!(server_secret.receiving, client_secret.sending);
+        assert_eq!(server_secret.sending, client_secret.receiving);
+
+        // The exchange itself never reaches the application
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(server_receiver.try_recv().is_err());
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_reaches_every_client() {
+        let (mut server, _sender) = NetworkManager::new();
+        server.config.server.max_clients = 2;
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut clients = Vec::new();
+        let mut receivers = Vec::new();
+        for _ in 0..2 {
+            let (mut client, _sender) = NetworkManager::new();
+            receivers.push(client.message_receiver.write().await.take().unwrap());
+            client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+            clients.push(client);
+        }
+        wait_for_clients(&server, 2).await;
+
+        let message = Message::new_text("hello everyone".to_string(), info.id);
+        server.send_message(message.clone()).await.unwrap();
+
+        for receiver in receivers.iter_mut() {
+            let received = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+            assert_eq!(received, Message { encrypted: true, ..message.clone() });
+        }
+        assert_eq!(server.get_stats().await.messages_sent, 2);
+
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_message_reaches_only_its_recipient() {
+        let (mut server, _sender) = NetworkManager::new();
+        server.config.server.max_clients = 2;
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let mut clients = Vec::new();
+        let mut receivers = Vec::new();
+        let mut peer_ids = Vec::new();
+        for connected in 1..=2 {
+            let (mut client, _sender) = NetworkManager::new();
+            receivers.push(client.message_receiver.write().await.take().unwrap());
+            client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+            clients.push(client);
+            wait_for_clients(&server, connected).await;
+            let peer_id = *server.clients.read().await.keys().find(|id| !peer_ids.contains(*id)).unwrap();
+            peer_ids.push(peer_id);
+        }
+
+        let direct = Message { recipient_id: Some(peer_ids[0]), ..Message::new_text("just for you".to_string(), info.id) };
+        server.send_message(direct.clone()).await.unwrap();
+        let everyone = Message::new_text("hello everyone".to_string(), info.id);
+        server.send_message(everyone.clone()).await.unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(2), receivers[0].recv()).await.unwrap().unwrap();
+        assert_eq!(received.id, direct.id);
+        assert_eq!(received.recipient_id, Some(peer_ids[0]));
+        // The other client's first message is the broadcast sent after the direct one
+        let received = tokio::time::timeout(Duration::from_secs(2), receivers[1].recv()).await.unwrap().unwrap();
+        assert_eq!(received.id, everyone.id);
+
+        // A recipient that isn't connected fails straight away
+        let mut stray = Message { recipient_id: Some(Uuid::new_v4()), ..Message::new_text("hello?".to_string(), info.id) };
+        assert!(matches!(server.send_message_with_retry(&mut stray).await, Err(MessengerError::ResourceNotFound(_))));
+        assert_eq!(stray.status, MessageStatus::Failed);
+        assert_eq!(stray.retry_count, 0);
+
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_sends_heartbeats() {
+        let (mut server, _sender) = NetworkManager::new();
+        let mut receiver = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        client.heartbeat_handler = Arc::new(RwLock::new(HeartbeatHandler::with_interval(Duration::from_millis(50))));
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&server, 1).await;
+        let peer_id = *server.clients.read().await.keys().next().unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // Heartbeats are counted per peer but not passed to the application
+        assert!(server.get_peer_stats(&peer_id).await.unwrap().messages_received >= 2);
+        assert!(server.clients.read().await[&peer_id].last_heartbeat.elapsed() < Duration::from_millis(200));
+        assert!(receiver.try_recv().is_err());
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_benchmark_loopback() {
+        let (mut server, _sender) = NetworkManager::new();
+        server.config.server.max_clients = 1;
+        let mut server_receiver = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        let mut client_receiver = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&server, 1).await;
+
+        let peer = ConnectionTarget::parse_peer(&format!("localhost:{}", info.port)).unwrap();
+        let report = client.benchmark(&peer, Duration::from_millis(200), 1024).await.unwrap();
+        assert!(report.messages > 0);
+        assert!(report.bytes_sent > 1024 * report.messages);
+        assert!(report.throughput_bytes_per_sec > 0.0);
+        assert!(report.messages_per_sec > 0.0);
+        assert!(report.rtt_p50_ms > 0.0);
+        assert!(report.rtt_p50_ms <= report.rtt_p90_ms && report.rtt_p90_ms <= report.rtt_p99_ms);
+
+        // The probes used the existing encrypted session rather than a connection of their own
+        assert_eq!(server.clients.read().await.len(), 1);
+        assert!(server.clients.read().await.values().all(|client| client.shared_secret.is_some()));
+        assert_eq!(server.get_server_info().await.unwrap().client_count, 1);
+
+        // Neither side passes probes to the application
+        while let Ok(message) = server_receiver.try_recv() {
+            assert!(!message.is_benchmark());
+        }
+        while let Ok(message) = client_receiver.try_recv() {
+            assert!(!message.is_benchmark());
+        }
+
+        // Only the connected server can be benchmarked, and only with a client connection
+        let other = ConnectionTarget::parse_peer(&format!("127.0.0.1:{}", info.port.wrapping_add(1).max(1))).unwrap();
+        assert!(matches!(client.benchmark(&other, Duration::from_millis(10), 16).await, Err(MessengerError::InvalidField { .. })));
+        assert!(ConnectionTarget::parse_peer("127.0.0.1").is_err());
+        assert!(matches!(server.benchmark(&peer, Duration::from_millis(10), 16).await, Err(MessengerError::NotConnected)));
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+    }
+
+    async fn wait_for_status(manager: &NetworkManager, matches: impl Fn(&ConnectionStatus) -> bool) -> ConnectionStatus {
+        for _ in 0..100 {
+            let status = manager.get_connection_status().await;
+            if matches(&status) {
+                return status;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        manager.get_connection_status().await
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_then_fails() {
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        client.config.client.retry_attempts = 2;
+        client.config.client.retry_delay = 10;
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+
+        // Once the server is gone the client's socket is closed
+        server.stop_server().await.unwrap();
+        wait_for_status(&client, |status| *status == ConnectionStatus::Disconnected).await;
+
+        let mut message = Message::new_text("anyone there?".to_string(), info.id);
+        assert!(client.send_message_with_retry(&mut message).await.is_err());
+        assert_eq!(message.retry_count, 2);
+        assert_eq!(message.status, MessageStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_offline_messages_are_queued_and_flushed_in_order() {
+        let (mut server, _sender) = NetworkManager::new();
+        let mut receiver = server.message_receiver.write().await.take().unwrap();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.auto_reconnect = false;
+        client.outbox = Arc::new(Outbox::new(3));
+
+        // Nothing is connected yet, so the messages wait in the outbox
+        let mut queued = Vec::new();
+        for n in 0..3 {
+            let mut message = Message::new_text(format!("offline {}", n), info.id);
+            client.send_message_with_retry(&mut message).await.unwrap();
+            assert_eq!(message.status, MessageStatus::Sending);
+            queued.push(message);
+        }
+        let mut overflow = Message::new_text("one too many".to_string(), info.id);
+        assert!(matches!(client.send_message_with_retry(&mut overflow).await, Err(MessengerError::OutboxFull { max: 3 })));
+        assert_eq!(overflow.status, MessageStatus::Failed);
+        assert_eq!(client.outbox.len().await, 3);
+
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        let mut online = Message::new_text("online".to_string(), info.id);
+        client.send_message_with_retry(&mut online).await.unwrap();
+        assert_eq!(online.status, MessageStatus::Sent);
+        assert!(client.outbox.is_empty().await);
+
+        for expected in queued.iter().chain([&online]) {
+            let received = tokio::time::timeout(Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+            assert_eq!(received.id, expected.id);
+        }
+
+        client.disconnect().await.unwrap();
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_reconnects_after_server_restart() {
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let address: SocketAddr = info.addresses[0].parse().unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        client.config.client.reconnect_delay = 0;
+        client.config.client.retry_delay = 20;
+        client.config.client.retry_attempts = 5;
+        let mut receiver = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), info.port).await.unwrap();
+        wait_for_clients(&server, 1).await;
+
+        // Kill the server; the client starts retrying
+        server.stop_server().await.unwrap();
+        let status = wait_for_status(&client, |status| *status != ConnectionStatus::Connected).await;
+        assert_eq!(status, ConnectionStatus::Reconnecting);
+        // Skip the disconnect notice sent on shutdown
+        let goodbye = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+        assert!(matches!(goodbye.message_type, MessageType::Disconnect { .. }));
+
+        // Bring it back on the same port
+        let (mut server, _sender) = NetworkManager::new();
+        server.start_server_on(vec![address]).await.unwrap();
+        wait_for_clients(&server, 1).await;
+        assert_eq!(wait_for_status(&client, |status| *status == ConnectionStatus::Connected).await, ConnectionStatus::Connected);
+
+        let peer_id = *server.clients.read().await.keys().next().unwrap();
+        let message = Message::new_text("welcome back".to_string(), info.id);
+        server.send_to_client(&peer_id, &message).await.unwrap();
+        // A fresh key exchange happened on the new connection
+        let received = tokio::time::timeout(std::time::Duration::from_secs(2), receiver.recv()).await.unwrap().unwrap();
+        assert_eq!(received, Message { encrypted: true, ..message });
+
+        // With the server gone for good the client gives up
+        server.stop_server().await.unwrap();
+        let status = wait_for_status(&client, |status| matches!(status, ConnectionStatus::Error(_))).await;
+        assert!(matches!(status, ConnectionStatus::Error(_)));
+    }
+
+    #[tokio::test]
+    async fn test_port_is_free_after_stop() {
+        let (mut server, _sender) = NetworkManager::new();
+        server.config.close_linger = 200;
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        let address: SocketAddr = info.addresses[0].parse().unwrap();
+
+        let mut peer = TcpStream::connect(address).await.unwrap();
+        wait_for_clients(&server, 1).await;
+
+        server.stop_server().await.unwrap();
+
+        // The peer is told why, and the listener is already gone
+        let goodbye = ProtocolHandler::receive_message(&mut peer).await.unwrap();
+        assert!(matches!(goodbye.message_type, MessageType::Disconnect { .. }));
+        let listener = TcpListener::bind(address).await.unwrap();
+        drop(listener);
+
+        // A new server can take the same port over
+        server.start_server_on(vec![address]).await.unwrap();
+        server.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_audit() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 1;
+        let info = manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let first = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+
+        // The second client is turned away because the server is full
+        let mut second = TcpStream::connect(("127.0.0.1", info.port)).await.unwrap();
+        let goodbye = tokio::time::timeout(Duration::from_secs(2), ProtocolHandler::receive_message(&mut second)).await.unwrap().unwrap();
+        assert!(matches!(goodbye.message_type, MessageType::Disconnect { .. }));
+
+        let audit = manager.get_connection_audit().await;
+        assert_eq!(audit.len(), 2);
+        assert_eq!(audit[0].address, first.local_addr().unwrap().to_string());
+        assert_eq!(audit[0].outcome, ConnectionOutcome::Accepted);
+        assert_eq!(audit[1].address, second.local_addr().unwrap().to_string());
+        assert_eq!(audit[1].outcome, ConnectionOutcome::OverCapacity);
+
+        manager.stop_server().await.unwrap();
+    }
+
+    #[test]
+    fn test_connection_audit_is_capped() {
+        let mut audit = ConnectionAudit::new(2);
+        let addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+
+        audit.record(addr, ConnectionOutcome::Blocklisted);
+        audit.record(addr, ConnectionOutcome::AuthenticationFailed);
+        audit.record(addr, ConnectionOutcome::Accepted);
+
+        let entries = audit.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].outcome, ConnectionOutcome::AuthenticationFailed);
+        assert_eq!(entries[1].outcome, ConnectionOutcome::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_server_reports_connectable_address() {
+        let (mut manager, _sender) = NetworkManager::new();
+        let info = manager.start_server_on(vec!["0.0.0.0:0".parse().unwrap()]).await.unwrap();
+
+        assert_ne!(info.address, "0.0.0.0");
+        assert!(!info.addresses.is_empty());
+        for address in &info.addresses {
+            let address: SocketAddr = address.parse().unwrap();
+            assert!(!address.ip().is_unspecified());
+            assert_eq!(address.port(), info.port);
+        }
+
+        // The reported address accepts connections
+        let _stream = TcpStream::connect((info.address.as_str(), info.port)).await.unwrap();
+        wait_for_clients(&manager, 1).await;
+        manager.stop_server().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_multiple_listeners() {
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.config.server.max_clients = 2;
+        let info = manager.start_server_on(vec![
+            "127.0.0.1:0".parse().unwrap(),
+            "[::1]:0".parse().unwrap(),
+        ]).await.unwrap();
+
+        assert_eq!(info.addresses.len(), 2);
+        let ipv4: SocketAddr = info.addresses[0].parse().unwrap();
+        let ipv6: SocketAddr = info.addresses[1].parse().unwrap();
+        assert!(ipv4.is_ipv4());
+        assert!(ipv6.is_ipv6());
+        assert_eq!(info.port, ipv4.port());
+
+        let _first = TcpStream::connect(ipv4).await.unwrap();
+        let _second = TcpStream::connect(ipv6).await.unwrap();
+
+        // Both listeners feed the same client map
+        for _ in 0..50 {
+            if manager.clients.read().await.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(manager.clients.read().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_connect_with_bad_address() {
+        let (mut manager, _sender) = NetworkManager::new();
+
+        match manager.connect_to_server("not an address".to_string(), 8000).await {
+            Err(MessengerError::InvalidField { field, reason }) => {
+                assert_eq!(field, "address");
+                assert!(reason.contains("not an address"));
+            },
+            other => panic!("Expected InvalidField, got {:?}", other.map(|_| ())),
+        }
+        assert!(manager.connection_type.is_none());
+
+        assert!(matches!(ConnectionTarget::parse("", 8000), Err(MessengerError::InvalidField { field, .. }) if field == "address"));
+        assert!(matches!(ConnectionTarget::parse("127.0.0.1", 0), Err(MessengerError::InvalidField { field, .. }) if field == "port"));
+        assert_eq!(ConnectionTarget::parse("[::1]", 8000).unwrap().host(), "::1");
+    }
+
+    #[tokio::test]
+    async fn test_connect_by_hostname() {
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        let client_info = client.connect_to_server("localhost".to_string(), info.port).await.unwrap();
+        assert_eq!(client_info.server_address, "localhost");
+        assert_eq!(client_info.server_port, info.port);
+        assert_eq!(client.connection_type, Some(ConnectionType::Client));
+    }
+
+    #[tokio::test]
+    async fn test_connect_to_ipv6_loopback() {
+        let (mut server, _sender) = NetworkManager::new();
+        let info = server.start_server_on(vec!["[::1]:0".parse().unwrap()]).await.unwrap();
+
+        let (mut client, _sender) = NetworkManager::new();
+        let client_info = client.connect_to_server("[::1]".to_string(), info.port).await.unwrap();
+        assert_eq!(client_info.server_address, "::1");
+        wait_for_clients(&server, 1).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_failures_return_errors() {
+        let (mut manager, _sender) = NetworkManager::new();
+        assert!(manager.connect_to_server("no-such-host.invalid".to_string(), 8000).await.is_err());
+        assert!(manager.connection_type.is_none());
+
+        // Nothing listening on the port
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert!(matches!(
+            manager.connect_to_server("127.0.0.1".to_string(), port).await,
+            Err(MessengerError::ConnectionRefused)
+        ));
     }
 
     #[test]
@@ -1,23 +1,69 @@
 use crate::{protocol_error, error::{MessengerError, Result}};
-use crate::types::Message;
+use crate::types::{ControlMessage, Message, MessageFlags, MessagePreview};
+use crate::encryption::{SecureMessage, SharedSecret, ENCRYPTION_OVERHEAD};
+use crate::config::{AcknowledgmentConfig, PendingAckPolicy};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
 
-/// Protocol version
-pub const PROTOCOL_VERSION: u8 = 1;
+/// Protocol version; version 2 added the header checksum
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Size of the message header in bytes
+pub const HEADER_SIZE: usize = 8;
+
+/// Bytes of session sequence number carried inside each encrypted body
+pub const SEQUENCE_SIZE: usize = 8;
+
+/// Largest frame accepted when no limit is configured, matching the `SecurityConfig` default
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Message type bytes reserved for control frames; data never uses this range
+pub const CONTROL_MESSAGE_TYPES: RangeInclusive<u8> = 0xF0..=0xFF;
+
+/// Control messages queued for the control handler before new ones are dropped
+pub const CONTROL_QUEUE_SIZE: usize = 64;
+
+/// Message type byte for file messages, whose bytes travel outside the JSON body
+pub const FILE_MESSAGE_TYPE: u8 = 0x02;
+
+/// Minimum serialized body size worth compressing
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Upper bound on an inflated message body
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// MIME types whose content is already compressed
+const COMPRESSED_MIME_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "application/zip",
+    "application/gzip",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/pdf",
+];
 
 /// Message header structure (8 bytes)
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct MessageHeader {
     pub version: u8,
     pub message_type: u8,
-    pub flags: u8,
+    pub flags: MessageFlags,
     pub length: u32,
 }
 
 
 impl MessageHeader {
-    pub fn new(message_type: u8, length: u32, flags: u8) -> Self {
+    pub fn new(message_type: u8, length: u32, flags: MessageFlags) -> Self {
         Self {
             version: PROTOCOL_VERSION,
             message_type,
@@ -31,9 +77,9 @@ impl MessageHeader {
         let mut bytes = [0u8; 8];
         bytes[0] = self.version;
         bytes[1] = self.message_type;
-        bytes[2] = self.flags;
-        bytes[3] = 0; // Reserved
+        bytes[2] = self.flags.to_byte();
         bytes[4..8].copy_from_slice(&self.length.to_be_bytes());
+        bytes[3] = header_checksum(&bytes);
         bytes
     }
 
@@ -43,13 +89,18 @@ impl MessageHeader {
             return Err(protocol_error!("Invalid header length: {}", bytes.len()));
         }
 
+        let checksum = header_checksum(&bytes[..8]);
+        if bytes[3] != checksum {
+            return Err(protocol_error!("Header checksum mismatch: expected 0x{:02X}, got 0x{:02X}", checksum, bytes[3]));
+        }
+
         let version = bytes[0];
         if version != PROTOCOL_VERSION {
             return Err(protocol_error!("Unsupported protocol version: {}", version));
         }
 
         let message_type = bytes[1];
-        let flags = bytes[2];
+        let flags = MessageFlags::from_byte(bytes[2]);
         let length = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
 
         Ok(Self {
@@ -59,6 +110,20 @@ impl MessageHeader {
             length,
         })
     }
+
+    /// Check if the frame is on the control channel
+    pub fn is_control(&self) -> bool {
+        CONTROL_MESSAGE_TYPES.contains(&self.message_type)
+    }
+}
+
+/// CRC-8 (polynomial 0x07) over every header byte except the checksum itself at index 3
+fn header_checksum(header: &[u8]) -> u8 {
+    header.iter().enumerate()
+        .filter(|(index, _)| *index != 3)
+        .fold(0u8, |crc, (_, byte)| {
+            (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+        })
 }
 
 /// Protocol message wrapper
@@ -70,22 +135,39 @@ pub struct ProtocolMessage {
 
 impl ProtocolMessage {
     pub fn new(message: &Message) -> Result<Self> {
-        let serialized = serde_json::to_vec(message)
-            .map_err(|e| protocol_error!("Failed to serialize message: {}", e))?;
+        let mut serialized = Self::encode_body(message)?;
 
         let message_type = match message.message_type {
             crate::types::MessageType::Text { .. } => 0x01,
-            crate::types::MessageType::File { .. } => 0x02,
+            crate::types::MessageType::File { .. } => FILE_MESSAGE_TYPE,
             crate::types::MessageType::System { .. } => 0x03,
             crate::types::MessageType::Heartbeat => 0x04,
             crate::types::MessageType::KeyExchange { .. } => 0x05,
             crate::types::MessageType::Disconnect { .. } => 0x06,
+            crate::types::MessageType::Reaction { .. } => 0x07,
+            crate::types::MessageType::Edit { .. } => 0x08,
+            crate::types::MessageType::ReadReceipt { .. } => 0x0B,
             crate::types::MessageType::Acknowledgment { .. } => 0x09,
+            crate::types::MessageType::Benchmark { .. } => 0x0A,
+            // 0x0A already belongs to benchmark probes
+            crate::types::MessageType::Typing { .. } => 0x0C,
+            crate::types::MessageType::Control { ref control } => match control {
+                ControlMessage::Capabilities { .. } => 0xF0,
+                ControlMessage::FlowControl { .. } => 0xF1,
+            },
         };
 
-        let mut flags = 0u8;
-        if message.encrypted { flags |= 0x01; }
-        if !message.is_system() { flags |= 0x04; }
+        // The encrypted flag is only set once the body is actually encrypted
+        let mut flags = MessageFlags::new();
+        flags.set(MessageFlags::ACKNOWLEDGMENT, AcknowledgmentHandler::requires_acknowledgment(message));
+        if let crate::types::MessageType::File { total_chunks: Some(total), .. } = message.message_type {
+            flags.set(MessageFlags::CHUNKED, total > 1);
+        }
+
+        if Self::should_compress(message, serialized.len()) {
+            serialized = Self::compress(&serialized)?;
+            flags |= MessageFlags::COMPRESSED;
+        }
 
         let header = MessageHeader::new(message_type, serialized.len() as u32, flags);
 
@@ -95,6 +177,209 @@ impl ProtocolMessage {
         })
     }
 
+    /// Serialize a message body. File contents are not put through JSON: the body is the
+    /// length-prefixed JSON of the message without its data, followed by the length-prefixed
+    /// raw bytes if there are any.
+    fn encode_body(message: &Message) -> Result<Vec<u8>> {
+        let crate::types::MessageType::File { data, .. } = &message.message_type else {
+            return serde_json::to_vec(message)
+                .map_err(|e| protocol_error!("Failed to serialize message: {}", e));
+        };
+
+        let mut metadata = message.clone();
+        if let crate::types::MessageType::File { data, .. } = &mut metadata.message_type {
+            *data = None;
+        }
+        let metadata = serde_json::to_vec(&metadata)
+            .map_err(|e| protocol_error!("Failed to serialize message: {}", e))?;
+
+        let mut body = Vec::with_capacity(8 + metadata.len() + data.as_ref().map_or(0, Vec::len));
+        body.extend_from_slice(&(metadata.len() as u32).to_be_bytes());
+        body.extend_from_slice(&metadata);
+        if let Some(data) = data {
+            body.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            body.extend_from_slice(data);
+        }
+        Ok(body)
+    }
+
+    /// Reverse `encode_body` for a frame of the given message type
+    fn decode_body(message_type: u8, body: &[u8]) -> Result<Message> {
+        if message_type != FILE_MESSAGE_TYPE {
+            return serde_json::from_slice(body)
+                .map_err(|e| protocol_error!("Failed to deserialize message: {}", e));
+        }
+
+        let (metadata, rest) = split_length_prefixed(body)?;
+        let mut message: Message = serde_json::from_slice(metadata)
+            .map_err(|e| protocol_error!("Failed to deserialize message: {}", e))?;
+        let data = if rest.is_empty() {
+            None
+        } else {
+            let (data, trailing) = split_length_prefixed(rest)?;
+            if !trailing.is_empty() {
+                return Err(protocol_error!("Unexpected {} bytes after file data", trailing.len()));
+            }
+            Some(data.to_vec())
+        };
+
+        match &mut message.message_type {
+            crate::types::MessageType::File { data: slot, .. } => *slot = data,
+            _ => return Err(protocol_error!("Message type 0x{:02X} does not match its body", message_type)),
+        }
+        Ok(message)
+    }
+
+    /// Decide whether a message body is worth compressing
+    pub fn should_compress(message: &Message, body_len: usize) -> bool {
+        if body_len < COMPRESSION_THRESHOLD {
+            return false;
+        }
+
+        match &message.message_type {
+            crate::types::MessageType::Text { .. } => true,
+            crate::types::MessageType::System { .. } => true,
+            crate::types::MessageType::File { mime_type, .. } => !is_compressed_mime_type(mime_type),
+            _ => false,
+        }
+    }
+
+    /// Check if the body is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.header.flags.encrypted()
+    }
+
+    /// Encrypt the body with a peer's shared secret, tagging it with the session's next sequence number.
+    /// The final header is authenticated as associated data, so altering any of its fields in transit
+    /// makes decryption fail.
+    pub fn encrypt(&self, secret: &SharedSecret) -> Result<Self> {
+        let mut plaintext = Vec::with_capacity(SEQUENCE_SIZE + self.data.len());
+        plaintext.extend_from_slice(&secret.next_sequence().to_be_bytes());
+        plaintext.extend_from_slice(&self.data);
+
+        let mut header = self.header;
+        header.flags |= MessageFlags::ENCRYPTED;
+        header.length = (plaintext.len() + ENCRYPTION_OVERHEAD) as u32;
+
+        let secure = secret.seal(&plaintext, &header.to_bytes())?;
+        let data = secure.to_bytes();
+        debug_assert_eq!(data.len(), header.length as usize);
+
+        Ok(Self { header, data })
+    }
+
+    /// Decrypt the body with a peer's shared secret, rejecting replayed or stale frames
+    pub fn decrypt(&self, secret: &SharedSecret) -> Result<Self> {
+        let secure = SecureMessage::from_bytes(&self.data)?;
+        let mut data = secret.open(&secure, &self.header.to_bytes())?;
+
+        if data.len() < SEQUENCE_SIZE {
+            return Err(protocol_error!("Encrypted message is missing its sequence number"));
+        }
+        let body = data.split_off(SEQUENCE_SIZE);
+        let sequence = u64::from_be_bytes(data.try_into().expect("sequence is 8 bytes"));
+        if !secret.accept_sequence(sequence) {
+            return Err(protocol_error!("Rejected replayed or stale encrypted message (sequence {})", sequence));
+        }
+        let data = body;
+
+        let mut header = self.header;
+        header.flags.set(MessageFlags::ENCRYPTED, false);
+        header.length = data.len() as u32;
+
+        Ok(Self { header, data })
+    }
+
+    /// Decrypt if needed and convert back to an application message
+    pub fn open(&self, secret: Option<&SharedSecret>) -> Result<Message> {
+        let message = if self.is_encrypted() {
+            let secret = secret.ok_or_else(|| protocol_error!("Received an encrypted message without a shared secret"))?;
+            let mut message = self.decrypt(secret)?.to_message()?;
+            message.encrypted = true;
+            message
+        } else {
+            self.to_message()?
+        };
+
+        // Control and data must not be smuggled through each other's type range
+        if message.is_control() != self.header.is_control() {
+            return Err(protocol_error!("Message type 0x{:02X} does not match its body", self.header.message_type));
+        }
+        Ok(message)
+    }
+
+    /// Check if the body was compressed by the sender
+    pub fn is_compressed(&self) -> bool {
+        self.header.flags.compressed()
+    }
+
+    fn compress(data: &[u8]) -> Result<Vec<u8>> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)
+            .map_err(|e| protocol_error!("Failed to compress message: {}", e))?;
+        encoder.finish()
+            .map_err(|e| protocol_error!("Failed to compress message: {}", e))
+    }
+
+    fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+        use std::io::Read;
+
+        let mut decompressed = Vec::new();
+        flate2::read::DeflateDecoder::new(data)
+            .take(MAX_DECOMPRESSED_SIZE as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| protocol_error!("Failed to decompress message: {}", e))?;
+
+        if decompressed.len() > MAX_DECOMPRESSED_SIZE {
+            return Err(protocol_error!("Decompressed message exceeds {} bytes", MAX_DECOMPRESSED_SIZE));
+        }
+        Ok(decompressed)
+    }
+
+    /// Preview how a message would be sent without sending it. It only fits if it is within
+    /// both the wire size limit and the character limit `send_message` enforces.
+    pub fn preview(message: &Message, max_message_size: usize, max_text_length: usize, encrypted: bool) -> Result<MessagePreview> {
+        let protocol_msg = Self::new(message)?;
+
+        let mut wire_size = protocol_msg.wire_size();
+        if encrypted {
+            wire_size += ENCRYPTION_OVERHEAD + SEQUENCE_SIZE;
+        }
+
+        let content = match &message.message_type {
+            crate::types::MessageType::Text { content } => content.clone(),
+            crate::types::MessageType::System { content, .. } => content.clone(),
+            _ => String::new(),
+        };
+
+        let text_length = crate::config::text_length(&content);
+        Ok(MessagePreview {
+            content,
+            wire_size,
+            max_message_size,
+            text_length,
+            max_text_length,
+            encrypted,
+            fits: wire_size <= max_message_size && text_length <= max_text_length,
+        })
+    }
+
+    /// Size of the message on the wire (header + data)
+    pub fn wire_size(&self) -> usize {
+        HEADER_SIZE + self.data.len()
+    }
+
+    /// Fail with `MessageTooLarge` if the frame would exceed `max_message_size` on the wire
+    pub fn check_size(&self, max_message_size: usize) -> Result<()> {
+        let size = self.wire_size();
+        if size > max_message_size {
+            return Err(MessengerError::MessageTooLarge { size, max: max_message_size });
+        }
+        Ok(())
+    }
+
     /// Serialize the entire protocol message to bytes
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
@@ -125,19 +410,67 @@ impl ProtocolMessage {
 
     /// Convert back to application message
     pub fn to_message(&self) -> Result<Message> {
-        let message: Message = serde_json::from_slice(&self.data)
-            .map_err(|e| protocol_error!("Failed to deserialize message: {}", e))?;
-        Ok(message)
+        if self.is_compressed() {
+            Self::decode_body(self.header.message_type, &Self::decompress(&self.data)?)
+        } else {
+            Self::decode_body(self.header.message_type, &self.data)
+        }
     }
 }
 
+/// Split a 4-byte big-endian length prefix and the bytes it covers off the front of `data`,
+/// the same framing `send_raw_bytes` puts on a stream
+fn split_length_prefixed(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    let Some((length, rest)) = data.split_first_chunk::<4>() else {
+        return Err(protocol_error!("Missing length prefix"));
+    };
+    let length = u32::from_be_bytes(*length) as usize;
+    if rest.len() < length {
+        return Err(protocol_error!("Length prefix of {} bytes exceeds the {} available", length, rest.len()));
+    }
+    Ok(rest.split_at(length))
+}
+
+/// Check if a MIME type is an already-compressed format
+fn is_compressed_mime_type(mime_type: &str) -> bool {
+    let mime_type = mime_type.to_lowercase();
+    COMPRESSED_MIME_TYPES.contains(&mime_type.as_str())
+        || mime_type.starts_with("video/")
+        || mime_type.starts_with("audio/")
+}
+
 /// Protocol handler for reading/writing messages
 pub struct ProtocolHandler;
 
 impl ProtocolHandler {
     /// Send a message through a TCP stream
-    pub async fn send_message(stream: &mut TcpStream, message: &Message) -> Result<()> {
+    pub async fn send_message<W: AsyncWrite + Unpin>(stream: &mut W, message: &Message) -> Result<()> {
+        Self::send_message_with_key(stream, message, None, DEFAULT_MAX_MESSAGE_SIZE).await
+    }
+
+    /// Send a message, encrypting it when a shared secret is given and refusing frames
+    /// larger than `max_message_size`
+    pub async fn send_message_with_key<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        message: &Message,
+        secret: Option<&SharedSecret>,
+        max_message_size: usize,
+    ) -> Result<()> {
         let protocol_msg = ProtocolMessage::new(message)?;
+        let protocol_msg = match secret {
+            Some(secret) => protocol_msg.encrypt(secret)?,
+            None => protocol_msg,
+        };
+        Self::send_protocol_message(stream, &protocol_msg, max_message_size).await
+    }
+
+    /// Send an already framed protocol message, refusing frames larger than `max_message_size`
+    pub async fn send_protocol_message<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        protocol_msg: &ProtocolMessage,
+        max_message_size: usize,
+    ) -> Result<()> {
+        protocol_msg.check_size(max_message_size)?;
         let bytes = protocol_msg.to_bytes();
         
         use tokio::io::AsyncWriteExt;
@@ -151,7 +484,31 @@ impl ProtocolHandler {
     }
 
     /// Receive a message from a TCP stream
-    pub async fn receive_message(stream: &mut TcpStream) -> Result<Message> {
+    pub async fn receive_message<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Message> {
+        let (message, _) = Self::receive_message_with_size(stream, DEFAULT_MAX_MESSAGE_SIZE).await?;
+        Ok(message)
+    }
+
+    /// Receive a message of at most `max_message_size` bytes, decrypting it with the shared
+    /// secret if it was encrypted
+    pub async fn receive_message_with_key<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        secret: Option<&SharedSecret>,
+        max_message_size: usize,
+    ) -> Result<Message> {
+        let protocol_msg = Self::receive_protocol_message(stream, max_message_size).await?;
+        protocol_msg.open(secret)
+    }
+
+    /// Receive a message of at most `max_message_size` bytes along with its size on the wire
+    pub async fn receive_message_with_size<R: AsyncRead + Unpin>(stream: &mut R, max_message_size: usize) -> Result<(Message, usize)> {
+        let protocol_msg = Self::receive_protocol_message(stream, max_message_size).await?;
+        Ok((protocol_msg.to_message()?, protocol_msg.wire_size()))
+    }
+
+    /// Receive a raw protocol message from a TCP stream without decoding it.
+    /// The length in the header is checked against `max_message_size` before anything is allocated.
+    pub async fn receive_protocol_message<R: AsyncRead + Unpin>(stream: &mut R, max_message_size: usize) -> Result<ProtocolMessage> {
         use tokio::io::AsyncReadExt;
         
         // First, read the header (8 bytes)
@@ -160,18 +517,35 @@ impl ProtocolHandler {
             .map_err(|e| protocol_error!("Failed to read header: {}", e))?;
 
         let header = MessageHeader::from_bytes(&header_bytes)?;
+        let size = HEADER_SIZE + header.length as usize;
+        if size > max_message_size {
+            return Err(MessengerError::MessageTooLarge { size, max: max_message_size });
+        }
 
         // Then read the message data
         let mut data = vec![0u8; header.length as usize];
         stream.read_exact(&mut data).await
             .map_err(|e| protocol_error!("Failed to read message data: {}", e))?;
 
-        let protocol_msg = ProtocolMessage { header, data };
-        protocol_msg.to_message()
+        Ok(ProtocolMessage { header, data })
+    }
+
+    /// Send a final message and close the write side, waiting at most `linger` for it to go out
+    pub async fn close_gracefully<W: AsyncWrite + Unpin>(stream: &mut W, final_message: &Message, linger: Duration) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let close = async {
+            Self::send_message(stream, final_message).await?;
+            stream.shutdown().await
+                .map_err(|e| protocol_error!("Failed to shut down stream: {}", e))
+        };
+
+        tokio::time::timeout(linger, close).await
+            .map_err(|_| MessengerError::ConnectionTimeout)?
     }
 
     /// Send raw bytes (for encrypted data)
-    pub async fn send_raw_bytes(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    pub async fn send_raw_bytes<W: AsyncWrite + Unpin>(stream: &mut W, data: &[u8]) -> Result<()> {
         use tokio::io::AsyncWriteExt;
         
         // Send length first (4 bytes)
@@ -190,7 +564,7 @@ impl ProtocolHandler {
     }
 
     /// Receive raw bytes (for encrypted data)
-    pub async fn receive_raw_bytes(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    pub async fn receive_raw_bytes<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Vec<u8>> {
         use tokio::io::AsyncReadExt;
         
         // First read the length (4 bytes)
@@ -209,7 +583,7 @@ impl ProtocolHandler {
     }
 
     /// Check if the stream has data available
-    pub async fn has_data_available(_stream: &TcpStream) -> Result<bool> {
+    pub async fn has_data_available(_stream: &tokio::net::TcpStream) -> Result<bool> {
         // For tokio::net::TcpStream, we can't easily check data availability
         // without potentially consuming data. This is a simplified implementation.
         // In a real application, you might want to use a different approach
@@ -224,22 +598,208 @@ pub struct AcknowledgmentHandler;
 impl AcknowledgmentHandler {
     /// Create an acknowledgment message
     pub fn create_acknowledgment(message_id: uuid::Uuid, sender_id: uuid::Uuid) -> Message {
-        Message {
-            id: uuid::Uuid::new_v4(),
-            message_type: crate::types::MessageType::Acknowledgment { message_id },
-            timestamp: chrono::Utc::now(),
-            sender_id,
-            recipient_id: None,
-            status: crate::types::MessageStatus::Sent,
-            encrypted: false,
-            retry_count: 0,
-            metadata: std::collections::HashMap::new(),
-        }
+        Message::new_acknowledgment(message_id, sender_id)
     }
 
-    /// Check if a message requires acknowledgment
+    /// Check if a message requires acknowledgment. Only conversation content is acknowledged;
+    /// signalling such as acks, receipts and typing never is, so acks can't bounce back and forth.
     pub fn requires_acknowledgment(message: &Message) -> bool {
-        !message.is_system() && message.status != crate::types::MessageStatus::Acknowledged
+        use crate::types::MessageType;
+
+        matches!(
+            message.message_type,
+            MessageType::Text { .. } | MessageType::File { .. } | MessageType::Reaction { .. } | MessageType::Edit { .. }
+        ) && message.status != crate::types::MessageStatus::Acknowledged
+    }
+}
+
+/// Tracks sent messages awaiting acknowledgment on one connection
+#[derive(Debug)]
+pub struct PendingAcknowledgments {
+    pending: VecDeque<uuid::Uuid>,
+    max_pending: usize,
+    policy: PendingAckPolicy,
+}
+
+impl PendingAcknowledgments {
+    pub fn new(config: &AcknowledgmentConfig) -> Self {
+        Self {
+            pending: VecDeque::new(),
+            max_pending: config.max_pending,
+            policy: config.pending_policy,
+        }
+    }
+
+    /// Start tracking a sent message. When full, either refuses the message
+    /// (backpressure) or drops the oldest pending message and returns its id
+    /// so the caller can mark it as failed. Tracking a message again, e.g. on a resend, is a no-op.
+    pub fn track(&mut self, message_id: uuid::Uuid) -> Result<Option<uuid::Uuid>> {
+        let mut dropped = None;
+        if self.is_pending(&message_id) {
+            return Ok(None);
+        }
+
+        if self.pending.len() >= self.max_pending {
+            match self.policy {
+                PendingAckPolicy::Backpressure => {
+                    return Err(MessengerError::TooManyPendingAcknowledgments { max: self.max_pending });
+                },
+                PendingAckPolicy::DropOldest => {
+                    dropped = self.pending.pop_front();
+                },
+            }
+        }
+
+        self.pending.push_back(message_id);
+        Ok(dropped)
+    }
+
+    /// Stop tracking an acknowledged message. Returns false if it was not pending.
+    pub fn acknowledge(&mut self, message_id: &uuid::Uuid) -> bool {
+        match self.pending.iter().position(|id| id == message_id) {
+            Some(index) => {
+                self.pending.remove(index);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Check if a message is awaiting acknowledgment
+    pub fn is_pending(&self, message_id: &uuid::Uuid) -> bool {
+        self.pending.contains(message_id)
+    }
+
+    /// Number of messages awaiting acknowledgment
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// Remembers recently received message ids so replays after a reconnect are dropped
+#[derive(Debug)]
+pub struct RecentMessages {
+    seen: HashSet<uuid::Uuid>,
+    order: VecDeque<(Instant, uuid::Uuid)>,
+    window: Duration,
+}
+
+impl RecentMessages {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            window,
+        }
+    }
+
+    /// Record a message id. Returns false if it was already seen within the window.
+    pub fn insert(&mut self, message_id: uuid::Uuid) -> bool {
+        self.expire(Instant::now());
+
+        if !self.seen.insert(message_id) {
+            return false;
+        }
+        self.order.push_back((Instant::now(), message_id));
+        true
+    }
+
+    /// Number of ids currently remembered
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((seen_at, message_id)) = self.order.front().copied() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            self.order.pop_front();
+            self.seen.remove(&message_id);
+        }
+    }
+}
+
+/// Per-peer state maintained from received control messages
+#[derive(Debug, Default)]
+pub struct ControlState {
+    capabilities: HashMap<uuid::Uuid, Vec<String>>,
+    paused: HashSet<uuid::Uuid>,
+}
+
+impl ControlState {
+    /// Apply a control message received from a peer
+    pub fn apply(&mut self, peer_id: uuid::Uuid, control: ControlMessage) {
+        match control {
+            ControlMessage::Capabilities { features } => {
+                self.capabilities.insert(peer_id, features);
+            },
+            ControlMessage::FlowControl { paused: true } => {
+                self.paused.insert(peer_id);
+            },
+            ControlMessage::FlowControl { paused: false } => {
+                self.paused.remove(&peer_id);
+            },
+        }
+    }
+
+    /// Features a peer last announced
+    pub fn capabilities(&self, peer_id: &uuid::Uuid) -> Option<&[String]> {
+        self.capabilities.get(peer_id).map(Vec::as_slice)
+    }
+
+    /// Check if a peer asked us to stop sending data
+    pub fn is_paused(&self, peer_id: &uuid::Uuid) -> bool {
+        self.paused.contains(peer_id)
+    }
+
+    /// Forget everything about a disconnected peer
+    pub fn remove_peer(&mut self, peer_id: &uuid::Uuid) {
+        self.capabilities.remove(peer_id);
+        self.paused.remove(peer_id);
+    }
+}
+
+/// Hands control messages to a dedicated task so they never wait behind data, or data behind them
+#[derive(Debug, Clone)]
+pub struct ControlChannel {
+    sender: mpsc::Sender<(uuid::Uuid, ControlMessage)>,
+    state: Arc<RwLock<ControlState>>,
+}
+
+impl ControlChannel {
+    /// Start the control handler task applying messages to `state`
+    pub fn spawn(state: Arc<RwLock<ControlState>>) -> (Self, JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::channel::<(uuid::Uuid, ControlMessage)>(CONTROL_QUEUE_SIZE);
+        let handler_state = state.clone();
+        let handler = tokio::spawn(async move {
+            while let Some((peer_id, control)) = receiver.recv().await {
+                debug!("Applying control message from peer {}: {:?}", peer_id, control);
+                handler_state.write().await.apply(peer_id, control);
+            }
+        });
+
+        (Self { sender, state }, handler)
+    }
+
+    /// Queue a control message without waiting; dropped with a warning if the handler is backed up
+    pub fn dispatch(&self, peer_id: uuid::Uuid, control: ControlMessage) {
+        if let Err(e) = self.sender.try_send((peer_id, control)) {
+            warn!("Dropping control message from peer {}: {}", peer_id, e);
+        }
+    }
+
+    /// Forget a disconnected peer's control state
+    pub async fn remove_peer(&self, peer_id: &uuid::Uuid) {
+        self.state.write().await.remove_peer(peer_id);
     }
 }
 
@@ -252,12 +812,22 @@ pub struct HeartbeatHandler {
 
 impl HeartbeatHandler {
     pub fn new(interval_seconds: u64) -> Self {
+        Self::with_interval(std::time::Duration::from_secs(interval_seconds))
+    }
+
+    /// Create a handler with a sub-second interval
+    pub fn with_interval(interval: std::time::Duration) -> Self {
         Self {
             last_heartbeat: std::time::Instant::now(),
-            interval: std::time::Duration::from_secs(interval_seconds),
+            interval,
         }
     }
 
+    /// How often heartbeats are due
+    pub fn interval(&self) -> std::time::Duration {
+        self.interval
+    }
+
     /// Check if it's time to send a heartbeat
     pub fn should_send_heartbeat(&self) -> bool {
         self.last_heartbeat.elapsed() >= self.interval
@@ -277,30 +847,394 @@ impl HeartbeatHandler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::MessageType;
 
     #[test]
     fn test_message_header_serialization() {
-        let flags = MessageFlags::new();
-        let header = MessageHeader::new(MessageType::Text, 100, flags);
+        let flags = MessageFlags::ENCRYPTED | MessageFlags::CHUNKED;
+        let header = MessageHeader::new(0x01, 100, flags);
         let bytes = header.to_bytes();
         let deserialized = MessageHeader::from_bytes(&bytes).unwrap();
         
         assert_eq!(header.version, deserialized.version);
         assert_eq!(header.message_type, deserialized.message_type);
+        assert_eq!(header.flags, deserialized.flags);
         assert_eq!(header.length, deserialized.length);
     }
 
+    #[tokio::test]
+    async fn test_corrupted_header_is_detected() {
+        let header = MessageHeader::new(0x01, 100, MessageFlags::ENCRYPTED);
+        let bytes = header.to_bytes();
+
+        // Every single-bit error in the header is caught, including in the checksum byte
+        for index in 0..HEADER_SIZE {
+            for bit in 0..8 {
+                let mut corrupted = bytes;
+                corrupted[index] ^= 1 << bit;
+                assert!(MessageHeader::from_bytes(&corrupted).is_err(), "flip of bit {} in byte {}", bit, index);
+            }
+        }
+
+        // A length blown up in transit fails before the body buffer is allocated
+        let mut corrupted = bytes;
+        corrupted[4] ^= 0x40;
+        let mut stream = &corrupted[..];
+        let result = ProtocolHandler::receive_protocol_message(&mut stream, usize::MAX).await;
+        assert!(matches!(result, Err(MessengerError::Protocol(ref message)) if message.contains("checksum")), "{:?}", result);
+    }
+
+    #[test]
+    fn test_reply_to_survives_protocol_roundtrip() {
+        let original = Message::new_text("Original".to_string(), uuid::Uuid::new_v4());
+        let reply = Message::new_text_reply("Agreed".to_string(), uuid::Uuid::new_v4(), original.id);
+        assert_eq!(reply.reply_to, Some(original.id));
+        assert!(matches!(&reply.message_type, crate::types::MessageType::Text { content } if content == "Agreed"));
+
+        let decoded = ProtocolMessage::new(&reply).unwrap().to_message().unwrap();
+        assert_eq!(decoded.reply_to, Some(original.id));
+
+        // Messages from peers that predate replies still decode
+        let mut legacy = serde_json::to_value(&original).unwrap();
+        legacy.as_object_mut().unwrap().remove("reply_to");
+        let legacy: Message = serde_json::from_value(legacy).unwrap();
+        assert_eq!(legacy.reply_to, None);
+    }
+
+    #[test]
+    fn test_reaction_protocol_roundtrip() {
+        let target_id = uuid::Uuid::new_v4();
+        let reaction = Message::new_reaction(target_id, "👍".to_string(), uuid::Uuid::new_v4());
+
+        let protocol_message = ProtocolMessage::new(&reaction).unwrap();
+        assert_eq!(protocol_message.header.message_type, 0x07);
+
+        let decoded = protocol_message.to_message().unwrap();
+        assert_eq!(decoded, reaction);
+        assert!(matches!(
+            decoded.message_type,
+            crate::types::MessageType::Reaction { target_id: id, ref emoji } if id == target_id && emoji == "👍"
+        ));
+    }
+
+    #[test]
+    fn test_typing_protocol_roundtrip() {
+        let typing = Message::new_typing(true, uuid::Uuid::new_v4());
+
+        let protocol_message = ProtocolMessage::new(&typing).unwrap();
+        assert_eq!(protocol_message.header.message_type, 0x0C);
+        assert_eq!(protocol_message.to_message().unwrap(), typing);
+    }
+
+    #[test]
+    fn test_preview_oversized_message() {
+        // Random content so compression cannot bring it under the limit
+        let content: String = (0..128).map(|_| uuid::Uuid::new_v4().simple().to_string()).collect();
+        let message = Message::new_text(content.clone(), uuid::Uuid::new_v4());
+
+        let preview = ProtocolMessage::preview(&message, 1024, usize::MAX, true).unwrap();
+        assert_eq!(preview.content, content);
+        let wire_size = ProtocolMessage::new(&message).unwrap().wire_size();
+        assert_eq!(preview.wire_size, wire_size + ENCRYPTION_OVERHEAD + SEQUENCE_SIZE);
+        assert!(!preview.fits);
+
+        let preview = ProtocolMessage::preview(&message, 1024 * 1024, usize::MAX, true).unwrap();
+        assert!(preview.fits);
+    }
+
+    #[test]
+    fn test_preview_over_text_length() {
+        let message = Message::new_text("héllö!".to_string(), uuid::Uuid::new_v4());
+
+        // Small on the wire, but one character over the limit
+        let preview = ProtocolMessage::preview(&message, 1024 * 1024, 5, false).unwrap();
+        assert_eq!(preview.text_length, 6);
+        assert!(preview.wire_size <= preview.max_message_size);
+        assert!(!preview.fits);
+
+        let preview = ProtocolMessage::preview(&message, 1024 * 1024, 6, false).unwrap();
+        assert!(preview.fits);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_round_trip() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+
+        let (secret, peer_secret) = SharedSecret::test_pair(7);
+        let message = Message::new_text("attack at dawn".to_string(), uuid::Uuid::new_v4());
+
+        // The bytes on the socket never contain the plaintext
+        ProtocolHandler::send_message_with_key(&mut sender, &message, Some(&secret), DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        let frame = ProtocolHandler::receive_protocol_message(&mut receiver, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        assert!(frame.is_encrypted());
+        assert!(!frame.data.windows(b"attack at dawn".len()).any(|w| w == b"attack at dawn"));
+        assert!(frame.open(None).is_err());
+
+        assert!(frame.open(Some(&secret)).is_err());
+        let decoded = frame.open(Some(&peer_secret)).unwrap();
+        assert!(decoded.encrypted);
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.message_type, message.message_type);
+
+        // Same through the receive helper
+        ProtocolHandler::send_message_with_key(&mut sender, &message, Some(&secret), DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        let decoded = ProtocolHandler::receive_message_with_key(&mut receiver, Some(&peer_secret), DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        assert_eq!(decoded.message_type, message.message_type);
+    }
+
+    #[tokio::test]
+    async fn test_tampered_header_is_rejected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+
+        let (sending, receiving) = SharedSecret::test_pair(5);
+        let message = Message::new_text("attack at dawn".to_string(), uuid::Uuid::new_v4());
+
+        // Set the compression flag and recompute the checksum, which anyone can do; the body and
+        // its MAC are untouched
+        let frame = ProtocolMessage::new(&message).unwrap().encrypt(&sending).unwrap();
+        let mut forged = frame.clone();
+        forged.header.flags |= MessageFlags::COMPRESSED;
+        tokio::io::AsyncWriteExt::write_all(&mut sender, &forged.to_bytes()).await.unwrap();
+
+        let tampered = ProtocolHandler::receive_protocol_message(&mut receiver, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        assert!(tampered.is_compressed());
+        assert!(tampered.open(Some(&receiving)).is_err());
+
+        // The same frame with its header intact still opens
+        let intact = ProtocolMessage::from_bytes(&frame.to_bytes()).unwrap();
+        assert_eq!(intact.open(Some(&receiving)).unwrap().id, message.id);
+    }
+
+    #[tokio::test]
+    async fn test_replayed_frame_is_rejected() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sender = tokio::net::TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut receiver, _) = listener.accept().await.unwrap();
+
+        // Each side holds its own session state for the same keys
+        let (sending, receiving) = SharedSecret::test_pair(3);
+        let receiving = receiving.with_replay_window(16);
+
+        let message = Message::new_text("transfer funds".to_string(), uuid::Uuid::new_v4());
+        ProtocolHandler::send_message_with_key(&mut sender, &message, Some(&sending), DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+        let captured = ProtocolHandler::receive_protocol_message(&mut receiver, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+
+        assert_eq!(captured.open(Some(&receiving)).unwrap().id, message.id);
+        assert!(captured.open(Some(&receiving)).is_err());
+
+        // Later frames in the session are still accepted
+        let next = ProtocolMessage::new(&message).unwrap().encrypt(&sending).unwrap();
+        assert!(next.open(Some(&receiving)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_outbound_message_is_rejected() {
+        let content: String = (0..128).map(|_| uuid::Uuid::new_v4().simple().to_string()).collect();
+        let message = Message::new_text(content, uuid::Uuid::new_v4());
+        let protocol_msg = ProtocolMessage::new(&message).unwrap();
+
+        let mut sink = Vec::new();
+        match ProtocolHandler::send_protocol_message(&mut sink, &protocol_msg, 1024).await {
+            Err(MessengerError::MessageTooLarge { size, max }) => {
+                assert_eq!(size, protocol_msg.wire_size());
+                assert_eq!(max, 1024);
+            },
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+        // Nothing was written
+        assert!(sink.is_empty());
+
+        ProtocolHandler::send_protocol_message(&mut sink, &protocol_msg, protocol_msg.wire_size()).await.unwrap();
+        assert_eq!(sink.len(), protocol_msg.wire_size());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_header_is_refused() {
+        // A header claiming a 4GB body, followed by nothing
+        let header = MessageHeader::new(0x01, u32::MAX, MessageFlags::NONE);
+        let mut stream: &[u8] = &header.to_bytes();
+
+        match ProtocolHandler::receive_protocol_message(&mut stream, DEFAULT_MAX_MESSAGE_SIZE).await {
+            Err(MessengerError::MessageTooLarge { size, max }) => {
+                assert_eq!(size, HEADER_SIZE + u32::MAX as usize);
+                assert_eq!(max, DEFAULT_MAX_MESSAGE_SIZE);
+            },
+            other => panic!("expected MessageTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_control_frames_use_reserved_type_range() {
+        let control = Message::new_control(ControlMessage::FlowControl { paused: true }, uuid::Uuid::new_v4());
+        let control_frame = ProtocolMessage::new(&control).unwrap();
+        assert!(control_frame.header.is_control());
+        assert_eq!(control_frame.open(None).unwrap().message_type, control.message_type);
+
+        let data = Message::new_text("hello".to_string(), uuid::Uuid::new_v4());
+        let data_frame = ProtocolMessage::new(&data).unwrap();
+        assert!(!data_frame.header.is_control());
+
+        // A body that disagrees with its header's type range is rejected
+        let mut smuggled = data_frame.clone();
+        smuggled.header.message_type = *CONTROL_MESSAGE_TYPES.start();
+        assert!(smuggled.open(None).is_err());
+        let mut disguised = control_frame;
+        disguised.header.message_type = 0x01;
+        assert!(disguised.open(None).is_err());
+    }
+
+    #[test]
+    fn test_compression_depends_on_content_type() {
+        let sender_id = uuid::Uuid::new_v4();
+
+        let jpeg = Message::new_file(
+            "photo.jpg".to_string(),
+            4096,
+            "image/jpeg".to_string(),
+            Some(vec![0xAB; 4096]),
+            sender_id,
+        );
+        let protocol_msg = ProtocolMessage::new(&jpeg).unwrap();
+        assert!(!protocol_msg.is_compressed());
+        assert_eq!(protocol_msg.to_message().unwrap(), jpeg);
+
+        let text = Message::new_text("hello ".repeat(1000), sender_id);
+        let protocol_msg = ProtocolMessage::new(&text).unwrap();
+        assert!(protocol_msg.is_compressed());
+        assert!(protocol_msg.data.len() < serde_json::to_vec(&text).unwrap().len());
+        assert_eq!(protocol_msg.to_message().unwrap(), text);
+
+        // Short text stays below the threshold
+        let short = Message::new_text("hello".to_string(), sender_id);
+        assert!(!ProtocolMessage::new(&short).unwrap().is_compressed());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_message_roundtrip_over_stream() {
+        let message = Message::new_text("all work and no play ".repeat(5000), uuid::Uuid::new_v4());
+        let plaintext_len = serde_json::to_vec(&message).unwrap().len();
+        let (mut writer, mut reader) = tokio::io::duplex(plaintext_len * 2);
+
+        ProtocolHandler::send_message(&mut writer, &message).await.unwrap();
+        let (received, wire_size) = ProtocolHandler::receive_message_with_size(&mut reader, DEFAULT_MAX_MESSAGE_SIZE).await.unwrap();
+
+        assert!(wire_size < plaintext_len / 10, "{} bytes on the wire for {} of JSON", wire_size, plaintext_len);
+        assert_eq!(received, message);
+    }
+
+    #[test]
+    fn test_file_data_is_framed_as_binary() {
+        let contents: Vec<u8> = (0..1024 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+        let file = Message::new_file(
+            "archive.zip".to_string(),
+            contents.len() as u64,
+            "application/zip".to_string(),
+            Some(contents.clone()),
+            uuid::Uuid::new_v4(),
+        );
+
+        let protocol_msg = ProtocolMessage::new(&file).unwrap();
+        assert_eq!(protocol_msg.header.message_type, FILE_MESSAGE_TYPE);
+        let json_size = HEADER_SIZE + serde_json::to_vec(&file).unwrap().len();
+        assert!(protocol_msg.wire_size() < contents.len() + 1024);
+        assert!(protocol_msg.wire_size() * 3 < json_size, "binary {} vs JSON {}", protocol_msg.wire_size(), json_size);
+        assert_eq!(protocol_msg.to_message().unwrap(), file);
+
+        // File messages without contents and empty files both survive the trip
+        let announcement = Message::new_file("later.zip".to_string(), 10, "application/zip".to_string(), None, uuid::Uuid::new_v4());
+        assert_eq!(ProtocolMessage::new(&announcement).unwrap().to_message().unwrap(), announcement);
+        let empty = Message::new_file("empty.zip".to_string(), 0, "application/zip".to_string(), Some(Vec::new()), uuid::Uuid::new_v4());
+        assert_eq!(ProtocolMessage::new(&empty).unwrap().to_message().unwrap(), empty);
+
+        // Truncated file data is rejected rather than misread
+        let mut truncated = protocol_msg.clone();
+        truncated.data.truncate(truncated.data.len() - 1);
+        assert!(truncated.to_message().is_err());
+    }
+
+    #[test]
+    fn test_recent_messages_expire() {
+        let mut recent = RecentMessages::new(Duration::from_millis(50));
+        let message_id = uuid::Uuid::new_v4();
+
+        assert!(recent.insert(message_id));
+        assert!(!recent.insert(message_id));
+        assert_eq!(recent.len(), 1);
+
+        // Once the window passes the id is forgotten
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(recent.insert(uuid::Uuid::new_v4()));
+        assert_eq!(recent.len(), 1);
+        assert!(recent.insert(message_id));
+    }
+
+    #[test]
+    fn test_pending_ack_backpressure() {
+        let config = AcknowledgmentConfig {
+            max_pending: 2,
+            pending_policy: PendingAckPolicy::Backpressure,
+        };
+        let mut pending = PendingAcknowledgments::new(&config);
+
+        let first = uuid::Uuid::new_v4();
+        assert_eq!(pending.track(first).unwrap(), None);
+        assert_eq!(pending.track(uuid::Uuid::new_v4()).unwrap(), None);
+
+        assert!(matches!(
+            pending.track(uuid::Uuid::new_v4()),
+            Err(MessengerError::TooManyPendingAcknowledgments { max: 2 })
+        ));
+        assert_eq!(pending.len(), 2);
+
+        // An acknowledgment makes room again
+        assert!(pending.acknowledge(&first));
+        assert!(pending.track(uuid::Uuid::new_v4()).is_ok());
+    }
+
+    #[test]
+    fn test_pending_ack_drop_oldest() {
+        let config = AcknowledgmentConfig {
+            max_pending: 2,
+            pending_policy: PendingAckPolicy::DropOldest,
+        };
+        let mut pending = PendingAcknowledgments::new(&config);
+
+        let first = uuid::Uuid::new_v4();
+        let second = uuid::Uuid::new_v4();
+        let third = uuid::Uuid::new_v4();
+        pending.track(first).unwrap();
+        pending.track(second).unwrap();
+
+        assert_eq!(pending.track(third).unwrap(), Some(first));
+        assert_eq!(pending.len(), 2);
+        assert!(!pending.is_pending(&first));
+        assert!(pending.is_pending(&second));
+        assert!(pending.is_pending(&third));
+
+        // A resend is already tracked and does not push anything out
+        assert_eq!(pending.track(second).unwrap(), None);
+        assert_eq!(pending.len(), 2);
+    }
+
     #[test]
     fn test_message_flags() {
         let mut flags = MessageFlags::new();
-        flags.encrypted = true;
-        flags.acknowledgment_required = true;
+        flags.set(MessageFlags::ENCRYPTED, true);
+        flags.set(MessageFlags::ACKNOWLEDGMENT, true);
         
         let byte = flags.to_byte();
+        assert_eq!(byte, 0x09);
         let deserialized = MessageFlags::from_byte(byte);
         
-        assert_eq!(flags.encrypted, deserialized.encrypted);
-        assert_eq!(flags.acknowledgment_required, deserialized.acknowledgment_required);
+        assert!(deserialized.encrypted());
+        assert!(deserialized.acknowledgment());
+        assert!(!deserialized.compressed());
+        assert!(!deserialized.chunked());
+
+        flags.set(MessageFlags::ENCRYPTED, false);
+        assert_eq!(flags, MessageFlags::ACKNOWLEDGMENT);
     }
 }
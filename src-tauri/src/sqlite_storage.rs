@@ -0,0 +1,323 @@
+use crate::error::{MessengerError, Result};
+use crate::storage::{prune_backups, MessageIndex, MessageStorage, StorageBackend, StorageConfig, StorageStats, BACKUP_PREFIX};
+use crate::types::{ExportOptions, Message, MessageFilter, MessageSearch, MessageType};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use tracing::info;
+use uuid::Uuid;
+
+/// Database file inside the messages directory
+const DATABASE_FILE: &str = "messages.db";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        timestamp TEXT NOT NULL,
+        sender_id TEXT NOT NULL,
+        message_type TEXT NOT NULL,
+        status TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_by_timestamp ON messages (timestamp);
+    CREATE INDEX IF NOT EXISTS messages_by_sender ON messages (sender_id, timestamp);
+    CREATE INDEX IF NOT EXISTS messages_by_type ON messages (message_type, timestamp);
+";
+
+/// Message store kept in a SQLite database. Each message is a row, so storing or
+/// deleting one does not rewrite the rest of the history.
+#[derive(Debug)]
+pub struct SqliteStorage {
+    storage_path: PathBuf,
+    connection: Option<Mutex<Connection>>,
+    max_messages: usize,
+    retention_days: u32,
+    max_backup_files: usize,
+}
+
+impl SqliteStorage {
+    /// Create SQLite storage with custom configuration
+    pub fn with_config(config: &StorageConfig) -> Self {
+        let mut storage_path = crate::config::resolve_data_directory(Some(&config.data_directory));
+        storage_path.push("messages");
+
+        Self {
+            storage_path,
+            connection: None,
+            max_messages: config.max_messages,
+            retention_days: config.message_retention_days,
+            max_backup_files: config.max_backup_files as usize,
+        }
+    }
+
+    /// Delete unpinned messages older than the retention period. Returns the number removed.
+    pub fn cleanup_expired_messages(&mut self) -> Result<usize> {
+        let cutoff_date = Utc::now() - chrono::Duration::days(self.retention_days as i64);
+        let removed = self.connection()?
+            .execute(
+                "DELETE FROM messages WHERE timestamp < ?1 AND NOT coalesce(json_extract(body, '$.pinned'), 0)",
+                params![timestamp_key(&cutoff_date)],
+            )
+            .map_err(|e| MessengerError::Storage(format!("Failed to delete expired messages: {}", e)))?;
+
+        if removed > 0 {
+            info!("Cleaned up {} messages older than {} days", removed, self.retention_days);
+        }
+        Ok(removed)
+    }
+
+    fn connection(&self) -> Result<MutexGuard<'_, Connection>> {
+        let connection = self.connection.as_ref()
+            .ok_or_else(|| MessengerError::Storage("SQLite storage is not initialized".to_string()))?;
+        connection.lock()
+            .map_err(|_| MessengerError::Storage("SQLite connection lock is poisoned".to_string()))
+    }
+
+    fn count(&self) -> Result<usize> {
+        self.connection()?
+            .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get::<_, i64>(0))
+            .map(|count| count as usize)
+            .map_err(|e| MessengerError::Storage(format!("Failed to count messages: {}", e)))
+    }
+
+    /// Run a query selecting message bodies and decode them
+    fn query_messages(&self, sql: &str, values: &[String]) -> Result<Vec<Message>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)
+            .map_err(|e| MessengerError::Storage(format!("Failed to prepare query: {}", e)))?;
+        let bodies = statement.query_map(params_from_iter(values), |row| row.get::<_, String>(0))
+            .map_err(|e| MessengerError::Storage(format!("Failed to query messages: {}", e)))?;
+
+        bodies
+            .map(|body| {
+                let body = body.map_err(|e| MessengerError::Storage(format!("Failed to read message row: {}", e)))?;
+                serde_json::from_str(&body)
+                    .map_err(|e| MessengerError::Storage(format!("Failed to parse message: {}", e)))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SqliteStorage {
+    async fn initialize(&mut self) -> Result<()> {
+        std::fs::create_dir_all(&self.storage_path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create storage directory: {}", e)))?;
+
+        let connection = Connection::open(self.storage_path.join(DATABASE_FILE))
+            .map_err(|e| MessengerError::Storage(format!("Failed to open database: {}", e)))?;
+        connection.execute_batch(SCHEMA)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create database schema: {}", e)))?;
+        self.connection = Some(Mutex::new(connection));
+
+        self.cleanup_expired_messages()?;
+        info!("SQLite storage initialized with {} messages", self.count()?);
+        Ok(())
+    }
+
+    async fn store_message(&mut self, message: Message) -> Result<()> {
+        if message.is_typing() {
+            return Ok(());
+        }
+        if self.count()? >= self.max_messages {
+            self.cleanup_expired_messages()?;
+        }
+
+        let body = serde_json::to_string(&message)
+            .map_err(|e| MessengerError::Storage(format!("Failed to serialize message: {}", e)))?;
+        self.connection()?
+            .execute(
+                "INSERT OR REPLACE INTO messages (id, timestamp, sender_id, message_type, status, body)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    message.id.to_string(),
+                    timestamp_key(&message.timestamp),
+                    message.sender_id.to_string(),
+                    MessageIndex::type_key(&message.message_type),
+                    message.status.to_string(),
+                    body,
+                ],
+            )
+            .map_err(|e| MessengerError::Storage(format!("Failed to store message: {}", e)))?;
+        Ok(())
+    }
+
+    fn get_message(&self, message_id: &Uuid) -> Result<Option<Message>> {
+        let body: Option<String> = self.connection()?
+            .query_row("SELECT body FROM messages WHERE id = ?1", params![message_id.to_string()], |row| row.get(0))
+            .optional()
+            .map_err(|e| MessengerError::Storage(format!("Failed to query message: {}", e)))?;
+
+        body.map(|body| serde_json::from_str(&body)
+                .map_err(|e| MessengerError::Storage(format!("Failed to parse message: {}", e))))
+            .transpose()
+    }
+
+    fn get_messages_with_filter(&self, filter: &MessageFilter) -> Result<Vec<Message>> {
+        fn any_of(column: &str, count: usize) -> String {
+            format!("{} IN ({})", column, vec!["?"; count].join(", "))
+        }
+
+        let mut conditions = vec!["1 = 1".to_string()];
+        let mut values = Vec::new();
+
+        if let Some(message_types) = &filter.message_types {
+            // Only content-bearing types can be filtered on, as with the JSON store
+            let type_keys: Vec<String> = message_types.iter()
+                .filter(|t| matches!(t, MessageType::Text { .. } | MessageType::File { .. } | MessageType::System { .. }))
+                .map(MessageIndex::type_key)
+                .collect();
+            conditions.push(any_of("message_type", type_keys.len()));
+            values.extend(type_keys);
+        }
+
+        if let Some(sender_ids) = &filter.sender_ids {
+            conditions.push(any_of("sender_id", sender_ids.len()));
+            values.extend(sender_ids.iter().map(|id| id.to_string()));
+        }
+
+        if let Some(start_date) = &filter.start_date {
+            conditions.push("timestamp >= ?".to_string());
+            values.push(timestamp_key(start_date));
+        }
+
+        if let Some(end_date) = &filter.end_date {
+            conditions.push("timestamp <= ?".to_string());
+            values.push(timestamp_key(end_date));
+        }
+
+        if let Some(status) = &filter.status {
+            conditions.push(any_of("status", status.len()));
+            values.extend(status.iter().map(|status| status.to_string()));
+        }
+
+        if let Some(reply_to) = &filter.reply_to {
+            conditions.push("json_extract(body, '$.reply_to') = ?".to_string());
+            values.push(reply_to.to_string());
+        }
+
+        let sql = format!(
+            "SELECT body FROM messages WHERE {} ORDER BY timestamp DESC LIMIT {} OFFSET {}",
+            conditions.join(" AND "),
+            filter.limit.map_or(-1, |limit| limit as i64),
+            filter.offset.unwrap_or(0),
+        );
+        self.query_messages(&sql, &values)
+    }
+
+    fn search_messages(&self, search: &MessageSearch) -> Result<Vec<Message>> {
+        let candidates = match &search.filter {
+            Some(filter) => self.get_messages_with_filter(filter)?,
+            None => self.query_messages("SELECT body FROM messages", &[])?,
+        };
+
+        let mut results: Vec<Message> = candidates.into_iter()
+            .filter(|message| MessageStorage::matches_search(message, search))
+            .collect();
+        results.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(a.id.cmp(&b.id)));
+        Ok(results)
+    }
+
+    async fn delete_message(&mut self, message_id: &Uuid) -> Result<()> {
+        self.connection()?
+            .execute("DELETE FROM messages WHERE id = ?1", params![message_id.to_string()])
+            .map_err(|e| MessengerError::Storage(format!("Failed to delete message: {}", e)))?;
+        Ok(())
+    }
+
+    async fn clear_all_messages(&mut self) -> Result<()> {
+        self.connection()?
+            .execute("DELETE FROM messages", [])
+            .map_err(|e| MessengerError::Storage(format!("Failed to clear messages: {}", e)))?;
+        info!("All messages cleared");
+        Ok(())
+    }
+
+    fn get_stats(&self) -> Result<StorageStats> {
+        let (total, oldest, newest): (i64, Option<String>, Option<String>) = self.connection()?
+            .query_row("SELECT COUNT(*), MIN(timestamp), MAX(timestamp) FROM messages", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .map_err(|e| MessengerError::Storage(format!("Failed to query storage statistics: {}", e)))?;
+
+        let parse = |timestamp: Option<String>| timestamp
+            .and_then(|timestamp| DateTime::parse_from_rfc3339(&timestamp).ok())
+            .map(|timestamp| timestamp.with_timezone(&Utc));
+        let storage_size_bytes = std::fs::metadata(self.storage_path.join(DATABASE_FILE))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+
+        Ok(StorageStats {
+            total_messages: total as usize,
+            storage_size_bytes,
+            oldest_message: parse(oldest),
+            newest_message: parse(newest),
+        })
+    }
+
+    async fn cleanup_expired_messages(&mut self) -> Result<usize> {
+        SqliteStorage::cleanup_expired_messages(self)
+    }
+
+    async fn export_messages(&self, options: &ExportOptions) -> Result<PathBuf> {
+        let messages = self.select_for_export(options)?;
+        MessageStorage::export_to_file(&self.storage_path, &messages.iter().collect::<Vec<_>>(), options)
+    }
+
+    async fn backup(&self) -> Result<PathBuf> {
+        let backup_dir = self.storage_path.parent().unwrap().join("backups");
+        std::fs::create_dir_all(&backup_dir)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create backup directory: {}", e)))?;
+
+        // SQLite writes a consistent copy of the live database, so no temporary file is needed
+        let backup_file = backup_dir.join(format!("{}{}.db", BACKUP_PREFIX, Utc::now().format("%Y%m%dT%H%M%S%.9fZ")));
+        self.connection()?
+            .execute("VACUUM INTO ?1", params![backup_file.to_string_lossy()])
+            .map_err(|e| MessengerError::Storage(format!("Failed to write backup: {}", e)))?;
+        prune_backups(&backup_dir, self.max_backup_files)?;
+
+        info!("Backed up database to {:?}", backup_file);
+        Ok(backup_file)
+    }
+}
+
+/// Fixed-width UTC timestamp, so text order in the database matches time order
+fn timestamp_key(timestamp: &DateTime<Utc>) -> String {
+    timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages_persist_across_reopen() {
+        let config = StorageConfig::temp(|config| config.message_retention_days = 1);
+        let dir = config.data_directory.clone();
+
+        let mut storage = SqliteStorage::with_config(&config);
+        storage.initialize().await.unwrap();
+        let kept = Message::new_text("Still here".to_string(), Uuid::new_v4());
+        let mut expired = Message::new_text("Too old".to_string(), Uuid::new_v4());
+        expired.timestamp = Utc::now() - chrono::Duration::days(2);
+        let mut pinned = expired.clone();
+        pinned.id = Uuid::new_v4();
+        pinned.pinned = true;
+        storage.store_message(kept.clone()).await.unwrap();
+        storage.store_message(expired.clone()).await.unwrap();
+        storage.store_message(pinned.clone()).await.unwrap();
+        drop(storage);
+
+        // Reopening keeps stored messages and drops unpinned ones past the retention period
+        let mut reopened = SqliteStorage::with_config(&config);
+        reopened.initialize().await.unwrap();
+        assert_eq!(reopened.get_message(&kept.id).unwrap(), Some(kept));
+        assert_eq!(reopened.get_message(&expired.id).unwrap(), None);
+        assert_eq!(reopened.get_message(&pinned.id).unwrap(), Some(pinned));
+        assert!(reopened.get_stats().unwrap().storage_size_bytes > 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
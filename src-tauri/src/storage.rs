@@ -1,13 +1,51 @@
 use crate::error::{MessengerError, Result};
-use crate::types::{Message, MessageFilter, MessageSearch, ExportFormat, ExportOptions};
+use crate::types::{Message, MessageRevision, MessageStatus, MessageType, MessageFilter, MessageSearch, ExportFormat, ExportOptions, ImportConflictPolicy, ImportReport, TimestampFormat, HistogramBucket};
+use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
-use tracing::{info, debug};
+use rayon::prelude::*;
+use tracing::{info, debug, warn};
+
+/// Store size at which searches are spread across threads
+pub const PARALLEL_SEARCH_THRESHOLD: usize = 5000;
+
+/// Largest number of buckets a histogram may be split into
+pub const MAX_HISTOGRAM_BUCKETS: usize = 10_000;
+
+/// Messages file written when compression is off
+const MESSAGES_FILE: &str = "messages.json";
+
+/// Gzip-compressed messages file written when compression is on
+const COMPRESSED_MESSAGES_FILE: &str = "messages.json.gz";
+
+/// Name prefix shared by all backup files
+pub(crate) const BACKUP_PREFIX: &str = "messages-";
+
+/// Changes made since the messages file was last written, one JSON entry per line
+const MESSAGES_LOG: &str = "messages.log";
+
+/// Log size past which the messages file is rewritten and the log cleared
+const LOG_COMPACTION_BYTES: u64 = 4 * 1024 * 1024;
+
+/// One change recorded in the messages log
+#[derive(Debug, Serialize, Deserialize)]
+enum LogEntry<M> {
+    Store(M),
+    Delete(Uuid),
+}
+
+/// Result of rewriting the messages file
+struct Compaction {
+    written: usize,
+    dropped: HashSet<Uuid>,
+    oldest_on_disk: Option<DateTime<Utc>>,
+}
 
 /// Message storage implementation
 #[derive(Debug, Default)]
@@ -16,6 +54,15 @@ pub struct MessageStorage {
     messages: HashMap<Uuid, Message>,
     max_messages: usize,
     compression_enabled: bool,
+    max_backup_files: usize,
+    retention_days: u32,
+    index: MessageIndex,
+    generation: u64,
+    parallel_search_threshold: usize,
+    /// Set by `load_recent`: messages older than this may exist only on disk
+    loaded_since: Option<DateTime<Utc>>,
+    /// No unpinned message left only on disk is older than this
+    oldest_on_disk: Option<DateTime<Utc>>,
 }
 
 /// Storage configuration
@@ -28,39 +75,500 @@ pub struct StorageConfig {
     pub backup_enabled: bool,
     pub backup_interval_hours: u64,
     pub max_backup_files: u32,
+    #[serde(default = "default_parallel_search_threshold")]
+    pub parallel_search_threshold: usize,
+    #[serde(default)]
+    pub backend: StorageBackendKind,
+}
+
+/// Where messages are kept on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StorageBackendKind {
+    /// A single JSON file, held fully in memory
+    #[default]
+    Json,
+    /// An indexed SQLite database, queried on demand
+    Sqlite,
+}
+
+fn default_parallel_search_threshold() -> usize {
+    PARALLEL_SEARCH_THRESHOLD
 }
 
 impl Default for StorageConfig {
     fn default() -> Self {
-        let mut data_dir = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
-        data_dir.push("tcp-messenger");
-
         Self {
-            data_directory: data_dir,
+            data_directory: crate::config::resolve_data_directory(None),
             max_messages: 10000,
             message_retention_days: 30,
             enable_compression: true,
             backup_enabled: true,
             backup_interval_hours: 24,
             max_backup_files: 7,
+            parallel_search_threshold: PARALLEL_SEARCH_THRESHOLD,
+            backend: StorageBackendKind::default(),
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Defaults pointed at a fresh temporary directory, adjusted by `configure`
+    #[cfg(test)]
+    pub(crate) fn temp(configure: impl FnOnce(&mut Self)) -> Self {
+        let mut config = Self {
+            data_directory: std::env::temp_dir().join(format!("tcp-messenger-test-{}", Uuid::new_v4())),
+            ..Default::default()
+        };
+        configure(&mut config);
+        config
+    }
+}
+
+/// Storage settings from the application config
+impl From<&crate::config::StorageConfig> for StorageConfig {
+    fn from(config: &crate::config::StorageConfig) -> Self {
+        Self {
+            data_directory: config.data_directory.clone(),
+            max_messages: config.max_messages,
+            message_retention_days: config.message_retention_days,
+            enable_compression: config.enable_compression,
+            backup_enabled: config.backup_enabled,
+            backup_interval_hours: config.backup_interval,
+            max_backup_files: config.max_backup_files,
+            parallel_search_threshold: PARALLEL_SEARCH_THRESHOLD,
+            backend: config.backend,
+        }
+    }
+}
+
+/// Operations every message store supports, whichever backend holds the messages.
+/// Operations with a default are built on the ones without; backends override them
+/// where they can answer more directly.
+#[async_trait]
+pub trait StorageBackend: Send + Sync + std::fmt::Debug {
+    /// Create or load whatever the store keeps on disk
+    async fn initialize(&mut self) -> Result<()>;
+    /// Store a message, replacing any message with the same id
+    async fn store_message(&mut self, message: Message) -> Result<()>;
+    fn get_message(&self, message_id: &Uuid) -> Result<Option<Message>>;
+    /// Messages passing a filter, newest first
+    fn get_messages_with_filter(&self, filter: &MessageFilter) -> Result<Vec<Message>>;
+    /// Messages matching a search, newest first
+    fn search_messages(&self, search: &MessageSearch) -> Result<Vec<Message>>;
+    async fn delete_message(&mut self, message_id: &Uuid) -> Result<()>;
+    async fn clear_all_messages(&mut self) -> Result<()>;
+    fn get_stats(&self) -> Result<StorageStats>;
+    /// Delete unpinned messages older than the retention period. Returns the number removed.
+    async fn cleanup_expired_messages(&mut self) -> Result<usize>;
+    /// Write the selected messages to a new file in the exports directory
+    async fn export_messages(&self, options: &ExportOptions) -> Result<PathBuf>;
+    /// Copy the store to a new file under `backups/`, pruning the oldest backups
+    async fn backup(&self) -> Result<PathBuf>;
+
+    /// A page of messages, newest first; all of them from `offset` when no limit is given
+    fn get_page(&self, offset: usize, limit: Option<usize>) -> Result<Vec<Message>> {
+        self.get_messages_with_filter(&MessageFilter { offset: Some(offset), limit, ..Default::default() })
+    }
+
+    /// Reactions to a message, oldest first. The target itself need not be stored.
+    fn get_reactions(&self, target_id: &Uuid) -> Result<Vec<Message>> {
+        let mut reactions: Vec<Message> = self.get_messages_with_filter(&MessageFilter::default())?
+            .into_iter()
+            .filter(|msg| matches!(&msg.message_type, MessageType::Reaction { target_id: target, .. } if target == target_id))
+            .collect();
+        reactions.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.id.cmp(&b.id)));
+        Ok(reactions)
+    }
+
+    /// Search messages, handing each match to `on_match`. Returns the number of matches,
+    /// or `None` if `cancelled` was set before the search finished.
+    fn search_messages_streaming(&self, search: &MessageSearch, cancelled: &AtomicBool, on_match: &mut dyn FnMut(&Message)) -> Result<Option<usize>> {
+        let mut total = 0;
+        for message in self.search_messages(search)? {
+            if cancelled.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            total += 1;
+            on_match(&message);
+        }
+        Ok(Some(total))
+    }
+
+    /// Messages covered by the export options
+    fn select_for_export(&self, options: &ExportOptions) -> Result<Vec<Message>> {
+        let mut messages = self.get_messages_with_filter(options.filter.as_ref().unwrap_or(&MessageFilter::default()))?;
+        if !options.include_system_messages {
+            messages.retain(|msg| !msg.is_system());
+        }
+        if let Some((start, end)) = &options.date_range {
+            messages.retain(|msg| msg.timestamp >= *start && msg.timestamp <= *end);
+        }
+        Ok(messages)
+    }
+
+    /// Count messages per `bucket` over `[start, end)`, including empty buckets
+    fn message_histogram(&self, bucket: std::time::Duration, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HistogramBucket>> {
+        let in_range = MessageFilter { start_date: Some(start), end_date: Some(end), ..Default::default() };
+        let mut timestamps: Vec<DateTime<Utc>> = self.get_messages_with_filter(&in_range)?
+            .into_iter()
+            .map(|message| message.timestamp)
+            .collect();
+        timestamps.sort();
+        histogram(bucket, start, end, |bucket_start, bucket_end| {
+            timestamps.partition_point(|timestamp| *timestamp < bucket_end) - timestamps.partition_point(|timestamp| *timestamp < bucket_start)
+        })
+    }
+
+    /// Count unread messages not sent by the given local user
+    fn get_unread_count(&self, local_id: &Uuid) -> Result<usize> {
+        Ok(self.get_messages_with_filter(&MessageFilter::default())?
+            .iter()
+            .filter(|msg| !msg.read && msg.sender_id != *local_id)
+            .count())
+    }
+
+    /// Update a message's delivery status. Returns whether it changed.
+    async fn set_message_status(&mut self, message_id: &Uuid, status: MessageStatus) -> Result<bool> {
+        let mut message = self.get_message(message_id)?
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", message_id)))?;
+        if message.status == status {
+            return Ok(false);
+        }
+        message.status = status;
+        self.store_message(message).await?;
+        Ok(true)
+    }
+
+    /// Mark a message as read
+    async fn mark_message_read(&mut self, message_id: &Uuid) -> Result<()> {
+        let mut message = self.get_message(message_id)?
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", message_id)))?;
+        if !message.read {
+            message.read = true;
+            self.store_message(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Pin or unpin a message
+    async fn set_message_pinned(&mut self, message_id: &Uuid, pinned: bool) -> Result<()> {
+        let mut message = self.get_message(message_id)?
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", message_id)))?;
+        if message.pinned != pinned {
+            message.pinned = pinned;
+            self.store_message(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply an edit message to the text message it targets. The previous content moves
+    /// to the target's edit history. Only the original sender may edit a message.
+    async fn apply_edit(&mut self, edit: &Message) -> Result<()> {
+        let target_id = edit_target(edit)?;
+        let message = self.get_message(&target_id)?
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", target_id)))?;
+        self.store_message(edited(message, edit)?).await
+    }
+
+    /// Delete the oldest unpinned messages until at most `target_max_messages` remain,
+    /// or only pinned messages are left. Returns the number of messages removed.
+    async fn trim_history(&mut self, target_max_messages: usize) -> Result<usize> {
+        let messages = self.get_messages_with_filter(&MessageFilter::default())?;
+        let excess = messages.len().saturating_sub(target_max_messages);
+        // Newest first, so the oldest are at the end
+        let doomed: Vec<Uuid> = messages.iter().rev()
+            .filter(|message| !message.pinned)
+            .take(excess)
+            .map(|message| message.id)
+            .collect();
+        for message_id in &doomed {
+            self.delete_message(message_id).await?;
         }
+        Ok(doomed.len())
     }
+
+    /// Merge archived messages into the store. Ids that are already stored with different
+    /// content are resolved by `policy`; exact duplicates always count as skipped.
+    async fn import_messages(&mut self, messages: Vec<Message>, policy: ImportConflictPolicy) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+        for message in messages {
+            match self.get_message(&message.id)? {
+                Some(existing) if !replaces_on_import(&existing, &message, policy) => {
+                    report.skipped += 1;
+                    continue;
+                },
+                Some(_) => report.overwritten += 1,
+                None => report.imported += 1,
+            }
+            self.store_message(message).await?;
+        }
+        Ok(report)
+    }
+
+    /// Write any pending changes out in the store's most compact form. This rewrites
+    /// files, so it takes `&mut self` to keep two compactions from overlapping.
+    async fn compact(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Build a fresh search index without blocking readers, with the store generation it
+    /// was built from. `None` for backends whose indexes maintain themselves.
+    fn build_index(&self) -> Result<Option<(MessageIndex, u64)>> {
+        Ok(None)
+    }
+
+    /// Swap in an index from `build_index`. Returns false without swapping if the store
+    /// has changed since the index was built.
+    fn replace_index(&mut self, _index: MessageIndex, _generation: u64) -> bool {
+        true
+    }
+
+    /// Discard and rebuild the search index
+    fn rebuild_index(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The backend selected by `config.backend`, not yet initialized
+pub fn create_backend(config: &StorageConfig) -> Box<dyn StorageBackend> {
+    match config.backend {
+        StorageBackendKind::Json => Box::new(MessageStorage::with_config(config)),
+        StorageBackendKind::Sqlite => Box::new(crate::sqlite_storage::SqliteStorage::with_config(config)),
+    }
+}
+
+/// Open and initialize the backend selected by `config.backend`
+pub async fn open_backend(config: &StorageConfig) -> Result<Box<dyn StorageBackend>> {
+    let mut backend = create_backend(config);
+    backend.initialize().await?;
+    Ok(backend)
+}
+
+/// The message an edit targets
+fn edit_target(edit: &Message) -> Result<Uuid> {
+    match &edit.message_type {
+        MessageType::Edit { target_id, .. } => Ok(*target_id),
+        _ => Err(MessengerError::InvalidMessageType(format!("Not an edit: {}", edit.id))),
+    }
+}
+
+/// `message` with `edit` applied: the new content in place, the old content at the end of
+/// the edit history and the edit's time stamped in the metadata
+fn edited(mut message: Message, edit: &Message) -> Result<Message> {
+    let MessageType::Edit { new_content, .. } = &edit.message_type else {
+        return Err(MessengerError::InvalidMessageType(format!("Not an edit: {}", edit.id)));
+    };
+    let MessageType::Text { content } = &mut message.message_type else {
+        return Err(MessengerError::InvalidMessageType(format!("Only text messages can be edited: {}", message.id)));
+    };
+    if message.sender_id != edit.sender_id {
+        return Err(MessengerError::PermissionDenied(format!("Message {} belongs to another sender", message.id)));
+    }
+
+    let original = std::mem::replace(content, new_content.clone());
+    message.edit_history.push(MessageRevision { content: original, replaced_at: edit.timestamp });
+    message.metadata.insert(crate::types::EDITED_AT_METADATA_KEY.to_string(), edit.timestamp.to_rfc3339());
+    Ok(message)
+}
+
+/// Backup files in `backup_dir`, oldest first
+fn list_backups(backup_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let entries = std::fs::read_dir(backup_dir)
+        .map_err(|e| MessengerError::Storage(format!("Failed to read backup directory: {}", e)))?;
+    let mut backups: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with(BACKUP_PREFIX)))
+        .collect();
+    backups.sort();
+    Ok(backups)
+}
+
+/// Delete the oldest backups in `backup_dir` beyond the newest `keep`
+pub(crate) fn prune_backups(backup_dir: &Path, keep: usize) -> Result<()> {
+    let backups = list_backups(backup_dir)?;
+    let excess = backups.len().saturating_sub(keep.max(1));
+    for old in &backups[..excess] {
+        std::fs::remove_file(old)
+            .map_err(|e| MessengerError::Storage(format!("Failed to remove old backup: {}", e)))?;
+    }
+    Ok(())
+}
+
+/// Whether an imported message replaces the stored one with the same id
+fn replaces_on_import(existing: &Message, imported: &Message, policy: ImportConflictPolicy) -> bool {
+    *existing != *imported && match policy {
+        ImportConflictPolicy::Skip => false,
+        ImportConflictPolicy::Overwrite => true,
+        ImportConflictPolicy::KeepNewest => imported.timestamp > existing.timestamp,
+    }
+}
+
+/// Split `[start, end)` into buckets starting at `start`, counting each with `count_between`.
+/// Every bucket is returned, including empty ones; the last is cut short at `end`.
+fn histogram(
+    bucket: std::time::Duration,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    count_between: impl Fn(DateTime<Utc>, DateTime<Utc>) -> usize,
+) -> Result<Vec<HistogramBucket>> {
+    let bucket = chrono::Duration::from_std(bucket)
+        .ok()
+        .filter(|bucket| *bucket > chrono::Duration::zero())
+        .ok_or_else(|| MessengerError::invalid_field("bucket", "must be greater than zero"))?;
+    if end < start {
+        return Err(MessengerError::invalid_field("range", "end is before start"));
+    }
+
+    let span = (end - start).num_milliseconds() as u64;
+    let bucket_count = span.div_ceil(bucket.num_milliseconds().max(1) as u64) as usize;
+    if bucket_count > MAX_HISTOGRAM_BUCKETS {
+        return Err(MessengerError::invalid_field("bucket", format!("range needs more than {} buckets", MAX_HISTOGRAM_BUCKETS)));
+    }
+
+    let mut buckets = Vec::with_capacity(bucket_count);
+    let mut bucket_start = start;
+    while bucket_start < end {
+        let bucket_end = (bucket_start + bucket).min(end);
+        buckets.push(HistogramBucket {
+            start: bucket_start,
+            count: count_between(bucket_start, bucket_end),
+        });
+        bucket_start = bucket_end;
+    }
+    Ok(buckets)
 }
 
 /// Message index for fast searching
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct MessageIndex {
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageIndex {
     by_sender: HashMap<Uuid, Vec<Uuid>>,
-    by_timestamp: Vec<Uuid>,
+    by_timestamp: Vec<(DateTime<Utc>, Uuid)>,
     by_type: HashMap<String, Vec<Uuid>>,
     by_content: HashMap<String, Vec<Uuid>>, // Simple keyword index
 }
 
+impl MessageIndex {
+    /// Build an index from a set of messages
+    fn build<'a>(messages: impl Iterator<Item = &'a Message>) -> Self {
+        let mut index = Self::default();
+        for message in messages {
+            index.insert(message);
+        }
+        index
+    }
+
+    /// Add a message to the index
+    fn insert(&mut self, message: &Message) {
+        self.by_sender.entry(message.sender_id).or_default().push(message.id);
+
+        let position = self.by_timestamp.partition_point(|(timestamp, _)| *timestamp <= message.timestamp);
+        self.by_timestamp.insert(position, (message.timestamp, message.id));
+
+        self.by_type.entry(Self::type_key(&message.message_type)).or_default().push(message.id);
+
+        for keyword in Self::keywords(message) {
+            self.by_content.entry(keyword).or_default().push(message.id);
+        }
+    }
+
+    /// Remove a message from the index
+    fn remove(&mut self, message: &Message) {
+        fn remove_id<K: std::hash::Hash + Eq>(map: &mut HashMap<K, Vec<Uuid>>, key: &K, id: &Uuid) {
+            if let Some(ids) = map.get_mut(key) {
+                ids.retain(|existing| existing != id);
+                if ids.is_empty() {
+                    map.remove(key);
+                }
+            }
+        }
+
+        remove_id(&mut self.by_sender, &message.sender_id, &message.id);
+        self.by_timestamp.retain(|(_, id)| *id != message.id);
+        remove_id(&mut self.by_type, &Self::type_key(&message.message_type), &message.id);
+        for keyword in Self::keywords(message) {
+            remove_id(&mut self.by_content, &keyword, &message.id);
+        }
+    }
+
+    /// Count messages with timestamps in `[start, end)`
+    fn count_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> usize {
+        let first = self.by_timestamp.partition_point(|(timestamp, _)| *timestamp < start);
+        let last = self.by_timestamp.partition_point(|(timestamp, _)| *timestamp < end);
+        last.saturating_sub(first)
+    }
+
+    /// Check that the index agrees with a linear scan of the message store
+    fn verify(&self, messages: &HashMap<Uuid, Message>) -> bool {
+        self.normalized() == Self::build(messages.values()).normalized()
+    }
+
+    /// Copy of the index with every id list sorted, for order-independent comparison
+    fn normalized(&self) -> Self {
+        fn sorted<K: Clone + std::hash::Hash + Eq>(map: &HashMap<K, Vec<Uuid>>) -> HashMap<K, Vec<Uuid>> {
+            map.iter()
+                .map(|(key, ids)| {
+                    let mut ids = ids.clone();
+                    ids.sort();
+                    (key.clone(), ids)
+                })
+                .collect()
+        }
+
+        let mut by_timestamp = self.by_timestamp.clone();
+        by_timestamp.sort();
+
+        Self {
+            by_sender: sorted(&self.by_sender),
+            by_timestamp,
+            by_type: sorted(&self.by_type),
+            by_content: sorted(&self.by_content),
+        }
+    }
+
+    pub(crate) fn type_key(message_type: &MessageType) -> String {
+        match message_type {
+            MessageType::Text { .. } => "Text",
+            MessageType::File { .. } => "File",
+            MessageType::System { .. } => "System",
+            MessageType::Heartbeat => "Heartbeat",
+            MessageType::KeyExchange { .. } => "KeyExchange",
+            MessageType::Disconnect { .. } => "Disconnect",
+            MessageType::Acknowledgment { .. } => "Acknowledgment",
+            MessageType::Benchmark { .. } => "Benchmark",
+            MessageType::Control { .. } => "Control",
+            MessageType::Reaction { .. } => "Reaction",
+            MessageType::Edit { .. } => "Edit",
+            MessageType::ReadReceipt { .. } => "ReadReceipt",
+            MessageType::Typing { .. } => "Typing",
+        }.to_string()
+    }
+
+    fn keywords(message: &Message) -> Vec<String> {
+        let content = match &message.message_type {
+            MessageType::Text { content } => content,
+            MessageType::System { content, .. } => content,
+            _ => return Vec::new(),
+        };
+
+        let mut keywords: Vec<String> = content
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect();
+        keywords.sort();
+        keywords.dedup();
+        keywords
+    }
+}
+
 impl MessageStorage {
     /// Create a new message storage
     pub fn new() -> Self {
-        let mut storage_path = dirs::data_dir().unwrap_or_else(|| PathBuf::from("."));
-        storage_path.push("tcp-messenger");
+        let mut storage_path = crate::config::resolve_data_directory(None);
         storage_path.push("messages");
 
         Self {
@@ -68,12 +576,19 @@ impl MessageStorage {
             messages: HashMap::new(),
             max_messages: 10000,
             compression_enabled: true,
+            max_backup_files: 7,
+            retention_days: 30,
+            index: MessageIndex::default(),
+            generation: 0,
+            parallel_search_threshold: PARALLEL_SEARCH_THRESHOLD,
+            loaded_since: None,
+            oldest_on_disk: None,
         }
     }
 
     /// Create message storage with custom configuration
     pub fn with_config(config: &StorageConfig) -> Self {
-        let mut storage_path = config.data_directory.clone();
+        let mut storage_path = crate::config::resolve_data_directory(Some(&config.data_directory));
         storage_path.push("messages");
 
         Self {
@@ -81,33 +596,118 @@ impl MessageStorage {
             messages: HashMap::new(),
             max_messages: config.max_messages,
             compression_enabled: config.enable_compression,
+            max_backup_files: config.max_backup_files as usize,
+            retention_days: config.message_retention_days,
+            index: MessageIndex::default(),
+            generation: 0,
+            parallel_search_threshold: config.parallel_search_threshold,
+            loaded_since: None,
+            oldest_on_disk: None,
         }
     }
 
-    /// Initialize storage (create directories, load existing messages)
+    /// Initialize storage (create directories, load up to `max_messages` of the newest messages)
     pub async fn initialize(&mut self) -> Result<()> {
         // Create storage directory if it doesn't exist
         std::fs::create_dir_all(&self.storage_path)
             .map_err(|e| MessengerError::Storage(format!("Failed to create storage directory: {}", e)))?;
 
-        // Load existing messages
-        self.load_messages().await?;
+        if let Err(e) = self.load_recent(self.max_messages).await {
+            // Fall back to the newest backup and put it in place of the unreadable file,
+            // keeping the changes logged since
+            let (backup, messages) = self.read_latest_backup().ok_or(e)?;
+            warn!("Messages file is unreadable, recovering {} messages from {:?}", messages.len(), backup);
+            let messages = self.apply_logged_changes(messages)?;
+            self.write_messages_file(&messages)?;
+            self.load_recent(self.max_messages).await?;
+        }
+
+        // Drop any messages past the retention period
+        self.cleanup_expired_messages().await?;
 
         info!("Message storage initialized with {} messages", self.messages.len());
         Ok(())
     }
 
+    /// Initialize storage holding only the `limit` newest messages in memory. Older
+    /// messages stay on disk: `get_page` reads them on demand, while filters, searches and
+    /// exports see only what is loaded until `load_all` is called.
+    pub async fn load_recent(&mut self, limit: usize) -> Result<()> {
+        std::fs::create_dir_all(&self.storage_path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create storage directory: {}", e)))?;
+
+        let (newest, total, oldest_unpinned) = self.newest_on_disk(limit)?;
+        self.oldest_on_disk = oldest_unpinned;
+        self.loaded_since = if total > newest.len() {
+            newest.last().map(|message| message.timestamp)
+        } else {
+            None
+        };
+        self.messages = newest.into_iter().map(|message| (message.id, message)).collect();
+        self.index = MessageIndex::build(self.messages.values());
+        self.generation += 1;
+
+        info!("Loaded {} of {} stored messages", self.messages.len(), total);
+        Ok(())
+    }
+
+    /// Bring every message on disk into memory after `load_recent`
+    pub async fn load_all(&mut self) -> Result<()> {
+        if self.loaded_since.is_none() {
+            return Ok(());
+        }
+
+        for message in self.disk_only_messages()? {
+            self.index.insert(&message);
+            self.messages.insert(message.id, message);
+        }
+        self.loaded_since = None;
+        self.generation += 1;
+        Ok(())
+    }
+
+    /// A page of messages, newest first. Pages within the loaded messages come from
+    /// memory; pages reaching further back are read from disk.
+    pub fn get_page(&self, offset: usize, limit: Option<usize>) -> Result<Vec<Message>> {
+        let page_size = limit.unwrap_or(usize::MAX);
+        let resident: Vec<&Message> = self.index.by_timestamp.iter()
+            .rev()
+            .skip(offset)
+            .take(page_size)
+            .filter_map(|(_, id)| self.messages.get(id))
+            .collect();
+
+        let in_memory = match self.loaded_since {
+            None => true,
+            Some(since) => resident.len() == page_size && resident.last().is_some_and(|message| message.timestamp >= since),
+        };
+        if in_memory {
+            return Ok(resident.into_iter().cloned().collect());
+        }
+
+        let (newest, _, _) = self.newest_on_disk(offset.saturating_add(page_size))?;
+        Ok(newest.into_iter().skip(offset).collect())
+    }
+
     /// Store a message
     pub async fn store_message(&mut self, message: Message) -> Result<()> {
         let message_id = message.id;
-        
+        if message.is_typing() {
+            debug!("Not storing typing indicator: {}", message_id);
+            return Ok(());
+        }
+
         // Check if we need to remove old messages
         if self.messages.len() >= self.max_messages {
-            self.cleanup_old_messages().await?;
+            self.cleanup_expired_messages().await?;
         }
 
         // Store the message
-        self.messages.insert(message_id, message.clone());
+        if let Some(previous) = self.messages.insert(message_id, message.clone()) {
+            self.index.remove(&previous);
+        }
+        self.index.insert(&message);
+        self.generation += 1;
 
         // Persist to disk
         self.persist_message(&message).await?;
@@ -128,7 +728,8 @@ impl MessageStorage {
 
     /// Get messages with filter
     pub fn get_messages_with_filter(&self, filter: &MessageFilter) -> Vec<&Message> {
-        let mut messages: Vec<&Message> = self.messages.values().collect();
+        let mut messages = self.indexed_candidates(filter)
+            .unwrap_or_else(|| self.messages.values().collect());
 
         // Apply filters
         if let Some(message_types) = &filter.message_types {
@@ -164,6 +765,13 @@ impl MessageStorage {
             messages.retain(|msg| status.contains(&msg.status));
         }
 
+        if let Some(reply_to) = &filter.reply_to {
+            messages.retain(|msg| msg.reply_to == Some(*reply_to));
+        }
+
+        // Sort by timestamp (newest first) so pages are stable
+        messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
         // Apply pagination
         if let Some(offset) = filter.offset {
             messages = messages.into_iter().skip(offset).collect();
@@ -173,73 +781,141 @@ impl MessageStorage {
             messages = messages.into_iter().take(limit).collect();
         }
 
-        // Sort by timestamp (newest first)
-        messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-
         messages
     }
 
-    /// Search messages
+    /// Search messages, scanning in parallel once the store reaches the configured size
     pub fn search_messages(&self, search: &MessageSearch) -> Vec<&Message> {
-        let mut results = Vec::new();
+        let candidates = search.filter.as_ref().and_then(|filter| self.indexed_candidates(filter));
+        let mut results: Vec<&Message> = match candidates {
+            Some(candidates) => candidates.into_iter()
+                .filter(|message| Self::matches_search(message, search))
+                .collect(),
+            None if self.messages.len() >= self.parallel_search_threshold => self.messages.par_iter()
+                .map(|(_, message)| message)
+                .filter(|message| Self::matches_search(message, search))
+                .collect(),
+            None => self.messages.values()
+                .filter(|message| Self::matches_search(message, search))
+                .collect(),
+        };
+
+        // Apply additional filter if provided
+        if let Some(filter) = &search.filter {
+            let filtered_ids = self.filtered_ids(filter);
+            results.retain(|msg| filtered_ids.contains(&msg.id));
+        }
+
+        // Sort by timestamp (newest first), breaking ties by id so the order is stable
+        results.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(a.id.cmp(&b.id)));
+
+        results
+    }
+
+    /// Search messages, handing each match to `on_match` as soon as it is found.
+    /// Matches arrive in storage order rather than by timestamp. Returns the number
+    /// of matches, or `None` if `cancelled` was set before the search finished.
+    pub fn search_messages_streaming<F>(&self, search: &MessageSearch, cancelled: &AtomicBool, mut on_match: F) -> Option<usize>
+    where
+        F: FnMut(&Message),
+    {
+        let filtered_ids = search.filter.as_ref().map(|filter| self.filtered_ids(filter));
+        let mut total = 0;
 
         for message in self.messages.values() {
-            let mut matches = false;
-
-            if search.search_content {
-                match &message.message_type {
-                    crate::types::MessageType::Text { content } => {
-                        if search.case_sensitive {
-                            matches = content.contains(&search.query);
-                        } else {
-                            matches = content.to_lowercase().contains(&search.query.to_lowercase());
-                        }
-                    },
-                    crate::types::MessageType::System { content, .. } => {
-                        if search.case_sensitive {
-                            matches = content.contains(&search.query);
-                        } else {
-                            matches = content.to_lowercase().contains(&search.query.to_lowercase());
-                        }
-                    },
-                    _ => {}
-                }
+            if cancelled.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let allowed = filtered_ids.as_ref().is_none_or(|ids| ids.contains(&message.id));
+            if allowed && Self::matches_search(message, search) {
+                total += 1;
+                on_match(message);
             }
+        }
+
+        Some(total)
+    }
+
+    /// Messages the index narrows a filter down to, by sender or failing that by type.
+    /// Returns `None` when the filter names neither and every message must be scanned.
+    fn indexed_candidates(&self, filter: &MessageFilter) -> Option<Vec<&Message>> {
+        let ids: Vec<&Uuid> = if let Some(sender_ids) = &filter.sender_ids {
+            let sender_ids: HashSet<&Uuid> = sender_ids.iter().collect();
+            sender_ids.into_iter().filter_map(|sender_id| self.index.by_sender.get(sender_id)).flatten().collect()
+        } else if let Some(message_types) = &filter.message_types {
+            let type_keys: HashSet<String> = message_types.iter().map(MessageIndex::type_key).collect();
+            type_keys.iter().filter_map(|key| self.index.by_type.get(key)).flatten().collect()
+        } else {
+            return None;
+        };
+
+        Some(ids.into_iter().filter_map(|id| self.messages.get(id)).collect())
+    }
 
-            if search.search_metadata {
-                for (key, value) in &message.metadata {
+    /// Reactions to a message, oldest first. The target itself need not be stored.
+    pub fn get_reactions(&self, target_id: &Uuid) -> Vec<&Message> {
+        let mut reactions: Vec<&Message> = self.index.by_type.get("Reaction")
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.messages.get(id))
+            .filter(|msg| matches!(&msg.message_type, MessageType::Reaction { target_id: target, .. } if target == target_id))
+            .collect();
+        reactions.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then(a.id.cmp(&b.id)));
+        reactions
+    }
+
+    /// Ids of the messages that pass a filter
+    fn filtered_ids(&self, filter: &MessageFilter) -> HashSet<Uuid> {
+        self.get_messages_with_filter(filter).iter().map(|msg| msg.id).collect()
+    }
+
+    /// Check a single message against the search query
+    pub(crate) fn matches_search(message: &Message, search: &MessageSearch) -> bool {
+        let mut matches = false;
+
+        if search.search_content {
+            match &message.message_type {
+                crate::types::MessageType::Text { content } => {
                     if search.case_sensitive {
-                        matches = matches || key.contains(&search.query) || value.contains(&search.query);
+                        matches = content.contains(&search.query);
                     } else {
-                        let query_lower = search.query.to_lowercase();
-                        matches = matches || 
-                            key.to_lowercase().contains(&query_lower) || 
-                            value.to_lowercase().contains(&query_lower);
+                        matches = content.to_lowercase().contains(&search.query.to_lowercase());
                     }
-                }
-            }
-
-            if matches {
-                results.push(message);
+                },
+                crate::types::MessageType::System { content, .. } => {
+                    if search.case_sensitive {
+                        matches = content.contains(&search.query);
+                    } else {
+                        matches = content.to_lowercase().contains(&search.query.to_lowercase());
+                    }
+                },
+                _ => {}
             }
         }
 
-        // Apply additional filter if provided
-        if let Some(filter) = &search.filter {
-            let filtered_results = self.get_messages_with_filter(filter);
-            let filtered_ids: std::collections::HashSet<_> = filtered_results.iter().map(|msg| msg.id).collect();
-            results.retain(|msg| filtered_ids.contains(&msg.id));
+        if search.search_metadata {
+            for (key, value) in &message.metadata {
+                if search.case_sensitive {
+                    matches = matches || key.contains(&search.query) || value.contains(&search.query);
+                } else {
+                    let query_lower = search.query.to_lowercase();
+                    matches = matches || 
+                        key.to_lowercase().contains(&query_lower) || 
+                        value.to_lowercase().contains(&query_lower);
+                }
+            }
         }
 
-        // Sort by timestamp (newest first)
-        results.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-
-        results
+        matches
     }
 
     /// Delete a message
     pub async fn delete_message(&mut self, message_id: &Uuid) -> Result<()> {
         if let Some(message) = self.messages.remove(message_id) {
+            self.index.remove(&message);
+            self.generation += 1;
+
             // Remove from disk
             self.remove_message_from_disk(&message).await?;
             debug!("Deleted message: {}", message_id);
@@ -247,151 +923,650 @@ impl MessageStorage {
         Ok(())
     }
 
-    /// Clear all messages
-    pub async fn clear_all_messages(&mut self) -> Result<()> {
-        self.messages.clear();
-        
-        // Clear disk storage
-        if self.storage_path.exists() {
-            std::fs::remove_dir_all(&self.storage_path)
-                .map_err(|e| MessengerError::Storage(format!("Failed to clear storage: {}", e)))?;
-        }
+    /// Mark a message as read
+    pub async fn mark_message_read(&mut self, message_id: &Uuid) -> Result<()> {
+        let message = self.messages.get_mut(message_id)
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", message_id)))?;
 
-        info!("Cleared all messages");
+        if !message.read {
+            message.read = true;
+            let message = message.clone();
+            self.persist_message(&message).await?;
+            debug!("Marked message as read: {}", message_id);
+        }
         Ok(())
     }
 
-    /// Export messages to file
-    pub async fn export_messages(&self, options: &ExportOptions) -> Result<PathBuf> {
-        let messages = if let Some(filter) = &options.filter {
-            self.get_messages_with_filter(filter)
-        } else {
-            self.get_all_messages()
-        };
-
-        let export_path = self.get_export_path(&options.format).await?;
+    /// Update a message's delivery status. Returns whether it changed.
+    pub async fn set_message_status(&mut self, message_id: &Uuid, status: MessageStatus) -> Result<bool> {
+        let message = self.messages.get_mut(message_id)
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", message_id)))?;
 
-        match options.format {
-            ExportFormat::Json => self.export_to_json(&messages, &export_path).await?,
-            ExportFormat::Csv => self.export_to_csv(&messages, &export_path).await?,
-            ExportFormat::Txt => self.export_to_txt(&messages, &export_path).await?,
-            ExportFormat::Html => self.export_to_html(&messages, &export_path).await?,
+        if message.status == status {
+            return Ok(false);
         }
-
-        info!("Exported {} messages to {:?}", messages.len(), export_path);
-        Ok(export_path)
+        message.status = status;
+        let message = message.clone();
+        self.persist_message(&message).await?;
+        debug!("Set message {} status: {}", message_id, message.status);
+        Ok(true)
     }
 
-    /// Get storage statistics
-    pub fn get_stats(&self) -> StorageStats {
-        StorageStats {
-            total_messages: self.messages.len(),
-            storage_size_bytes: self.calculate_storage_size(),
-            oldest_message: self.get_oldest_message_timestamp(),
-            newest_message: self.get_newest_message_timestamp(),
+    /// Pin or unpin a message
+    pub async fn set_message_pinned(&mut self, message_id: &Uuid, pinned: bool) -> Result<()> {
+        let message = self.messages.get_mut(message_id)
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", message_id)))?;
+
+        if message.pinned != pinned {
+            message.pinned = pinned;
+            let message = message.clone();
+            self.persist_message(&message).await?;
+            debug!("Set message {} pinned: {}", message_id, pinned);
         }
+        Ok(())
     }
 
-    // Private helper methods
+    /// Apply an edit message to the text message it targets. The previous content moves
+    /// to the target's edit history. Only the original sender may edit a message.
+    pub async fn apply_edit(&mut self, edit: &Message) -> Result<()> {
+        let target_id = edit_target(edit)?;
+        let message = self.messages.get(&target_id)
+            .ok_or_else(|| MessengerError::ResourceNotFound(format!("Message not found: {}", target_id)))?;
+        let message = edited(message.clone(), edit)?;
+
+        // The keyword index covers content, so re-index under the new text
+        if let Some(previous) = self.messages.insert(message.id, message.clone()) {
+            self.index.remove(&previous);
+        }
+        self.index.insert(&message);
+        self.persist_message(&message).await?;
+        debug!("Applied edit {} to message {}", edit.id, target_id);
+        Ok(())
+    }
+
+    /// Delete the oldest unpinned messages until at most `target_max_messages` remain,
+    /// or only pinned messages are left. Returns the number of messages removed.
+    pub async fn trim_history(&mut self, target_max_messages: usize) -> Result<usize> {
+        self.load_all().await?;
+        let excess = self.messages.len().saturating_sub(target_max_messages);
+        if excess == 0 {
+            return Ok(0);
+        }
+
+        let mut candidates: Vec<&Message> = self.messages.values()
+            .filter(|message| !message.pinned)
+            .collect();
+        candidates.sort_by_key(|message| (message.timestamp, message.id));
+        let doomed: Vec<Uuid> = candidates.iter().take(excess).map(|message| message.id).collect();
+
+        for message_id in &doomed {
+            if let Some(message) = self.messages.remove(message_id) {
+                self.index.remove(&message);
+            }
+        }
+        self.generation += 1;
+        self.compact().await?;
+
+        info!("Trimmed {} messages from history", doomed.len());
+        Ok(doomed.len())
+    }
+
+    /// Delete unpinned messages older than the retention period. Returns the number removed.
+    pub async fn cleanup_expired_messages(&mut self) -> Result<usize> {
+        let cutoff_date = Utc::now() - chrono::Duration::days(self.retention_days as i64);
+        let is_expired = |message: &Message| message.timestamp < cutoff_date && !message.pinned;
+
+        let mut expired: HashSet<Uuid> = self.messages
+            .values()
+            .filter(|message| is_expired(message))
+            .map(|message| message.id)
+            .collect();
+
+        // Messages left on disk by `load_recent` are only read when one of them may have expired
+        let check_disk = self.loaded_since.is_some() && self.oldest_on_disk.is_some_and(|oldest| oldest < cutoff_date);
+        if expired.is_empty() && !check_disk {
+            return Ok(0);
+        }
+
+        for message_id in &expired {
+            if let Some(message) = self.messages.remove(message_id) {
+                self.index.remove(&message);
+            }
+        }
+        let compaction = self.write_compacted(|message| !is_expired(message))?;
+        expired.extend(compaction.dropped);
+        self.oldest_on_disk = compaction.oldest_on_disk;
+        self.generation += 1;
+
+        if !expired.is_empty() {
+            info!("Cleaned up {} messages older than {} days", expired.len(), self.retention_days);
+        }
+        Ok(expired.len())
+    }
 
-    async fn load_messages(&mut self) -> Result<()> {
-        let messages_file = self.storage_path.join("messages.json");
+    /// Count messages per `bucket` over `[start, end)`, starting at `start`. Every bucket is
+    /// returned, including empty ones; the last is cut short at `end`.
+    pub fn message_histogram(&self, bucket: std::time::Duration, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HistogramBucket>> {
+        // Two binary searches per bucket on the timestamp-sorted index
+        histogram(bucket, start, end, |bucket_start, bucket_end| self.index.count_between(bucket_start, bucket_end))
+    }
+
+    /// Count unread messages not sent by the given local user
+    pub fn get_unread_count(&self, local_id: &Uuid) -> usize {
+        self.messages.values()
+            .filter(|msg| !msg.read && msg.sender_id != *local_id)
+            .count()
+    }
+
+    /// Clear all messages
+    pub async fn clear_all_messages(&mut self) -> Result<()> {
+        self.messages.clear();
+        self.index = MessageIndex::default();
+        self.loaded_since = None;
+        self.generation += 1;
         
-        if !messages_file.exists() {
-            return Ok(());
+        // Clear disk storage, leaving an empty directory for new messages
+        if self.storage_path.exists() {
+            std::fs::remove_dir_all(&self.storage_path)
+                .map_err(|e| MessengerError::Storage(format!("Failed to clear storage: {}", e)))?;
+            std::fs::create_dir_all(&self.storage_path)
+                .map_err(|e| MessengerError::Storage(format!("Failed to create storage directory: {}", e)))?;
         }
 
-        let content = std::fs::read_to_string(&messages_file)
-            .map_err(|e| MessengerError::Storage(format!("Failed to read messages file: {}", e)))?;
+        info!("Cleared all messages");
+        Ok(())
+    }
 
-        let messages: Vec<Message> = serde_json::from_str(&content)
-            .map_err(|e| MessengerError::Storage(format!("Failed to parse messages: {}", e)))?;
+    /// Export messages to file
+    pub async fn export_messages(&self, options: &ExportOptions) -> Result<PathBuf> {
+        let messages = self.select_for_export(options);
+        Self::export_to_file(&self.storage_path, &messages, options)
+    }
+
+    /// Write messages to a new file in the exports directory beside `storage_path`
+    pub(crate) fn export_to_file(storage_path: &Path, messages: &[&Message], options: &ExportOptions) -> Result<PathBuf> {
+        let export_path = Self::get_export_path(storage_path, &options.format)?;
+
+        write_export(&export_path, |writer| {
+            Self::export_to_writer(messages, &options.format, options.include_metadata, options.timestamp_format, writer)
+        })?;
+
+        info!("Exported {} messages to {:?}", messages.len(), export_path);
+        Ok(export_path)
+    }
+
+    /// Merge archived messages into the store. Ids that are already stored with different
+    /// content are resolved by `policy`; exact duplicates always count as skipped.
+    pub async fn import_messages(&mut self, messages: Vec<Message>, policy: ImportConflictPolicy) -> Result<ImportReport> {
+        self.load_all().await?;
+        let mut report = ImportReport::default();
 
         for message in messages {
+            if let Some(existing) = self.messages.get(&message.id) {
+                if !replaces_on_import(existing, &message, policy) {
+                    report.skipped += 1;
+                    continue;
+                }
+                self.index.remove(existing);
+                report.overwritten += 1;
+            } else {
+                report.imported += 1;
+            }
+
+            self.index.insert(&message);
             self.messages.insert(message.id, message);
         }
 
-        Ok(())
+        // Write the merged store once rather than rewriting the file per message
+        if report.imported + report.overwritten > 0 {
+            self.generation += 1;
+            self.compact().await?;
+        }
+
+        info!("Imported {} messages ({} skipped, {} overwritten)", report.imported, report.skipped, report.overwritten);
+        Ok(report)
     }
 
-    async fn persist_message(&self, message: &Message) -> Result<()> {
-        let messages_file = self.storage_path.join("messages.json");
-        
-        // Read existing messages
-        let mut all_messages = if messages_file.exists() {
-            let content = std::fs::read_to_string(&messages_file)
-                .map_err(|e| MessengerError::Storage(format!("Failed to read messages file: {}", e)))?;
-            serde_json::from_str::<Vec<Message>>(&content)
-                .map_err(|e| MessengerError::Storage(format!("Failed to parse messages: {}", e)))?
+    /// Read messages from a JSON export
+    pub fn read_archive(path: &Path) -> Result<Vec<Message>> {
+        let file = File::open(path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to open archive: {}", e)))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|e| MessengerError::Storage(format!("Failed to parse archive: {}", e)))
+    }
+
+    /// Messages covered by the export options, oldest first
+    pub fn select_for_export(&self, options: &ExportOptions) -> Vec<&Message> {
+        let mut messages = if let Some(filter) = &options.filter {
+            self.get_messages_with_filter(filter)
         } else {
-            Vec::new()
+            self.get_all_messages()
         };
 
-        // Add or update the message
-        if let Some(existing_index) = all_messages.iter().position(|m| m.id == message.id) {
-            all_messages[existing_index] = message.clone();
-        } else {
-            all_messages.push(message.clone());
+        if !options.include_system_messages {
+            messages.retain(|msg| !msg.is_system());
         }
 
-        // Write back to file
-        let content = serde_json::to_string_pretty(&all_messages)
-            .map_err(|e| MessengerError::Storage(format!("Failed to serialize messages: {}", e)))?;
+        if let Some((start, end)) = &options.date_range {
+            messages.retain(|msg| msg.timestamp >= *start && msg.timestamp <= *end);
+        }
 
-        std::fs::write(&messages_file, content)
-            .map_err(|e| MessengerError::Storage(format!("Failed to write messages file: {}", e)))?;
+        messages
+    }
+
+    /// Write messages in an export format to any destination, such as stdout or a pipe.
+    /// Messages are written one at a time so memory use does not grow with the export.
+    pub fn export_to_writer<W: Write>(
+        messages: &[&Message],
+        format: &ExportFormat,
+        include_metadata: bool,
+        timestamp_format: TimestampFormat,
+        writer: &mut W,
+    ) -> Result<()> {
+        match format {
+            ExportFormat::Json => Self::export_to_json(messages, include_metadata, timestamp_format, writer),
+            ExportFormat::Csv => Self::export_to_csv(messages, writer),
+            ExportFormat::Txt => Self::export_to_txt(messages, writer),
+            ExportFormat::Html => Self::export_to_html(messages, writer),
+        }
+    }
+
+    /// Get messages from a sender using the index
+    pub fn get_messages_by_sender(&self, sender_id: &Uuid) -> Vec<&Message> {
+        self.index.by_sender.get(sender_id)
+            .map(|ids| ids.iter().filter_map(|id| self.messages.get(id)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Build a fresh index from the message store, verified against a linear scan.
+    /// Returns the index with the store generation it was built from.
+    pub fn build_index(&self) -> Result<(MessageIndex, u64)> {
+        let index = MessageIndex::build(self.messages.values());
+        if !index.verify(&self.messages) {
+            return Err(MessengerError::Storage("Rebuilt index does not match message store".to_string()));
+        }
+        Ok((index, self.generation))
+    }
 
+    /// Swap in an index built by `build_index`. Returns false without swapping if the
+    /// store has changed since the index was built.
+    pub fn replace_index(&mut self, index: MessageIndex, generation: u64) -> bool {
+        if generation != self.generation {
+            return false;
+        }
+        self.index = index;
+        true
+    }
+
+    /// Discard and rebuild the index from the message store
+    pub fn rebuild_index(&mut self) -> Result<()> {
+        let (index, generation) = self.build_index()?;
+        self.replace_index(index, generation);
         Ok(())
     }
 
-    async fn remove_message_from_disk(&self, message: &Message) -> Result<()> {
-        let messages_file = self.storage_path.join("messages.json");
-        
-        if !messages_file.exists() {
-            return Ok(());
+    /// Rewrite the messages file from the in-memory store, folding in the log
+    pub async fn compact(&self) -> Result<()> {
+        let compaction = self.write_compacted(|_| true)?;
+        info!("Compacted storage to {} messages", compaction.written);
+        Ok(())
+    }
+
+    /// Write the message store to a timestamped file under `backups/`, then delete the
+    /// oldest backups beyond `max_backup_files`. Returns the new backup's path.
+    pub async fn backup(&self) -> Result<PathBuf> {
+        let backup_dir = self.backup_directory();
+        std::fs::create_dir_all(&backup_dir)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create backup directory: {}", e)))?;
+
+        let on_disk = self.disk_only_messages()?;
+        let mut messages: Vec<&Message> = self.messages.values().chain(&on_disk).collect();
+        messages.sort_by_key(|message| message.timestamp);
+        let content = Self::encode_messages(&messages, self.compression_enabled)?;
+
+        // The timestamp sorts lexically, so file names order backups oldest first
+        let extension = if self.compression_enabled { "json.gz" } else { "json" };
+        let backup_file = backup_dir.join(format!(
+            "{}{}.{}", BACKUP_PREFIX, Utc::now().format("%Y%m%dT%H%M%S%.9fZ"), extension
+        ));
+        let temp_file = backup_dir.join("backup.tmp");
+        std::fs::write(&temp_file, content)
+            .map_err(|e| MessengerError::Storage(format!("Failed to write backup: {}", e)))?;
+        std::fs::rename(&temp_file, &backup_file)
+            .map_err(|e| MessengerError::Storage(format!("Failed to write backup: {}", e)))?;
+
+        prune_backups(&backup_dir, self.max_backup_files)?;
+
+        info!("Backed up {} messages to {:?}", messages.len(), backup_file);
+        Ok(backup_file)
+    }
+
+    /// Backup files, oldest first
+    pub fn list_backups(&self) -> Result<Vec<PathBuf>> {
+        list_backups(&self.backup_directory())
+    }
+
+    /// Replace the message store with the contents of a backup file; returns the number
+    /// of messages restored
+    pub async fn restore_backup(&mut self, path: &Path) -> Result<usize> {
+        let content = std::fs::read(path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to read backup: {}", e)))?;
+        let compressed = path.extension().is_some_and(|extension| extension == "gz");
+        let messages = Self::decode_messages(&content, compressed)?;
+
+        self.write_messages_file(&messages)?;
+        self.messages = messages.into_iter().map(|message| (message.id, message)).collect();
+        self.index = MessageIndex::build(self.messages.values());
+        self.loaded_since = None;
+        self.generation += 1;
+
+        info!("Restored {} messages from {:?}", self.messages.len(), path);
+        Ok(self.messages.len())
+    }
+
+    /// Get storage statistics
+    pub fn get_stats(&self) -> StorageStats {
+        StorageStats {
+            total_messages: self.messages.len(),
+            storage_size_bytes: self.calculate_storage_size(),
+            oldest_message: self.get_oldest_message_timestamp(),
+            newest_message: self.get_newest_message_timestamp(),
+        }
+    }
+
+    // Private helper methods
+
+    /// The messages file in the configured format, then the one in the other format
+    fn messages_files(&self) -> (PathBuf, PathBuf) {
+        let plain = self.storage_path.join(MESSAGES_FILE);
+        let compressed = self.storage_path.join(COMPRESSED_MESSAGES_FILE);
+        if self.compression_enabled {
+            (compressed, plain)
+        } else {
+            (plain, compressed)
+        }
+    }
+
+    /// Hand each stored message to `visit`: those in the messages file as they are parsed,
+    /// without holding the whole file in memory, then those changed in the log
+    fn visit_messages_file<F: FnMut(Message)>(&self, mut visit: F) -> Result<()> {
+        let changes = self.logged_changes()?;
+        let (current, other) = self.messages_files();
+        let messages_file = match (current.exists(), other.exists()) {
+            (true, _) => Some(current),
+            (false, true) => Some(other),
+            (false, false) => None,
+        };
+        if let Some(messages_file) = messages_file {
+            Self::visit_file(&messages_file, |message| {
+                if !changes.contains_key(&message.id) {
+                    visit(message);
+                }
+            })?;
+        }
+
+        changes.into_values().flatten().for_each(visit);
+        Ok(())
+    }
+
+    /// Parse a messages file one message at a time
+    fn visit_file<F: FnMut(Message)>(messages_file: &Path, visit: F) -> Result<()> {
+        struct MessageSeqVisitor<F>(F);
+
+        impl<'de, F: FnMut(Message)> serde::de::Visitor<'de> for MessageSeqVisitor<F> {
+            type Value = ();
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("an array of messages")
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(mut self, mut seq: A) -> std::result::Result<(), A::Error> {
+                while let Some(message) = seq.next_element()? {
+                    (self.0)(message);
+                }
+                Ok(())
+            }
         }
 
-        let content = std::fs::read_to_string(&messages_file)
+        let file = File::open(messages_file)
             .map_err(|e| MessengerError::Storage(format!("Failed to read messages file: {}", e)))?;
+        let reader: Box<dyn Read> = if messages_file.ends_with(COMPRESSED_MESSAGES_FILE) {
+            Box::new(flate2::read::GzDecoder::new(std::io::BufReader::new(file)))
+        } else {
+            Box::new(std::io::BufReader::new(file))
+        };
+        serde::Deserializer::deserialize_seq(&mut serde_json::Deserializer::from_reader(reader), MessageSeqVisitor(visit))
+            .map_err(|e| MessengerError::Storage(format!("Failed to parse messages: {}", e)))
+    }
+
+    /// The `count` newest messages on disk, newest first, the number of messages on disk
+    /// and the timestamp of the oldest unpinned one
+    fn newest_on_disk(&self, count: usize) -> Result<(Vec<Message>, usize, Option<DateTime<Utc>>)> {
+        /// Orders messages oldest first, so the heap's top is the one to evict
+        struct ByAge(Message);
+
+        impl PartialEq for ByAge {
+            fn eq(&self, other: &Self) -> bool {
+                self.cmp(other) == std::cmp::Ordering::Equal
+            }
+        }
+        impl Eq for ByAge {}
+        impl PartialOrd for ByAge {
+            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+        impl Ord for ByAge {
+            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+                (other.0.timestamp, other.0.id).cmp(&(self.0.timestamp, self.0.id))
+            }
+        }
 
-        let mut all_messages: Vec<Message> = serde_json::from_str(&content)
-            .map_err(|e| MessengerError::Storage(format!("Failed to parse messages: {}", e)))?;
+        let mut newest = BinaryHeap::new();
+        let mut total = 0;
+        let mut oldest_unpinned: Option<DateTime<Utc>> = None;
+        self.visit_messages_file(|message| {
+            total += 1;
+            if !message.pinned && oldest_unpinned.is_none_or(|oldest| message.timestamp < oldest) {
+                oldest_unpinned = Some(message.timestamp);
+            }
+            newest.push(ByAge(message));
+            if newest.len() > count {
+                newest.pop();
+            }
+        })?;
+        Ok((newest.into_sorted_vec().into_iter().map(|ByAge(message)| message).collect(), total, oldest_unpinned))
+    }
 
-        // Remove the message
-        all_messages.retain(|m| m.id != message.id);
+    /// Messages on disk that are not loaded, when `load_recent` left some behind
+    fn disk_only_messages(&self) -> Result<Vec<Message>> {
+        if self.loaded_since.is_none() {
+            return Ok(Vec::new());
+        }
 
-        // Write back to file
-        let content = serde_json::to_string_pretty(&all_messages)
-            .map_err(|e| MessengerError::Storage(format!("Failed to serialize messages: {}", e)))?;
+        let mut on_disk = Vec::new();
+        self.visit_messages_file(|message| {
+            if !self.messages.contains_key(&message.id) {
+                on_disk.push(message);
+            }
+        })?;
+        Ok(on_disk)
+    }
+
+    /// Rewrite the messages file from the in-memory store and any messages `load_recent` left
+    /// on disk, keeping those that pass `keep`
+    fn write_compacted(&self, keep: impl Fn(&Message) -> bool) -> Result<Compaction> {
+        std::fs::create_dir_all(&self.storage_path)
+            .map_err(|e| MessengerError::Storage(format!("Failed to create storage directory: {}", e)))?;
+
+        let on_disk = self.disk_only_messages()?;
+        let mut dropped = HashSet::new();
+        let mut messages: Vec<&Message> = Vec::new();
+        for message in self.messages.values().chain(&on_disk) {
+            if keep(message) {
+                messages.push(message);
+            } else {
+                dropped.insert(message.id);
+            }
+        }
+        messages.sort_by_key(|message| message.timestamp);
+        self.write_messages_file(&messages)?;
+
+        let oldest_on_disk = on_disk.iter()
+            .filter(|message| !message.pinned && !dropped.contains(&message.id))
+            .map(|message| message.timestamp)
+            .min();
+        Ok(Compaction { written: messages.len(), dropped, oldest_on_disk })
+    }
 
-        std::fs::write(&messages_file, content)
+    /// Replace the messages file in the configured format, remove any copy left in the
+    /// other one and clear the log, whose changes `messages` must already include
+    fn write_messages_file<T: Serialize>(&self, messages: &[T]) -> Result<()> {
+        let content = Self::encode_messages(messages, self.compression_enabled)?;
+        let (messages_file, stale_file) = self.messages_files();
+
+        // Write beside the real file and rename over it, so a crash mid-write leaves
+        // the previous file intact rather than a truncated one
+        let mut temp_name = messages_file.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp_file = messages_file.with_file_name(temp_name);
+        File::create(&temp_file)
+            .and_then(|mut file| {
+                file.write_all(&content)?;
+                file.sync_all()
+            })
             .map_err(|e| MessengerError::Storage(format!("Failed to write messages file: {}", e)))?;
+        std::fs::rename(&temp_file, &messages_file)
+            .map_err(|e| MessengerError::Storage(format!("Failed to replace messages file: {}", e)))?;
+        if stale_file.exists() {
+            std::fs::remove_file(&stale_file)
+                .map_err(|e| MessengerError::Storage(format!("Failed to remove old messages file: {}", e)))?;
+        }
 
+        // A crash before this point replays the log over a file that already has its changes,
+        // which leaves the same messages
+        let log_file = self.storage_path.join(MESSAGES_LOG);
+        if log_file.exists() {
+            std::fs::remove_file(&log_file)
+                .map_err(|e| MessengerError::Storage(format!("Failed to clear messages log: {}", e)))?;
+        }
         Ok(())
     }
 
-    async fn cleanup_old_messages(&mut self) -> Result<()> {
-        let cutoff_date = Utc::now() - chrono::Duration::days(30);
-        
-        let old_message_ids: Vec<Uuid> = self.messages
-            .iter()
-            .filter(|(_, msg)| msg.timestamp < cutoff_date)
-            .map(|(id, _)| *id)
-            .collect();
+    /// Append a change to the log, rewriting the messages file once the log grows large
+    fn append_to_log(&self, entry: &LogEntry<&Message>) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)
+            .map_err(|e| MessengerError::Storage(format!("Failed to serialize log entry: {}", e)))?;
+        line.push(b'\n');
+
+        let log_size = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.storage_path.join(MESSAGES_LOG))
+            .and_then(|mut file| {
+                file.write_all(&line)?;
+                file.sync_data()?;
+                file.metadata()
+            })
+            .map_err(|e| MessengerError::Storage(format!("Failed to write messages log: {}", e)))?
+            .len();
+
+        if log_size > LOG_COMPACTION_BYTES {
+            self.write_compacted(|_| true)?;
+        }
+        Ok(())
+    }
+
+    /// The latest logged state of each message the log mentions: `None` once deleted.
+    /// A line cut short by a crash mid-append is skipped.
+    fn logged_changes(&self) -> Result<HashMap<Uuid, Option<Message>>> {
+        let log_file = self.storage_path.join(MESSAGES_LOG);
+        if !log_file.exists() {
+            return Ok(HashMap::new());
+        }
 
-        let count = old_message_ids.len();
-        for message_id in old_message_ids {
-            if let Some(message) = self.messages.remove(&message_id) {
-                self.remove_message_from_disk(&message).await?;
+        let content = std::fs::read_to_string(&log_file)
+            .map_err(|e| MessengerError::Storage(format!("Failed to read messages log: {}", e)))?;
+        let mut changes = HashMap::new();
+        for line in content.lines() {
+            match serde_json::from_str::<LogEntry<Message>>(line) {
+                Ok(LogEntry::Store(message)) => {
+                    changes.insert(message.id, Some(message));
+                }
+                Ok(LogEntry::Delete(message_id)) => {
+                    changes.insert(message_id, None);
+                }
+                Err(e) => warn!("Skipping unreadable messages log entry: {}", e),
             }
         }
+        Ok(changes)
+    }
 
-        info!("Cleaned up {} old messages", count);
-        Ok(())
+    /// `messages` with the changes in the log applied
+    fn apply_logged_changes(&self, messages: Vec<Message>) -> Result<Vec<Message>> {
+        let changes = self.logged_changes()?;
+        let mut merged: Vec<Message> = messages.into_iter()
+            .filter(|message| !changes.contains_key(&message.id))
+            .collect();
+        merged.extend(changes.into_values().flatten());
+        Ok(merged)
+    }
+
+    /// Serialize messages as JSON, gzip-compressed if `compressed` is set
+    fn encode_messages<T: Serialize>(messages: &[T], compressed: bool) -> Result<Vec<u8>> {
+        let content = serde_json::to_vec_pretty(messages)
+            .map_err(|e| MessengerError::Storage(format!("Failed to serialize messages: {}", e)))?;
+        if !compressed {
+            return Ok(content);
+        }
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&content)
+            .map_err(|e| MessengerError::Storage(format!("Failed to compress messages: {}", e)))?;
+        encoder.finish()
+            .map_err(|e| MessengerError::Storage(format!("Failed to compress messages: {}", e)))
+    }
+
+    /// Parse messages written by `encode_messages`
+    fn decode_messages(content: &[u8], compressed: bool) -> Result<Vec<Message>> {
+        if !compressed {
+            return serde_json::from_slice(content)
+                .map_err(|e| MessengerError::Storage(format!("Failed to parse messages: {}", e)));
+        }
+
+        let mut decompressed = Vec::new();
+        flate2::read::GzDecoder::new(content)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| MessengerError::Storage(format!("Failed to decompress messages file: {}", e)))?;
+        Self::decode_messages(&decompressed, false)
+    }
+
+    fn backup_directory(&self) -> PathBuf {
+        let mut backup_path = self.storage_path.parent().unwrap().to_path_buf();
+        backup_path.push("backups");
+        backup_path
+    }
+
+    /// Messages from the newest backup that can still be read
+    fn read_latest_backup(&self) -> Option<(PathBuf, Vec<Message>)> {
+        self.list_backups().ok()?.into_iter().rev().find_map(|path| {
+            let content = std::fs::read(&path).ok()?;
+            let compressed = path.extension().is_some_and(|extension| extension == "gz");
+            let messages = Self::decode_messages(&content, compressed).ok()?;
+            Some((path, messages))
+        })
+    }
+
+    async fn persist_message(&self, message: &Message) -> Result<()> {
+        self.append_to_log(&LogEntry::Store(message))
     }
 
-    async fn get_export_path(&self, format: &ExportFormat) -> Result<PathBuf> {
-        let mut export_path = self.storage_path.parent().unwrap().to_path_buf();
+    async fn remove_message_from_disk(&self, message: &Message) -> Result<()> {
+        self.append_to_log(&LogEntry::Delete(message.id))
+    }
+
+    fn get_export_path(storage_path: &Path, format: &ExportFormat) -> Result<PathBuf> {
+        let mut export_path = storage_path.parent().unwrap().to_path_buf();
         export_path.push("exports");
         std::fs::create_dir_all(&export_path)
             .map_err(|e| MessengerError::Storage(format!("Failed to create export directory: {}", e)))?;
@@ -408,23 +1583,29 @@ impl MessageStorage {
         Ok(export_path)
     }
 
-    async fn export_to_json(&self, messages: &[&Message], path: &Path) -> Result<()> {
-        let file = File::create(path)
-            .map_err(|e| MessengerError::Storage(format!("Failed to create export file: {}", e)))?;
-        
-        let mut writer = BufWriter::new(file);
-        serde_json::to_writer_pretty(&mut writer, messages)
-            .map_err(|e| MessengerError::Storage(format!("Failed to write JSON export: {}", e)))?;
+    fn export_to_json<W: Write>(messages: &[&Message], include_metadata: bool, timestamp_format: TimestampFormat, writer: &mut W) -> Result<()> {
+        use serde::ser::{SerializeSeq, Serializer};
+
+        let json_error = |e: serde_json::Error| MessengerError::Storage(format!("Failed to write JSON export: {}", e));
+        let mut serializer = serde_json::Serializer::pretty(&mut *writer);
+        let mut seq = serializer.serialize_seq(Some(messages.len())).map_err(json_error)?;
+        for message in messages {
+            if include_metadata {
+                seq.serialize_element(&ExportedMessage { message, timestamp_format }).map_err(json_error)?;
+            } else {
+                let message = Message { metadata: HashMap::new(), ..(*message).clone() };
+                seq.serialize_element(&ExportedMessage { message: &message, timestamp_format }).map_err(json_error)?;
+            }
+        }
+        seq.end().map_err(json_error)?;
 
         Ok(())
     }
 
-    async fn export_to_csv(&self, messages: &[&Message], path: &Path) -> Result<()> {
-        let file = File::create(path)
-            .map_err(|e| MessengerError::Storage(format!("Failed to create export file: {}", e)))?;
-        
-        let mut writer = BufWriter::new(file);
-        writer.write_all(b"id,timestamp,sender_id,type,content,status\n")
+    fn export_to_csv<W: Write>(messages: &[&Message], writer: &mut W) -> Result<()> {
+        // The csv writer quotes fields as needed and doubles embedded quotes (RFC 4180)
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        csv_writer.write_record(["id", "timestamp", "sender_id", "type", "content", "status"])
             .map_err(|e| MessengerError::Storage(format!("Failed to write CSV header: {}", e)))?;
 
         for message in messages {
@@ -434,24 +1615,22 @@ impl MessageStorage {
                 _ => "",
             };
 
-            writeln!(writer, "{},{},{},{:?},{},{:?}",
-                message.id,
+            csv_writer.write_record([
+                message.id.to_string(),
                 message.timestamp.to_rfc3339(),
-                message.sender_id,
-                message.message_type,
-                content.replace('\n', " ").replace('\r', " "),
-                message.status
-            ).map_err(|e| MessengerError::Storage(format!("Failed to write CSV row: {}", e)))?;
+                message.sender_id.to_string(),
+                format!("{:?}", message.message_type),
+                content.to_string(),
+                format!("{:?}", message.status),
+            ]).map_err(|e| MessengerError::Storage(format!("Failed to write CSV row: {}", e)))?;
         }
 
+        csv_writer.flush()
+            .map_err(|e| MessengerError::Storage(format!("Failed to flush CSV export: {}", e)))?;
         Ok(())
     }
 
-    async fn export_to_txt(&self, messages: &[&Message], path: &Path) -> Result<()> {
-        let file = File::create(path)
-            .map_err(|e| MessengerError::Storage(format!("Failed to create export file: {}", e)))?;
-        
-        let mut writer = BufWriter::new(file);
+    fn export_to_txt<W: Write>(messages: &[&Message], writer: &mut W) -> Result<()> {
 
         for message in messages {
             writeln!(writer, "[{}] {} ({})",
@@ -481,11 +1660,7 @@ impl MessageStorage {
         Ok(())
     }
 
-    async fn export_to_html(&self, messages: &[&Message], path: &Path) -> Result<()> {
-        let file = File::create(path)
-            .map_err(|e| MessengerError::Storage(format!("Failed to create export file: {}", e)))?;
-        
-        let mut writer = BufWriter::new(file);
+    fn export_to_html<W: Write>(messages: &[&Message], writer: &mut W) -> Result<()> {
 
         writeln!(writer, r#"<!DOCTYPE html>
 <html>
@@ -563,72 +1738,1339 @@ impl MessageStorage {
     }
 }
 
-/// Storage statistics
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct StorageStats {
-    pub total_messages: usize,
-    pub storage_size_bytes: u64,
-    pub oldest_message: Option<DateTime<Utc>>,
-    pub newest_message: Option<DateTime<Utc>>,
+/// A message as written to a JSON export, with its timestamp in the requested format
+struct ExportedMessage<'a> {
+    message: &'a Message,
+    timestamp_format: TimestampFormat,
 }
 
-impl Default for StorageStats {
-    fn default() -> Self {
-        Self {
-            total_messages: 0,
-            storage_size_bytes: 0,
-            oldest_message: None,
-            newest_message: None,
-        }
+impl Serialize for ExportedMessage<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::Error;
+
+        let timestamp = match self.timestamp_format {
+            TimestampFormat::Rfc3339 => return self.message.serialize(serializer),
+            TimestampFormat::EpochSeconds => self.message.timestamp.timestamp(),
+            TimestampFormat::EpochMillis => self.message.timestamp.timestamp_millis(),
+        };
+
+        let mut value = serde_json::to_value(self.message).map_err(S::Error::custom)?;
+        value["timestamp"] = timestamp.into();
+        value.serialize(serializer)
     }
 }
 
-/// HTML escape function
-fn html_escape(s: &str) -> String {
-    s.replace('&', "&amp;")
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;")
-        .replace('\'', "&#x27;")
-}
+#[async_trait]
+impl StorageBackend for MessageStorage {
+    async fn initialize(&mut self) -> Result<()> {
+        MessageStorage::initialize(self).await
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::types::MessageType;
+    async fn store_message(&mut self, message: Message) -> Result<()> {
+        MessageStorage::store_message(self, message).await
+    }
 
-    #[tokio::test]
-    async fn test_message_storage() {
-        let mut storage = MessageStorage::new();
-        storage.initialize().await.unwrap();
+    fn get_message(&self, message_id: &Uuid) -> Result<Option<Message>> {
+        Ok(MessageStorage::get_message(self, message_id).cloned())
+    }
 
-        let message = Message::new_text("Hello, World!".to_string(), Uuid::new_v4());
-        storage.store_message(message.clone()).await.unwrap();
+    fn get_messages_with_filter(&self, filter: &MessageFilter) -> Result<Vec<Message>> {
+        Ok(MessageStorage::get_messages_with_filter(self, filter).into_iter().cloned().collect())
+    }
 
-        let retrieved = storage.get_message(&message.id);
-        assert!(retrieved.is_some());
-        assert_eq!(retrieved.unwrap().id, message.id);
+    fn search_messages(&self, search: &MessageSearch) -> Result<Vec<Message>> {
+        Ok(MessageStorage::search_messages(self, search).into_iter().cloned().collect())
     }
 
-    #[tokio::test]
-    async fn test_message_filtering() {
-        let mut storage = MessageStorage::new();
-        storage.initialize().await.unwrap();
+    async fn delete_message(&mut self, message_id: &Uuid) -> Result<()> {
+        MessageStorage::delete_message(self, message_id).await
+    }
 
-        let sender_id = Uuid::new_v4();
-        let message1 = Message::new_text("Message 1".to_string(), sender_id);
-        let message2 = Message::new_text("Message 2".to_string(), Uuid::new_v4());
+    async fn clear_all_messages(&mut self) -> Result<()> {
+        MessageStorage::clear_all_messages(self).await
+    }
 
-        storage.store_message(message1.clone()).await.unwrap();
-        storage.store_message(message2.clone()).await.unwrap();
+    fn get_stats(&self) -> Result<StorageStats> {
+        Ok(MessageStorage::get_stats(self))
+    }
 
-        let filter = MessageFilter {
-            sender_ids: Some(vec![sender_id]),
-            ..Default::default()
-        };
+    async fn cleanup_expired_messages(&mut self) -> Result<usize> {
+        MessageStorage::cleanup_expired_messages(self).await
+    }
+
+    async fn export_messages(&self, options: &ExportOptions) -> Result<PathBuf> {
+        MessageStorage::export_messages(self, options).await
+    }
+
+    async fn backup(&self) -> Result<PathBuf> {
+        MessageStorage::backup(self).await
+    }
+
+    fn get_page(&self, offset: usize, limit: Option<usize>) -> Result<Vec<Message>> {
+        MessageStorage::get_page(self, offset, limit)
+    }
+
+    fn get_reactions(&self, target_id: &Uuid) -> Result<Vec<Message>> {
+        Ok(MessageStorage::get_reactions(self, target_id).into_iter().cloned().collect())
+    }
+
+    fn search_messages_streaming(&self, search: &MessageSearch, cancelled: &AtomicBool, on_match: &mut dyn FnMut(&Message)) -> Result<Option<usize>> {
+        Ok(MessageStorage::search_messages_streaming(self, search, cancelled, on_match))
+    }
+
+    fn select_for_export(&self, options: &ExportOptions) -> Result<Vec<Message>> {
+        Ok(MessageStorage::select_for_export(self, options).into_iter().cloned().collect())
+    }
+
+    fn message_histogram(&self, bucket: std::time::Duration, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<HistogramBucket>> {
+        MessageStorage::message_histogram(self, bucket, start, end)
+    }
+
+    fn get_unread_count(&self, local_id: &Uuid) -> Result<usize> {
+        Ok(MessageStorage::get_unread_count(self, local_id))
+    }
+
+    async fn set_message_status(&mut self, message_id: &Uuid, status: MessageStatus) -> Result<bool> {
+        MessageStorage::set_message_status(self, message_id, status).await
+    }
+
+    async fn mark_message_read(&mut self, message_id: &Uuid) -> Result<()> {
+        MessageStorage::mark_message_read(self, message_id).await
+    }
+
+    async fn set_message_pinned(&mut self, message_id: &Uuid, pinned: bool) -> Result<()> {
+        MessageStorage::set_message_pinned(self, message_id, pinned).await
+    }
+
+    async fn apply_edit(&mut self, edit: &Message) -> Result<()> {
+        MessageStorage::apply_edit(self, edit).await
+    }
+
+    async fn trim_history(&mut self, target_max_messages: usize) -> Result<usize> {
+        MessageStorage::trim_history(self, target_max_messages).await
+    }
+
+    async fn import_messages(&mut self, messages: Vec<Message>, policy: ImportConflictPolicy) -> Result<ImportReport> {
+        MessageStorage::import_messages(self, messages, policy).await
+    }
+
+    async fn compact(&mut self) -> Result<()> {
+        MessageStorage::compact(self).await
+    }
+
+    fn build_index(&self) -> Result<Option<(MessageIndex, u64)>> {
+        MessageStorage::build_index(self).map(Some)
+    }
+
+    fn replace_index(&mut self, index: MessageIndex, generation: u64) -> bool {
+        MessageStorage::replace_index(self, index, generation)
+    }
+
+    fn rebuild_index(&mut self) -> Result<()> {
+        MessageStorage::rebuild_index(self)
+    }
+}
+
+/// Storage statistics
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StorageStats {
+    pub total_messages: usize,
+    pub storage_size_bytes: u64,
+    pub oldest_message: Option<DateTime<Utc>>,
+    pub newest_message: Option<DateTime<Utc>>,
+}
+
+impl Default for StorageStats {
+    fn default() -> Self {
+        Self {
+            total_messages: 0,
+            storage_size_bytes: 0,
+            oldest_message: None,
+            newest_message: None,
+        }
+    }
+}
+
+/// Back up `storage` every `interval`, starting one interval from now. Runs until the
+/// task is dropped; failed backups are logged and retried at the next interval.
+pub async fn run_backups(storage: std::sync::Arc<tokio::sync::RwLock<Box<dyn StorageBackend>>>, interval: std::time::Duration) {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = storage.read().await.backup().await {
+            warn!("Scheduled backup failed: {}", e);
+        }
+    }
+}
+
+/// Write an export through a temporary file that is renamed into place only on success,
+/// so a failed export never leaves a partial file at the final path
+fn write_export<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> Result<()>,
+{
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path)
+        .map_err(|e| MessengerError::Storage(format!("Failed to create export file: {}", e)))
+        .and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+
+            writer.flush()
+                .map_err(|e| MessengerError::Storage(format!("Failed to flush export file: {}", e)))?;
+            writer.get_ref().sync_all()
+                .map_err(|e| MessengerError::Storage(format!("Failed to sync export file: {}", e)))?;
+
+            std::fs::rename(&temp_path, path)
+                .map_err(|e| MessengerError::Storage(format!("Failed to finalize export file: {}", e)))
+        });
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    result
+}
+
+/// HTML escape function
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#x27;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_storage() -> (MessageStorage, PathBuf) {
+        let (storage, config) = temp_storage_with(|_| {});
+        (storage, config.data_directory)
+    }
+
+    fn temp_storage_with(configure: impl FnOnce(&mut StorageConfig)) -> (MessageStorage, StorageConfig) {
+        let config = StorageConfig::temp(configure);
+        (MessageStorage::with_config(&config), config)
+    }
+
+    #[tokio::test]
+    async fn test_message_storage() {
+        let mut storage = MessageStorage::new();
+        storage.initialize().await.unwrap();
+
+        let message = Message::new_text("Hello, World!".to_string(), Uuid::new_v4());
+        storage.store_message(message.clone()).await.unwrap();
+
+        let retrieved = storage.get_message(&message.id);
+        assert!(retrieved.is_some());
+        assert_eq!(retrieved.unwrap().id, message.id);
+    }
+
+    #[test]
+    fn test_failed_export_leaves_no_file() {
+        let dir = StorageConfig::temp(|_| {}).data_directory;
+        std::fs::create_dir_all(&dir).unwrap();
+        let export_path = dir.join("messages.json");
+
+        let result = write_export(&export_path, |writer| {
+            writer.write_all(b"[{\"partial\":").unwrap();
+            Err(MessengerError::Storage("simulated failure".to_string()))
+        });
+
+        assert!(result.is_err());
+        assert!(!export_path.exists());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        write_export(&export_path, |writer| {
+            writer.write_all(b"[]").map_err(|e| MessengerError::Storage(e.to_string()))
+        }).unwrap();
+        assert_eq!(std::fs::read_to_string(&export_path).unwrap(), "[]");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Exercise the operations every backend shares
+    async fn run_backend_suite(backend: &mut dyn StorageBackend) {
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let now = Utc::now();
+
+        let mut old = Message::new_text("Old news from Alice".to_string(), alice);
+        old.timestamp = now - chrono::Duration::days(2);
+        let mut recent = Message::new_text("Hello, world".to_string(), alice);
+        recent.timestamp = now - chrono::Duration::hours(3);
+        let mut greeting = Message::new_text("Bob says hello".to_string(), bob);
+        greeting.timestamp = now - chrono::Duration::hours(2);
+        greeting.metadata.insert("topic".to_string(), "greeting".to_string());
+        let mut file = Message::new_file("notes.txt".to_string(), 3, "text/plain".to_string(), Some(vec![1, 2, 3]), bob);
+        file.timestamp = now - chrono::Duration::hours(1);
+        for message in [&old, &recent, &greeting, &file] {
+            backend.store_message(message.clone()).await.unwrap();
+        }
+
+        assert_eq!(backend.get_message(&recent.id).unwrap(), Some(recent.clone()));
+        assert_eq!(backend.get_message(&Uuid::new_v4()).unwrap(), None);
+
+        // Storing the same id again replaces the message
+        recent.status = crate::types::MessageStatus::Delivered;
+        backend.store_message(recent.clone()).await.unwrap();
+        assert_eq!(backend.get_message(&recent.id).unwrap(), Some(recent.clone()));
+
+        let ids = |messages: Vec<Message>| messages.iter().map(|message| message.id).collect::<Vec<_>>();
+        let filter = MessageFilter {
+            sender_ids: Some(vec![alice]),
+            start_date: Some(now - chrono::Duration::days(1)),
+            end_date: Some(now),
+            ..Default::default()
+        };
+        assert_eq!(ids(backend.get_messages_with_filter(&filter).unwrap()), vec![recent.id]);
+
+        let filter = MessageFilter {
+            message_types: Some(vec![MessageType::Text { content: String::new() }]),
+            ..Default::default()
+        };
+        assert_eq!(ids(backend.get_messages_with_filter(&filter).unwrap()), vec![greeting.id, recent.id, old.id]);
+
+        let filter = MessageFilter {
+            status: Some(vec![crate::types::MessageStatus::Delivered]),
+            ..Default::default()
+        };
+        assert_eq!(ids(backend.get_messages_with_filter(&filter).unwrap()), vec![recent.id]);
+
+        let filter = MessageFilter { offset: Some(1), limit: Some(2), ..Default::default() };
+        assert_eq!(ids(backend.get_messages_with_filter(&filter).unwrap()), vec![greeting.id, recent.id]);
+
+        let mut search = MessageSearch {
+            query: "HELLO".to_string(),
+            case_sensitive: false,
+            search_content: true,
+            search_metadata: false,
+            filter: None,
+        };
+        assert_eq!(ids(backend.search_messages(&search).unwrap()), vec![greeting.id, recent.id]);
+        search.filter = Some(MessageFilter { sender_ids: Some(vec![alice]), ..Default::default() });
+        assert_eq!(ids(backend.search_messages(&search).unwrap()), vec![recent.id]);
+        let search = MessageSearch {
+            query: "greeting".to_string(),
+            case_sensitive: true,
+            search_content: false,
+            search_metadata: true,
+            filter: None,
+        };
+        assert_eq!(ids(backend.search_messages(&search).unwrap()), vec![greeting.id]);
+
+        let stats = backend.get_stats().unwrap();
+        assert_eq!(stats.total_messages, 4);
+        assert_eq!(stats.oldest_message, Some(old.timestamp));
+        assert_eq!(stats.newest_message, Some(file.timestamp));
+
+        backend.delete_message(&old.id).await.unwrap();
+        assert_eq!(backend.get_message(&old.id).unwrap(), None);
+        assert_eq!(backend.get_stats().unwrap().total_messages, 3);
+
+        // Replies are stored even when their target is gone, and can be looked up by target
+        let reply = Message::new_text_reply("Hi Bob".to_string(), alice, greeting.id);
+        let orphan = Message::new_text_reply("About that old news".to_string(), bob, old.id);
+        backend.store_message(reply.clone()).await.unwrap();
+        backend.store_message(orphan.clone()).await.unwrap();
+        let filter = MessageFilter { reply_to: Some(greeting.id), ..Default::default() };
+        assert_eq!(ids(backend.get_messages_with_filter(&filter).unwrap()), vec![reply.id]);
+        let filter = MessageFilter { reply_to: Some(old.id), ..Default::default() };
+        assert_eq!(ids(backend.get_messages_with_filter(&filter).unwrap()), vec![orphan.id]);
+
+        // What the app does beyond the core operations works the same on every backend
+        let hour = std::time::Duration::from_secs(3600);
+        let buckets = backend.message_histogram(hour, now - chrono::Duration::hours(4), now).unwrap();
+        assert_eq!(buckets.iter().map(|bucket| bucket.count).collect::<Vec<_>>(), vec![0, 1, 1, 1]);
+
+        let before_replies = MessageFilter { end_date: Some(now), ..Default::default() };
+        assert_eq!(ids(backend.get_messages_with_filter(&before_replies).unwrap()), vec![file.id, greeting.id, recent.id]);
+        assert_eq!(backend.get_page(0, None).unwrap().len(), 5);
+        assert_eq!(backend.get_page(4, Some(2)).unwrap(), vec![recent.clone()]);
+
+        assert_eq!(backend.get_unread_count(&alice).unwrap(), 3);
+        backend.mark_message_read(&greeting.id).await.unwrap();
+        assert!(backend.get_message(&greeting.id).unwrap().unwrap().read);
+        assert_eq!(backend.get_unread_count(&alice).unwrap(), 2);
+        assert!(backend.set_message_status(&file.id, crate::types::MessageStatus::Delivered).await.unwrap());
+        assert!(!backend.set_message_status(&file.id, crate::types::MessageStatus::Delivered).await.unwrap());
+        assert!(backend.mark_message_read(&Uuid::new_v4()).await.is_err());
+
+        let reaction = Message::new_reaction(file.id, "👍".to_string(), alice);
+        backend.store_message(reaction.clone()).await.unwrap();
+        assert_eq!(backend.get_reactions(&file.id).unwrap(), vec![reaction.clone()]);
+
+        let cancelled = AtomicBool::new(false);
+        let mut streamed = Vec::new();
+        let search = MessageSearch { query: "hello".to_string(), case_sensitive: false, search_content: true, search_metadata: false, filter: None };
+        assert_eq!(backend.search_messages_streaming(&search, &cancelled, &mut |message| streamed.push(message.id)).unwrap(), Some(2));
+        streamed.sort();
+        let mut expected = vec![greeting.id, recent.id];
+        expected.sort();
+        assert_eq!(streamed, expected);
+
+        let mut changed = file.clone();
+        changed.status = crate::types::MessageStatus::Failed;
+        let imported = Message::new_text("From the archive".to_string(), bob);
+        let report = backend.import_messages(vec![changed.clone(), imported.clone()], ImportConflictPolicy::Skip).await.unwrap();
+        assert_eq!(report, ImportReport { imported: 1, skipped: 1, overwritten: 0 });
+        let report = backend.import_messages(vec![changed.clone()], ImportConflictPolicy::Overwrite).await.unwrap();
+        assert_eq!(report, ImportReport { imported: 0, skipped: 0, overwritten: 1 });
+        assert_eq!(backend.get_message(&file.id).unwrap(), Some(changed));
+
+        let options = ExportOptions {
+            format: ExportFormat::Json,
+            include_metadata: true,
+            include_system_messages: true,
+            date_range: None,
+            filter: Some(MessageFilter { sender_ids: Some(vec![bob]), end_date: Some(now), ..Default::default() }),
+            timestamp_format: TimestampFormat::Rfc3339,
+        };
+        assert_eq!(ids(backend.select_for_export(&options).unwrap()), vec![file.id, greeting.id]);
+        let exported = MessageStorage::read_archive(&backend.export_messages(&options).await.unwrap()).unwrap();
+        assert_eq!(ids(exported), vec![file.id, greeting.id]);
+        assert!(backend.backup().await.unwrap().exists());
+
+        // Pinned messages outlive trimming; the oldest unpinned one goes first
+        backend.set_message_pinned(&recent.id, true).await.unwrap();
+        let total = backend.get_stats().unwrap().total_messages;
+        assert_eq!(backend.trim_history(total - 1).await.unwrap(), 1);
+        assert!(backend.get_message(&recent.id).unwrap().is_some());
+        assert_eq!(backend.get_message(&greeting.id).unwrap(), None);
+        assert_eq!(backend.cleanup_expired_messages().await.unwrap(), 0);
+
+        // Edits replace the content of a text message and keep what it said before
+        let draft = Message::new_text("See you at 5".to_string(), alice);
+        backend.store_message(draft.clone()).await.unwrap();
+        let mut first = Message::new_edit(draft.id, "See you at 6".to_string(), alice);
+        first.timestamp = draft.timestamp + chrono::Duration::minutes(1);
+        let mut second = Message::new_edit(draft.id, "See you at 7".to_string(), alice);
+        second.timestamp = draft.timestamp + chrono::Duration::minutes(2);
+        backend.apply_edit(&first).await.unwrap();
+        backend.apply_edit(&second).await.unwrap();
+
+        let edited = backend.get_message(&draft.id).unwrap().unwrap();
+        assert!(matches!(&edited.message_type, MessageType::Text { content } if content == "See you at 7"));
+        let history: Vec<&str> = edited.edit_history.iter().map(|revision| revision.content.as_str()).collect();
+        assert_eq!(history, vec!["See you at 5", "See you at 6"]);
+        assert_eq!(edited.edit_history[1].replaced_at, second.timestamp);
+        assert_eq!(edited.metadata[crate::types::EDITED_AT_METADATA_KEY], second.timestamp.to_rfc3339());
+
+        // Edits to missing or non-text messages, or by anyone but the sender, change nothing
+        let missing = Message::new_edit(Uuid::new_v4(), "Changed".to_string(), alice);
+        assert!(matches!(backend.apply_edit(&missing).await, Err(MessengerError::ResourceNotFound(_))));
+        let non_text = Message::new_edit(file.id, "Changed".to_string(), bob);
+        assert!(matches!(backend.apply_edit(&non_text).await, Err(MessengerError::InvalidMessageType(_))));
+        let impostor = Message::new_edit(draft.id, "Changed".to_string(), bob);
+        assert!(matches!(backend.apply_edit(&impostor).await, Err(MessengerError::PermissionDenied(_))));
+        assert_eq!(backend.get_message(&draft.id).unwrap(), Some(edited));
+
+        backend.clear_all_messages().await.unwrap();
+        assert_eq!(backend.get_stats().unwrap().total_messages, 0);
+        assert!(backend.get_messages_with_filter(&MessageFilter::default()).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_backend_suite_json() {
+        let config = StorageConfig::temp(|_| {});
+        let mut backend = open_backend(&config).await.unwrap();
+        run_backend_suite(backend.as_mut()).await;
+        std::fs::remove_dir_all(&config.data_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backend_suite_sqlite() {
+        let config = StorageConfig::temp(|config| config.backend = StorageBackendKind::Sqlite);
+        let mut backend = open_backend(&config).await.unwrap();
+        run_backend_suite(backend.as_mut()).await;
+        std::fs::remove_dir_all(&config.data_directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_filtering() {
+        let mut storage = MessageStorage::new();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let message1 = Message::new_text("Message 1".to_string(), sender_id);
+        let message2 = Message::new_text("Message 2".to_string(), Uuid::new_v4());
+
+        storage.store_message(message1.clone()).await.unwrap();
+        storage.store_message(message2.clone()).await.unwrap();
+
+        let filter = MessageFilter {
+            sender_ids: Some(vec![sender_id]),
+            ..Default::default()
+        };
 
         let filtered = storage.get_messages_with_filter(&filter);
         assert_eq!(filtered.len(), 1);
         assert_eq!(filtered[0].id, message1.id);
     }
+
+    #[tokio::test]
+    async fn test_filter_by_sender_and_date_range() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+
+        let mut old_message = Message::new_text("Old".to_string(), alice);
+        old_message.timestamp = Utc::now() - chrono::Duration::days(2);
+        let recent_message = Message::new_text("Recent".to_string(), alice);
+        let other_message = Message::new_text("Other".to_string(), bob);
+
+        storage.store_message(old_message.clone()).await.unwrap();
+        storage.store_message(recent_message.clone()).await.unwrap();
+        storage.store_message(other_message.clone()).await.unwrap();
+
+        let filter = MessageFilter {
+            sender_ids: Some(vec![alice]),
+            start_date: Some(Utc::now() - chrono::Duration::days(1)),
+            end_date: Some(Utc::now()),
+            ..Default::default()
+        };
+
+        let filtered = storage.get_messages_with_filter(&filter);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].id, recent_message.id);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sender_filter_uses_index() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let senders: Vec<Uuid> = (0..20).map(|_| Uuid::new_v4()).collect();
+        let mut messages = Vec::new();
+        for i in 0..1000 {
+            let message = Message::new_text(format!("Message {}", i), senders[i % senders.len()]);
+            messages.push(message.clone());
+            storage.messages.insert(message.id, message.clone());
+            storage.index.insert(&message);
+        }
+        storage.delete_message(&messages[0].id).await.unwrap();
+
+        let filter = MessageFilter {
+            sender_ids: Some(vec![senders[0], senders[0]]),
+            ..Default::default()
+        };
+        let expected: HashSet<Uuid> = messages[1..].iter()
+            .filter(|message| message.sender_id == senders[0])
+            .map(|message| message.id)
+            .collect();
+
+        // The index hands back exactly the sender's messages instead of the whole store
+        let candidates = storage.indexed_candidates(&filter).unwrap();
+        assert_eq!(candidates.len(), expected.len());
+        assert!(storage.indexed_candidates(&MessageFilter::default()).is_none());
+
+        let filtered: HashSet<Uuid> = storage.get_messages_with_filter(&filter).iter().map(|message| message.id).collect();
+        assert_eq!(filtered, expected);
+
+        let search = MessageSearch {
+            query: "message".to_string(),
+            case_sensitive: false,
+            search_content: true,
+            search_metadata: false,
+            filter: Some(filter),
+        };
+        let found: HashSet<Uuid> = storage.search_messages(&search).iter().map(|message| message.id).collect();
+        assert_eq!(found, expected);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_reactions_for_message() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        let message = Message::new_text("Lunch?".to_string(), alice);
+        let mut first = Message::new_reaction(message.id, "👍".to_string(), bob);
+        first.timestamp = message.timestamp + chrono::Duration::seconds(1);
+        let mut second = Message::new_reaction(message.id, "🎉".to_string(), alice);
+        second.timestamp = message.timestamp + chrono::Duration::seconds(2);
+        // Reactions can arrive for messages this side never saw
+        let unknown_target = Uuid::new_v4();
+        let stray = Message::new_reaction(unknown_target, "❓".to_string(), bob);
+        for reaction in [&message, &second, &first, &stray] {
+            storage.store_message(reaction.clone()).await.unwrap();
+        }
+
+        let reactions: Vec<Uuid> = storage.get_reactions(&message.id).iter().map(|reaction| reaction.id).collect();
+        assert_eq!(reactions, vec![first.id, second.id]);
+        assert_eq!(storage.get_reactions(&unknown_target).len(), 1);
+        assert!(storage.get_reactions(&first.id).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_search_content_and_metadata() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let greeting = Message::new_text("Hello There".to_string(), sender_id);
+        let mut tagged = Message::new_text("Unrelated".to_string(), sender_id);
+        tagged.metadata.insert("topic".to_string(), "hello-world".to_string());
+
+        storage.store_message(greeting.clone()).await.unwrap();
+        storage.store_message(tagged.clone()).await.unwrap();
+
+        let mut search = MessageSearch {
+            query: "hello".to_string(),
+            case_sensitive: false,
+            search_content: true,
+            search_metadata: false,
+            filter: None,
+        };
+
+        // Case-insensitive content match only
+        let results = storage.search_messages(&search);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, greeting.id);
+
+        // Case-sensitive content search finds nothing
+        search.case_sensitive = true;
+        assert!(storage.search_messages(&search).is_empty());
+
+        // Metadata-only match
+        search.search_content = false;
+        search.search_metadata = true;
+        let results = storage.search_messages(&search);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, tagged.id);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_search_matches_sequential() {
+        let mut storage = MessageStorage::new();
+        let sender_id = Uuid::new_v4();
+        let now = Utc::now();
+
+        for i in 0..20_000 {
+            let mut message = Message::new_text(format!("message {} {}", i, if i % 7 == 0 { "needle" } else { "hay" }), sender_id);
+            // Plenty of shared timestamps to exercise the tie-break
+            message.timestamp = now - chrono::Duration::seconds(i % 100);
+            storage.messages.insert(message.id, message);
+        }
+
+        let search = MessageSearch {
+            query: "NEEDLE".to_string(),
+            case_sensitive: false,
+            search_content: true,
+            search_metadata: false,
+            filter: None,
+        };
+
+        storage.parallel_search_threshold = usize::MAX;
+        let sequential: Vec<Uuid> = storage.search_messages(&search).iter().map(|m| m.id).collect();
+        storage.parallel_search_threshold = 0;
+        let parallel: Vec<Uuid> = storage.search_messages(&search).iter().map(|m| m.id).collect();
+
+        assert_eq!(sequential.len(), 20_000 / 7 + 1);
+        assert_eq!(sequential, parallel);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_search() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        for i in 0..5 {
+            storage.store_message(Message::new_text(format!("match {}", i), sender_id)).await.unwrap();
+        }
+        storage.store_message(Message::new_text("other".to_string(), sender_id)).await.unwrap();
+
+        let search = MessageSearch {
+            query: "match".to_string(),
+            case_sensitive: false,
+            search_content: true,
+            search_metadata: false,
+            filter: None,
+        };
+
+        // Every match is emitted individually and the total agrees
+        let cancelled = AtomicBool::new(false);
+        let mut emitted = Vec::new();
+        let total = storage.search_messages_streaming(&search, &cancelled, |message| emitted.push(message.id));
+        assert_eq!(total, Some(5));
+        assert_eq!(emitted.len(), 5);
+
+        let mut expected: Vec<Uuid> = storage.search_messages(&search).iter().map(|m| m.id).collect();
+        expected.sort();
+        emitted.sort();
+        assert_eq!(emitted, expected);
+
+        // Cancelling mid-search stops further results
+        let mut emitted = 0;
+        let total = storage.search_messages_streaming(&search, &cancelled, |_| {
+            emitted += 1;
+            cancelled.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(total, None);
+        assert_eq!(emitted, 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_corrupted_index() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let first = Message::new_text("first message".to_string(), sender_id);
+        let second = Message::new_text("second message".to_string(), sender_id);
+        storage.store_message(first.clone()).await.unwrap();
+        storage.store_message(second.clone()).await.unwrap();
+        assert!(storage.index.verify(&storage.messages));
+
+        // Corrupt the index
+        storage.index.by_sender.insert(sender_id, vec![Uuid::new_v4()]);
+        storage.index.by_content.clear();
+        assert!(!storage.index.verify(&storage.messages));
+        assert!(storage.get_messages_by_sender(&sender_id).is_empty());
+
+        storage.compact().await.unwrap();
+        storage.rebuild_index().unwrap();
+
+        assert!(storage.index.verify(&storage.messages));
+        let mut found: Vec<Uuid> = storage.get_messages_by_sender(&sender_id).iter().map(|m| m.id).collect();
+        let mut expected = vec![first.id, second.id];
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+
+        // An index built before a store changed is not swapped in
+        let (stale_index, generation) = storage.build_index().unwrap();
+        storage.store_message(Message::new_text("third".to_string(), sender_id)).await.unwrap();
+        assert!(!storage.replace_index(stale_index, generation));
+        assert_eq!(storage.get_messages_by_sender(&sender_id).len(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stats_bracket_timestamps() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let now = Utc::now();
+        let mut timestamps = Vec::new();
+        for hours_ago in [5, 1, 3] {
+            let mut message = Message::new_text(format!("{} hours ago", hours_ago), sender_id);
+            message.timestamp = now - chrono::Duration::hours(hours_ago);
+            timestamps.push(message.timestamp);
+            storage.store_message(message).await.unwrap();
+        }
+
+        let stats = storage.get_stats();
+        assert_eq!(stats.total_messages, 3);
+        assert!(stats.storage_size_bytes > 0);
+        assert_eq!(stats.oldest_message, timestamps.iter().min().copied());
+        assert_eq!(stats.newest_message, timestamps.iter().max().copied());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trim_history_keeps_pinned_messages() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let mut messages = Vec::new();
+        for i in 0..100 {
+            let mut message = Message::new_text(format!("message {}", i), sender_id);
+            message.timestamp = start + chrono::Duration::seconds(i);
+            messages.push(message);
+        }
+        storage.import_messages(messages.clone(), ImportConflictPolicy::Skip).await.unwrap();
+
+        // Pin a few of the oldest messages, which would otherwise go first
+        for message in &messages[..5] {
+            storage.set_message_pinned(&message.id, true).await.unwrap();
+        }
+
+        assert_eq!(storage.trim_history(40).await.unwrap(), 60);
+        assert_eq!(storage.get_all_messages().len(), 40);
+        for message in &messages[..5] {
+            assert!(storage.get_message(&message.id).unwrap().pinned);
+        }
+        // The survivors besides the pinned ones are the newest 35
+        for message in &messages[65..] {
+            assert!(storage.get_message(&message.id).is_some());
+        }
+        // Trimmed messages no longer show up in searches
+        let search = MessageSearch {
+            query: "message 6".to_string(),
+            case_sensitive: false,
+            search_content: true,
+            search_metadata: false,
+            filter: None,
+        };
+        assert!(storage.search_messages(&search).iter().all(|message| message.timestamp >= messages[65].timestamp));
+
+        // Already small enough
+        assert_eq!(storage.trim_history(50).await.unwrap(), 0);
+
+        // Pinned messages are kept even past the target
+        assert_eq!(storage.trim_history(2).await.unwrap(), 35);
+        assert_eq!(storage.get_all_messages().len(), 5);
+
+        // The trimmed store is what gets reloaded
+        let mut reloaded = MessageStorage::with_config(&StorageConfig { data_directory: dir.clone(), ..Default::default() });
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages().len(), 5);
+        assert!(reloaded.get_all_messages().iter().all(|message| message.pinned));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_histogram() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let start = DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap().with_timezone(&Utc);
+        let mut messages = Vec::new();
+        for minutes in [0, 59, 70, 185, 190, 230, 300] {
+            let mut message = Message::new_text(format!("at {}", minutes), sender_id);
+            message.timestamp = start + chrono::Duration::minutes(minutes);
+            messages.push(message);
+        }
+        storage.import_messages(messages, ImportConflictPolicy::Skip).await.unwrap();
+
+        let hour = std::time::Duration::from_secs(3600);
+        let histogram = storage.message_histogram(hour, start, start + chrono::Duration::hours(5)).unwrap();
+        let counts: Vec<usize> = histogram.iter().map(|bucket| bucket.count).collect();
+        // The message at 5:00 falls outside the half-open range
+        assert_eq!(counts, vec![2, 1, 0, 3, 0]);
+        assert_eq!(histogram[2].start, start + chrono::Duration::hours(2));
+
+        // A range that does not divide evenly ends with a short bucket
+        let histogram = storage.message_histogram(hour, start + chrono::Duration::minutes(30), start + chrono::Duration::minutes(200)).unwrap();
+        let counts: Vec<usize> = histogram.iter().map(|bucket| bucket.count).collect();
+        assert_eq!(counts, vec![2, 0, 2]);
+
+        assert!(storage.message_histogram(std::time::Duration::ZERO, start, start + chrono::Duration::hours(1)).is_err());
+        assert!(storage.message_histogram(hour, start + chrono::Duration::hours(1), start).is_err());
+        assert!(storage.message_histogram(std::time::Duration::from_millis(1), start, start + chrono::Duration::days(1)).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mark_message_read() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let local_id = Uuid::new_v4();
+        let peer_id = Uuid::new_v4();
+        let first = Message::new_text("First".to_string(), peer_id);
+        let second = Message::new_text("Second".to_string(), peer_id);
+        storage.store_message(first.clone()).await.unwrap();
+        storage.store_message(second.clone()).await.unwrap();
+        storage.store_message(Message::new_text("Mine".to_string(), local_id)).await.unwrap();
+
+        // Our own messages never count as unread
+        assert_eq!(storage.get_unread_count(&local_id), 2);
+
+        storage.mark_message_read(&first.id).await.unwrap();
+        assert!(storage.get_message(&first.id).unwrap().read);
+        assert_eq!(storage.get_unread_count(&local_id), 1);
+
+        // Marking twice does not change the count
+        storage.mark_message_read(&first.id).await.unwrap();
+        assert_eq!(storage.get_unread_count(&local_id), 1);
+
+        assert!(storage.mark_message_read(&Uuid::new_v4()).await.is_err());
+
+        // Read state survives a reload
+        let config = StorageConfig {
+            data_directory: dir.clone(),
+            ..Default::default()
+        };
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert!(reloaded.get_message(&first.id).unwrap().read);
+        assert!(!reloaded.get_message(&second.id).unwrap().read);
+        assert_eq!(reloaded.get_unread_count(&local_id), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delete_message_persists() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let kept = Message::new_text("Keep me".to_string(), sender_id);
+        let deleted = Message::new_text("Delete me".to_string(), sender_id);
+        storage.store_message(kept.clone()).await.unwrap();
+        storage.store_message(deleted.clone()).await.unwrap();
+
+        storage.delete_message(&deleted.id).await.unwrap();
+        assert!(storage.get_message(&kept.id).is_some());
+        assert!(storage.get_message(&deleted.id).is_none());
+
+        // Reload from disk
+        let config = StorageConfig {
+            data_directory: dir.clone(),
+            ..Default::default()
+        };
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages().len(), 1);
+        assert!(reloaded.get_message(&kept.id).is_some());
+        assert!(reloaded.get_message(&deleted.id).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compressed_storage_roundtrip() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let mut stored = Vec::new();
+        for i in 0..20 {
+            let message = Message::new_text(format!("Message number {}", i), sender_id);
+            storage.store_message(message.clone()).await.unwrap();
+            stored.push(message);
+        }
+        storage.compact().await.unwrap();
+        let messages_dir = dir.join("messages");
+        assert!(messages_dir.join("messages.json.gz").exists());
+        assert!(!messages_dir.join("messages.json").exists());
+
+        let mut config = StorageConfig {
+            data_directory: dir.clone(),
+            ..Default::default()
+        };
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        for message in &stored {
+            assert_eq!(reloaded.get_message(&message.id), Some(message));
+        }
+
+        // Turning compression off still reads the compressed file, then replaces it
+        config.enable_compression = false;
+        let mut plain = MessageStorage::with_config(&config);
+        plain.initialize().await.unwrap();
+        assert_eq!(plain.get_all_messages().len(), stored.len());
+        plain.compact().await.unwrap();
+        assert!(messages_dir.join("messages.json").exists());
+        assert!(!messages_dir.join("messages.json.gz").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_changes_are_appended_to_log() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let first = Message::new_text("First".to_string(), sender_id);
+        let second = Message::new_text("Second".to_string(), sender_id);
+        storage.store_message(first.clone()).await.unwrap();
+        storage.store_message(second.clone()).await.unwrap();
+        storage.mark_message_read(&first.id).await.unwrap();
+        storage.delete_message(&second.id).await.unwrap();
+
+        // Nothing rewrites the messages file until compaction
+        let messages_dir = dir.join("messages");
+        assert!(!messages_dir.join("messages.json.gz").exists());
+        let log_file = messages_dir.join("messages.log");
+        assert_eq!(std::fs::read_to_string(&log_file).unwrap().lines().count(), 4);
+
+        // A line cut short by a crash is skipped
+        let mut log = std::fs::OpenOptions::new().append(true).open(&log_file).unwrap();
+        log.write_all(b"{\"Store\":{\"id\"").unwrap();
+        let config = StorageConfig { data_directory: dir.clone(), ..Default::default() };
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        let mut read_first = first.clone();
+        read_first.read = true;
+        assert_eq!(reloaded.get_all_messages(), vec![&read_first]);
+
+        reloaded.compact().await.unwrap();
+        assert!(messages_dir.join("messages.json.gz").exists());
+        assert!(!log_file.exists());
+        let mut compacted = MessageStorage::with_config(&config);
+        compacted.initialize().await.unwrap();
+        assert_eq!(compacted.get_all_messages(), vec![&read_first]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backups_are_pruned_and_restorable() {
+        let (mut storage, config) = temp_storage_with(|config| config.max_backup_files = 3);
+        let dir = config.data_directory.clone();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let mut backups = Vec::new();
+        for i in 0..5 {
+            storage.store_message(Message::new_text(format!("Message {}", i), sender_id)).await.unwrap();
+            backups.push(storage.backup().await.unwrap());
+        }
+
+        // Only the newest three survive
+        assert_eq!(storage.list_backups().unwrap(), backups[2..].to_vec());
+        assert!(!backups[0].exists());
+
+        // Restoring the oldest surviving backup brings back its three messages
+        assert_eq!(storage.restore_backup(&backups[2]).await.unwrap(), 3);
+        assert_eq!(storage.get_all_messages().len(), 3);
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages().len(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_uses_configured_retention() {
+        let (mut storage, config) = temp_storage_with(|config| config.message_retention_days = 1);
+        let dir = config.data_directory.clone();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let recent = Message::new_text("Recent".to_string(), sender_id);
+        let mut old = Message::new_text("Old".to_string(), sender_id);
+        old.timestamp = Utc::now() - chrono::Duration::days(2);
+        let mut pinned = Message::new_text("Old but pinned".to_string(), sender_id);
+        pinned.timestamp = old.timestamp;
+        pinned.pinned = true;
+        storage.store_message(recent.clone()).await.unwrap();
+        storage.store_message(old.clone()).await.unwrap();
+        storage.store_message(pinned.clone()).await.unwrap();
+
+        // Pinned messages outlive the retention period, as they do trimming
+        assert_eq!(storage.cleanup_expired_messages().await.unwrap(), 1);
+        assert!(storage.get_message(&recent.id).is_some());
+        assert!(storage.get_message(&old.id).is_none());
+        assert!(storage.get_message(&pinned.id).is_some());
+        storage.delete_message(&pinned.id).await.unwrap();
+
+        // Expired messages are also dropped when the store is loaded
+        storage.store_message(old.clone()).await.unwrap();
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages(), vec![&recent]);
+
+        // Startup loads only the newest messages, but still drops expired ones left on disk
+        let newer = Message::new_text("Newer".to_string(), sender_id);
+        reloaded.store_message(old.clone()).await.unwrap();
+        reloaded.store_message(newer.clone()).await.unwrap();
+        let windowed = StorageConfig { max_messages: 1, ..config.clone() };
+        let mut partial = MessageStorage::with_config(&windowed);
+        partial.initialize().await.unwrap();
+        assert_eq!(partial.get_all_messages(), vec![&newer]);
+        partial.load_all().await.unwrap();
+        assert_eq!(partial.get_all_messages().len(), 2);
+        assert!(partial.get_message(&old.id).is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_write_keeps_previous_messages() {
+        let (mut storage, config) = temp_storage_with(|config| config.enable_compression = false);
+        let dir = config.data_directory.clone();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let first = Message::new_text("First".to_string(), sender_id);
+        let second = Message::new_text("Second".to_string(), sender_id);
+        storage.store_message(first.clone()).await.unwrap();
+        storage.backup().await.unwrap();
+        storage.store_message(second.clone()).await.unwrap();
+        storage.compact().await.unwrap();
+
+        // A write killed halfway leaves a truncated temporary file, not a truncated store
+        let messages_file = dir.join("messages").join("messages.json");
+        let content = std::fs::read(&messages_file).unwrap();
+        std::fs::write(dir.join("messages").join("messages.json.tmp"), &content[..content.len() / 2]).unwrap();
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages().len(), 2);
+
+        // If the store itself is damaged, the newest backup is restored in its place
+        std::fs::write(&messages_file, &content[..content.len() / 2]).unwrap();
+        let mut recovered = MessageStorage::with_config(&config);
+        recovered.initialize().await.unwrap();
+        assert_eq!(recovered.get_all_messages(), vec![&first]);
+        recovered.store_message(second.clone()).await.unwrap();
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_load_recent_pages_from_disk() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let start = Utc::now() - chrono::Duration::hours(1);
+        let mut messages = Vec::new();
+        for i in 0..500 {
+            let mut message = Message::new_text(format!("Message {}", i), sender_id);
+            message.timestamp = start + chrono::Duration::seconds(i);
+            storage.messages.insert(message.id, message.clone());
+            messages.push(message);
+        }
+        storage.compact().await.unwrap();
+        messages.reverse();
+
+        let config = StorageConfig { data_directory: dir.clone(), ..Default::default() };
+        let mut recent = MessageStorage::with_config(&config);
+        recent.load_recent(50).await.unwrap();
+        assert_eq!(recent.get_all_messages().len(), 50);
+
+        // The first page is served from memory, a later one read from disk without loading it
+        assert_eq!(recent.get_page(0, Some(20)).unwrap(), messages[..20].to_vec());
+        assert_eq!(recent.get_page(300, Some(20)).unwrap(), messages[300..320].to_vec());
+        assert_eq!(recent.get_page(490, Some(20)).unwrap(), messages[490..].to_vec());
+        assert_eq!(recent.get_all_messages().len(), 50);
+
+        // Writes while partially loaded keep the messages left on disk
+        let latest = Message::new_text("Latest".to_string(), sender_id);
+        recent.store_message(latest.clone()).await.unwrap();
+        recent.compact().await.unwrap();
+        let mut reloaded = MessageStorage::with_config(&config);
+        reloaded.initialize().await.unwrap();
+        assert_eq!(reloaded.get_all_messages().len(), 501);
+        assert_eq!(reloaded.get_page(0, Some(1)).unwrap(), vec![latest]);
+
+        recent.load_all().await.unwrap();
+        assert_eq!(recent.get_all_messages().len(), 501);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clear_all_messages() {
+        let (mut storage, dir) = temp_storage();
+
+        // Clearing before the storage directory exists succeeds
+        assert!(!dir.exists());
+        storage.clear_all_messages().await.unwrap();
+
+        storage.initialize().await.unwrap();
+        let sender_id = Uuid::new_v4();
+        storage.store_message(Message::new_text("One".to_string(), sender_id)).await.unwrap();
+        storage.store_message(Message::new_text("Two".to_string(), sender_id)).await.unwrap();
+
+        storage.clear_all_messages().await.unwrap();
+        assert!(storage.get_all_messages().is_empty());
+        assert_eq!(std::fs::read_dir(dir.join("messages")).unwrap().count(), 0);
+
+        // Storage is still usable after clearing
+        storage.store_message(Message::new_text("Three".to_string(), sender_id)).await.unwrap();
+        assert_eq!(storage.get_all_messages().len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_export_json_and_csv() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let mut text = Message::new_text("Hello, \"world\"".to_string(), sender_id);
+        text.metadata.insert("secret".to_string(), "value".to_string());
+        let system = Message::new_system("User joined".to_string(), crate::types::SystemMessageLevel::Info, sender_id);
+        storage.store_message(text.clone()).await.unwrap();
+        storage.store_message(system.clone()).await.unwrap();
+
+        let mut options = ExportOptions {
+            format: ExportFormat::Json,
+            include_metadata: false,
+            include_system_messages: false,
+            date_range: None,
+            filter: None,
+            timestamp_format: TimestampFormat::Rfc3339,
+        };
+
+        let path = storage.export_messages(&options).await.unwrap();
+        assert!(path.exists());
+        let exported: Vec<Message> = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(exported.len(), 1);
+        assert_eq!(exported[0].id, text.id);
+        assert!(exported[0].metadata.is_empty());
+
+        options.format = ExportFormat::Csv;
+        options.include_system_messages = true;
+        let path = storage.export_messages(&options).await.unwrap();
+        assert!(path.exists());
+        assert_eq!(path.extension().unwrap(), "csv");
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert!(csv.contains(&text.id.to_string()));
+        assert!(csv.contains(&system.id.to_string()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_csv_export_quotes_fields() {
+        let content = "Hello, \"world\"\nsecond line, with commas";
+        let mut message = Message::new_text(content.to_string(), Uuid::new_v4());
+        message.status = crate::types::MessageStatus::Delivered;
+
+        let mut output = Vec::new();
+        MessageStorage::export_to_writer(&[&message], &ExportFormat::Csv, false, TimestampFormat::Rfc3339, &mut output).unwrap();
+
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        assert_eq!(reader.headers().unwrap(), vec!["id", "timestamp", "sender_id", "type", "content", "status"]);
+        let records: Vec<csv::StringRecord> = reader.records().map(|record| record.unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(&records[0][0], message.id.to_string());
+        assert_eq!(&records[0][4], content);
+        assert_eq!(&records[0][5], "Delivered");
+    }
+
+    #[tokio::test]
+    async fn test_import_conflict_policies() {
+        let sender_id = Uuid::new_v4();
+        let original = Message::new_text("original".to_string(), sender_id);
+        let unchanged = Message::new_text("unchanged".to_string(), sender_id);
+        let mut edited = original.clone();
+        edited.message_type = MessageType::Text { content: "edited".to_string() };
+        edited.timestamp = original.timestamp + chrono::Duration::seconds(5);
+        let added = Message::new_text("added".to_string(), sender_id);
+
+        let cases = [
+            (ImportConflictPolicy::Skip, "original", ImportReport { imported: 1, skipped: 2, overwritten: 0 }),
+            (ImportConflictPolicy::Overwrite, "edited", ImportReport { imported: 1, skipped: 1, overwritten: 1 }),
+            (ImportConflictPolicy::KeepNewest, "edited", ImportReport { imported: 1, skipped: 1, overwritten: 1 }),
+        ];
+        for (policy, expected_content, expected_report) in cases {
+            let (mut storage, dir) = temp_storage();
+            storage.initialize().await.unwrap();
+            storage.store_message(original.clone()).await.unwrap();
+            storage.store_message(unchanged.clone()).await.unwrap();
+
+            // Round-trip the archive through a JSON export file
+            let archive = dir.join("archive.json");
+            write_export(&archive, |writer| {
+                MessageStorage::export_to_writer(&[&edited, &unchanged, &added], &ExportFormat::Json, true, TimestampFormat::Rfc3339, writer)
+            }).unwrap();
+            let messages = MessageStorage::read_archive(&archive).unwrap();
+
+            let report = storage.import_messages(messages, policy).await.unwrap();
+            assert_eq!(report, expected_report, "{:?}", policy);
+            assert_eq!(storage.get_all_messages().len(), 3);
+            assert_eq!(
+                storage.get_message(&original.id).unwrap().message_type,
+                MessageType::Text { content: expected_content.to_string() },
+                "{:?}", policy
+            );
+
+            // The merged store is persisted and searchable
+            let mut reloaded = MessageStorage::with_config(&StorageConfig { data_directory: dir.clone(), ..Default::default() });
+            reloaded.initialize().await.unwrap();
+            assert_eq!(reloaded.get_message(&original.id), storage.get_message(&original.id));
+            let search = MessageSearch {
+                query: expected_content.to_string(),
+                case_sensitive: false,
+                search_content: true,
+                search_metadata: false,
+                filter: None,
+            };
+            assert_eq!(storage.search_messages(&search).len(), 1);
+
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+
+        // An older conflicting copy does not replace a newer stored one
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+        storage.store_message(edited.clone()).await.unwrap();
+        let report = storage.import_messages(vec![original.clone()], ImportConflictPolicy::KeepNewest).await.unwrap();
+        assert_eq!(report, ImportReport { imported: 0, skipped: 1, overwritten: 0 });
+        assert_eq!(storage.get_message(&original.id).unwrap(), &edited);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_epoch_millis_timestamps() {
+        let message = Message::new_text("Hello".to_string(), Uuid::new_v4());
+
+        let mut buffer = Vec::new();
+        MessageStorage::export_to_writer(&[&message], &ExportFormat::Json, true, TimestampFormat::EpochMillis, &mut buffer).unwrap();
+
+        let exported: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        let millis = exported[0]["timestamp"].as_i64().unwrap();
+        assert_eq!(millis, message.timestamp.timestamp_millis());
+        let parsed = DateTime::from_timestamp_millis(millis).unwrap();
+        assert!((message.timestamp - parsed).num_milliseconds().abs() < 1);
+        assert_eq!(exported[0]["id"], message.id.to_string());
+
+        let mut buffer = Vec::new();
+        MessageStorage::export_to_writer(&[&message], &ExportFormat::Json, true, TimestampFormat::EpochSeconds, &mut buffer).unwrap();
+        let exported: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(exported[0]["timestamp"].as_i64().unwrap(), message.timestamp.timestamp());
+    }
+
+    #[tokio::test]
+    async fn test_export_to_writer_matches_file() {
+        let (mut storage, dir) = temp_storage();
+        storage.initialize().await.unwrap();
+
+        let sender_id = Uuid::new_v4();
+        let mut text = Message::new_text("Hello <world>".to_string(), sender_id);
+        text.metadata.insert("topic".to_string(), "greeting".to_string());
+        storage.store_message(text).await.unwrap();
+        storage.store_message(Message::new_system("User joined".to_string(), crate::types::SystemMessageLevel::Info, sender_id)).await.unwrap();
+
+        for format in [ExportFormat::Json, ExportFormat::Csv, ExportFormat::Txt, ExportFormat::Html] {
+            let options = ExportOptions {
+                format,
+                include_metadata: false,
+                include_system_messages: true,
+                date_range: None,
+                filter: None,
+                timestamp_format: TimestampFormat::Rfc3339,
+            };
+
+            let mut buffer = Vec::new();
+            let messages = storage.select_for_export(&options);
+            MessageStorage::export_to_writer(&messages, &options.format, options.include_metadata, options.timestamp_format, &mut buffer).unwrap();
+
+            let path = storage.export_messages(&options).await.unwrap();
+            assert_eq!(buffer, std::fs::read(&path).unwrap(), "{:?} export differs", options.format);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,137 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
+
+/// Restarts allowed before a failing task is left stopped
+pub const MAX_TASK_RESTARTS: u32 = 5;
+
+/// Pause before restarting a failed task, multiplied by the attempt number
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Details of a supervised task ending unexpectedly
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaskFailure {
+    /// Number of failures so far, starting at 1
+    pub attempt: u32,
+    /// Whether the task is about to be restarted
+    pub restarting: bool,
+}
+
+/// Aborts the running task when dropped, so aborting the supervisor stops the task too
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Run the task produced by `start`, starting a fresh one whenever it panics.
+/// Supervision ends when the task returns, when the supervisor is aborted, or after
+/// `MAX_TASK_RESTARTS` restarts. `on_failure` runs after every panic, before any restart.
+pub fn supervise<S, T, F, R>(name: &'static str, mut start: S, mut on_failure: F) -> JoinHandle<()>
+where
+    S: FnMut() -> T + Send + 'static,
+    T: Future<Output = ()> + Send + 'static,
+    F: FnMut(TaskFailure) -> R + Send + 'static,
+    R: Future<Output = ()> + Send,
+{
+    tokio::spawn(async move {
+        let mut attempt = 0;
+        loop {
+            let mut task = AbortOnDrop(tokio::spawn(start()));
+            let error = match (&mut task.0).await {
+                Ok(()) => return,
+                Err(e) if e.is_cancelled() => return,
+                Err(e) => e,
+            };
+
+            attempt += 1;
+            let restarting = attempt <= MAX_TASK_RESTARTS;
+            error!("Network task '{}' failed: {}", name, error);
+            on_failure(TaskFailure { attempt, restarting }).await;
+
+            if !restarting {
+                warn!("Giving up on network task '{}' after {} restarts", name, MAX_TASK_RESTARTS);
+                return;
+            }
+            tokio::time::sleep(RESTART_DELAY * attempt).await;
+            warn!("Restarting network task '{}' (attempt {})", name, attempt);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ConnectionStatus;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, RwLock};
+
+    #[tokio::test]
+    async fn test_panicked_task_is_restarted() {
+        let status = Arc::new(RwLock::new(ConnectionStatus::Connected));
+        let failures = Arc::new(RwLock::new(Vec::new()));
+        let starts = Arc::new(AtomicU32::new(0));
+
+        let task_starts = starts.clone();
+        let failure_status = status.clone();
+        let failure_log = failures.clone();
+        let supervisor = supervise(
+            "test",
+            move || {
+                let starts = task_starts.clone();
+                async move {
+                    // Fail on the first run, then recover and keep running
+                    if starts.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("forced failure");
+                    }
+                    std::future::pending::<()>().await;
+                }
+            },
+            move |failure| {
+                *failure_status.write().unwrap() = ConnectionStatus::Reconnecting;
+                failure_log.write().unwrap().push(failure);
+                async {}
+            },
+        );
+
+        for _ in 0..50 {
+            if starts.load(Ordering::SeqCst) == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(*status.read().unwrap(), ConnectionStatus::Reconnecting);
+        assert_eq!(*failures.read().unwrap(), vec![TaskFailure { attempt: 1, restarting: true }]);
+
+        // Aborting the supervisor stops the running task instead of restarting it
+        supervisor.abort();
+        assert!(supervisor.await.unwrap_err().is_cancelled());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_gives_up_after_max_restarts() {
+        let failures = Arc::new(RwLock::new(Vec::new()));
+        let failure_log = failures.clone();
+
+        let supervisor = supervise(
+            "test",
+            || async { panic!("always fails") },
+            move |failure| {
+                failure_log.write().unwrap().push(failure);
+                async {}
+            },
+        );
+        supervisor.await.unwrap();
+
+        let failures = failures.read().unwrap();
+        assert_eq!(failures.len() as u32, MAX_TASK_RESTARTS + 1);
+        assert!(failures[..MAX_TASK_RESTARTS as usize].iter().all(|failure| failure.restarting));
+        assert!(!failures.last().unwrap().restarting);
+    }
+}
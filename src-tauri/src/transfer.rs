@@ -0,0 +1,437 @@
+use crate::error::{MessengerError, Result};
+use crate::types::{FileTransferProgressEvent, FileTransferStatus, Message, MessageType};
+use crate::AppState;
+use std::collections::{BTreeMap, HashMap};
+use tokio::io::AsyncReadExt;
+use tracing::info;
+use uuid::Uuid;
+
+/// Files larger than this are split into chunks of at most this size
+pub const FILE_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Room left in each frame for a chunk's metadata, framing, compression and encryption
+const FILE_CHUNK_RESERVE: usize = 4 * 1024;
+
+/// Chunk size that keeps every chunk's frame within `max_message_size`
+pub fn file_chunk_size(max_message_size: usize) -> usize {
+    FILE_CHUNK_SIZE.min(max_message_size.saturating_sub(FILE_CHUNK_RESERVE)).max(1)
+}
+
+/// A file put back together from its chunks
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReassembledFile {
+    pub transfer_id: Uuid,
+    pub name: String,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Chunks received so far for one transfer
+#[derive(Debug)]
+struct PartialTransfer {
+    name: String,
+    size: u64,
+    mime_type: String,
+    total_chunks: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+/// Collects file chunks per transfer, in any order and interleaved with other transfers
+#[derive(Debug, Default)]
+pub struct FileReassembler {
+    transfers: HashMap<Uuid, PartialTransfer>,
+}
+
+impl FileReassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a chunk, returning the whole file once its last missing chunk arrives
+    pub fn add_chunk(&mut self, message: &Message) -> Result<Option<ReassembledFile>> {
+        let MessageType::File { name, size, mime_type, data, chunk_index, total_chunks, transfer_id } = &message.message_type else {
+            return Err(MessengerError::InvalidMessageType(format!("Message {} is not a file chunk", message.id)));
+        };
+        let (Some(transfer_id), Some(chunk_index), Some(total_chunks), Some(data)) = (transfer_id, chunk_index, total_chunks, data) else {
+            return Err(MessengerError::FileTransferError(format!("Message {} is missing chunk information", message.id)));
+        };
+        if chunk_index >= total_chunks {
+            return Err(MessengerError::FileTransferError(format!("Chunk {} is out of range for {} chunks", chunk_index, total_chunks)));
+        }
+
+        let transfer = self.transfers.entry(*transfer_id).or_insert_with(|| PartialTransfer {
+            name: name.clone(),
+            size: *size,
+            mime_type: mime_type.clone(),
+            total_chunks: *total_chunks,
+            chunks: BTreeMap::new(),
+        });
+        if transfer.total_chunks != *total_chunks || transfer.size != *size {
+            return Err(MessengerError::FileTransferError(format!("Chunk {} does not match transfer {}", chunk_index, transfer_id)));
+        }
+        transfer.chunks.insert(*chunk_index, data.clone());
+
+        if transfer.chunks.len() < transfer.total_chunks as usize {
+            return Ok(None);
+        }
+
+        // Chunks are keyed by index, so concatenating in map order restores the file
+        let transfer = self.transfers.remove(transfer_id).expect("transfer was just updated");
+        let data: Vec<u8> = transfer.chunks.into_values().flatten().collect();
+        if data.len() as u64 != transfer.size {
+            return Err(MessengerError::FileTransferError(format!(
+                "Transfer {} reassembled to {} bytes, expected {}", transfer_id, data.len(), transfer.size
+            )));
+        }
+
+        Ok(Some(ReassembledFile {
+            transfer_id: *transfer_id,
+            name: transfer.name,
+            mime_type: transfer.mime_type,
+            data,
+        }))
+    }
+
+    /// Number of transfers still waiting for chunks
+    pub fn pending_transfers(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Drop the chunks collected for a transfer that will not complete
+    pub fn abandon(&mut self, transfer_id: &Uuid) -> bool {
+        self.transfers.remove(transfer_id).is_some()
+    }
+}
+
+/// Send the file at `file_path`, reporting progress after every chunk and once more when
+/// the transfer completes or fails. Returns the transfer id.
+pub async fn send_file(
+    state: &AppState,
+    file_path: &str,
+    mut on_progress: impl FnMut(FileTransferProgressEvent),
+) -> Result<Uuid> {
+    let mut progress = FileTransferProgressEvent {
+        transfer_id: Uuid::new_v4(),
+        bytes_sent: 0,
+        total_bytes: 0,
+        progress: 0.0,
+        status: FileTransferStatus::InProgress,
+        error: None,
+    };
+
+    let result = send_chunks(state, file_path, &mut progress, &mut on_progress).await;
+    match &result {
+        Ok(()) => progress.status = FileTransferStatus::Completed,
+        Err(e) => {
+            progress.status = FileTransferStatus::Failed;
+            progress.error = Some(e.to_string());
+        }
+    }
+    let transfer_id = progress.transfer_id;
+    on_progress(progress);
+    result.map(|()| transfer_id)
+}
+
+async fn send_chunks(
+    state: &AppState,
+    file_path: &str,
+    progress: &mut FileTransferProgressEvent,
+    on_progress: &mut impl FnMut(FileTransferProgressEvent),
+) -> Result<()> {
+    // Check the file against the security settings before reading any of it
+    let (max_file_size, chunk_size) = {
+        let config = state.config.read().await;
+        if !config.is_file_type_allowed(file_path) {
+            return Err(MessengerError::PermissionDenied(format!("File type not allowed: {}", file_path)));
+        }
+        (config.security.max_file_size, file_chunk_size(config.security.max_message_size) as u64)
+    };
+
+    let metadata = tokio::fs::metadata(file_path).await
+        .map_err(|e| MessengerError::File(format!("Failed to read file metadata: {}", e)))?;
+    if metadata.len() > max_file_size {
+        return Err(MessengerError::FileTooLarge { size: metadata.len(), max: max_file_size });
+    }
+
+    let file_name = std::path::Path::new(file_path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("unknown")
+        .to_string();
+
+    let mime_type = mime_guess::from_path(file_path)
+        .first_or_octet_stream()
+        .to_string();
+
+    // Read one chunk at a time so the whole file is never held in memory
+    let mut file = tokio::fs::File::open(file_path).await
+        .map_err(|e| MessengerError::File(format!("Failed to open file: {}", e)))?;
+
+    // Small files go out as a single message whose id is the transfer id
+    let total_bytes = metadata.len();
+    let chunked = total_bytes > chunk_size;
+    let total_chunks = total_bytes.div_ceil(chunk_size).max(1) as u32;
+    progress.total_bytes = total_bytes;
+    if chunked {
+        info!("Sending large file in {} chunks", total_chunks);
+    }
+
+    for chunk_index in 0..total_chunks {
+        let remaining = total_bytes - progress.bytes_sent;
+        let mut chunk_data = vec![0u8; remaining.min(chunk_size) as usize];
+        file.read_exact(&mut chunk_data).await
+            .map_err(|e| MessengerError::File(format!("Failed to read file chunk: {}", e)))?;
+        let chunk_len = chunk_data.len() as u64;
+
+        let mut message = Message::new_file(file_name.clone(), total_bytes, mime_type.clone(), Some(chunk_data), state.local_id);
+        if chunked {
+            if let MessageType::File { chunk_index: index, total_chunks: total, transfer_id, .. } = &mut message.message_type {
+                *index = Some(chunk_index);
+                *total = Some(total_chunks);
+                *transfer_id = Some(progress.transfer_id);
+            }
+        } else {
+            message.id = progress.transfer_id;
+        }
+
+        // Store and send chunk
+        {
+            let mut storage = state.storage.write().await;
+            storage.store_message(message.clone()).await?;
+        }
+
+        {
+            let network_manager = state.network_manager.read().await;
+            if let Some(manager) = network_manager.as_ref() {
+                manager.send_message(message).await?;
+            } else {
+                return Err(MessengerError::NotConnected);
+            }
+        }
+
+        progress.bytes_sent += chunk_len;
+        progress.progress = if total_bytes == 0 { 1.0 } else { progress.bytes_sent as f32 / total_bytes as f32 };
+        on_progress(progress.clone());
+        if chunked {
+            info!("Sent chunk {}/{} of file {}", chunk_index + 1, total_chunks, file_name);
+        }
+    }
+
+    info!("File sent: {}", progress.transfer_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::NetworkManager;
+    use crate::storage::{open_backend, StorageConfig};
+    use crate::types::MessageFilter;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    /// State with a listening server and storage in a fresh directory
+    async fn sending_state() -> (AppState, std::path::PathBuf) {
+        let config = StorageConfig::temp(|_| {});
+        let storage = open_backend(&config).await.unwrap();
+        let state = AppState {
+            storage: Arc::new(RwLock::new(storage)),
+            ..AppState::new()
+        };
+        let (mut manager, _sender) = NetworkManager::new();
+        manager.start_server_on(vec!["127.0.0.1:0".parse().unwrap()]).await.unwrap();
+        *state.network_manager.write().await = Some(manager);
+        (state, config.data_directory)
+    }
+
+    fn chunks(name: &str, contents: &[u8], chunk_size: usize, sender_id: Uuid) -> Vec<Message> {
+        let transfer_id = Uuid::new_v4();
+        let total_chunks = contents.len().div_ceil(chunk_size) as u32;
+        contents.chunks(chunk_size).enumerate().map(|(index, chunk)| {
+            let mut message = Message::new_file(name.to_string(), contents.len() as u64, "application/octet-stream".to_string(), Some(chunk.to_vec()), sender_id);
+            if let MessageType::File { chunk_index, total_chunks: total, transfer_id: id, .. } = &mut message.message_type {
+                *chunk_index = Some(index as u32);
+                *total = Some(total_chunks);
+                *id = Some(transfer_id);
+            }
+            message
+        }).collect()
+    }
+
+    #[test]
+    fn test_interleaved_transfers_reassemble_separately() {
+        // Same sender, same name and size: only the transfer id tells them apart
+        let sender_id = Uuid::new_v4();
+        let first_contents: Vec<u8> = (0..100u8).collect();
+        let second_contents: Vec<u8> = (100..200u8).collect();
+        let first = chunks("report.bin", &first_contents, 30, sender_id);
+        let second = chunks("report.bin", &second_contents, 30, sender_id);
+
+        let mut reassembler = FileReassembler::new();
+        let mut completed = Vec::new();
+        // Interleave the transfers, with the second one's chunks arriving in reverse
+        for (a, b) in first.iter().zip(second.iter().rev()) {
+            completed.extend(reassembler.add_chunk(a).unwrap());
+            completed.extend(reassembler.add_chunk(b).unwrap());
+        }
+
+        assert_eq!(completed.len(), 2);
+        assert_eq!(reassembler.pending_transfers(), 0);
+        let first_file = completed.iter().find(|file| file.data == first_contents).unwrap();
+        let second_file = completed.iter().find(|file| file.data == second_contents).unwrap();
+        assert_ne!(first_file.transfer_id, second_file.transfer_id);
+        assert_eq!(first_file.name, "report.bin");
+    }
+
+    #[test]
+    fn test_invalid_chunks_are_rejected() {
+        let sender_id = Uuid::new_v4();
+        let mut reassembler = FileReassembler::new();
+
+        let whole = Message::new_file("a.txt".to_string(), 3, "text/plain".to_string(), Some(vec![1, 2, 3]), sender_id);
+        assert!(reassembler.add_chunk(&whole).is_err());
+        assert!(reassembler.add_chunk(&Message::new_text("hi".to_string(), sender_id)).is_err());
+
+        let mut out_of_range = chunks("a.txt", &[1, 2, 3], 2, sender_id).remove(0);
+        if let MessageType::File { chunk_index, .. } = &mut out_of_range.message_type {
+            *chunk_index = Some(5);
+        }
+        assert!(reassembler.add_chunk(&out_of_range).is_err());
+
+        let partial = chunks("a.txt", &[1, 2, 3], 2, sender_id);
+        assert_eq!(reassembler.add_chunk(&partial[0]).unwrap(), None);
+        assert_eq!(reassembler.pending_transfers(), 1);
+        let MessageType::File { transfer_id: Some(transfer_id), .. } = partial[0].message_type else { unreachable!() };
+        assert!(reassembler.abandon(&transfer_id));
+        assert_eq!(reassembler.pending_transfers(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_send_file_reports_progress_per_chunk() {
+        let (state, dir) = sending_state().await;
+        let path = dir.join("large.txt");
+        std::fs::write(&path, vec![7u8; FILE_CHUNK_SIZE * 2 + FILE_CHUNK_SIZE / 2]).unwrap();
+
+        let (events, received) = std::sync::mpsc::channel();
+        let transfer_id = send_file(&state, path.to_str().unwrap(), |event| events.send(event).unwrap()).await.unwrap();
+        let events: Vec<FileTransferProgressEvent> = received.try_iter().collect();
+
+        // One event per chunk, then the completion
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.transfer_id == transfer_id));
+        let chunks = &events[..3];
+        assert!(chunks.iter().all(|event| event.status == FileTransferStatus::InProgress));
+        assert!(chunks.windows(2).all(|pair| pair[0].progress < pair[1].progress));
+        assert_eq!(chunks[2].progress, 1.0);
+        assert_eq!(chunks[2].bytes_sent, chunks[2].total_bytes);
+        assert_eq!(events[3].status, FileTransferStatus::Completed);
+
+        // A missing file fails and says so
+        let (events, received) = std::sync::mpsc::channel();
+        let missing = dir.join("missing.txt");
+        assert!(send_file(&state, missing.to_str().unwrap(), |event| events.send(event).unwrap()).await.is_err());
+        let failed = received.try_recv().unwrap();
+        assert_eq!(failed.status, FileTransferStatus::Failed);
+        assert!(failed.error.is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_file_is_sent_in_chunks() {
+        let (state, dir) = sending_state().await;
+        let path = dir.join("large.txt");
+        let contents: Vec<u8> = (0..FILE_CHUNK_SIZE * 3 + 17).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let transfer_id = send_file(&state, path.to_str().unwrap(), |_| {}).await.unwrap();
+
+        let storage = state.storage.read().await;
+        let chunks = storage.get_messages_with_filter(&MessageFilter::default()).unwrap();
+        assert_eq!(chunks.len(), contents.len().div_ceil(FILE_CHUNK_SIZE));
+        let mut reassembler = FileReassembler::new();
+        let mut reassembled = None;
+        for chunk in chunks {
+            let MessageType::File { data: Some(data), transfer_id: id, .. } = &chunk.message_type else { panic!("expected a file chunk") };
+            assert!(data.len() <= FILE_CHUNK_SIZE);
+            assert_eq!(*id, Some(transfer_id));
+            reassembled = reassembler.add_chunk(&chunk).unwrap().or(reassembled);
+        }
+        assert_eq!(reassembled.unwrap().data, contents);
+
+        drop(storage);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connected_client_reassembles_sent_file() {
+        let (state, dir) = sending_state().await;
+        let port = state.network_manager.read().await.as_ref().unwrap().server_info.as_ref().unwrap().port;
+        let (mut client, _sender) = NetworkManager::new();
+        let mut receiver = client.message_receiver.write().await.take().unwrap();
+        client.connect_to_server("127.0.0.1".to_string(), port).await.unwrap();
+
+        // Random bytes do not compress, so every chunk goes out at its full size
+        let mut contents = vec![0u8; FILE_CHUNK_SIZE * 4 + 123];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut contents);
+        let path = dir.join("archive.zip");
+        std::fs::write(&path, &contents).unwrap();
+        let transfer_id = send_file(&state, path.to_str().unwrap(), |_| {}).await.unwrap();
+
+        let mut reassembler = FileReassembler::new();
+        let file = loop {
+            let message = tokio::time::timeout(std::time::Duration::from_secs(5), receiver.recv()).await.unwrap().unwrap();
+            if !matches!(message.message_type, MessageType::File { .. }) {
+                continue;
+            }
+            if let Some(file) = reassembler.add_chunk(&message).unwrap() {
+                break file;
+            }
+        };
+        assert_eq!(file.transfer_id, transfer_id);
+        assert_eq!(file.name, "archive.zip");
+        assert_eq!(file.data, contents);
+
+        client.disconnect().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chunks_fit_the_message_size_limit() {
+        assert_eq!(file_chunk_size(crate::protocol::DEFAULT_MAX_MESSAGE_SIZE), FILE_CHUNK_SIZE);
+        assert_eq!(file_chunk_size(64 * 1024), 60 * 1024);
+        assert_eq!(file_chunk_size(1), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_file_enforces_security_settings() {
+        let (state, dir) = sending_state().await;
+
+        // The extension is rejected before the file is even looked at
+        let (events, received) = std::sync::mpsc::channel();
+        let disallowed = dir.join("setup.exe");
+        let result = send_file(&state, disallowed.to_str().unwrap(), |event| events.send(event).unwrap()).await;
+        assert!(matches!(result, Err(MessengerError::PermissionDenied(_))));
+        assert_eq!(received.try_recv().unwrap().status, FileTransferStatus::Failed);
+
+        // A sparse file over the limit is rejected from its metadata alone
+        let max_file_size = state.config.read().await.security.max_file_size;
+        let oversized = dir.join("huge.txt");
+        std::fs::File::create(&oversized).unwrap().set_len(max_file_size + 1).unwrap();
+        let (events, received) = std::sync::mpsc::channel();
+        let result = send_file(&state, oversized.to_str().unwrap(), |event| events.send(event).unwrap()).await;
+        match result {
+            Err(MessengerError::FileTooLarge { size, max }) => {
+                assert_eq!(size, max_file_size + 1);
+                assert_eq!(max, max_file_size);
+            }
+            other => panic!("expected FileTooLarge, got {:?}", other),
+        }
+        let events: Vec<FileTransferProgressEvent> = received.try_iter().collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].bytes_sent, 0);
+        assert!(state.storage.read().await.get_messages_with_filter(&MessageFilter::default()).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
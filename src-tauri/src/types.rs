@@ -17,6 +17,9 @@ pub enum MessageType {
         data: Option<Vec<u8>>, // Only included for small files
         chunk_index: Option<u32>,
         total_chunks: Option<u32>,
+        /// Shared by every chunk of one file, so concurrent transfers can be told apart
+        #[serde(default)]
+        transfer_id: Option<Uuid>,
     },
     /// System message (connection status, errors, etc.)
     System { content: String, level: SystemMessageLevel },
@@ -28,6 +31,27 @@ pub enum MessageType {
     Disconnect { reason: String },
     /// Message acknowledgment
     Acknowledgment { message_id: Uuid },
+    /// Link benchmark probe, echoed back by the server and never stored
+    Benchmark { sequence: u64, payload: Vec<u8> },
+    /// Out-of-band control message, handled by the network layer and never stored
+    Control { control: ControlMessage },
+    /// Emoji reaction to an earlier message
+    Reaction { target_id: Uuid, emoji: String },
+    /// Replacement content for an earlier text message
+    Edit { target_id: Uuid, new_content: String },
+    /// Tells the sender of a message that it has been read
+    ReadReceipt { message_id: Uuid },
+    /// Typing indicator, passed on to the peer and never stored
+    Typing { is_typing: bool },
+}
+
+/// Instructions carried on the control channel rather than as data
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ControlMessage {
+    /// Features the sender supports
+    Capabilities { features: Vec<String> },
+    /// Ask the peer to pause or resume sending data
+    FlowControl { paused: bool },
 }
 
 /// System message severity levels
@@ -61,32 +85,87 @@ impl std::fmt::Display for MessageStatus {
     }
 }
 
-/// Message flags for protocol handling
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum MessageFlags {
-    None = 0,
-    Encrypted = 1,
-    Compressed = 2,
-    Chunked = 4,
-    Acknowledgment = 8,
+/// Message flags for protocol handling, any combination of which fits in the header's flags byte
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageFlags(u8);
+
+impl MessageFlags {
+    pub const NONE: Self = Self(0);
+    pub const ENCRYPTED: Self = Self(1);
+    pub const COMPRESSED: Self = Self(2);
+    pub const CHUNKED: Self = Self(4);
+    pub const ACKNOWLEDGMENT: Self = Self(8);
+
+    /// Create an empty set of flags
+    pub fn new() -> Self {
+        Self::NONE
+    }
+
+    /// Read flags from a header byte; bits we don't know about are kept as they are
+    pub fn from_byte(byte: u8) -> Self {
+        Self(byte)
+    }
+
+    /// The header byte for these flags
+    pub fn to_byte(self) -> u8 {
+        self.0
+    }
+
+    /// Check that every flag in `other` is set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Set or clear the flags in `other`
+    pub fn set(&mut self, other: Self, value: bool) {
+        if value {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    pub fn encrypted(self) -> bool {
+        self.contains(Self::ENCRYPTED)
+    }
+
+    pub fn compressed(self) -> bool {
+        self.contains(Self::COMPRESSED)
+    }
+
+    pub fn chunked(self) -> bool {
+        self.contains(Self::CHUNKED)
+    }
+
+    pub fn acknowledgment(self) -> bool {
+        self.contains(Self::ACKNOWLEDGMENT)
+    }
+}
+
+impl std::ops::BitOr for MessageFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for MessageFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
 }
 
 impl From<u8> for MessageFlags {
     fn from(value: u8) -> Self {
-        match value {
-            0 => MessageFlags::None,
-            1 => MessageFlags::Encrypted,
-            2 => MessageFlags::Compressed,
-            4 => MessageFlags::Chunked,
-            8 => MessageFlags::Acknowledgment,
-            _ => MessageFlags::None,
-        }
+        Self::from_byte(value)
     }
 }
 
 impl From<MessageFlags> for u8 {
     fn from(flags: MessageFlags) -> Self {
-        flags as u8
+        flags.to_byte()
     }
 }
 
@@ -102,39 +181,91 @@ pub struct Message {
     pub encrypted: bool,
     pub retry_count: u32,
     pub metadata: HashMap<String, String>,
+    #[serde(default)]
+    pub read: bool,
+    /// Pinned messages are kept when history is trimmed
+    #[serde(default)]
+    pub pinned: bool,
+    /// How long the message stays relevant after `timestamp`; receivers drop it once expired
+    #[serde(default)]
+    pub ttl: Option<u64>, // milliseconds
+    /// Earlier message this one replies to. It may since have been deleted.
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
+    /// Earlier versions of the content, oldest first
+    #[serde(default)]
+    pub edit_history: Vec<MessageRevision>,
+}
+
+/// Metadata key holding when a message was last edited (RFC 3339)
+pub const EDITED_AT_METADATA_KEY: &str = "edited_at";
+
+/// Content a message had before an edit replaced it
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageRevision {
+    pub content: String,
+    pub replaced_at: DateTime<Utc>,
 }
 
 impl Message {
-    /// Create a new text message
-    pub fn new_text(content: String, sender_id: Uuid) -> Self {
+    /// A new message of the given type, with every other field at its default
+    fn with_type(message_type: MessageType, sender_id: Uuid, status: MessageStatus) -> Self {
         Self {
             id: Uuid::new_v4(),
-            message_type: MessageType::Text { content },
+            message_type,
             timestamp: Utc::now(),
             sender_id,
             recipient_id: None,
-            status: MessageStatus::Sending,
+            status,
             encrypted: false,
             retry_count: 0,
             metadata: HashMap::new(),
+            read: false,
+            pinned: false,
+            ttl: None,
+            reply_to: None,
+            edit_history: Vec::new(),
         }
     }
 
-    /// Create a new system message
-    pub fn new_system(content: String, level: SystemMessageLevel, sender_id: Uuid) -> Self {
+    /// Create a new text message
+    pub fn new_text(content: String, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Text { content }, sender_id, MessageStatus::Sending)
+    }
+
+    /// Create a text message replying to an earlier one
+    pub fn new_text_reply(content: String, sender_id: Uuid, reply_to: Uuid) -> Self {
         Self {
-            id: Uuid::new_v4(),
-            message_type: MessageType::System { content, level },
-            timestamp: Utc::now(),
-            sender_id,
-            recipient_id: None,
-            status: MessageStatus::Sent,
-            encrypted: false,
-            retry_count: 0,
-            metadata: HashMap::new(),
+            reply_to: Some(reply_to),
+            ..Self::new_text(content, sender_id)
         }
     }
 
+    /// Create an emoji reaction to an earlier message
+    pub fn new_reaction(target_id: Uuid, emoji: String, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Reaction { target_id, emoji }, sender_id, MessageStatus::Sending)
+    }
+
+    /// Create an edit replacing the content of an earlier text message
+    pub fn new_edit(target_id: Uuid, new_content: String, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Edit { target_id, new_content }, sender_id, MessageStatus::Sending)
+    }
+
+    /// Create a typing indicator for the peer
+    pub fn new_typing(is_typing: bool, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Typing { is_typing }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create a receipt telling a message's sender that it was read
+    pub fn new_read_receipt(message_id: Uuid, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::ReadReceipt { message_id }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create a new system message
+    pub fn new_system(content: String, level: SystemMessageLevel, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::System { content, level }, sender_id, MessageStatus::Sent)
+    }
+
     /// Create a new file message
     pub fn new_file(
         name: String,
@@ -143,41 +274,70 @@ impl Message {
         data: Option<Vec<u8>>,
         sender_id: Uuid,
     ) -> Self {
-        Self {
-            id: Uuid::new_v4(),
-            message_type: MessageType::File {
+        Self::with_type(
+            MessageType::File {
                 name,
                 size,
                 mime_type,
                 data,
                 chunk_index: None,
                 total_chunks: None,
+                transfer_id: None,
             },
-            timestamp: Utc::now(),
             sender_id,
-            recipient_id: None,
-            status: MessageStatus::Sending,
-            encrypted: false,
-            retry_count: 0,
-            metadata: HashMap::new(),
-        }
+            MessageStatus::Sending,
+        )
     }
 
     /// Create a heartbeat message
     pub fn new_heartbeat(sender_id: Uuid) -> Self {
-        Self {
-            id: Uuid::new_v4(),
-            message_type: MessageType::Heartbeat,
-            timestamp: Utc::now(),
-            sender_id,
-            recipient_id: None,
-            status: MessageStatus::Sent,
-            encrypted: false,
-            retry_count: 0,
-            metadata: HashMap::new(),
+        Self::with_type(MessageType::Heartbeat, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create an acknowledgment that a message arrived
+    pub fn new_acknowledgment(message_id: Uuid, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Acknowledgment { message_id }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create a disconnect notification
+    pub fn new_disconnect(reason: String, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Disconnect { reason }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create a key exchange message carrying an encoded public key
+    pub fn new_key_exchange(public_key: Vec<u8>, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::KeyExchange { public_key }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create a benchmark probe carrying a payload of the given size
+    pub fn new_benchmark(sequence: u64, payload_size: usize, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Benchmark { sequence, payload: vec![0xA5; payload_size] }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Create an out-of-band control message
+    pub fn new_control(control: ControlMessage, sender_id: Uuid) -> Self {
+        Self::with_type(MessageType::Control { control }, sender_id, MessageStatus::Sent)
+    }
+
+    /// Limit how long after sending the message may still be delivered
+    pub fn with_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.ttl = Some(ttl.as_millis() as u64);
+        self
+    }
+
+    /// Check if the message outlived its TTL by the given time
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        match self.ttl {
+            Some(ttl) => (now - self.timestamp).num_milliseconds() > ttl as i64,
+            None => false,
         }
     }
 
+    /// Check if the message outlived its TTL
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(Utc::now())
+    }
+
     /// Get the content size estimate for the message
     pub fn size_estimate(&self) -> usize {
         match &self.message_type {
@@ -188,6 +348,12 @@ impl Message {
             MessageType::KeyExchange { public_key } => public_key.len(),
             MessageType::Disconnect { reason } => reason.len(),
             MessageType::Acknowledgment { .. } => 16, // UUID size
+            MessageType::Benchmark { payload, .. } => payload.len(),
+            MessageType::Control { .. } => 0,
+            MessageType::Reaction { emoji, .. } => 16 + emoji.len(),
+            MessageType::Edit { new_content, .. } => 16 + new_content.len(),
+            MessageType::ReadReceipt { .. } => 16,
+            MessageType::Typing { .. } => 1,
         }
     }
 
@@ -196,12 +362,40 @@ impl Message {
         matches!(self.message_type, MessageType::System { .. })
     }
 
+    /// Check if the message is a typing indicator
+    pub fn is_typing(&self) -> bool {
+        matches!(self.message_type, MessageType::Typing { .. })
+    }
+
+    /// Check if the message is benchmark traffic
+    pub fn is_benchmark(&self) -> bool {
+        matches!(self.message_type, MessageType::Benchmark { .. })
+    }
+
+    /// Check if the message belongs on the control channel
+    pub fn is_control(&self) -> bool {
+        matches!(self.message_type, MessageType::Control { .. })
+    }
+
     /// Check if the message is a file transfer
     pub fn is_file(&self) -> bool {
         matches!(self.message_type, MessageType::File { .. })
     }
 }
 
+/// Preview of a message as it would be sent over the wire
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessagePreview {
+    pub content: String,
+    pub wire_size: usize,
+    pub max_message_size: usize,
+    /// Length in characters, checked against `max_text_length`
+    pub text_length: usize,
+    pub max_text_length: usize,
+    pub encrypted: bool,
+    pub fits: bool,
+}
+
 /// Connection types for the application
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ConnectionType {
@@ -225,6 +419,7 @@ pub struct ServerInfo {
     pub id: Uuid,
     pub address: String,
     pub port: u16,
+    pub addresses: Vec<String>,
     pub status: ConnectionStatus,
     pub started_at: DateTime<Utc>,
     pub client_count: u32,
@@ -253,6 +448,73 @@ pub struct NetworkStats {
     pub last_activity: Option<DateTime<Utc>>,
 }
 
+/// Result of benchmarking the link to a server
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct BenchmarkReport {
+    pub duration_ms: u64,
+    pub messages: u64,
+    pub bytes_sent: u64,
+    pub throughput_bytes_per_sec: f64,
+    pub messages_per_sec: f64,
+    pub rtt_p50_ms: f64,
+    pub rtt_p90_ms: f64,
+    pub rtt_p99_ms: f64,
+}
+
+/// Per-peer network statistics
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PeerStats {
+    pub peer_id: Uuid,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub connected_duration: u64, // in seconds
+    pub last_activity: Option<DateTime<Utc>>,
+    pub pending_acks: usize,
+}
+
+impl PeerStats {
+    /// Create empty statistics for a peer
+    pub fn new(peer_id: Uuid) -> Self {
+        Self {
+            peer_id,
+            ..Default::default()
+        }
+    }
+
+    /// Record a message sent to the peer
+    pub fn record_sent(&mut self, bytes: usize) {
+        self.messages_sent += 1;
+        self.bytes_sent += bytes as u64;
+        self.last_activity = Some(Utc::now());
+    }
+
+    /// Record a message received from the peer
+    pub fn record_received(&mut self, bytes: usize) {
+        self.messages_received += 1;
+        self.bytes_received += bytes as u64;
+        self.last_activity = Some(Utc::now());
+    }
+}
+
+/// Outcome of an incoming connection attempt
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum ConnectionOutcome {
+    Accepted,
+    Blocklisted,
+    AuthenticationFailed,
+    OverCapacity,
+}
+
+/// Audit record of an incoming connection attempt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionAttempt {
+    pub address: String,
+    pub timestamp: DateTime<Utc>,
+    pub outcome: ConnectionOutcome,
+}
+
 /// File transfer information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileTransferInfo {
@@ -308,6 +570,9 @@ pub struct MessageFilter {
     pub start_date: Option<DateTime<Utc>>,
     pub end_date: Option<DateTime<Utc>>,
     pub status: Option<Vec<MessageStatus>>,
+    /// Only replies to this message
+    #[serde(default)]
+    pub reply_to: Option<Uuid>,
     pub limit: Option<usize>,
     pub offset: Option<usize>,
 }
@@ -322,6 +587,47 @@ pub struct MessageSearch {
     pub filter: Option<MessageFilter>,
 }
 
+/// A single match emitted by a streaming search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResultEvent {
+    pub search_id: Uuid,
+    pub message: Message,
+}
+
+/// Emitted once a streaming search finishes or is cancelled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchCompleteEvent {
+    pub search_id: Uuid,
+    pub total: usize,
+    pub cancelled: bool,
+}
+
+/// Emitted as `message-status-changed` when a peer reports on one of our messages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageStatusEvent {
+    pub message_id: Uuid,
+    pub status: MessageStatus,
+}
+
+/// Emitted as `peer-typing` when a peer starts or stops typing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TypingEvent {
+    pub sender_id: Uuid,
+    pub is_typing: bool,
+}
+
+/// Emitted as `file-transfer-progress` after each chunk of an outgoing file and once
+/// more when the transfer completes or fails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTransferProgressEvent {
+    pub transfer_id: Uuid,
+    pub bytes_sent: u64,
+    pub total_bytes: u64,
+    pub progress: f32, // 0.0 to 1.0
+    pub status: FileTransferStatus,
+    pub error: Option<String>,
+}
+
 /// Export format for messages
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum ExportFormat {
@@ -331,6 +637,18 @@ pub enum ExportFormat {
     Html,
 }
 
+/// How timestamps are written in JSON exports
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum TimestampFormat {
+    /// RFC 3339 string, e.g. "2024-01-01T12:00:00Z"
+    #[default]
+    Rfc3339,
+    /// Seconds since the Unix epoch
+    EpochSeconds,
+    /// Milliseconds since the Unix epoch
+    EpochMillis,
+}
+
 /// Export options
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExportOptions {
@@ -339,4 +657,35 @@ pub struct ExportOptions {
     pub include_system_messages: bool,
     pub date_range: Option<(DateTime<Utc>, DateTime<Utc>)>,
     pub filter: Option<MessageFilter>,
+    /// Only applies to JSON; the other formats write timestamps as RFC 3339
+    #[serde(default)]
+    pub timestamp_format: TimestampFormat,
+}
+
+/// How an imported message is handled when its id is already stored with different content
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum ImportConflictPolicy {
+    /// Keep the stored message
+    #[default]
+    Skip,
+    /// Replace the stored message with the imported one
+    Overwrite,
+    /// Keep whichever copy has the later timestamp
+    KeepNewest,
+}
+
+/// Message count for one bucket of a histogram
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HistogramBucket {
+    /// Start of the bucket, inclusive
+    pub start: DateTime<Utc>,
+    pub count: usize,
+}
+
+/// Outcome of a message import
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub skipped: usize,
+    pub overwritten: usize,
 }